@@ -0,0 +1,151 @@
+//! A simple framed protocol for streaming samples to a remote collector.
+//!
+//! A stream starts with a handshake: the magic bytes, the protocol version as a
+//! little endian `u32`, then the length of the access token as a `u16` followed by the
+//! token itself. The collector answers with a single [`HandshakeReply`] byte.
+//!
+//! Samples then follow in blocks, each a little endian `u32` sample count followed by
+//! that many packed samples. An empty block ends the stream, after which the collector
+//! answers with the number of samples it accepted as a little endian `u64`.
+
+use crate::PackedSample;
+use std::io::{self, Read, Write};
+
+pub const MAGIC: [u8; 4] = *b"TTSB";
+pub const VERSION: u32 = 1;
+/// Largest block accepted, keeping the memory a single block can claim bounded.
+pub const MAX_BLOCK_SAMPLES: usize = 1 << 16;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeReply {
+    Accepted = 0,
+    InvalidToken = 1,
+    UnsupportedVersion = 2,
+}
+
+impl HandshakeReply {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(HandshakeReply::Accepted),
+            1 => Some(HandshakeReply::InvalidToken),
+            2 => Some(HandshakeReply::UnsupportedVersion),
+            _ => None,
+        }
+    }
+}
+
+/// The handshake opening a stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u32,
+    pub token: String,
+}
+
+impl Handshake {
+    pub fn new(token: &str) -> Self {
+        Handshake {
+            version: VERSION,
+            token: token.to_string(),
+        }
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let token_len = u16::try_from(self.token.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "access token is too long"))?;
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        writer.write_all(&token_len.to_le_bytes())?;
+        writer.write_all(self.token.as_bytes())
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a sample stream",
+            ));
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let mut token_len = [0; 2];
+        reader.read_exact(&mut token_len)?;
+        let mut token = vec![0; u16::from_le_bytes(token_len) as usize];
+        reader.read_exact(&mut token)?;
+        Ok(Handshake {
+            version: u32::from_le_bytes(version),
+            token: String::from_utf8_lossy(&token).into_owned(),
+        })
+    }
+}
+
+/// A block of samples as sent over a stream.
+#[derive(Clone, Debug, Default)]
+pub struct SampleBlock {
+    pub samples: Vec<PackedSample>,
+}
+
+impl SampleBlock {
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        assert!(self.samples.len() <= MAX_BLOCK_SAMPLES);
+        writer.write_all(&(self.samples.len() as u32).to_le_bytes())?;
+        writer.write_all(bytemuck::cast_slice(&self.samples))
+    }
+
+    /// Writes the empty block ending a stream.
+    pub fn write_end(writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&0u32.to_le_bytes())
+    }
+
+    /// Reads the next block, returning `None` at the end of the stream.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        if len > MAX_BLOCK_SAMPLES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "block of {} samples exceeds the limit of {}",
+                    len, MAX_BLOCK_SAMPLES
+                ),
+            ));
+        }
+        let mut samples = vec![PackedSample::default(); len];
+        reader.read_exact(bytemuck::cast_slice_mut(&mut samples))?;
+        Ok(Some(SampleBlock { samples }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_round_trip() {
+        let mut samples = vec![PackedSample::default(); 3];
+        samples[1].set_eval(Some(42));
+        let block = SampleBlock { samples };
+
+        let mut bytes = Vec::new();
+        Handshake::new("secret").write_to(&mut bytes).unwrap();
+        block.write_to(&mut bytes).unwrap();
+        SampleBlock::write_end(&mut bytes).unwrap();
+
+        let mut reader = bytes.as_slice();
+        assert_eq!(
+            Handshake::read_from(&mut reader).unwrap(),
+            Handshake::new("secret")
+        );
+        let read = SampleBlock::read_from(&mut reader).unwrap().unwrap();
+        assert_eq!(read.samples.len(), 3);
+        assert_eq!(read.samples[1].eval(), Some(42));
+        assert!(SampleBlock::read_from(&mut reader).unwrap().is_none());
+        assert!(reader.is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+//! Conversions between dama's positions and those of other chess libraries, each behind
+//! a feature named after the library, so samples can be made from them without going
+//! through FEN strings.
+
+use crate::Sample;
+use dama::{InvalidPositionError, Outcome, Position};
+
+/// A position of another chess library which samples can be made of.
+pub trait ForeignPosition {
+    /// Sets up the same position in dama, which checks it like one read from a FEN.
+    fn to_dama(&self) -> Result<Position, InvalidPositionError>;
+}
+
+impl Sample {
+    #[inline]
+    pub fn from_foreign(
+        position: &impl ForeignPosition,
+        outcome: Outcome,
+        eval: Option<i16>,
+    ) -> Result<Self, InvalidPositionError> {
+        Ok(Sample {
+            position: position.to_dama()?,
+            outcome,
+            eval,
+        })
+    }
+}
+
+/// Conversions to and from [shakmaty](::shakmaty), with standard castling only.
+#[cfg(feature = "shakmaty")]
+pub mod shakmaty {
+    use super::ForeignPosition;
+    use ::shakmaty::{
+        self as sm, CastlingMode, CastlingSide, Chess, FromSetup, Position as _, PositionError,
+        Role,
+    };
+    use dama::{
+        position::Setup, Color, InvalidPositionError, Outcome, Piece, Position, Rank, Square,
+    };
+    use std::num::NonZeroU32;
+
+    impl ForeignPosition for Chess {
+        fn to_dama(&self) -> Result<Position, InvalidPositionError> {
+            let mut setup = Setup::new_empty();
+            for (square, piece) in self.board().iter() {
+                setup.put_piece(
+                    square_of(square),
+                    color_of(piece.color),
+                    piece_of(piece.role),
+                );
+            }
+            for color in [sm::Color::White, sm::Color::Black] {
+                let rook_file = |side| {
+                    self.castles()
+                        .rook(color, side)
+                        .map(|rook| square_of(rook).file())
+                };
+                let castling = &mut setup.castling[color_of(color)];
+                castling.king_side = rook_file(CastlingSide::KingSide);
+                castling.queen_side = rook_file(CastlingSide::QueenSide);
+            }
+            setup
+                .set_side_to_move(color_of(self.turn()))
+                .set_en_passant(self.maybe_ep_square().map(square_of))
+                .set_halfmove_clock(self.halfmoves())
+                .set_fullmove_number(self.fullmoves().get());
+            setup.into_position()
+        }
+    }
+
+    /// The same position in shakmaty. The error is boxed, as shakmaty's holds the whole
+    /// position.
+    pub fn to_shakmaty(position: &Position) -> Result<Chess, Box<PositionError<Chess>>> {
+        let mut setup = sm::Setup::empty();
+        for square in position.occupied() {
+            let (color, piece) = position
+                .color_piece_at(square)
+                .expect("occupied square has a piece");
+            setup.board.set_piece_at(
+                sm::Square::new(square as u32),
+                sm::Piece {
+                    color: sm_color(color),
+                    role: role_of(piece),
+                },
+            );
+        }
+        for color in [Color::White, Color::Black] {
+            let castling = position.castling(color);
+            for file in [castling.king_side, castling.queen_side]
+                .into_iter()
+                .flatten()
+            {
+                let rook = Square::new(file, Rank::back_rank(color));
+                setup.castling_rights.add(sm::Square::new(rook as u32));
+            }
+        }
+        setup.turn = sm_color(position.side_to_move());
+        setup.ep_square = position
+            .en_passant()
+            .map(|square| sm::Square::new(square as u32));
+        setup.halfmoves = position.halfmove_clock();
+        setup.fullmoves = position
+            .fullmove_number()
+            .try_into()
+            .unwrap_or(NonZeroU32::MIN);
+        Chess::from_setup(setup, CastlingMode::Standard).map_err(Box::new)
+    }
+
+    #[inline]
+    pub fn outcome_of(outcome: sm::Outcome) -> Outcome {
+        match outcome {
+            sm::Outcome::Decisive { winner } => Outcome::Winner(color_of(winner)),
+            sm::Outcome::Draw => Outcome::Draw,
+        }
+    }
+
+    #[inline]
+    fn square_of(square: sm::Square) -> Square {
+        Square::try_from_index(square as usize).expect("shakmaty squares are in range")
+    }
+
+    #[inline]
+    fn color_of(color: sm::Color) -> Color {
+        match color {
+            sm::Color::White => Color::White,
+            sm::Color::Black => Color::Black,
+        }
+    }
+
+    #[inline]
+    fn sm_color(color: Color) -> sm::Color {
+        match color {
+            Color::White => sm::Color::White,
+            Color::Black => sm::Color::Black,
+        }
+    }
+
+    #[inline]
+    fn piece_of(role: Role) -> Piece {
+        match role {
+            Role::Pawn => Piece::Pawn,
+            Role::Knight => Piece::Knight,
+            Role::Bishop => Piece::Bishop,
+            Role::Rook => Piece::Rook,
+            Role::Queen => Piece::Queen,
+            Role::King => Piece::King,
+        }
+    }
+
+    #[inline]
+    fn role_of(piece: Piece) -> Role {
+        match piece {
+            Piece::Pawn => Role::Pawn,
+            Piece::Knight => Role::Knight,
+            Piece::Bishop => Role::Bishop,
+            Piece::Rook => Role::Rook,
+            Piece::Queen => Role::Queen,
+            Piece::King => Role::King,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{to_shakmaty, ForeignPosition};
+        use ::shakmaty::{fen::Fen, CastlingMode, Chess};
+        use dama::Position;
+
+        #[test]
+        fn roundtrip_through_shakmaty() {
+            for fen in [
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+                "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 12 40",
+                "r3k3/8/8/8/8/8/8/4K2R b Kq - 3 25",
+            ] {
+                let position = Position::from_fen(fen).unwrap();
+                let chess: Chess = fen
+                    .parse::<Fen>()
+                    .unwrap()
+                    .into_position(CastlingMode::Standard)
+                    .unwrap();
+                assert_eq!(chess.to_dama().unwrap(), position, "{}", fen);
+                assert_eq!(to_shakmaty(&position).unwrap(), chess, "{}", fen);
+            }
+        }
+    }
+}
@@ -2,8 +2,14 @@ use dama::{
     position, ByColor, Color, InvalidPositionError, Outcome, Piece, Position, Rank, Square,
     SquareSet,
 };
+use std::{fmt, iter::FusedIterator, str::FromStr};
 use thiserror::Error;
 
+pub mod block;
+pub mod convert;
+pub mod manifest;
+pub mod shard;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Sample {
     pub position: Position,
@@ -24,6 +30,11 @@ pub struct PackedSample {
     game_outcome: u8,
 }
 
+// Datasets are read and written as arrays of packed samples, so their layout is the
+// file format and must not change by accident.
+const _: () = assert!(std::mem::size_of::<PackedSample>() == 32);
+const _: () = assert!(std::mem::align_of::<PackedSample>() == 1);
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Error)]
 pub enum PackError {
     #[error("no position can have more than 32 pieces.")]
@@ -44,8 +55,225 @@ pub enum UnpackError {
     InvalidPiece,
     #[error("too many pieces in packed position.")]
     TooManyPieces,
+    #[error("castling rook off its back rank in packed position.")]
+    InvalidCastlingRook,
+}
+
+/// The squares of a position's pieces by type and by color, which is all some passes
+/// over samples need of the position.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PieceSets {
+    pieces: [u64; 6],
+    colors: [u64; 2],
+}
+
+impl PieceSets {
+    pub fn of(position: &Position) -> Self {
+        let mut sets = PieceSets::default();
+        for piece in Piece::all() {
+            sets.pieces[piece as usize] = position.pieces(piece).to_bits();
+        }
+        for color in Color::all() {
+            sets.colors[color as usize] = position.colored(color).to_bits();
+        }
+        sets
+    }
+
+    #[inline]
+    pub fn pieces(&self, piece: Piece) -> SquareSet {
+        SquareSet::from(self.pieces[piece as usize])
+    }
+
+    #[inline]
+    pub fn colored(&self, color: Color) -> SquareSet {
+        SquareSet::from(self.colors[color as usize])
+    }
+
+    #[inline]
+    pub fn occupied(&self) -> SquareSet {
+        SquareSet::from(self.colors[0] | self.colors[1])
+    }
+
+    /// The pieces with their colors swapped and the board flipped from top to bottom,
+    /// as the other side would see them.
+    #[inline]
+    pub fn flip_colors(&self) -> Self {
+        PieceSets {
+            pieces: self.pieces.map(u64::swap_bytes),
+            colors: [self.colors[1].swap_bytes(), self.colors[0].swap_bytes()],
+        }
+    }
+
+    /// The pieces with the board flipped from left to right.
+    #[inline]
+    pub fn flip_horizontal(&self) -> Self {
+        let flip = |bits: u64| SquareSet::from(bits).flip_horizontal().to_bits();
+        PieceSets {
+            pieces: self.pieces.map(flip),
+            colors: self.colors.map(flip),
+        }
+    }
+}
+
+/// How a game's outcome was decided when it was cut short instead of played out.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Adjudication {
+    /// The game ended on the board, or how it ended wasn't recorded.
+    #[default]
+    None,
+    /// Both engines agreed that one side was winning for long enough.
+    Resign,
+    /// Both engines agreed that the position was level for long enough.
+    Draw,
+    /// The position reached the tablebases.
+    Tablebase,
 }
 
+/// Whose point of view a dataset's evals are given from. Batches and most tools expect
+/// the side to move's, which is why datasets say otherwise in their manifest.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EvalPerspective {
+    #[default]
+    SideToMove,
+    White,
+}
+
+impl EvalPerspective {
+    pub const ALL: [EvalPerspective; 2] = [EvalPerspective::SideToMove, EvalPerspective::White];
+
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            EvalPerspective::SideToMove => "side-to-move",
+            EvalPerspective::White => "white",
+        }
+    }
+
+    /// Turns an eval given from this perspective into one from the side to move's.
+    #[inline]
+    pub fn to_side_to_move(self, eval: i16, side_to_move: Color) -> i16 {
+        match (self, side_to_move) {
+            (EvalPerspective::White, Color::Black) => eval.saturating_neg(),
+            _ => eval,
+        }
+    }
+
+    /// Turns an eval given from the side to move's perspective into one from this
+    /// perspective.
+    #[inline]
+    pub fn from_side_to_move(self, eval: i16, side_to_move: Color) -> i16 {
+        // Flipping the sign is its own inverse.
+        self.to_side_to_move(eval, side_to_move)
+    }
+}
+
+impl fmt::Display for EvalPerspective {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+#[error("unknown eval perspective `{0}`, expected `side-to-move` or `white`")]
+pub struct UnknownEvalPerspectiveError(pub String);
+
+impl FromStr for EvalPerspective {
+    type Err = UnknownEvalPerspectiveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EvalPerspective::ALL
+            .into_iter()
+            .find(|perspective| perspective.name() == s)
+            .ok_or_else(|| UnknownEvalPerspectiveError(s.to_string()))
+    }
+}
+
+/// Largest eval a packed sample holds either way. `i16::MIN` marks a sample without
+/// one, so the range is symmetric and one short of the type's.
+pub const MAX_EVAL: i16 = i16::MAX;
+
+/// What to do with an eval outside of what a packed sample holds, for engines whose
+/// evals go past `±MAX_EVAL` centipawns.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EvalOverflow {
+    /// Saturates the eval at `±MAX_EVAL`.
+    #[default]
+    Clamp,
+    /// Keeps the sample without an eval.
+    DropEval,
+    /// Leaves the sample out.
+    Skip,
+    /// Fails, for evals which shouldn't ever go that far.
+    Error,
+}
+
+/// An eval as it fits in a packed sample.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FittedEval {
+    Eval(i16),
+    NoEval,
+    Skip,
+}
+
+impl EvalOverflow {
+    pub const ALL: [EvalOverflow; 4] = [
+        EvalOverflow::Clamp,
+        EvalOverflow::DropEval,
+        EvalOverflow::Skip,
+        EvalOverflow::Error,
+    ];
+
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            EvalOverflow::Clamp => "clamp",
+            EvalOverflow::DropEval => "drop-eval",
+            EvalOverflow::Skip => "skip",
+            EvalOverflow::Error => "error",
+        }
+    }
+
+    /// Fits an eval in centipawns into a packed sample.
+    #[inline]
+    pub fn fit(self, eval: i32) -> Result<FittedEval, EvalOutOfRangeError> {
+        let max = MAX_EVAL as i32;
+        if (-max..=max).contains(&eval) {
+            return Ok(FittedEval::Eval(eval as i16));
+        }
+        match self {
+            EvalOverflow::Clamp => Ok(FittedEval::Eval(eval.clamp(-max, max) as i16)),
+            EvalOverflow::DropEval => Ok(FittedEval::NoEval),
+            EvalOverflow::Skip => Ok(FittedEval::Skip),
+            EvalOverflow::Error => Err(EvalOutOfRangeError(eval)),
+        }
+    }
+}
+
+impl fmt::Display for EvalOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+#[error("unknown eval overflow policy `{0}`, expected `clamp`, `drop-eval`, `skip` or `error`")]
+pub struct UnknownEvalOverflowError(pub String);
+
+impl FromStr for EvalOverflow {
+    type Err = UnknownEvalOverflowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EvalOverflow::ALL
+            .into_iter()
+            .find(|overflow| overflow.name() == s)
+            .ok_or_else(|| UnknownEvalOverflowError(s.to_string()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+#[error("eval of {0} centipawns is out of the range of packed samples")]
+pub struct EvalOutOfRangeError(pub i32);
+
 impl Sample {
     #[inline]
     pub fn pack(&self) -> Result<PackedSample, PackError> {
@@ -96,6 +324,130 @@ impl PackedSample {
         })
     }
 
+    /// The bytes identifying the position itself, leaving out the move counters,
+    /// evaluation and game outcome.
+    #[inline]
+    pub fn position_key(&self) -> [u8; 26] {
+        let mut key = [0; 26];
+        key[..16].copy_from_slice(&self.pieces.0);
+        key[16..24].copy_from_slice(&self.occupied);
+        key[24] = self.en_passant;
+        key[25] = self.side_to_move;
+        key
+    }
+
+    /// How the outcome of the sample's game was adjudicated, kept in the bits of the
+    /// outcome byte above the outcome itself.
+    #[inline]
+    pub fn adjudication(&self) -> Adjudication {
+        match (self.game_outcome & ADJUDICATION_MASK) >> ADJUDICATION_SHIFT {
+            0b01 => Adjudication::Resign,
+            0b10 => Adjudication::Draw,
+            0b11 => Adjudication::Tablebase,
+            _ => Adjudication::None,
+        }
+    }
+
+    /// Packs `sample` over this one, keeping the adjudication of its game, which an
+    /// unpacked [`Sample`] doesn't hold.
+    #[inline]
+    pub fn repack(&mut self, sample: &Sample) -> Result<(), PackError> {
+        let adjudication = self.adjudication();
+        *self = sample.pack()?;
+        self.set_adjudication(adjudication);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn set_adjudication(&mut self, adjudication: Adjudication) {
+        let bits = match adjudication {
+            Adjudication::None => 0b00,
+            Adjudication::Resign => 0b01,
+            Adjudication::Draw => 0b10,
+            Adjudication::Tablebase => 0b11,
+        };
+        self.game_outcome = self.game_outcome & !ADJUDICATION_MASK | bits << ADJUDICATION_SHIFT;
+    }
+
+    #[inline]
+    pub fn eval(&self) -> Option<i16> {
+        match i16::from_le_bytes(self.eval) {
+            NO_EVAL => None,
+            eval => Some(eval),
+        }
+    }
+
+    #[inline]
+    pub fn set_eval(&mut self, eval: Option<i16>) {
+        self.eval = eval.unwrap_or(NO_EVAL).to_le_bytes();
+    }
+
+    // The accessors below read single fields straight from the packed bytes, for passes
+    // over many samples that don't need the whole position.
+
+    #[inline]
+    pub fn side_to_move(&self) -> Result<Color, UnpackError> {
+        Color::try_from_index(self.side_to_move as usize).ok_or(UnpackError::InvalidSideToMove)
+    }
+
+    #[inline]
+    pub fn outcome(&self) -> Result<Outcome, UnpackError> {
+        match self.game_outcome & OUTCOME_MASK {
+            0b11 => Ok(Outcome::Draw),
+            0b10 => Ok(Outcome::Winner(Color::White)),
+            0b01 => Ok(Outcome::Winner(Color::Black)),
+            _ => Err(UnpackError::InvalidOutcome),
+        }
+    }
+
+    #[inline]
+    pub fn occupied(&self) -> SquareSet {
+        SquareSet::from(u64::from_le_bytes(self.occupied))
+    }
+
+    /// Number of pieces on the board, kings and pawns included.
+    #[inline]
+    pub fn piece_count(&self) -> u32 {
+        u64::from_le_bytes(self.occupied).count_ones()
+    }
+
+    #[inline]
+    pub fn fullmove_number(&self) -> u32 {
+        u16::from_le_bytes(self.fullmove_number) as u32
+    }
+
+    #[inline]
+    pub fn halfmove_clock(&self) -> u32 {
+        self.halfmove_clock as u32
+    }
+
+    /// The pieces on the board as `(square, color, piece, is_castling_rook)` in square
+    /// order, decoded without setting up the position. Like [`PackedSample::piece_sets`]
+    /// it doesn't check that the position is legal, but a corrupt piece encoding or more
+    /// than 32 pieces yields an error, after which the iterator ends.
+    #[inline]
+    pub fn pieces(&self) -> Pieces<'_> {
+        let occupied = u64::from_le_bytes(self.occupied);
+        Pieces {
+            pieces: &self.pieces,
+            occupied,
+            index: 0,
+            error: (occupied.count_ones() > 32).then_some(UnpackError::TooManyPieces),
+        }
+    }
+
+    /// Decodes where each piece stands without setting up the position, so unlike
+    /// [`PackedSample::unpack`] it doesn't check that the position is legal.
+    pub fn piece_sets(&self) -> Result<PieceSets, UnpackError> {
+        let mut sets = PieceSets::default();
+        for piece in self.pieces() {
+            let (square, color, piece, _) = piece?;
+            sets.pieces[piece as usize] |= 1 << square as u32;
+            sets.colors[color as usize] |= 1 << square as u32;
+        }
+        Ok(sets)
+    }
+
     pub fn unpack(&self) -> Result<Sample, UnpackError> {
         let mut setup = position::Setup::new_empty();
 
@@ -105,29 +457,23 @@ impl PackedSample {
             setup.set_en_passant(Some(en_passant));
         }
 
-        let side_to_move = Color::try_from_index(self.side_to_move as usize)
-            .ok_or(UnpackError::InvalidSideToMove)?;
-
-        setup.set_side_to_move(side_to_move)
-            .set_fullmove_number(u16::from_le_bytes(self.fullmove_number) as u32)
-            .set_halfmove_clock(self.halfmove_clock as u32);
-
-        let occupied = SquareSet::from(u64::from_le_bytes(self.occupied));
-        if occupied.count() > 32 {
-            return Err(UnpackError::TooManyPieces);
-        }
+        setup.set_side_to_move(self.side_to_move()?)
+            .set_fullmove_number(self.fullmove_number())
+            .set_halfmove_clock(self.halfmove_clock());
 
         let mut saw_king = ByColor::default();
-        for (n, square) in occupied.iter().enumerate() {
-            let (color, piece, is_castling_rook) = self
-                .pieces
-                .get(n)
-                .ok_or(UnpackError::InvalidPiece)?;
+        for piece in self.pieces() {
+            let (square, color, piece, is_castling_rook) = piece?;
 
             if piece == Piece::King {
                 saw_king[color] = true;
             }
             if is_castling_rook {
+                // Castling rights are only checked against the rooks on the back rank,
+                // so a corrupt file could otherwise hand out rights to any file.
+                if square.rank() != Rank::back_rank(color) {
+                    return Err(UnpackError::InvalidCastlingRook);
+                }
                 if saw_king[color] {
                     setup.castling[color].king_side = Some(square.file());
                 } else {
@@ -141,26 +487,64 @@ impl PackedSample {
             .into_position()
             .map_err(UnpackError::InvalidPosition)?;
 
-        let outcome = match self.game_outcome {
-            0b11 => Outcome::Draw,
-            0b10 => Outcome::Winner(Color::White),
-            0b01 => Outcome::Winner(Color::Black),
-            _ => return Err(UnpackError::InvalidOutcome),
-        };
-
-        let eval = match i16::from_le_bytes(self.eval) {
-            NO_EVAL => None,
-            eval => Some(eval),
-        };
-
         Ok(Sample {
             position,
-            outcome,
-            eval,
+            outcome: self.outcome()?,
+            eval: self.eval(),
         })
     }
 }
 
+/// Iterator over the pieces of a packed sample, see [`PackedSample::pieces`].
+#[derive(Clone, Debug)]
+pub struct Pieces<'a> {
+    pieces: &'a PackedPieces,
+    /// Squares whose pieces are still to be decoded.
+    occupied: u64,
+    /// Index into `pieces` of the next piece.
+    index: usize,
+    /// An error found up front, yielded in place of the first piece.
+    error: Option<UnpackError>,
+}
+
+impl Iterator for Pieces<'_> {
+    type Item = Result<(Square, Color, Piece, bool), UnpackError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            self.occupied = 0;
+            return Some(Err(err));
+        }
+        if self.occupied == 0 {
+            return None;
+        }
+        let square = Square::try_from_index(self.occupied.trailing_zeros() as usize)?;
+        self.occupied &= self.occupied - 1;
+        let index = self.index;
+        self.index += 1;
+        match self.pieces.get(index) {
+            Some((color, piece, is_castling_rook)) => {
+                Some(Ok((square, color, piece, is_castling_rook)))
+            }
+            None => {
+                self.occupied = 0;
+                Some(Err(UnpackError::InvalidPiece))
+            }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.error {
+            Some(_) => (1, Some(1)),
+            None => (0, Some(self.occupied.count_ones() as usize)),
+        }
+    }
+}
+
+impl FusedIterator for Pieces<'_> {}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
 struct PackedPieces([u8; 16]);
@@ -191,13 +575,16 @@ impl PackedPieces {
         };
         let (piece, is_castling_rook) = match value & PIECE_MASK {
             CASTLING_ROOK => (Some(Piece::Rook), true),
-            piece => (Piece::try_from_index(piece as usize - 1), false),
+            piece => ((piece as usize).checked_sub(1).and_then(Piece::try_from_index), false),
         };
         Some((color, piece?, is_castling_rook))
     }
 }
 
 const NO_EVAL: i16 = i16::MIN;
+const OUTCOME_MASK: u8 = 0b0011;
+const ADJUDICATION_MASK: u8 = 0b1100;
+const ADJUDICATION_SHIFT: u8 = 2;
 const BLACK: u8 = 0b0000;
 const WHITE: u8 = 0b1000;
 const COLOR_MASK: u8 = 0b1000;
@@ -219,8 +606,8 @@ fn encode_piece(piece: Piece) -> u8 {
 
 #[cfg(test)]
 mod tests {
-    use super::Sample;
-    use dama::{Color, Outcome, Position, SanMove};
+    use super::{Adjudication, EvalOverflow, FittedEval, PackedSample, PieceSets, Sample};
+    use dama::{Color, Outcome, Piece, Position, Rank, SanMove};
     use rand::{seq::IndexedRandom, Rng, SeedableRng};
     use std::str::FromStr;
 
@@ -274,6 +661,104 @@ mod tests {
         let packed = sample.pack().unwrap();
         let unpacked = packed.unpack().unwrap();
         assert_eq!(sample, unpacked);
+
+        assert_eq!(packed.side_to_move(), Ok(position.side_to_move()));
+        assert_eq!(packed.outcome(), Ok(sample.outcome));
+        assert_eq!(packed.eval(), sample.eval);
+        assert_eq!(packed.occupied(), position.occupied());
+        assert_eq!(packed.piece_count(), position.occupied().count());
+        assert_eq!(packed.fullmove_number(), position.fullmove_number());
+        assert_eq!(packed.halfmove_clock(), position.halfmove_clock().min(255));
+        assert_eq!(packed.piece_sets(), Ok(PieceSets::of(position)));
+
+        let pieces: Vec<_> = packed.pieces().map(Result::unwrap).collect();
+        assert_eq!(pieces.len() as u32, position.occupied().count());
+        for (square, color, piece, is_castling_rook) in pieces {
+            assert_eq!(position.piece_at(square), Some(piece));
+            assert_eq!(position.color_at(square), Some(color));
+            assert_eq!(
+                is_castling_rook,
+                piece == Piece::Rook
+                    && square.rank() == Rank::back_rank(color)
+                    && position.castling(color).contains(square.file())
+            );
+        }
+    }
+
+    #[test]
+    fn flip_piece_sets() {
+        let position =
+            Position::from_fen("r3k2r/ppp2ppp/2n5/4p3/3qP3/2N5/PPP2PPP/R1BQK2R w KQkq - 0 9")
+                .unwrap();
+        let flipped =
+            Position::from_fen("r1bqk2r/ppp2ppp/2n5/3Qp3/4P3/2N5/PPP2PPP/R3K2R b KQkq - 0 9")
+                .unwrap();
+        let mirrored =
+            Position::from_fen("r2k3r/ppp2ppp/5n2/3p4/3Pq3/5N2/PPP2PPP/R2KQB1R w - - 0 9")
+                .unwrap();
+        let sets = PieceSets::of(&position);
+        assert_eq!(sets.flip_colors(), PieceSets::of(&flipped));
+        assert_eq!(sets.flip_horizontal(), PieceSets::of(&mirrored));
+        assert_eq!(sets.flip_colors().flip_colors(), sets);
+    }
+
+    /// Samples read from disk can hold anything, and decoding them must fail with an
+    /// error rather than panic, which would take down the loader thread.
+    #[test]
+    fn unpack_arbitrary_bytes() {
+        let mut rng = rand_xoshiro::Xoroshiro128Plus::seed_from_u64(0x5D1F0A93C2E4B786);
+        let mut bytes = [0u8; std::mem::size_of::<PackedSample>()];
+        for _ in 0..100_000 {
+            rng.fill(&mut bytes[..]);
+            // Keep the occupancy within 32 pieces half of the time so decoding goes past
+            // the piece count.
+            if rng.random_bool(0.5) {
+                let occupied = rng.random::<u64>() & rng.random::<u64>() & rng.random::<u64>();
+                bytes[16..24].copy_from_slice(&occupied.to_le_bytes());
+            }
+            check_decoding(bytemuck::from_bytes(&bytes));
+        }
+    }
+
+    /// Like [`unpack_arbitrary_bytes`], for samples a few flipped bits away from valid
+    /// ones, which get much further into decoding.
+    #[test]
+    fn unpack_corrupted_samples() {
+        let mut rng = rand_xoshiro::Xoroshiro128Plus::seed_from_u64(0x0E8B47D2A6193F5C);
+        for _ in 0..200 {
+            let mut position = Position::new_initial();
+            for _ in 0..80 {
+                let moves = position.legal_moves();
+                if moves.is_empty() {
+                    break;
+                }
+                position.play_unchecked(moves.choose(&mut rng).unwrap());
+
+                let sample = Sample {
+                    position: position.clone(),
+                    outcome: random_outcome(&mut rng),
+                    eval: random_eval(&mut rng),
+                };
+                let mut packed = sample.pack().unwrap();
+                let bytes: &mut [u8] = bytemuck::bytes_of_mut(&mut packed);
+                for _ in 0..rng.random_range(1..=3) {
+                    let bit = rng.random_range(0..8 * bytes.len());
+                    bytes[bit / 8] ^= 1 << (bit % 8);
+                }
+                check_decoding(&packed);
+            }
+        }
+    }
+
+    fn check_decoding(packed: &PackedSample) {
+        let unpacked = packed.unpack();
+        let sets = packed.piece_sets();
+        if let Ok(sample) = &unpacked {
+            assert_eq!(sets, Ok(PieceSets::of(&sample.position)));
+            assert_eq!(packed.side_to_move(), Ok(sample.position.side_to_move()));
+            assert_eq!(packed.outcome(), Ok(sample.outcome));
+        }
+        let _ = (packed.eval(), packed.adjudication(), packed.position_key());
     }
 
     fn random_eval(rng: &mut impl Rng) -> Option<i16> {
@@ -293,6 +778,93 @@ mod tests {
         .unwrap()
     }
 
+    #[test]
+    fn position_key_ignores_eval_and_counters() {
+        let mut position = Position::new_initial();
+        let first = Sample {
+            position: position.clone(),
+            outcome: Outcome::Draw,
+            eval: Some(20),
+        }
+        .pack()
+        .unwrap();
+
+        for mv in ["Nf3", "Nf6", "Ng1", "Ng8"].map(SanMove::from_str).map(Result::unwrap) {
+            position.play(&mv).unwrap();
+        }
+        let mut second = Sample {
+            position,
+            outcome: Outcome::Winner(Color::White),
+            eval: None,
+        }
+        .pack()
+        .unwrap();
+        assert_eq!(first.position_key(), second.position_key());
+
+        second.set_eval(Some(-15));
+        assert_eq!(second.eval(), Some(-15));
+        second.set_eval(None);
+        assert_eq!(second.eval(), None);
+
+        let mut position = Position::new_initial();
+        position.play(&SanMove::from_str("Nf3").unwrap()).unwrap();
+        let third = Sample {
+            position,
+            outcome: Outcome::Draw,
+            eval: Some(20),
+        }
+        .pack()
+        .unwrap();
+        assert_ne!(first.position_key(), third.position_key());
+    }
+
+    #[test]
+    fn adjudication_keeps_outcome() {
+        let sample = Sample {
+            position: Position::new_initial(),
+            outcome: Outcome::Winner(Color::Black),
+            eval: Some(-900),
+        };
+        let mut packed = sample.pack().unwrap();
+        assert_eq!(packed.adjudication(), Adjudication::None);
+
+        for adjudication in [
+            Adjudication::Resign,
+            Adjudication::Draw,
+            Adjudication::Tablebase,
+            Adjudication::None,
+        ] {
+            packed.set_adjudication(adjudication);
+            assert_eq!(packed.adjudication(), adjudication);
+            assert_eq!(packed.unpack().unwrap(), sample);
+        }
+    }
+
+    #[test]
+    fn repack_keeps_adjudication() {
+        let sample = Sample {
+            position: Position::new_initial(),
+            outcome: Outcome::Winner(Color::White),
+            eval: Some(250),
+        };
+        for adjudication in [
+            Adjudication::None,
+            Adjudication::Resign,
+            Adjudication::Draw,
+            Adjudication::Tablebase,
+        ] {
+            let mut packed = sample.pack().unwrap();
+            packed.set_adjudication(adjudication);
+
+            let mut relabeled = packed.unpack().unwrap();
+            relabeled.outcome = Outcome::Draw;
+            relabeled.eval = Some(0);
+            packed.repack(&relabeled).unwrap();
+            assert_eq!(packed.adjudication(), adjudication);
+            assert_eq!(packed.unpack().unwrap(), relabeled);
+        }
+    }
+
     #[test]
     fn pack_roundtrip_game() {
         #[rustfmt::skip]
@@ -331,4 +903,27 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn fit_evals_out_of_range() {
+        for overflow in EvalOverflow::ALL {
+            assert_eq!(overflow.fit(-32767), Ok(FittedEval::Eval(-32767)));
+            assert_eq!(overflow.fit(123), Ok(FittedEval::Eval(123)));
+        }
+        assert_eq!(EvalOverflow::Clamp.fit(-32768), Ok(FittedEval::Eval(-32767)));
+        assert_eq!(EvalOverflow::Clamp.fit(100000), Ok(FittedEval::Eval(32767)));
+        assert_eq!(EvalOverflow::DropEval.fit(-40000), Ok(FittedEval::NoEval));
+        assert_eq!(EvalOverflow::Skip.fit(40000), Ok(FittedEval::Skip));
+        assert!(EvalOverflow::Error.fit(40000).is_err());
+
+        let mut sample = Sample {
+            position: Position::new_initial(),
+            outcome: Outcome::Draw,
+            eval: None,
+        };
+        if let Ok(FittedEval::Eval(eval)) = EvalOverflow::Clamp.fit(i32::MIN) {
+            sample.eval = Some(eval);
+        }
+        assert_eq!(sample.pack().unwrap().unpack().unwrap(), sample);
+    }
 }
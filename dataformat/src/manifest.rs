@@ -0,0 +1,686 @@
+//! Manifests describing how a dataset was made and what it should contain.
+//!
+//! A manifest is a small TOML file stored as `manifest.toml` inside a sharded dataset
+//! directory, or next to a single-file dataset as `<file>.manifest.toml`. Only the
+//! subset of TOML written by [`Manifest::to_toml`] is understood: top-level keys, a
+//! `[generation]` table of strings and `[[files]]`, `[[sources]]` and `[[history]]`
+//! arrays of tables.
+
+use std::{
+    fmt::{self, Write as _},
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::EvalPerspective;
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.toml";
+pub const MANIFEST_VERSION: u32 = 1;
+/// Generation setting giving the [`EvalPerspective`] of a dataset's evals.
+pub const EVAL_PERSPECTIVE_SETTING: &str = "eval_perspective";
+/// Generation setting giving the full command line the dataset was made with.
+pub const COMMAND_LINE_SETTING: &str = "command_line";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// Command and settings the dataset was generated with, in the order they were given.
+    pub generation: Vec<(String, String)>,
+    /// Every file of the dataset, named relative to the manifest's directory.
+    pub files: Vec<FileEntry>,
+    /// Inputs the dataset was generated from.
+    pub sources: Vec<Source>,
+    /// How the datasets among the sources were made, and the datasets they were made
+    /// from in turn, back to the first ones with a manifest.
+    pub history: Vec<Step>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileEntry {
+    pub name: String,
+    pub samples: u64,
+    /// Size of the file on disk, compressed or not.
+    pub bytes: u64,
+    /// [`hash_file`] of the file's contents.
+    pub hash: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Source {
+    pub path: String,
+    /// [`hash_file`] of a plain input, or [`Manifest::dataset_hash`] of a dataset input.
+    pub hash: Option<u64>,
+}
+
+/// One invocation in the making of a dataset's sources.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+    /// [`Manifest::dataset_hash`] of the dataset it made.
+    pub hash: u64,
+    /// Only the command for datasets made before command lines were recorded.
+    pub command_line: String,
+    /// Hashes of the inputs it read, as in [`Source::hash`].
+    pub sources: Vec<u64>,
+}
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("line {line} of the manifest: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("unsupported manifest version {0}")]
+    UnsupportedVersion(u64),
+    #[error("`{0}` is listed in the manifest but missing")]
+    MissingFile(String),
+    #[error("`{0}` is part of the dataset but not listed in the manifest")]
+    UnlistedFile(String),
+    #[error("`{name}` holds {actual} bytes but the manifest expects {expected}")]
+    SizeMismatch {
+        name: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("`{0}` doesn't match the hash in the manifest")]
+    HashMismatch(String),
+    #[error("invalid `{key}` setting: {message}")]
+    InvalidSetting { key: String, message: String },
+}
+
+impl Manifest {
+    /// Where the manifest of a dataset file or shard directory lives.
+    pub fn path_for(dataset: &Path) -> PathBuf {
+        if dataset.is_dir() {
+            dataset.join(MANIFEST_FILE_NAME)
+        } else {
+            let mut name = dataset.as_os_str().to_owned();
+            name.push(".");
+            name.push(MANIFEST_FILE_NAME);
+            PathBuf::from(name)
+        }
+    }
+
+    /// Reads the manifest of a dataset, if it has one.
+    pub fn find(dataset: &Path) -> Result<Option<Self>, ManifestError> {
+        let path = Self::path_for(dataset);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Self::load(&path).map(Some)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ManifestError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_toml())
+    }
+
+    /// Total number of samples in the dataset.
+    pub fn samples(&self) -> u64 {
+        self.files.iter().map(|file| file.samples).sum()
+    }
+
+    /// The value of a generation setting.
+    pub fn setting(&self, key: &str) -> Option<&str> {
+        self.generation
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whose point of view the dataset's evals are given from, the side to move's unless
+    /// the manifest says otherwise.
+    pub fn eval_perspective(&self) -> Result<EvalPerspective, ManifestError> {
+        self.setting(EVAL_PERSPECTIVE_SETTING)
+            .map_or(Ok(EvalPerspective::SideToMove), |value| {
+                value.parse().map_err(|err: crate::UnknownEvalPerspectiveError| {
+                    ManifestError::InvalidSetting {
+                        key: EVAL_PERSPECTIVE_SETTING.to_string(),
+                        message: err.to_string(),
+                    }
+                })
+            })
+    }
+
+    /// This manifest's dataset as a step of the history of those made from it.
+    pub fn step(&self) -> Step {
+        Step {
+            hash: self.dataset_hash(),
+            command_line: self
+                .setting(COMMAND_LINE_SETTING)
+                .or(self.setting("command"))
+                .unwrap_or_default()
+                .to_string(),
+            sources: self
+                .sources
+                .iter()
+                .filter_map(|source| source.hash)
+                .collect(),
+        }
+    }
+
+    /// A hash identifying the contents of the whole dataset.
+    pub fn dataset_hash(&self) -> u64 {
+        let mut hasher = Hasher::default();
+        for file in &self.files {
+            hasher.update(&file.hash.to_le_bytes());
+        }
+        hasher.finish()
+    }
+
+    /// Checks that `files` are exactly the files listed, with the listed sizes. This is
+    /// cheap enough to run every time a dataset is opened.
+    pub fn check_files(&self, files: &[PathBuf]) -> Result<(), ManifestError> {
+        for entry in &self.files {
+            let path = files
+                .iter()
+                .find(|path| file_name(path) == entry.name)
+                .ok_or_else(|| ManifestError::MissingFile(entry.name.clone()))?;
+            let actual = fs::metadata(path)?.len();
+            if actual != entry.bytes {
+                return Err(ManifestError::SizeMismatch {
+                    name: entry.name.clone(),
+                    expected: entry.bytes,
+                    actual,
+                });
+            }
+        }
+        if let Some(path) = files
+            .iter()
+            .find(|path| !self.files.iter().any(|entry| entry.name == file_name(path)))
+        {
+            return Err(ManifestError::UnlistedFile(file_name(path)));
+        }
+        Ok(())
+    }
+
+    /// Checks the files like [`Manifest::check_files`], then hashes their contents.
+    pub fn verify_files(&self, files: &[PathBuf]) -> Result<(), ManifestError> {
+        self.check_files(files)?;
+        for path in files {
+            let name = file_name(path);
+            let entry = self.files.iter().find(|entry| entry.name == name).unwrap();
+            if hash_file(path)? != entry.hash {
+                return Err(ManifestError::HashMismatch(name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut toml = String::new();
+        writeln!(toml, "version = {}", MANIFEST_VERSION).unwrap();
+        writeln!(toml, "samples = {}", self.samples()).unwrap();
+        writeln!(toml, "hash = {}", quote(&hex(self.dataset_hash()))).unwrap();
+        if !self.generation.is_empty() {
+            writeln!(toml, "\n[generation]").unwrap();
+            for (key, value) in &self.generation {
+                writeln!(toml, "{} = {}", key, quote(value)).unwrap();
+            }
+        }
+        for file in &self.files {
+            writeln!(toml, "\n[[files]]").unwrap();
+            writeln!(toml, "name = {}", quote(&file.name)).unwrap();
+            writeln!(toml, "samples = {}", file.samples).unwrap();
+            writeln!(toml, "bytes = {}", file.bytes).unwrap();
+            writeln!(toml, "hash = {}", quote(&hex(file.hash))).unwrap();
+        }
+        for source in &self.sources {
+            writeln!(toml, "\n[[sources]]").unwrap();
+            writeln!(toml, "path = {}", quote(&source.path)).unwrap();
+            if let Some(hash) = source.hash {
+                writeln!(toml, "hash = {}", quote(&hex(hash))).unwrap();
+            }
+        }
+        for step in &self.history {
+            let sources: Vec<_> = step.sources.iter().map(|&hash| hex(hash)).collect();
+            writeln!(toml, "\n[[history]]").unwrap();
+            writeln!(toml, "hash = {}", quote(&hex(step.hash))).unwrap();
+            writeln!(toml, "command_line = {}", quote(&step.command_line)).unwrap();
+            writeln!(toml, "sources = {}", quote(&sources.join(","))).unwrap();
+        }
+        toml
+    }
+
+    pub fn parse(toml: &str) -> Result<Self, ManifestError> {
+        #[derive(PartialEq)]
+        enum Section {
+            Root,
+            Generation,
+            Files,
+            Sources,
+            History,
+        }
+
+        let mut manifest = Manifest::default();
+        let mut section = Section::Root;
+        for (index, line) in toml.lines().enumerate() {
+            let line_number = index + 1;
+            let syntax = |message: String| ManifestError::Syntax {
+                line: line_number,
+                message,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                section = match line {
+                    "[generation]" => Section::Generation,
+                    "[[files]]" => {
+                        manifest.files.push(FileEntry {
+                            name: String::new(),
+                            samples: 0,
+                            bytes: 0,
+                            hash: 0,
+                        });
+                        Section::Files
+                    }
+                    "[[sources]]" => {
+                        manifest.sources.push(Source {
+                            path: String::new(),
+                            hash: None,
+                        });
+                        Section::Sources
+                    }
+                    "[[history]]" => {
+                        manifest.history.push(Step {
+                            hash: 0,
+                            command_line: String::new(),
+                            sources: Vec::new(),
+                        });
+                        Section::History
+                    }
+                    _ => return Err(syntax(format!("unknown table {}", line))),
+                };
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| syntax("expected `key = value`".to_string()))?;
+            let key = key.trim();
+            let value = Value::parse(value.trim()).map_err(syntax)?;
+            match section {
+                Section::Root => match key {
+                    "version" => {
+                        let version = value.integer().map_err(syntax)?;
+                        if version != MANIFEST_VERSION as u64 {
+                            return Err(ManifestError::UnsupportedVersion(version));
+                        }
+                    }
+                    // Both are derived from the files.
+                    "samples" | "hash" => {}
+                    _ => return Err(syntax(format!("unknown key `{}`", key))),
+                },
+                Section::Generation => {
+                    let value = value.string().map_err(syntax)?;
+                    manifest.generation.push((key.to_string(), value));
+                }
+                Section::Files => {
+                    let file = manifest.files.last_mut().unwrap();
+                    match key {
+                        "name" => file.name = value.string().map_err(syntax)?,
+                        "samples" => file.samples = value.integer().map_err(syntax)?,
+                        "bytes" => file.bytes = value.integer().map_err(syntax)?,
+                        "hash" => file.hash = value.hash().map_err(syntax)?,
+                        _ => return Err(syntax(format!("unknown key `{}`", key))),
+                    }
+                }
+                Section::Sources => {
+                    let source = manifest.sources.last_mut().unwrap();
+                    match key {
+                        "path" => source.path = value.string().map_err(syntax)?,
+                        "hash" => source.hash = Some(value.hash().map_err(syntax)?),
+                        _ => return Err(syntax(format!("unknown key `{}`", key))),
+                    }
+                }
+                Section::History => {
+                    let step = manifest.history.last_mut().unwrap();
+                    match key {
+                        "hash" => step.hash = value.hash().map_err(syntax)?,
+                        "command_line" => step.command_line = value.string().map_err(syntax)?,
+                        "sources" => {
+                            step.sources = value
+                                .string()
+                                .map_err(syntax)?
+                                .split(',')
+                                .filter(|hash| !hash.is_empty())
+                                .map(|hash| Value::String(hash.to_string()).hash())
+                                .collect::<Result<_, _>>()
+                                .map_err(syntax)?
+                        }
+                        _ => return Err(syntax(format!("unknown key `{}`", key))),
+                    }
+                }
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_toml())
+    }
+}
+
+enum Value {
+    String(String),
+    Integer(u64),
+}
+
+impl Value {
+    fn parse(value: &str) -> Result<Self, String> {
+        let Some(quoted) = value.strip_prefix('"') else {
+            return value
+                .parse()
+                .map(Value::Integer)
+                .map_err(|_| format!("invalid value `{}`", value));
+        };
+        let mut string = String::new();
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    _ => return Err("invalid escape sequence".to_string()),
+                },
+                Some(ch) => string.push(ch),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+        let rest = chars.as_str().trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(format!("unexpected `{}` after string", rest));
+        }
+        Ok(Value::String(string))
+    }
+
+    fn string(self) -> Result<String, String> {
+        match self {
+            Value::String(string) => Ok(string),
+            Value::Integer(_) => Err("expected a string".to_string()),
+        }
+    }
+
+    fn integer(self) -> Result<u64, String> {
+        match self {
+            Value::Integer(integer) => Ok(integer),
+            Value::String(_) => Err("expected an integer".to_string()),
+        }
+    }
+
+    fn hash(self) -> Result<u64, String> {
+        let string = self.string()?;
+        u64::from_str_radix(&string, 16).map_err(|_| format!("invalid hash `{}`", string))
+    }
+}
+
+/// The history of a dataset made from `sources`: how each of them which is a dataset
+/// with a manifest was made, followed by its own history, each step listed once.
+pub fn history_of(sources: &[Source]) -> Result<Vec<Step>, ManifestError> {
+    let mut history: Vec<Step> = Vec::new();
+    for source in sources {
+        let Some(manifest) = Manifest::find(Path::new(&source.path))? else {
+            continue;
+        };
+        for step in std::iter::once(manifest.step()).chain(manifest.history) {
+            if !history.iter().any(|known| known.hash == step.hash) {
+                history.push(step);
+            }
+        }
+    }
+    Ok(history)
+}
+
+/// Options whose values are secrets, left out of recorded command lines since those
+/// are copied into every manifest downstream.
+const SECRET_OPTIONS: [&str; 1] = ["--token"];
+
+/// Stands in for the values of [`SECRET_OPTIONS`].
+const REDACTED: &str = "<redacted>";
+
+/// The command line of the running program, quoted the way a POSIX shell would take
+/// it back, with the values of secret options redacted.
+pub fn command_line() -> String {
+    format_command_line(std::env::args())
+}
+
+fn format_command_line(args: impl IntoIterator<Item = String>) -> String {
+    let mut secret_next = false;
+    args.into_iter()
+        .map(|arg| {
+            let arg = if std::mem::take(&mut secret_next) {
+                REDACTED.to_string()
+            } else if SECRET_OPTIONS.contains(&arg.as_str()) {
+                secret_next = true;
+                arg
+            } else {
+                match arg.split_once('=') {
+                    Some((option, _)) if SECRET_OPTIONS.contains(&option) => {
+                        format!("{}={}", option, REDACTED)
+                    }
+                    _ => arg,
+                }
+            };
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || "-_./=:,+@%".contains(ch));
+            if plain {
+                arg
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// A fast non-cryptographic hash of a stream of bytes, catching corruption and
+/// truncation rather than tampering.
+#[derive(Clone, Debug)]
+pub struct Hasher {
+    state: u64,
+    len: u64,
+    pending: [u8; 8],
+    pending_len: usize,
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Hasher {
+            state: 0x9e3779b97f4a7c15,
+            len: 0,
+            pending: [0; 8],
+            pending_len: 0,
+        }
+    }
+}
+
+impl Hasher {
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+        if self.pending_len > 0 {
+            let take = (8 - self.pending_len).min(bytes.len());
+            self.pending[self.pending_len..self.pending_len + take].copy_from_slice(&bytes[..take]);
+            self.pending_len += take;
+            bytes = &bytes[take..];
+            if self.pending_len < 8 {
+                return;
+            }
+            self.state = mix(self.state ^ u64::from_le_bytes(self.pending));
+            self.pending_len = 0;
+        }
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.state = mix(self.state ^ u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        let rest = words.remainder();
+        self.pending[..rest.len()].copy_from_slice(rest);
+        self.pending_len = rest.len();
+    }
+
+    pub fn finish(&self) -> u64 {
+        let mut last = [0; 8];
+        last[..self.pending_len].copy_from_slice(&self.pending[..self.pending_len]);
+        mix(mix(self.state ^ u64::from_le_bytes(last)) ^ self.len)
+    }
+}
+
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Hashes the contents of a file with [`Hasher`].
+pub fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = Hasher::default();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_round_trip() {
+        let manifest = Manifest {
+            generation: vec![
+                ("command".to_string(), "selfplay".to_string()),
+                ("engine".to_string(), "./teras \"dev\"".to_string()),
+            ],
+            files: vec![FileEntry {
+                name: "shard-0000.bin".to_string(),
+                samples: 3,
+                bytes: 96,
+                hash: 0xdeadbeef,
+            }],
+            sources: vec![Source {
+                path: "games.pgn".to_string(),
+                hash: None,
+            }],
+            history: vec![
+                Step {
+                    hash: 0x1234,
+                    command_line: "datatools extract 'my games.pgn' -o games.bin".to_string(),
+                    sources: vec![0xabcd, 0xef],
+                },
+                Step {
+                    hash: 0x5678,
+                    command_line: "selfplay".to_string(),
+                    sources: vec![],
+                },
+            ],
+        };
+        assert_eq!(Manifest::parse(&manifest.to_toml()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn parse_reports_errors() {
+        assert!(matches!(
+            Manifest::parse("version = 2"),
+            Err(ManifestError::UnsupportedVersion(2))
+        ));
+        for (toml, error_line) in [
+            ("version = 1\n[settings]", 2),
+            ("\n\n[generation]\ncommand", 4),
+            ("[[files]]\nname = \"a.bin\"\nsize = 3", 3),
+            ("[[sources]]\nhash = \"xyz\"", 2),
+            ("[generation]\ncommand = \"unterminated", 2),
+        ] {
+            match Manifest::parse(toml) {
+                Err(ManifestError::Syntax { line, .. }) => assert_eq!(line, error_line, "{}", toml),
+                other => panic!("unexpected result {:?} for {:?}", other, toml),
+            }
+        }
+    }
+
+    #[test]
+    fn command_lines_redact_secrets() {
+        let args = [
+            "datatools",
+            "collect",
+            "--token",
+            "hunter2",
+            "--token=hunter2",
+            "-o",
+            "my games.bin",
+        ];
+        assert_eq!(
+            format_command_line(args.map(String::from)),
+            "datatools collect --token '<redacted>' '--token=<redacted>' -o 'my games.bin'"
+        );
+    }
+
+    #[test]
+    fn eval_perspective_defaults_to_side_to_move() {
+        let mut manifest = Manifest::default();
+        assert_eq!(
+            manifest.eval_perspective().unwrap(),
+            EvalPerspective::SideToMove
+        );
+        manifest
+            .generation
+            .push((EVAL_PERSPECTIVE_SETTING.to_string(), "white".to_string()));
+        assert_eq!(manifest.eval_perspective().unwrap(), EvalPerspective::White);
+        manifest.generation[0].1 = "black".to_string();
+        assert!(manifest.eval_perspective().is_err());
+    }
+
+    #[test]
+    fn hash_ignores_chunking() {
+        let bytes: Vec<u8> = (0..100).collect();
+        let mut whole = Hasher::default();
+        whole.update(&bytes);
+        let mut split = Hasher::default();
+        for chunk in bytes.chunks(3) {
+            split.update(chunk);
+        }
+        assert_eq!(whole.finish(), split.finish());
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Name of the shard with the given index in a sharded dataset directory.
+pub fn shard_file_name(index: usize, compressed: bool) -> String {
+    if compressed {
+        format!("shard-{:04}.bin.zst", index)
+    } else {
+        format!("shard-{:04}.bin", index)
+    }
+}
+
+/// Index of the shard a file holds, if its name is one given by [`shard_file_name`].
+pub fn shard_index(path: &Path) -> Option<usize> {
+    let name = path.file_name()?.to_str()?;
+    let name = name.strip_suffix(".zst").unwrap_or(name);
+    let digits = name.strip_prefix("shard-")?.strip_suffix(".bin")?;
+    if digits.len() < 4 || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// Whether a file in a sharded dataset directory holds samples, going by its name.
+/// Other files, such as the manifest, are left alone.
+pub fn is_shard_file(path: &Path) -> bool {
+    shard_index(path).is_some()
+}
+
+/// Lists the dataset files in a sharded dataset directory, sorted by index.
+pub fn shard_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(index) = shard_index(&path).filter(|_| path.is_file()) {
+            files.push((index, path));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Index to give the next shard written to a directory holding `files`, after the
+/// highest existing one.
+pub fn next_shard_index(files: &[PathBuf]) -> usize {
+    files
+        .iter()
+        .filter_map(|path| shard_index(path))
+        .max()
+        .map_or(0, |index| index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shard_names_round_trip() {
+        for index in [0, 7, 1234, 98765] {
+            for compressed in [false, true] {
+                let name = shard_file_name(index, compressed);
+                assert_eq!(shard_index(Path::new(&name)), Some(index));
+            }
+        }
+    }
+
+    #[test]
+    fn other_files_are_not_shards() {
+        for name in [
+            "manifest.toml",
+            "notes.bin",
+            "backup.zst",
+            "shard-12.bin",
+            "shard-00a1.bin",
+            "shard-0001.bin.gz",
+            "my-shard-0001.bin",
+        ] {
+            assert!(!is_shard_file(Path::new(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn next_index_follows_the_highest() {
+        let files = [
+            PathBuf::from("out/shard-0000.bin"),
+            PathBuf::from("out/shard-0003.bin.zst"),
+        ];
+        assert_eq!(next_shard_index(&files), 4);
+        assert_eq!(next_shard_index(&[]), 0);
+    }
+}
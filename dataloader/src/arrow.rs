@@ -0,0 +1,85 @@
+//! Batches as Arrow record batches, for looking into them with polars, pandas and other
+//! tools reading Arrow data.
+
+use crate::batch::Batch;
+use arrow_array::{
+    ArrayRef, Float32Array, RecordBatch, StringArray, UInt32Array,
+    builder::{FixedSizeListBuilder, Float32Builder, ListBuilder, UInt32Builder},
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::ArrowError;
+use std::{io::Write, sync::Arc};
+
+impl Batch {
+    /// The batch as a record batch with a row per sample, with the columns:
+    ///
+    /// - `eval`: centipawns from the side to move's perspective, null without an eval.
+    /// - `outcome`: the encoded outcome, a list of [`OutcomeEncoding::width`] values.
+    /// - `eval_score`, `target` and `weight`.
+    /// - `stm_features` and `non_stm_features`: lists of the active features.
+    /// - `fen`, `piece_count` and `phase`, if the loader keeps them.
+    ///
+    /// [`OutcomeEncoding::width`]: crate::loader::OutcomeEncoding::width
+    pub fn to_record_batch(&self) -> Result<RecordBatch, ArrowError> {
+        let evals = Float32Array::from_iter(self.evals().iter().map(|&eval| (!eval.is_nan()).then_some(eval)));
+
+        let width = self.outcome_encoding().width();
+        let mut outcomes = FixedSizeListBuilder::with_capacity(Float32Builder::new(), width as i32, self.len());
+        for outcome in self.outcomes().chunks_exact(width) {
+            outcomes.values().append_slice(outcome);
+            outcomes.append(true);
+        }
+
+        let mut columns: Vec<(&str, ArrayRef)> = vec![
+            ("eval", Arc::new(evals)),
+            ("outcome", Arc::new(outcomes.finish())),
+            ("eval_score", Arc::new(Float32Array::from(self.eval_scores().to_vec()))),
+            ("target", Arc::new(Float32Array::from(self.targets().to_vec()))),
+            ("weight", Arc::new(Float32Array::from(self.weights().to_vec()))),
+            ("stm_features", Arc::new(self.feature_lists(self.stm_features()))),
+            ("non_stm_features", Arc::new(self.feature_lists(self.non_stm_features()))),
+        ];
+        if !self.fens.is_empty() {
+            let fens = StringArray::from_iter_values((0..self.len()).map(|index| self.fen(index).unwrap_or_default()));
+            columns.push(("fen", Arc::new(fens)));
+        }
+        if !self.piece_counts().is_empty() {
+            columns.push(("piece_count", Arc::new(UInt32Array::from(self.piece_counts().to_vec()))));
+            columns.push(("phase", Arc::new(UInt32Array::from(self.phases().to_vec()))));
+        }
+        RecordBatch::try_from_iter(columns)
+    }
+
+    /// Splits `(sample, feature)` pairs into a list of features per sample.
+    fn feature_lists(&self, features: &[u32]) -> arrow_array::ListArray {
+        let mut lists = ListBuilder::with_capacity(UInt32Builder::with_capacity(features.len() / 2), self.len());
+        let mut pairs = features.chunks_exact(2).peekable();
+        for sample in 0..self.len() as u32 {
+            while let Some(pair) = pairs.next_if(|pair| pair[0] == sample) {
+                lists.values().append_value(pair[1]);
+            }
+            lists.append(true);
+        }
+        lists.finish()
+    }
+}
+
+/// Writes batches as an Arrow IPC stream, such as for `pyarrow.ipc.open_stream`. The
+/// batches must all come from loaders with the same options, to share their columns, and
+/// nothing is written without any.
+pub fn write_ipc_stream<'a, W: Write>(writer: W, batches: impl IntoIterator<Item = &'a Batch>) -> Result<(), ArrowError> {
+    let mut stream: Option<StreamWriter<W>> = None;
+    let mut writer = Some(writer);
+    for batch in batches {
+        let batch = batch.to_record_batch()?;
+        let stream = match &mut stream {
+            Some(stream) => stream,
+            None => stream.insert(StreamWriter::try_new(writer.take().unwrap(), &batch.schema())?),
+        };
+        stream.write(&batch)?;
+    }
+    if let Some(mut stream) = stream {
+        stream.finish()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use crate::batch::Entry;
+use dama::Outcome;
+use std::{fmt, str::FromStr};
+
+/// A symmetry of the board applied at random to samples as they're added to the batches,
+/// parsed from `<mirror>:<probability>` such as `colors:0.5` or `horizontal:0.5`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Augmentation {
+    pub mirror: Mirror,
+    /// Chance of each sample being mirrored.
+    pub probability: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mirror {
+    /// Swaps the colors and flips the board from top to bottom, so the other side is to
+    /// move and the eval and outcome change sides with the pieces. The feature sets here
+    /// see the board from the side to move's perspective and give the same features
+    /// either way, so it's only of use to networks which don't.
+    Colors,
+    /// Flips the board from left to right. Castling rights aren't part of the features,
+    /// so the mirrored positions are only off where they would matter.
+    Horizontal,
+}
+
+impl Mirror {
+    pub const ALL: [Mirror; 2] = [Mirror::Colors, Mirror::Horizontal];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mirror::Colors => "colors",
+            Mirror::Horizontal => "horizontal",
+        }
+    }
+
+    /// The entry as seen through the mirror, with an eval from the side to move's
+    /// perspective.
+    #[inline]
+    pub(crate) fn apply(self, entry: Entry) -> Entry {
+        match self {
+            Mirror::Colors => Entry {
+                pieces: entry.pieces.flip_colors(),
+                side_to_move: !entry.side_to_move,
+                outcome: match entry.outcome {
+                    Outcome::Winner(winner) => Outcome::Winner(!winner),
+                    Outcome::Draw => Outcome::Draw,
+                },
+                ..entry
+            },
+            Mirror::Horizontal => Entry {
+                pieces: entry.pieces.flip_horizontal(),
+                ..entry
+            },
+        }
+    }
+}
+
+impl Augmentation {
+    /// The mirror to apply to a sample given a roll picked uniformly from `[0, 1)`.
+    #[inline]
+    pub(crate) fn mirror(&self, roll: f64) -> Option<Mirror> {
+        (roll < self.probability).then_some(self.mirror)
+    }
+}
+
+impl fmt::Display for Augmentation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.mirror.name(), self.probability)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidAugmentationError(String);
+
+impl fmt::Display for InvalidAugmentationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidAugmentationError {}
+
+impl FromStr for Augmentation {
+    type Err = InvalidAugmentationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = InvalidAugmentationError;
+        let (mirror, probability) = s
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("augmentation `{}` must be of the form `<mirror>:<probability>`", s.trim())))?;
+        let name = mirror.trim();
+        let mirror = Mirror::ALL
+            .into_iter()
+            .find(|mirror| mirror.name() == name)
+            .ok_or_else(|| invalid(format!("unknown mirror `{}`, expected `colors` or `horizontal`", name)))?;
+        let probability = probability
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|probability| (0.0..=1.0).contains(probability))
+            .ok_or_else(|| invalid(format!("invalid probability `{}`, expected a number between 0 and 1", probability.trim())))?;
+        Ok(Augmentation { mirror, probability })
+    }
+}
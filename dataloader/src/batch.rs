@@ -1,82 +1,336 @@
-use crate::feature::{MAX_ACTIVE_FEATURES, feature};
-use dama::{Color, Piece, Position};
-use dataformat::Sample;
+use crate::{
+    augment::Mirror,
+    feature::FeatureSet,
+    loader::{LoaderOptions, OutcomeEncoding},
+    wdl,
+};
+use dama::{Color, Outcome};
+use dataformat::{Adjudication, EvalPerspective, PackedSample, PieceSets, Sample, UnpackError};
+use std::ffi::CString;
 
 #[derive(Clone, Debug)]
 pub struct Batch {
     pub(crate) entries: usize,
     pub(crate) capacity: usize,
     pub(crate) total_features: usize,
-    pub(crate) stm_features: Box<[u32]>,
-    pub(crate) non_stm_features: Box<[u32]>,
+    /// Most active features kept for a single sample.
+    pub(crate) max_features: usize,
+    pub(crate) truncated_samples: usize,
+    pub(crate) truncated_features: usize,
+    /// FENs of the samples, only kept with [`LoaderOptions::keep_fens`].
+    pub(crate) fens: Vec<CString>,
+    /// Piece counts and phases of the samples, only kept with
+    /// [`LoaderOptions::keep_material`].
+    pub(crate) piece_counts: Vec<u32>,
+    pub(crate) phases: Vec<u32>,
+    pub(crate) stm_features: Vec<u32>,
+    pub(crate) non_stm_features: Vec<u32>,
     pub(crate) eval_centipawns: Box<[f32]>,
+    /// Encoded outcomes, with room for the widest encoding.
     pub(crate) outcomes: Box<[f32]>,
+    pub(crate) outcome_encoding: OutcomeEncoding,
+    pub(crate) eval_scores: Box<[f32]>,
+    pub(crate) targets: Box<[f32]>,
+    pub(crate) weights: Box<[f32]>,
 }
 
 impl Batch {
+    /// Creates a batch of up to `capacity` samples, with room for as many features as
+    /// they can have under `feature_set`.
     #[inline]
-    pub fn new(capacity: usize) -> Batch {
+    pub fn new(capacity: usize, feature_set: FeatureSet) -> Batch {
+        let max_features = feature_set.max_active_features();
         Batch {
             entries: 0,
             capacity,
             total_features: 0,
-            stm_features: vec![0; 2 * MAX_ACTIVE_FEATURES * capacity].into(),
-            non_stm_features: vec![0; 2 * MAX_ACTIVE_FEATURES * capacity].into(),
+            max_features,
+            truncated_samples: 0,
+            truncated_features: 0,
+            fens: Vec::new(),
+            piece_counts: Vec::new(),
+            phases: Vec::new(),
+            stm_features: Vec::with_capacity(2 * max_features * capacity),
+            non_stm_features: Vec::with_capacity(2 * max_features * capacity),
             eval_centipawns: vec![0.0; capacity].into(),
-            outcomes: vec![0.0; capacity].into(),
+            outcomes: vec![0.0; OutcomeEncoding::Wdl.width() * capacity].into(),
+            outcome_encoding: OutcomeEncoding::default(),
+            eval_scores: vec![0.0; capacity].into(),
+            targets: vec![0.0; capacity].into(),
+            weights: vec![0.0; capacity].into(),
         }
     }
 
+    /// Number of samples in the batch.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Active features from the side to move's perspective, as `(sample, feature)` pairs
+    /// sorted by sample.
+    #[inline]
+    pub fn stm_features(&self) -> &[u32] {
+        &self.stm_features[..2 * self.total_features]
+    }
+
+    /// Active features from the other side's perspective, paired up like [`Batch::stm_features`].
+    #[inline]
+    pub fn non_stm_features(&self) -> &[u32] {
+        &self.non_stm_features[..2 * self.total_features]
+    }
+
+    /// Evaluations in centipawns from the side to move's perspective, NaN for samples
+    /// without one.
+    #[inline]
+    pub fn evals(&self) -> &[f32] {
+        &self.eval_centipawns[..self.entries]
+    }
+
+    /// Game outcomes from the side to move's perspective, encoded as the loader's
+    /// [`OutcomeEncoding`] with [`OutcomeEncoding::width`] values per sample. Scores by
+    /// default: 1 for a win, 0.5 for a draw.
+    #[inline]
+    pub fn outcomes(&self) -> &[f32] {
+        &self.outcomes[..self.entries * self.outcome_encoding.width()]
+    }
+
+    #[inline]
+    pub fn outcome_encoding(&self) -> OutcomeEncoding {
+        self.outcome_encoding
+    }
+
+    /// Expected scores given by the loader's WDL model to the evaluations, or the
+    /// outcome for samples without an evaluation.
+    #[inline]
+    pub fn eval_scores(&self) -> &[f32] {
+        &self.eval_scores[..self.entries]
+    }
+
+    /// Training targets, blending the eval scores and outcomes by the loader's eval weight.
+    #[inline]
+    pub fn targets(&self) -> &[f32] {
+        &self.targets[..self.entries]
+    }
+
+    /// Weights scaling each sample's share of the loss, 1 unless the loader's
+    /// [`SampleWeighting`](crate::weight::SampleWeighting) says otherwise.
+    #[inline]
+    pub fn weights(&self) -> &[f32] {
+        &self.weights[..self.entries]
+    }
+
+    /// Samples with more active features than their feature set allows for, which only
+    /// a corrupt sample or a bug in the feature set can produce. Their extra features
+    /// are left out of the batch.
+    #[inline]
+    pub fn truncated_samples(&self) -> usize {
+        self.truncated_samples
+    }
+
+    /// Active features left out of the batch's truncated samples.
+    #[inline]
+    pub fn truncated_features(&self) -> usize {
+        self.truncated_features
+    }
+
+    /// FEN of a sample, if the loader keeps them with [`LoaderOptions::keep_fens`].
+    #[inline]
+    pub fn fen(&self, index: usize) -> Option<&str> {
+        self.fens.get(index).and_then(|fen| fen.to_str().ok())
+    }
+
+    /// Pieces on the board of each sample, kings and pawns included, if the loader keeps
+    /// them with [`LoaderOptions::keep_material`], and empty otherwise.
+    #[inline]
+    pub fn piece_counts(&self) -> &[u32] {
+        &self.piece_counts
+    }
+
+    /// Phase of each sample from 0 in pawn endings to [`wdl::MAX_PHASE`] with all pieces
+    /// on the board, kept like [`Batch::piece_counts`].
+    #[inline]
+    pub fn phases(&self) -> &[u32] {
+        &self.phases
+    }
+
     #[inline]
     pub fn clear(&mut self) {
         self.entries = 0;
         self.total_features = 0;
+        self.truncated_samples = 0;
+        self.truncated_features = 0;
+        self.fens.clear();
+        self.piece_counts.clear();
+        self.phases.clear();
+        self.stm_features.clear();
+        self.non_stm_features.clear();
+    }
+
+    #[inline]
+    pub fn add(&mut self, sample: &Sample, adjudication: Adjudication, options: &LoaderOptions) {
+        self.add_mirrored(sample, adjudication, None, options);
+    }
+
+    /// Adds a sample seen through a mirror, see [`Augmentation`](crate::augment::Augmentation).
+    /// Its FEN is kept as stored.
+    #[inline]
+    pub(crate) fn add_mirrored(&mut self, sample: &Sample, adjudication: Adjudication, mirror: Option<Mirror>, options: &LoaderOptions) {
+        self.add_entry(&Entry::of(sample, adjudication), mirror, options);
+        if options.keep_fens {
+            let fen = CString::new(sample.position.fen().to_string()).expect("FEN has no NUL bytes");
+            self.fens.push(fen);
+        }
+    }
+
+    /// Adds a sample straight from its packed form, decoding only what the batch needs
+    /// instead of unpacking it into a validated position.
+    #[inline]
+    pub fn add_packed(&mut self, packed: &PackedSample, options: &LoaderOptions) -> Result<(), UnpackError> {
+        self.add_packed_mirrored(packed, None, options)
+    }
+
+    #[inline]
+    pub(crate) fn add_packed_mirrored(&mut self, packed: &PackedSample, mirror: Option<Mirror>, options: &LoaderOptions) -> Result<(), UnpackError> {
+        if options.keep_fens {
+            self.add_mirrored(&packed.unpack()?, packed.adjudication(), mirror, options);
+            return Ok(());
+        }
+        self.add_entry(&Entry::from_packed(packed)?, mirror, options);
+        Ok(())
     }
 
     #[inline]
-    pub fn add(&mut self, sample: &Sample) {
+    fn add_entry(&mut self, entry: &Entry, mirror: Option<Mirror>, options: &LoaderOptions) {
         assert!(self.entries < self.capacity);
 
+        let entry = entry.to_side_to_move(options.eval_perspective.unwrap_or_default());
+        let entry = &mirror.map_or(entry, |mirror| mirror.apply(entry));
+
         let index = self.entries;
-        self.eval_centipawns[index] =
-            sample
-                .eval
-                .map(|e| e as f32)
-                .unwrap_or(match sample.outcome.winner() {
-                    Some(color) if color == sample.position.side_to_move() => f32::INFINITY,
-                    Some(_) => f32::NEG_INFINITY,
-                    None => 0.0,
-                });
-        self.outcomes[index] = match sample.outcome.winner() {
-            Some(color) if color == sample.position.side_to_move() => 1.0,
+        self.eval_centipawns[index] = entry.eval.map_or(f32::NAN, |eval| eval as f32);
+        let outcome = match entry.outcome.winner() {
+            Some(color) if color == entry.side_to_move => 1.0,
             Some(_) => 0.0,
             None => 0.5,
         };
-        self.add_features(&sample.position);
+        let outcome = outcome + options.outcome_smoothing * (0.5 - outcome);
+        let width = options.outcome_encoding.width();
+        self.outcome_encoding = options.outcome_encoding;
+        options.outcome_encoding.encode(outcome, &mut self.outcomes[index * width..(index + 1) * width]);
+        self.eval_scores[index] = match entry.eval {
+            Some(eval) => options.wdl_model.expected_score(
+                eval as f32 / options.eval_temperature.unwrap_or(1.0),
+                entry.phase() as f32 / wdl::MAX_PHASE as f32,
+            ),
+            None => outcome,
+        };
+        let outcome_weight = match options.adjudicated_outcome_scale {
+            Some(scale) if entry.adjudication != Adjudication::None => (1.0 - options.eval_weight) * scale,
+            _ => 1.0 - options.eval_weight,
+        };
+        self.targets[index] = (1.0 - outcome_weight) * self.eval_scores[index]
+            + outcome_weight * outcome;
+        self.weights[index] = options.weighting.weight(entry, self.eval_scores[index], outcome);
+        if options.keep_material {
+            self.piece_counts.push(entry.piece_count());
+            self.phases.push(entry.phase());
+        }
+        self.add_features(options.feature_set, entry);
         self.entries += 1;
     }
 
+    /// Adds the features of a sample up to [`FeatureSet::max_active_features`], so the
+    /// features of a batch never outgrow the room set aside for them.
     #[inline]
-    fn add_features(&mut self, position: &Position) {
-        for color in Color::all() {
-            for piece in Piece::all() {
-                for square in position.pieces(piece) & position.colored(color) {
-                    self.add_feature(
-                        feature(position.side_to_move(), color, piece, square),
-                        feature(!position.side_to_move(), color, piece, square),
-                    );
-                }
+    fn add_features(&mut self, feature_set: FeatureSet, entry: &Entry) {
+        let limit = self.total_features + self.max_features;
+        let mut dropped = 0;
+        feature_set.active_features_of(&entry.pieces, entry.side_to_move, |stm, non_stm| {
+            if self.total_features < limit {
+                self.add_feature(stm, non_stm);
+            } else {
+                dropped += 1;
             }
+        });
+        if dropped > 0 {
+            self.truncated_samples += 1;
+            self.truncated_features += dropped;
         }
     }
 
     #[inline]
     fn add_feature(&mut self, stm: u32, non_stm: u32) {
-        let index = 2 * self.total_features;
-        self.stm_features[index] = self.entries as u32;
-        self.non_stm_features[index] = self.entries as u32;
-        self.stm_features[index + 1] = stm;
-        self.non_stm_features[index + 1] = non_stm;
+        self.stm_features.extend([self.entries as u32, stm]);
+        self.non_stm_features.extend([self.entries as u32, non_stm]);
         self.total_features += 1;
     }
 }
+
+/// What a batch needs of a sample, which can be read from either an unpacked sample or
+/// straight from a packed one.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Entry {
+    pub pieces: PieceSets,
+    pub side_to_move: Color,
+    pub outcome: Outcome,
+    pub eval: Option<i16>,
+    pub fullmove_number: u32,
+    pub adjudication: Adjudication,
+}
+
+impl Entry {
+    #[inline]
+    fn of(sample: &Sample, adjudication: Adjudication) -> Self {
+        Entry {
+            pieces: PieceSets::of(&sample.position),
+            side_to_move: sample.position.side_to_move(),
+            outcome: sample.outcome,
+            eval: sample.eval,
+            fullmove_number: sample.position.fullmove_number(),
+            adjudication,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn from_packed(packed: &PackedSample) -> Result<Self, UnpackError> {
+        Ok(Entry {
+            pieces: packed.piece_sets()?,
+            side_to_move: packed.side_to_move()?,
+            outcome: packed.outcome()?,
+            eval: packed.eval(),
+            fullmove_number: packed.fullmove_number(),
+            adjudication: packed.adjudication(),
+        })
+    }
+
+    /// The entry with its eval turned from `perspective` into the side to move's.
+    #[inline]
+    pub(crate) fn to_side_to_move(self, perspective: EvalPerspective) -> Self {
+        Entry {
+            eval: self.eval.map(|eval| perspective.to_side_to_move(eval, self.side_to_move)),
+            ..self
+        }
+    }
+
+    /// Plies played since the start of the game, as far as the move number tells.
+    #[inline]
+    pub fn ply(&self) -> u32 {
+        2 * self.fullmove_number.saturating_sub(1) + (self.side_to_move == Color::Black) as u32
+    }
+
+    #[inline]
+    pub fn phase(&self) -> u32 {
+        wdl::phase_of(&self.pieces)
+    }
+
+    #[inline]
+    pub fn piece_count(&self) -> u32 {
+        self.pieces.occupied().to_bits().count_ones()
+    }
+}
@@ -0,0 +1,196 @@
+use crate::loader::read_samples;
+use dataformat::{PackedSample, shard};
+use std::{collections::HashSet, fmt::Write as _, fs::{self, File}, io, path::Path};
+
+/// Bits set per hash, which with [`BITS_PER_HASH`] bits per excluded position lets
+/// about one position in a hundred through by mistake.
+const PROBES: u32 = 7;
+const BITS_PER_HASH: usize = 10;
+
+/// A Bloom filter over the Zobrist hashes of positions kept out of the batches, such
+/// as those of a validation set. Excluded positions are always caught, while a small
+/// share of other positions is dropped along with them.
+#[derive(Clone, Debug)]
+pub struct ExclusionFilter {
+    bits: Box<[u64]>,
+    hashes: usize,
+}
+
+impl ExclusionFilter {
+    pub fn new(hashes: &[u64]) -> Self {
+        let words = (hashes.len() * BITS_PER_HASH).div_ceil(64).max(1);
+        let mut filter = ExclusionFilter {
+            bits: vec![0; words].into_boxed_slice(),
+            hashes: hashes.len(),
+        };
+        for &hash in hashes {
+            for bit in probes(words, hash) {
+                filter.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        filter
+    }
+
+    /// Reads a file written by [`write_hashes`].
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(&read_hashes(path)?))
+    }
+
+    /// Number of hashes the filter was built from.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.hashes
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.hashes == 0
+    }
+
+    #[inline]
+    pub fn contains(&self, hash: u64) -> bool {
+        probes(self.bits.len(), hash)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// Bit positions of a hash in a filter of `words` 64-bit words, derived by double
+/// hashing from its two halves since Zobrist hashes are already uniformly distributed.
+#[inline]
+fn probes(words: usize, hash: u64) -> impl Iterator<Item = usize> {
+    let len = words as u64 * 64;
+    let step = hash.rotate_left(32) | 1;
+    (0..PROBES as u64).map(move |n| (hash.wrapping_add(n.wrapping_mul(step)) % len) as usize)
+}
+
+/// Reads a list of position hashes, one in hexadecimal per line, ignoring blank lines
+/// and `#` comments.
+pub fn read_hashes(path: &Path) -> io::Result<Vec<u64>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut hashes = Vec::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let digits = line.strip_prefix("0x").unwrap_or(line);
+        let hash = u64::from_str_radix(digits, 16)
+            .map_err(|_| invalid(format!("invalid position hash `{}`", line)))?;
+        hashes.push(hash);
+    }
+    Ok(hashes)
+}
+
+pub fn write_hashes(path: &Path, hashes: &[u64]) -> io::Result<()> {
+    let mut contents = String::with_capacity(hashes.len() * 17);
+    for hash in hashes {
+        writeln!(contents, "{:016x}", hash).unwrap();
+    }
+    fs::write(path, contents)
+}
+
+/// How much of a dataset repeats the positions of another, such as a training set those
+/// of the validation set it's measured against.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Overlap {
+    /// Samples of the dataset.
+    pub samples: u64,
+    /// Samples of the dataset whose position is one of the other's.
+    pub contaminated: u64,
+    /// Distinct positions of the other dataset.
+    pub reference_positions: u64,
+    /// Distinct positions of the other dataset found in the dataset.
+    pub shared_positions: u64,
+}
+
+impl Overlap {
+    /// Share of the dataset's samples whose position is one of the other's.
+    #[inline]
+    pub fn rate(&self) -> f64 {
+        self.contaminated as f64 / self.samples.max(1) as f64
+    }
+}
+
+/// Counts the samples of a dataset whose positions are in a reference set, unlike an
+/// [`ExclusionFilter`] without mistaking any other position for one of them.
+#[derive(Clone, Debug)]
+pub struct OverlapCheck {
+    reference: HashSet<u64>,
+    found: HashSet<u64>,
+    overlap: Overlap,
+}
+
+impl OverlapCheck {
+    pub fn new(reference: HashSet<u64>) -> Self {
+        let overlap = Overlap {
+            reference_positions: reference.len() as u64,
+            ..Overlap::default()
+        };
+        OverlapCheck {
+            reference,
+            found: HashSet::new(),
+            overlap,
+        }
+    }
+
+    /// Counts a sample of the dataset by the hash of its position.
+    #[inline]
+    pub fn add(&mut self, hash: u64) {
+        self.overlap.samples += 1;
+        if self.reference.contains(&hash) {
+            self.overlap.contaminated += 1;
+            self.found.insert(hash);
+        }
+    }
+
+    #[inline]
+    pub fn overlap(&self) -> Overlap {
+        Overlap {
+            shared_positions: self.found.len() as u64,
+            ..self.overlap
+        }
+    }
+}
+
+/// Measures how much of `dataset` repeats the positions of `reference`, both
+/// uncompressed files or directories of shards.
+pub fn check_overlap(dataset: &Path, reference: &Path) -> io::Result<Overlap> {
+    let mut hashes = HashSet::new();
+    for_each_hash(reference, |hash| {
+        hashes.insert(hash);
+    })?;
+    let mut check = OverlapCheck::new(hashes);
+    for_each_hash(dataset, |hash| check.add(hash))?;
+    Ok(check.overlap())
+}
+
+/// Calls `f` with the hash of every position of a dataset.
+fn for_each_hash(path: &Path, mut f: impl FnMut(u64)) -> io::Result<()> {
+    let files = if path.is_dir() {
+        shard::shard_files(path)?
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut buffer = vec![PackedSample::default(); 65536];
+    for path in files {
+        if path.extension().is_some_and(|ext| ext == "zst") {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "compressed datasets cannot be checked"));
+        }
+        let mut file = File::open(&path)?;
+        loop {
+            let samples = read_samples(&mut file, &mut buffer)?;
+            if samples == 0 {
+                break;
+            }
+            for packed in &buffer[..samples] {
+                let sample = packed
+                    .unpack()
+                    .map_err(|err| invalid(format!("invalid sample in `{}`: {}", path.display(), err)))?;
+                f(sample.position.hash());
+            }
+        }
+    }
+    Ok(())
+}
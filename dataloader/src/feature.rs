@@ -1,6 +1,154 @@
-use dama::{Color, Piece, Square};
+use dama::{Color, Piece, Position, Square};
+use dataformat::PieceSets;
+use std::{fmt, str::FromStr};
 
-pub const MAX_ACTIVE_FEATURES: usize = 64;
+use crate::threats;
+
+/// Size of the board block, one feature per (relative color, piece, square).
+const BOARD_FEATURES: usize = 2 * Piece::COUNT * Square::COUNT;
+
+/// Most pieces on the board of a legal position, each with one board feature.
+const MAX_PIECES: usize = 32;
+
+/// Most threat features of one side: 8 squares around the king, 2 for each of 8 pawns,
+/// and at most the whole board for each of the other pieces.
+const MAX_THREATS_PER_SIDE: usize = 8 + 2 * 8 + 4 * Square::COUNT;
+
+/// An input encoding of positions, turning every piece on the board into one active
+/// feature from the side to move's perspective and one from the other side's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FeatureSet {
+    /// One feature per (relative color, piece, square), 768 in total.
+    #[default]
+    Chess768,
+    /// The board features followed by a threat block with one feature per (relative
+    /// color, piece, square) for every square attacked by that side's pieces of that
+    /// type, 1536 in total.
+    Chess768Threats,
+}
+
+impl FeatureSet {
+    pub const ALL: [FeatureSet; 2] = [FeatureSet::Chess768, FeatureSet::Chess768Threats];
+
+    #[inline]
+    pub fn name(self) -> &'static str {
+        match self {
+            FeatureSet::Chess768 => "chess768",
+            FeatureSet::Chess768Threats => "chess768-threats",
+        }
+    }
+
+    /// Number of distinct features, the input size of the network.
+    #[inline]
+    pub fn feature_count(self) -> usize {
+        match self {
+            FeatureSet::Chess768 => BOARD_FEATURES,
+            FeatureSet::Chess768Threats => 2 * BOARD_FEATURES,
+        }
+    }
+
+    /// Upper bound on the active features of a position, to size buffers of features up
+    /// front.
+    #[inline]
+    pub fn max_active_features(self) -> usize {
+        match self {
+            FeatureSet::Chess768 => MAX_PIECES,
+            FeatureSet::Chess768Threats => MAX_PIECES + 2 * MAX_THREATS_PER_SIDE,
+        }
+    }
+
+    /// Whether the feature set has a block of attacked squares after the board features.
+    #[inline]
+    pub fn has_threats(self) -> bool {
+        match self {
+            FeatureSet::Chess768 => false,
+            FeatureSet::Chess768Threats => true,
+        }
+    }
+
+    /// Calls `add` with the side to move and non-side to move indices of every active feature.
+    #[inline]
+    pub fn active_features(self, position: &Position, add: impl FnMut(u32, u32)) {
+        self.active_features_of(&PieceSets::of(position), position.side_to_move(), add)
+    }
+
+    /// Like [`FeatureSet::active_features`], for the pieces of a position read from a
+    /// packed sample.
+    #[inline]
+    pub fn active_features_of(self, pieces: &PieceSets, side_to_move: Color, mut add: impl FnMut(u32, u32)) {
+        for color in Color::all() {
+            for piece in Piece::all() {
+                for square in pieces.pieces(piece) & pieces.colored(color) {
+                    add(
+                        feature(side_to_move, color, piece, square),
+                        feature(!side_to_move, color, piece, square),
+                    );
+                }
+            }
+        }
+        if self.has_threats() {
+            let offset = BOARD_FEATURES as u32;
+            for color in Color::all() {
+                for piece in Piece::all() {
+                    for square in threats::attacked(pieces, color, piece) {
+                        add(
+                            offset + feature(side_to_move, color, piece, square),
+                            offset + feature(!side_to_move, color, piece, square),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Describes a feature index in terms of the board, for debugging.
+    pub fn describe(self, index: u32) -> String {
+        let index = index as usize;
+        if index >= self.feature_count() {
+            return "out of range".to_string();
+        }
+        let threat = index >= BOARD_FEATURES;
+        let index = index % BOARD_FEATURES;
+        let square = Square::try_from_index(index % Square::COUNT);
+        let piece = Piece::try_from_index(index / Square::COUNT % Piece::COUNT);
+        let side = if index / (Square::COUNT * Piece::COUNT) == 0 { "own" } else { "their" };
+        match (piece, square) {
+            (Some(piece), Some(square)) if threat => {
+                format!("{} {:?} attacks {:?}", side, piece, square)
+            }
+            (Some(piece), Some(square)) => format!("{} {:?} on {:?}", side, piece, square),
+            _ => "out of range".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for FeatureSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownFeatureSetError(String);
+
+impl fmt::Display for UnknownFeatureSetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown feature set `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownFeatureSetError {}
+
+impl FromStr for FeatureSet {
+    type Err = UnknownFeatureSetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FeatureSet::ALL
+            .into_iter()
+            .find(|set| set.name() == s)
+            .ok_or_else(|| UnknownFeatureSetError(s.to_string()))
+    }
+}
 
 #[inline]
 pub fn feature(perspective: Color, color: Color, piece: Piece, square: Square) -> u32 {
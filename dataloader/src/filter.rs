@@ -0,0 +1,126 @@
+use crate::batch::Entry;
+use std::{fmt, str::FromStr};
+
+/// A condition on a sample, parsed from `<field><op><value>` such as `ply>=16` or
+/// `abs-eval<1000`. Evals are matched from the side to move's perspective, and samples
+/// without one never match conditions on it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleFilter {
+    field: Field,
+    comparison: Comparison,
+    value: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Ply,
+    /// Non-pawn material as counted by [`wdl::phase`](crate::wdl::phase).
+    Phase,
+    Pieces,
+    Eval,
+    AbsEval,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    GreaterOrEqual,
+    Greater,
+}
+
+const OPERATORS: [(&str, Comparison); 6] = [
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("!=", Comparison::NotEqual),
+    ("=", Comparison::Equal),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+impl SampleFilter {
+    #[inline]
+    pub(crate) fn matches(&self, entry: &Entry) -> bool {
+        let value = match self.field {
+            Field::Ply => entry.ply() as i32,
+            Field::Phase => entry.phase() as i32,
+            Field::Pieces => entry.piece_count() as i32,
+            Field::Eval => match entry.eval {
+                Some(eval) => eval as i32,
+                None => return false,
+            },
+            Field::AbsEval => match entry.eval {
+                Some(eval) => (eval as i32).abs(),
+                None => return false,
+            },
+        };
+        match self.comparison {
+            Comparison::Less => value < self.value,
+            Comparison::LessOrEqual => value <= self.value,
+            Comparison::Equal => value == self.value,
+            Comparison::NotEqual => value != self.value,
+            Comparison::GreaterOrEqual => value >= self.value,
+            Comparison::Greater => value > self.value,
+        }
+    }
+}
+
+impl fmt::Display for SampleFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let field = match self.field {
+            Field::Ply => "ply",
+            Field::Phase => "phase",
+            Field::Pieces => "pieces",
+            Field::Eval => "eval",
+            Field::AbsEval => "abs-eval",
+        };
+        let (op, _) = OPERATORS
+            .iter()
+            .find(|(_, comparison)| *comparison == self.comparison)
+            .unwrap();
+        write!(f, "{}{}{}", field, op, self.value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidSampleFilterError(String);
+
+impl fmt::Display for InvalidSampleFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidSampleFilterError {}
+
+impl FromStr for SampleFilter {
+    type Err = InvalidSampleFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = InvalidSampleFilterError;
+        let (position, op, comparison) = OPERATORS
+            .iter()
+            .filter_map(|&(op, cmp)| s.find(op).map(|position| (position, op, cmp)))
+            .min_by_key(|&(position, op, _)| (position, usize::MAX - op.len()))
+            .ok_or_else(|| invalid(format!("missing comparison operator in `{}`", s.trim())))?;
+        let field = match s[..position].trim() {
+            "ply" => Field::Ply,
+            "phase" => Field::Phase,
+            "pieces" => Field::Pieces,
+            "eval" => Field::Eval,
+            "abs-eval" => Field::AbsEval,
+            field => return Err(invalid(format!("unknown field `{}`", field))),
+        };
+        let value = s[position + op.len()..].trim();
+        let value = value
+            .parse()
+            .map_err(|_| invalid(format!("invalid value `{}` in `{}`", value, s.trim())))?;
+        Ok(SampleFilter {
+            field,
+            comparison,
+            value,
+        })
+    }
+}
@@ -1,14 +1,33 @@
+use augment::Augmentation;
 use batch::Batch;
 use core::ptr;
-use loader::BatchLoader;
+use exclude::{ExclusionFilter, Overlap};
+use filter::SampleFilter;
+use limits::ResourceLimits;
+use loader::{BatchLoader, LoaderOptions, LoaderState, Partition};
 use std::{
     ffi::{CStr, c_char},
-    fs::File,
+    path::Path,
+    sync::Arc,
 };
+use stratify::Stratification;
+use wdl::WdlModel;
+use weight::WeightRule;
 
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod augment;
 pub mod batch;
+pub mod exclude;
 pub mod feature;
+pub mod filter;
+pub mod limits;
 pub mod loader;
+pub mod stratify;
+pub mod threats;
+pub mod transform;
+pub mod wdl;
+pub mod weight;
 
 #[unsafe(no_mangle)]
 unsafe extern "C" fn open_loader(path: *const c_char, batch_size: u32) -> *mut BatchLoader {
@@ -16,11 +35,62 @@ unsafe extern "C" fn open_loader(path: *const c_char, batch_size: u32) -> *mut B
         Ok(path) => path,
         Err(_) => return ptr::null_mut(),
     };
-    let file = match File::open(path) {
-        Ok(file) => file,
+    match BatchLoader::from_path(Path::new(path), batch_size as usize) {
+        Ok(loader) => Box::into_raw(Box::new(loader)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn open_loader_with_options(
+    path: *const c_char,
+    batch_size: u32,
+    options: *const LoaderOptions,
+) -> *mut BatchLoader {
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(path) => path,
+        Err(_) => return ptr::null_mut(),
+    };
+    let options = unsafe { options.as_ref().unwrap().clone() };
+    match BatchLoader::with_options(Path::new(path), batch_size as usize, options) {
+        Ok(loader) => Box::into_raw(Box::new(loader)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Opens the `rank`th of `world_size` disjoint parts of a dataset, for each rank of a
+/// distributed training run to load different samples.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn open_loader_sharded(
+    path: *const c_char,
+    rank: u32,
+    world_size: u32,
+    batch_size: u32,
+) -> *mut BatchLoader {
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(path) => path,
         Err(_) => return ptr::null_mut(),
     };
-    Box::into_raw(Box::new(BatchLoader::from_file(file, batch_size as usize)))
+    let options = LoaderOptions {
+        partition: Some(Partition {
+            rank: rank as usize,
+            world_size: world_size as usize,
+        }),
+        ..LoaderOptions::default()
+    };
+    match BatchLoader::with_options(Path::new(path), batch_size as usize, options) {
+        Ok(loader) => Box::into_raw(Box::new(loader)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn reload_loader(loader: *mut BatchLoader, path: *const c_char) -> bool {
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    unsafe { loader.as_mut().unwrap().reload(Path::new(path)).is_ok() }
 }
 
 #[unsafe(no_mangle)]
@@ -28,6 +98,383 @@ unsafe extern "C" fn close_loader(loader: *mut BatchLoader) {
     drop(unsafe { Box::from_raw(loader) })
 }
 
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_new() -> *mut LoaderOptions {
+    Box::into_raw(Box::default())
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_free(options: *mut LoaderOptions) {
+    drop(unsafe { Box::from_raw(options) })
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_eval_weight(options: *mut LoaderOptions, eval_weight: f32) {
+    unsafe { options.as_mut().unwrap().eval_weight = eval_weight }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_skip_adjudicated(options: *mut LoaderOptions, skip: bool) {
+    unsafe { options.as_mut().unwrap().skip_adjudicated = skip }
+}
+
+/// Scales the outcome's share of the target for samples from adjudicated games.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_adjudicated_outcome_scale(
+    options: *mut LoaderOptions,
+    scale: f32,
+) {
+    unsafe { options.as_mut().unwrap().adjudicated_outcome_scale = Some(scale) }
+}
+
+/// Moves this share of the outcome targets towards a draw.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_outcome_smoothing(
+    options: *mut LoaderOptions,
+    smoothing: f32,
+) {
+    unsafe { options.as_mut().unwrap().outcome_smoothing = smoothing }
+}
+
+/// Divides evaluations by this temperature before they become expected scores.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_eval_temperature(
+    options: *mut LoaderOptions,
+    temperature: f32,
+) {
+    unsafe { options.as_mut().unwrap().eval_temperature = Some(temperature) }
+}
+
+/// Loads position hashes written by `datatools export-hashes` to leave out of the
+/// batches, returning false if they can't be read.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_exclusions(
+    options: *mut LoaderOptions,
+    path: *const c_char,
+) -> bool {
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    match ExclusionFilter::load(Path::new(path)) {
+        Ok(filter) => {
+            unsafe { options.as_mut().unwrap().exclusions = Some(Arc::new(filter)) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Sets whose point of view the dataset's evals are given from, `side-to-move` or
+/// `white`, instead of reading it from the manifest. Returns false for other names.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_eval_perspective(
+    options: *mut LoaderOptions,
+    perspective: *const c_char,
+) -> bool {
+    let perspective = match unsafe { CStr::from_ptr(perspective) }.to_str() {
+        Ok(perspective) => perspective,
+        Err(_) => return false,
+    };
+    match perspective.parse() {
+        Ok(perspective) => {
+            unsafe { options.as_mut().unwrap().eval_perspective = Some(perspective) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Sets what becomes of samples without an evaluation, `outcome` or `skip`. Returns
+/// false for other names.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_missing_eval(
+    options: *mut LoaderOptions,
+    missing: *const c_char,
+) -> bool {
+    let missing = match unsafe { CStr::from_ptr(missing) }.to_str() {
+        Ok(missing) => missing,
+        Err(_) => return false,
+    };
+    match missing.parse() {
+        Ok(missing) => {
+            unsafe { options.as_mut().unwrap().missing_eval = missing };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Sets how the batches' outcomes are given, `score`, `signed` or `wdl`, the last with
+/// three values per sample. Returns false for other names.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_outcome_encoding(
+    options: *mut LoaderOptions,
+    encoding: *const c_char,
+) -> bool {
+    let encoding = match unsafe { CStr::from_ptr(encoding) }.to_str() {
+        Ok(encoding) => encoding,
+        Err(_) => return false,
+    };
+    match encoding.parse() {
+        Ok(encoding) => {
+            unsafe { options.as_mut().unwrap().outcome_encoding = encoding };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Keeps the FEN of every sample in the batches, read with `batch_fen`.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_keep_fens(options: *mut LoaderOptions, keep: bool) {
+    unsafe { options.as_mut().unwrap().keep_fens = keep }
+}
+
+/// Keeps the piece count and phase of every sample in the batches, read with
+/// `batch_piece_counts` and `batch_phases`.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_keep_material(options: *mut LoaderOptions, keep: bool) {
+    unsafe { options.as_mut().unwrap().keep_material = keep }
+}
+
+/// Makes the weights rise linearly over this many plies from the start of the game.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_ply_ramp(options: *mut LoaderOptions, plies: u32) {
+    unsafe { options.as_mut().unwrap().weighting.ply_ramp = Some(plies) }
+}
+
+/// Lowers the weights of samples whose eval score disagrees with the outcome.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_eval_agreement_weight(
+    options: *mut LoaderOptions,
+    strength: f32,
+) {
+    unsafe { options.as_mut().unwrap().weighting.eval_agreement = strength }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_adjudicated_weight(
+    options: *mut LoaderOptions,
+    weight: f32,
+) {
+    unsafe { options.as_mut().unwrap().weighting.adjudicated = weight }
+}
+
+/// Adds a rule such as `ply<16:0.5` scaling the weights of matching samples, returning
+/// false if it can't be parsed.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_add_weight_rule(
+    options: *mut LoaderOptions,
+    rule: *const c_char,
+) -> bool {
+    let rule = match unsafe { CStr::from_ptr(rule) }.to_str() {
+        Ok(rule) => rule,
+        Err(_) => return false,
+    };
+    match rule.parse::<WeightRule>() {
+        Ok(rule) => {
+            unsafe { options.as_mut().unwrap().weighting.rules.push(rule) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Adds a condition such as `abs-eval<1000` that samples must meet to be loaded,
+/// returning false if it can't be parsed.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_add_filter(
+    options: *mut LoaderOptions,
+    filter: *const c_char,
+) -> bool {
+    let filter = match unsafe { CStr::from_ptr(filter) }.to_str() {
+        Ok(filter) => filter,
+        Err(_) => return false,
+    };
+    match filter.parse::<SampleFilter>() {
+        Ok(filter) => {
+            unsafe { options.as_mut().unwrap().filters.push(filter) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Sets the makeup of every batch, such as `outcome:1,1,1`, returning false if it can't
+/// be parsed.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_stratification(
+    options: *mut LoaderOptions,
+    stratification: *const c_char,
+) -> bool {
+    let stratification = match unsafe { CStr::from_ptr(stratification) }.to_str() {
+        Ok(stratification) => stratification,
+        Err(_) => return false,
+    };
+    match stratification.parse::<Stratification>() {
+        Ok(stratification) => {
+            unsafe { options.as_mut().unwrap().stratification = Some(stratification) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Mirrors samples at random, such as `horizontal:0.5`, returning false if it can't be
+/// parsed.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_augmentation(
+    options: *mut LoaderOptions,
+    augmentation: *const c_char,
+) -> bool {
+    let augmentation = match unsafe { CStr::from_ptr(augmentation) }.to_str() {
+        Ok(augmentation) => augmentation,
+        Err(_) => return false,
+    };
+    match augmentation.parse::<Augmentation>() {
+        Ok(augmentation) => {
+            unsafe { options.as_mut().unwrap().augmentation = Some(augmentation) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Seeds the order samples are loaded in, which is drawn at random otherwise.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_seed(options: *mut LoaderOptions, seed: u64) {
+    unsafe { options.as_mut().unwrap().seed = Some(seed) };
+}
+
+/// Loads the `rank`th of `world_size` disjoint parts of the dataset, returning false if
+/// the rank is out of the world.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_partition(
+    options: *mut LoaderOptions,
+    rank: u32,
+    world_size: u32,
+) -> bool {
+    if rank >= world_size {
+        return false;
+    }
+    let partition = Partition {
+        rank: rank as usize,
+        world_size: world_size as usize,
+    };
+    unsafe { options.as_mut().unwrap().partition = Some(partition) };
+    true
+}
+
+/// Caps the dataset files open and the bytes of samples buffered by every loader of the
+/// process together, each left uncapped when 0.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn set_resource_limits(max_open_files: u64, max_buffer_bytes: u64) {
+    limits::set_resource_limits(ResourceLimits {
+        max_open_files: (max_open_files != 0).then_some(max_open_files as usize),
+        max_buffer_bytes: (max_buffer_bytes != 0).then_some(max_buffer_bytes as usize),
+    });
+}
+
+/// Measures how much of a dataset repeats the positions of a reference dataset, such as
+/// the training data those of the validation set, returning false if either can't be read.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn check_overlap(
+    dataset: *const c_char,
+    reference: *const c_char,
+    overlap: *mut Overlap,
+) -> bool {
+    let (Ok(dataset), Ok(reference)) = (
+        unsafe { CStr::from_ptr(dataset) }.to_str(),
+        unsafe { CStr::from_ptr(reference) }.to_str(),
+    ) else {
+        return false;
+    };
+    match exclude::check_overlap(Path::new(dataset), Path::new(reference)) {
+        Ok(result) => {
+            unsafe { *overlap.as_mut().unwrap() = result };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Loads the WDL model written by `datatools fit-wdl`, returning false if it can't be read.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_options_set_wdl_model(
+    options: *mut LoaderOptions,
+    path: *const c_char,
+) -> bool {
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    match WdlModel::load(Path::new(path)) {
+        Ok(model) => {
+            unsafe { options.as_mut().unwrap().wdl_model = model };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Replaces the loader's filters with a comma-separated list of conditions, or none for
+/// an empty string, such as between epochs of a curriculum. Returns false, leaving the
+/// filters as they were, if one can't be parsed.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_set_filters(loader: *mut BatchLoader, filters: *const c_char) -> bool {
+    let filters = match unsafe { CStr::from_ptr(filters) }.to_str() {
+        Ok(filters) => filters,
+        Err(_) => return false,
+    };
+    let filters = filters
+        .split(',')
+        .filter(|filter| !filter.trim().is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<SampleFilter>, _>>();
+    match filters {
+        Ok(filters) => {
+            unsafe { loader.as_mut().unwrap().set_filters(filters) };
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Number of values written by `loader_state` and read by `loader_restore`.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_state_words() -> u32 {
+    LoaderState::WORDS as u32
+}
+
+/// Writes where the loader is after the batches loaded so far to `words`, which must
+/// have room for `loader_state_words()` values.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_state(loader: *const BatchLoader, words: *mut u64) {
+    let state = unsafe { loader.as_ref().unwrap().state() }.to_words();
+    unsafe { ptr::copy_nonoverlapping(state.as_ptr(), words, LoaderState::WORDS) };
+}
+
+/// Goes back to a state written by `loader_state`, returning false if it isn't one of
+/// this loader's dataset.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_restore(loader: *mut BatchLoader, words: *const u64) -> bool {
+    let mut state = [0; LoaderState::WORDS];
+    unsafe { ptr::copy_nonoverlapping(words, state.as_mut_ptr(), LoaderState::WORDS) };
+    unsafe {
+        loader
+            .as_mut()
+            .unwrap()
+            .restore(LoaderState::from_words(state))
+            .is_ok()
+    }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn loader_max_features(loader: *const BatchLoader) -> u32 {
+    unsafe { loader.as_ref().unwrap().max_active_features() as u32 }
+}
+
 #[unsafe(no_mangle)]
 unsafe extern "C" fn load_batch(loader: *mut BatchLoader) -> *mut Batch {
     unsafe { Box::into_raw(Box::new(loader.as_mut().unwrap().load())) }
@@ -53,6 +500,69 @@ unsafe extern "C" fn batch_total_features(batch: *const Batch) -> u32 {
     unsafe { batch.as_ref().unwrap().total_features as u32 }
 }
 
+/// Samples of the batch whose features were cut short, see [`Batch::truncated_samples`].
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_truncated_samples(batch: *const Batch) -> u32 {
+    unsafe { batch.as_ref().unwrap().truncated_samples as u32 }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_truncated_features(batch: *const Batch) -> u32 {
+    unsafe { batch.as_ref().unwrap().truncated_features as u32 }
+}
+
+/// FEN of a sample of the batch, null unless the loader keeps them. It lives as long as
+/// the batch.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_fen(batch: *const Batch, index: u32) -> *const c_char {
+    match unsafe { batch.as_ref().unwrap().fens.get(index as usize) } {
+        Some(fen) => fen.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Piece counts of the batch's samples, null unless the loader keeps them.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_piece_counts(batch: *const Batch) -> *const u32 {
+    let batch = unsafe { batch.as_ref().unwrap() };
+    if batch.piece_counts.is_empty() {
+        ptr::null()
+    } else {
+        batch.piece_counts.as_ptr()
+    }
+}
+
+/// Phases of the batch's samples from 0 to 24, null unless the loader keeps them.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_phases(batch: *const Batch) -> *const u32 {
+    let batch = unsafe { batch.as_ref().unwrap() };
+    if batch.phases.is_empty() {
+        ptr::null()
+    } else {
+        batch.phases.as_ptr()
+    }
+}
+
+/// The batch as an Arrow IPC stream of a single record batch, whose length is written to
+/// `len`. It must be freed with `drop_arrow_ipc`, and is null if it can't be written.
+#[cfg(feature = "arrow")]
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_arrow_ipc(batch: *const Batch, len: *mut usize) -> *mut u8 {
+    let mut stream = Vec::new();
+    if arrow::write_ipc_stream(&mut stream, [unsafe { batch.as_ref().unwrap() }]).is_err() {
+        return ptr::null_mut();
+    }
+    let stream = stream.into_boxed_slice();
+    unsafe { *len = stream.len() };
+    Box::into_raw(stream) as *mut u8
+}
+
+#[cfg(feature = "arrow")]
+#[unsafe(no_mangle)]
+unsafe extern "C" fn drop_arrow_ipc(stream: *mut u8, len: usize) {
+    drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(stream, len)) })
+}
+
 #[unsafe(no_mangle)]
 unsafe extern "C" fn batch_stm_features(batch: *const Batch) -> *const u32 {
     unsafe { batch.as_ref().unwrap().stm_features.as_ptr() }
@@ -73,3 +583,23 @@ unsafe extern "C" fn batch_outcomes(batch: *const Batch) -> *const f32 {
     unsafe { batch.as_ref().unwrap().outcomes.as_ptr() }
 }
 
+/// Values given by `batch_outcomes` for each sample.
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_outcome_width(batch: *const Batch) -> u32 {
+    unsafe { batch.as_ref().unwrap().outcome_encoding.width() as u32 }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_eval_scores(batch: *const Batch) -> *const f32 {
+    unsafe { batch.as_ref().unwrap().eval_scores.as_ptr() }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_targets(batch: *const Batch) -> *const f32 {
+    unsafe { batch.as_ref().unwrap().targets.as_ptr() }
+}
+
+#[unsafe(no_mangle)]
+unsafe extern "C" fn batch_weights(batch: *const Batch) -> *const f32 {
+    unsafe { batch.as_ref().unwrap().weights.as_ptr() }
+}
@@ -0,0 +1,103 @@
+//! Caps on what every loader of the process takes together, for when many are open at
+//! once, such as a training and a validation loader for each GPU. Loaders share what
+//! the caps allow instead of each taking its fill.
+
+use crate::loader::BUFFER_SIZE;
+use dataformat::PackedSample;
+use std::{
+    mem,
+    sync::{Condvar, Mutex},
+};
+
+/// Fewest samples a loader buffers whatever its share of the buffer memory, below which
+/// shuffling them would hardly mix the dataset.
+pub const MIN_BUFFER_SIZE: usize = 65536;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Dataset files open at once. With a cap, loaders close their file after every read
+    /// and wait for their turn to open it again.
+    pub max_open_files: Option<usize>,
+    /// Bytes of samples buffered for shuffling, split evenly between the loaders. Each
+    /// buffers at least [`MIN_BUFFER_SIZE`] samples, which goes over the cap with more
+    /// loaders than it has room for.
+    pub max_buffer_bytes: Option<usize>,
+}
+
+struct Usage {
+    limits: ResourceLimits,
+    loaders: usize,
+    open_files: usize,
+}
+
+static USAGE: Mutex<Usage> = Mutex::new(Usage {
+    limits: ResourceLimits { max_open_files: None, max_buffer_bytes: None },
+    loaders: 0,
+    open_files: 0,
+});
+static FILE_CLOSED: Condvar = Condvar::new();
+
+/// Sets the caps of every loader, taking effect the next time each of them reads.
+pub fn set_resource_limits(limits: ResourceLimits) {
+    USAGE.lock().unwrap().limits = limits;
+    // Loaders waiting for a file may be let through by a higher cap.
+    FILE_CLOSED.notify_all();
+}
+
+pub fn resource_limits() -> ResourceLimits {
+    USAGE.lock().unwrap().limits
+}
+
+/// A loader counted towards the share of the buffer memory each gets, until dropped.
+#[derive(Debug)]
+pub(crate) struct LoaderShare(());
+
+impl LoaderShare {
+    pub(crate) fn new() -> Self {
+        USAGE.lock().unwrap().loaders += 1;
+        LoaderShare(())
+    }
+
+    /// Samples the loader may buffer.
+    pub(crate) fn buffer_size(&self) -> usize {
+        let usage = USAGE.lock().unwrap();
+        match usage.limits.max_buffer_bytes {
+            Some(bytes) => (bytes / mem::size_of::<PackedSample>() / usage.loaders).clamp(MIN_BUFFER_SIZE, BUFFER_SIZE),
+            None => BUFFER_SIZE,
+        }
+    }
+
+    /// Whether files have to be closed between reads.
+    pub(crate) fn limits_files(&self) -> bool {
+        USAGE.lock().unwrap().limits.max_open_files.is_some()
+    }
+}
+
+impl Drop for LoaderShare {
+    fn drop(&mut self) {
+        USAGE.lock().unwrap().loaders -= 1;
+    }
+}
+
+/// A dataset file counted as open, until dropped.
+#[derive(Debug)]
+pub(crate) struct FilePermit(());
+
+impl FilePermit {
+    /// Waits until a file may be opened.
+    pub(crate) fn acquire() -> Self {
+        let mut usage = USAGE.lock().unwrap();
+        while usage.limits.max_open_files.is_some_and(|max| usage.open_files >= max.max(1)) {
+            usage = FILE_CLOSED.wait(usage).unwrap();
+        }
+        usage.open_files += 1;
+        FilePermit(())
+    }
+}
+
+impl Drop for FilePermit {
+    fn drop(&mut self) {
+        USAGE.lock().unwrap().open_files -= 1;
+        FILE_CLOSED.notify_one();
+    }
+}
@@ -1,39 +1,534 @@
-use dataformat::PackedSample;
-use rand::seq::SliceRandom;
+use dataformat::{Adjudication, EvalPerspective, PackedSample, manifest::Manifest, shard};
+use rand::{Rng, SeedableRng, seq::SliceRandom};
+use rand_xoshiro::Xoshiro256PlusPlus;
 use std::{
-    fs::File, io::{self, Read, Seek}, mem, sync::mpsc, thread::{self, JoinHandle}
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    mem,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, mpsc},
+    thread::{self, JoinHandle},
 };
 
-use crate::batch::Batch;
+use crate::{
+    augment::{Augmentation, Mirror},
+    batch::{Batch, Entry},
+    exclude::ExclusionFilter,
+    feature::FeatureSet,
+    filter::SampleFilter,
+    limits::{FilePermit, LoaderShare},
+    stratify::Stratification,
+    transform::{self, SampleTransform},
+    wdl::WdlModel,
+    weight::SampleWeighting,
+};
 
+/// Most samples a loader buffers for shuffling, fewer if [`ResourceLimits`] cap the
+/// memory of the buffers.
+///
+/// [`ResourceLimits`]: crate::limits::ResourceLimits
 pub const BUFFER_SIZE: usize = 4194304;
+/// Samples read per batch entry before giving up on filling a batch, so a dataset
+/// made mostly of unusable samples yields short batches instead of hanging.
+const MAX_ATTEMPTS: usize = 4;
+/// Samples held back for each stratum of a [`Stratification`] until a batch takes them,
+/// beyond which more samples of the stratum are passed over.
+const STRATUM_CAPACITY: usize = 65536;
+/// Told apart from the values seeding a sample's mirror, for the rolls of the
+/// transforms to be drawn independently of it.
+const TRANSFORM_STREAM: u64 = 1;
+
+/// Settings controlling how samples are turned into batches.
+#[derive(Clone, Debug, Default)]
+pub struct LoaderOptions {
+    /// Model turning evaluations into expected scores.
+    pub wdl_model: WdlModel,
+    /// Share of the blended target coming from the evaluation, the rest comes from the outcome.
+    pub eval_weight: f32,
+    /// Encoding of the positions into the batches' features.
+    pub feature_set: FeatureSet,
+    /// Leaves samples from games decided by adjudication out of the batches.
+    pub skip_adjudicated: bool,
+    /// Scales the outcome's share of the blended target for samples from adjudicated
+    /// games, the evaluation makes up the rest. Unset, they count like any other sample.
+    pub adjudicated_outcome_scale: Option<f32>,
+    /// Moves this share of the outcome target towards a draw, so a win becomes
+    /// `1 - smoothing / 2` and a loss `smoothing / 2`.
+    pub outcome_smoothing: f32,
+    /// How the batches' outcomes are given, see [`Batch::outcomes`]. The targets and
+    /// weights are worked out from scores whatever the encoding.
+    pub outcome_encoding: OutcomeEncoding,
+    /// Divides evaluations before they are turned into expected scores, softening the
+    /// eval targets above 1 and sharpening them below. Unset, evaluations are left as is.
+    pub eval_temperature: Option<f32>,
+    /// Positions left out of the batches, matched by their Zobrist hash.
+    pub exclusions: Option<Arc<ExclusionFilter>>,
+    /// How much each sample counts towards the loss, see [`Batch::weights`].
+    pub weighting: SampleWeighting,
+    /// Whose point of view the dataset's evals are given from, turned into the side to
+    /// move's for the batches. Unset, it's read from the dataset's manifest.
+    pub eval_perspective: Option<EvalPerspective>,
+    /// What becomes of samples stored without an evaluation.
+    pub missing_eval: MissingEval,
+    /// Keeps the FEN of every sample in the batches, see [`Batch::fen`], to look into the
+    /// positions behind odd losses. Every sample is unpacked for it, which slows loading.
+    pub keep_fens: bool,
+    /// Keeps the piece count and phase of every sample in the batches, see
+    /// [`Batch::piece_counts`] and [`Batch::phases`], for bucketed output heads or
+    /// validation metrics broken down by material.
+    pub keep_material: bool,
+    /// Conditions every sample must meet to be loaded, changed while loading with
+    /// [`BatchLoader::set_filters`].
+    pub filters: Vec<SampleFilter>,
+    /// Makeup every batch is drawn to, see [`Stratification`]. Strata too rare to fill
+    /// their share leave the rest of the batch to samples of any kind.
+    pub stratification: Option<Stratification>,
+    /// Seeds the order samples are loaded in, drawn at random when unset. Either way
+    /// it's part of the [`LoaderState`].
+    pub seed: Option<u64>,
+    /// Mirrors samples at random as they're added to the batches, which the seed also
+    /// decides.
+    pub augmentation: Option<Augmentation>,
+    /// The part of the dataset loaded, for each rank of a distributed training run to
+    /// load different samples. Unset, the whole dataset is loaded.
+    pub partition: Option<Partition>,
+    /// Custom transforms every sample is run through in order before it's added to a
+    /// batch, see [`SampleTransform`]. Samples are fully unpacked for them.
+    pub transforms: Vec<Arc<dyn SampleTransform>>,
+}
+
+/// One of `world_size` disjoint parts of a dataset, made of the `rank`th slice of the
+/// samples of every file. The parts only depend on the files' sizes, so every rank
+/// works them out the same way on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub rank: usize,
+    pub world_size: usize,
+}
+
+impl Partition {
+    /// The samples of a file of `samples` samples in this part.
+    #[inline]
+    pub fn range(&self, samples: u64) -> Range<u64> {
+        let slice = |rank: usize| (samples as u128 * rank as u128 / self.world_size as u128) as u64;
+        slice(self.rank)..slice(self.rank + 1)
+    }
+}
+
+/// How the loader treats samples without an evaluation, such as those extracted from
+/// unannotated games or positions the engine found a mate in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingEval {
+    /// Trains them on their outcome alone, whatever the eval weight.
+    #[default]
+    Outcome,
+    /// Leaves them out of the batches.
+    Skip,
+}
+
+impl MissingEval {
+    pub const ALL: [MissingEval; 2] = [MissingEval::Outcome, MissingEval::Skip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MissingEval::Outcome => "outcome",
+            MissingEval::Skip => "skip",
+        }
+    }
+}
+
+impl fmt::Display for MissingEval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownMissingEvalError(String);
+
+impl fmt::Display for UnknownMissingEvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown missing eval handling `{}`, expected `outcome` or `skip`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownMissingEvalError {}
+
+impl FromStr for MissingEval {
+    type Err = UnknownMissingEvalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MissingEval::ALL
+            .into_iter()
+            .find(|missing| missing.name() == s)
+            .ok_or_else(|| UnknownMissingEvalError(s.to_string()))
+    }
+}
+
+/// How game outcomes are given in the batches, all from the side to move's perspective
+/// and smoothed towards a draw the same way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutcomeEncoding {
+    /// 1 for a win, 0.5 for a draw and 0 for a loss.
+    #[default]
+    Score,
+    /// 1 for a win, 0 for a draw and -1 for a loss.
+    Signed,
+    /// Probabilities of a win, a draw and a loss, one-hot unless smoothed.
+    Wdl,
+}
+
+impl OutcomeEncoding {
+    pub const ALL: [OutcomeEncoding; 3] = [
+        OutcomeEncoding::Score,
+        OutcomeEncoding::Signed,
+        OutcomeEncoding::Wdl,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OutcomeEncoding::Score => "score",
+            OutcomeEncoding::Signed => "signed",
+            OutcomeEncoding::Wdl => "wdl",
+        }
+    }
+
+    /// Values given for each sample.
+    #[inline]
+    pub fn width(self) -> usize {
+        match self {
+            OutcomeEncoding::Score | OutcomeEncoding::Signed => 1,
+            OutcomeEncoding::Wdl => 3,
+        }
+    }
+
+    /// Writes the encoding of a smoothed score, a draw taking the smoothing off a win or
+    /// a loss, into `values` of [`OutcomeEncoding::width`] elements.
+    #[inline]
+    pub(crate) fn encode(self, score: f32, values: &mut [f32]) {
+        match self {
+            OutcomeEncoding::Score => values[0] = score,
+            OutcomeEncoding::Signed => values[0] = 2.0 * score - 1.0,
+            OutcomeEncoding::Wdl => {
+                let win = (2.0 * score - 1.0).max(0.0);
+                let loss = (1.0 - 2.0 * score).max(0.0);
+                values.copy_from_slice(&[win, 1.0 - win - loss, loss]);
+            }
+        }
+    }
+}
+
+impl fmt::Display for OutcomeEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownOutcomeEncodingError(String);
+
+impl fmt::Display for UnknownOutcomeEncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown outcome encoding `{}`, expected `score`, `signed` or `wdl`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownOutcomeEncodingError {}
+
+impl FromStr for OutcomeEncoding {
+    type Err = UnknownOutcomeEncodingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OutcomeEncoding::ALL
+            .into_iter()
+            .find(|encoding| encoding.name() == s)
+            .ok_or_else(|| UnknownOutcomeEncodingError(s.to_string()))
+    }
+}
+
+/// Where a loader is in its dataset, from which it can go on with the same samples in the
+/// same order, see [`BatchLoader::state`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoaderState {
+    seed: u64,
+    /// Files of the dataset, to tell when a state is restored on another dataset.
+    files: u64,
+    /// Epochs started, each visiting the files in an order drawn from the seed.
+    epoch: u64,
+    /// Files of the epoch opened so far.
+    opened: u64,
+    /// Byte offset in the last file opened of the buffer samples are taken from.
+    offset: u64,
+    /// Samples read into the buffer, which restoring reads again whatever share of the
+    /// buffer memory the loader has by then.
+    buffered: u64,
+    /// Samples taken from the buffer.
+    taken: u64,
+}
+
+impl LoaderState {
+    /// Number of values a state is made of, see [`LoaderState::to_words`].
+    pub const WORDS: usize = 7;
+
+    /// The state as plain numbers, to store along with a training checkpoint.
+    pub fn to_words(&self) -> [u64; Self::WORDS] {
+        [
+            self.seed,
+            self.files,
+            self.epoch,
+            self.opened,
+            self.offset,
+            self.buffered,
+            self.taken,
+        ]
+    }
+
+    pub fn from_words(words: [u64; Self::WORDS]) -> Self {
+        let [seed, files, epoch, opened, offset, buffered, taken] = words;
+        LoaderState {
+            seed,
+            files,
+            epoch,
+            opened,
+            offset,
+            buffered,
+            taken,
+        }
+    }
+}
+
+/// Batches from the loading thread, each with the state after it.
+type BatchReceiver = mpsc::Receiver<(Batch, LoaderState)>;
 
 #[derive(Debug)]
 pub struct BatchLoader {
-    batch_receiver: mpsc::Receiver<Batch>,
+    batch_receiver: BatchReceiver,
+    batch_size: usize,
+    /// The dataset being loaded, which [`BatchLoader::restore`] reopens.
+    path: PathBuf,
+    /// The state after the last batch loaded.
+    state: LoaderState,
+    /// The options as given, before the eval perspective is taken from the manifest of
+    /// the dataset, so reloading takes it from the new one.
+    options: LoaderOptions,
+    /// The filters of the loading thread, which it picks up before every batch.
+    filters: Arc<Mutex<Vec<SampleFilter>>>,
     _worker: JoinHandle<()>,
 }
 
 impl BatchLoader {
-    pub fn from_file(file: File, batch_size: usize) -> Self {
-        let (batch_sender, batch_receiver) = mpsc::sync_channel(32);
-        Self {
+    /// Opens a dataset file, or a directory of shards which are visited in a new random
+    /// order every epoch. Compressed datasets are not supported.
+    pub fn from_path(path: &Path, batch_size: usize) -> io::Result<Self> {
+        Self::with_options(path, batch_size, LoaderOptions::default())
+    }
+
+    pub fn with_options(
+        path: &Path,
+        batch_size: usize,
+        options: LoaderOptions,
+    ) -> io::Result<Self> {
+        let filters = Arc::new(Mutex::new(options.filters.clone()));
+        let (batch_receiver, worker, state) =
+            spawn_loader(path, batch_size, options.clone(), filters.clone(), None)?;
+        Ok(Self {
             batch_receiver,
-            _worker: thread::spawn(move || loader_thread(file, batch_size, batch_sender))
-        }
+            batch_size,
+            path: path.to_path_buf(),
+            state,
+            options,
+            filters,
+            _worker: worker,
+        })
+    }
+
+    /// Switches to another dataset with the same options. Batches already loaded from
+    /// the old dataset are dropped, so the next batch comes from the new one. The loader
+    /// keeps going with the old dataset if the new one can't be opened.
+    pub fn reload(&mut self, path: &Path) -> io::Result<()> {
+        self.respawn(path, None)?;
+        self.path = path.to_path_buf();
+        Ok(())
+    }
+
+    /// Where the loader is after the batches loaded so far. Restoring it, even in
+    /// another process, goes on with the samples that would have come next.
+    #[inline]
+    pub fn state(&self) -> LoaderState {
+        self.state
+    }
+
+    /// Goes back to a state of this loader's dataset, such as to resume training from a
+    /// checkpoint in the middle of an epoch. Batches already loaded are dropped like on
+    /// [`BatchLoader::reload`], and the filters are the loader's current ones. Samples
+    /// held back for a stratification aren't part of the state, so the batches only match
+    /// exactly without one.
+    pub fn restore(&mut self, state: LoaderState) -> io::Result<()> {
+        let path = self.path.clone();
+        self.respawn(&path, Some(state))
+    }
+
+    fn respawn(&mut self, path: &Path, state: Option<LoaderState>) -> io::Result<()> {
+        let filters = Arc::new(Mutex::new(self.options.filters.clone()));
+        let (batch_receiver, worker, state) = spawn_loader(
+            path,
+            self.batch_size,
+            self.options.clone(),
+            filters.clone(),
+            state,
+        )?;
+        // The old thread stops as soon as it finds its receiver gone.
+        self.batch_receiver = batch_receiver;
+        self.state = state;
+        self.filters = filters;
+        self._worker = worker;
+        Ok(())
+    }
+
+    /// Replaces the conditions samples must meet to be loaded, such as to let in harder
+    /// positions as training goes on. Batches loaded ahead of time, as many as the
+    /// loader queues up, still follow the old filters.
+    pub fn set_filters(&mut self, filters: Vec<SampleFilter>) {
+        *self.filters.lock().unwrap() = filters.clone();
+        self.options.filters = filters;
     }
 
     pub fn load(&mut self) -> Batch {
-        self.batch_receiver.recv().expect("batch loading thread has disconnected")
+        let (batch, state) = self
+            .batch_receiver
+            .recv()
+            .expect("batch loading thread has disconnected");
+        self.state = state;
+        batch
+    }
+
+    /// Most active features a sample of the loaded batches can have.
+    #[inline]
+    pub fn max_active_features(&self) -> usize {
+        self.options.feature_set.max_active_features()
+    }
+}
+
+/// Checks the dataset and starts a thread loading batches from it, from the given state
+/// or the start. Returns the state it starts from.
+fn spawn_loader(
+    path: &Path,
+    batch_size: usize,
+    mut options: LoaderOptions,
+    filters: Arc<Mutex<Vec<SampleFilter>>>,
+    state: Option<LoaderState>,
+) -> io::Result<(BatchReceiver, JoinHandle<()>, LoaderState)> {
+    let files = if path.is_dir() {
+        shard::shard_files(path)?
+    } else {
+        vec![path.to_path_buf()]
+    };
+    if files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "dataset has no shards",
+        ));
+    }
+    if files
+        .iter()
+        .any(|path| path.extension().is_some_and(|ext| ext == "zst"))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "compressed datasets cannot be loaded",
+        ));
     }
+    if let Some(partition) = options.partition
+        && partition.rank >= partition.world_size
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "rank {} is out of a world of {}",
+                partition.rank, partition.world_size
+            ),
+        ));
+    }
+    // Fail early on files that can't be opened rather than in the loader thread.
+    for path in &files {
+        let _permit = FilePermit::acquire();
+        let trailing = File::open(path)?.metadata()?.len() % mem::size_of::<PackedSample>() as u64;
+        if trailing != 0 {
+            eprintln!(
+                "warning: ignoring the last {} bytes of `{}`, which don't make up a whole sample",
+                trailing,
+                path.display()
+            );
+        }
+    }
+    // A dataset changed since its manifest was written was most likely cut short or
+    // mixed up with another one.
+    let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+    let manifest = Manifest::find(path).map_err(invalid)?;
+    if let Some(manifest) = &manifest {
+        manifest.check_files(&files).map_err(invalid)?;
+    }
+    if options.eval_perspective.is_none() {
+        options.eval_perspective = match &manifest {
+            Some(manifest) => Some(manifest.eval_perspective().map_err(invalid)?),
+            None => Some(EvalPerspective::SideToMove),
+        };
+    }
+
+    let seed = options.seed.unwrap_or_else(rand::random);
+    let partition = options.partition;
+    let mut batch_loader = BufferedLoader::from_files(files, options, seed);
+    // The loading thread can only fail by panicking, so a dataset it would find empty
+    // is turned down here.
+    if !batch_loader.has_samples()? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            match partition {
+                Some(partition) => format!(
+                    "dataset has no samples for rank {} of a world of {}",
+                    partition.rank, partition.world_size
+                ),
+                None => "dataset is empty".to_string(),
+            },
+        ));
+    }
+    if let Some(state) = state {
+        batch_loader.restore(state)?;
+    }
+    let state = batch_loader.state();
+
+    let (batch_sender, batch_receiver) = mpsc::sync_channel(32);
+    let worker =
+        thread::spawn(move || loader_thread(batch_loader, batch_size, filters, batch_sender));
+    Ok((batch_receiver, worker, state))
 }
 
-fn loader_thread(file: File, batch_size: usize, batch_sender: mpsc::SyncSender<Batch>) {
-    let mut batch_loader = BufferedLoader::from_file(file);
+fn loader_thread(
+    mut batch_loader: BufferedLoader,
+    batch_size: usize,
+    filters: Arc<Mutex<Vec<SampleFilter>>>,
+    batch_sender: mpsc::SyncSender<(Batch, LoaderState)>,
+) {
+    let feature_set = batch_loader.options.feature_set;
     loop {
-        let mut batch = Batch::new(batch_size);
+        batch_loader
+            .options
+            .filters
+            .clone_from(&filters.lock().unwrap());
+        let mut batch = Batch::new(batch_size, feature_set);
         batch_loader.load_into(&mut batch);
-        if batch_sender.send(batch).is_err() {
+        if batch_sender.send((batch, batch_loader.state())).is_err() {
             return;
         }
     }
@@ -41,54 +536,420 @@ fn loader_thread(file: File, batch_size: usize, batch_sender: mpsc::SyncSender<B
 
 #[derive(Debug)]
 struct BufferedLoader {
-    file: File,
+    files: Vec<PathBuf>,
+    /// Index into `files` of the next file to open.
+    next_file: usize,
+    /// Whether the last file opened may have samples left to read.
+    in_file: bool,
+    /// The last file opened, closed between reads when open files are capped.
+    file: Option<(File, FilePermit)>,
     buffer: Vec<PackedSample>,
+    /// This loader's part of the buffer memory.
+    share: LoaderShare,
+    seed: u64,
+    epoch: u64,
+    /// Bytes read from the open file.
+    file_offset: u64,
+    /// Byte offset in the open file past the last sample of the loader's partition.
+    file_end: u64,
+    /// Byte offset in the open file of the samples in the buffer.
+    buffer_offset: u64,
+    /// Samples read into the buffer.
+    buffered: usize,
+    /// Samples taken from the buffer.
+    taken: usize,
+    options: LoaderOptions,
+    /// Samples read while looking for another stratum, one list per stratum.
+    strata: Vec<Vec<PackedSample>>,
+    /// Whether truncated samples were reported already, which is only done once.
+    reported_truncation: bool,
+    /// Whether a batch was reported to be cut short already, which is only done once.
+    reported_short_batch: bool,
 }
 
 impl BufferedLoader {
-    pub fn from_file(file: File) -> Self {
+    pub fn from_files(files: Vec<PathBuf>, options: LoaderOptions, seed: u64) -> Self {
         Self {
-            file,
-            buffer: Vec::with_capacity(BUFFER_SIZE),
+            next_file: files.len(),
+            files,
+            in_file: false,
+            file: None,
+            seed,
+            epoch: 0,
+            file_offset: 0,
+            file_end: 0,
+            buffer_offset: 0,
+            buffered: 0,
+            taken: 0,
+            strata: vec![
+                Vec::new();
+                options
+                    .stratification
+                    .as_ref()
+                    .map_or(0, Stratification::strata)
+            ],
+            options,
+            buffer: Vec::new(),
+            share: LoaderShare::new(),
+            reported_truncation: false,
+            reported_short_batch: false,
         }
     }
 
     pub fn load_into(&mut self, batch: &mut Batch) {
         batch.clear();
-        for _ in 0..batch.capacity {
-            if let Some(sample) = self.next() {
-                let sample = match sample.unpack() {
-                    Ok(sample) => sample,
-                    Err(err) => {
-                        eprintln!("error: failed to unpack sample: {}", err);
-                        continue;
+        if let Some(stratification) = self.options.stratification.clone() {
+            for (stratum, count) in stratification
+                .counts(batch.capacity)
+                .into_iter()
+                .enumerate()
+            {
+                let target = batch.len() + count;
+                let mut attempts = 0;
+                while batch.len() < target && attempts < MAX_ATTEMPTS * batch.capacity {
+                    attempts += 1;
+                    if let Some(packed) = self.next_in(&stratification, stratum) {
+                        self.add(batch, &packed);
                     }
-                };
-                batch.add(&sample);
-            } else {
+                }
+            }
+        }
+        let mut attempts = 0;
+        while batch.len() < batch.capacity && attempts < MAX_ATTEMPTS * batch.capacity {
+            attempts += 1;
+            if let Some(packed) = self.next() {
+                self.add(batch, &packed);
+            }
+        }
+        if batch.len() < batch.capacity && !self.reported_short_batch {
+            eprintln!(
+                "warning: a batch was cut short at {} of {} samples, as too few of the samples read were let through",
+                batch.len(),
+                batch.capacity
+            );
+            self.reported_short_batch = true;
+        }
+        if batch.truncated_samples() > 0 && !self.reported_truncation {
+            eprintln!(
+                "error: {} samples of a batch have more than the {} active features the {} feature set allows for, {} extra features were left out",
+                batch.truncated_samples(),
+                self.options.feature_set.max_active_features(),
+                self.options.feature_set,
+                batch.truncated_features()
+            );
+            self.reported_truncation = true;
+        }
+    }
+
+    fn add(&self, batch: &mut Batch, packed: &PackedSample) {
+        // Rolled from where the sample was read, so that a restored state mirrors the
+        // same samples.
+        let mirror = self.options.augmentation.and_then(|augmentation| {
+            let mut rng = seeded_rng(&[
+                self.seed,
+                self.epoch,
+                self.next_file as u64,
+                self.buffer_offset,
+                self.taken as u64,
+            ]);
+            augmentation.mirror(rng.random())
+        });
+        if !self.options.transforms.is_empty() {
+            self.add_transformed(batch, packed, mirror);
+            return;
+        }
+        let added = match &self.options.exclusions {
+            // Matching exclusions takes the position's hash, which only a full unpack
+            // gives. Excluded positions are replaced rather than leaving the batch short.
+            Some(exclusions) => packed.unpack().map(|sample| {
+                if !exclusions.contains(sample.position.hash()) {
+                    batch.add_mirrored(&sample, packed.adjudication(), mirror, &self.options);
+                }
+            }),
+            None => batch.add_packed_mirrored(packed, mirror, &self.options),
+        };
+        if let Err(err) = added {
+            eprintln!("error: failed to unpack sample: {}", err);
+        }
+    }
+
+    /// Adds the samples the transforms make of `packed`, as many as the batch has room for.
+    fn add_transformed(&self, batch: &mut Batch, packed: &PackedSample, mirror: Option<Mirror>) {
+        let mut sample = match packed.unpack() {
+            Ok(sample) => sample,
+            Err(err) => {
+                eprintln!("error: failed to unpack sample: {}", err);
+                return;
+            }
+        };
+        if self
+            .options
+            .exclusions
+            .as_ref()
+            .is_some_and(|exclusions| exclusions.contains(sample.position.hash()))
+        {
+            return;
+        }
+        // Transforms see evals from the side to move's perspective, and the batch turns
+        // them back from the dataset's.
+        let perspective = self.options.eval_perspective.unwrap_or_default();
+        let side_to_move = sample.position.side_to_move();
+        sample.eval = sample
+            .eval
+            .map(|eval| perspective.to_side_to_move(eval, side_to_move));
+        let mut rng = seeded_rng(&[
+            self.seed,
+            self.epoch,
+            self.next_file as u64,
+            self.buffer_offset,
+            self.taken as u64,
+            TRANSFORM_STREAM,
+        ]);
+        for mut sample in transform::apply_all(&self.options.transforms, sample, &mut rng) {
+            if batch.len() == batch.capacity {
                 break;
             }
+            let side_to_move = sample.position.side_to_move();
+            sample.eval = sample
+                .eval
+                .map(|eval| perspective.from_side_to_move(eval, side_to_move));
+            batch.add_mirrored(&sample, packed.adjudication(), mirror, &self.options);
+        }
+    }
+
+    /// A sample of `stratum`, held back earlier or the next one read if it belongs to
+    /// it. A sample read of another stratum is held back for it instead, and `None`
+    /// returned so the caller can count the attempt.
+    fn next_in(&mut self, stratification: &Stratification, stratum: usize) -> Option<PackedSample> {
+        if let Some(sample) = self.strata[stratum].pop() {
+            return Some(sample);
+        }
+        let sample = self.next()?;
+        // Samples which can't be unpacked are left to the batch to report.
+        let Ok(entry) = Entry::from_packed(&sample) else {
+            return Some(sample);
+        };
+        let other = stratification.stratum(&entry);
+        if other == stratum {
+            return Some(sample);
+        }
+        if self.strata[other].len() < STRATUM_CAPACITY {
+            self.strata[other].push(sample);
+        }
+        None
+    }
+
+    fn state(&self) -> LoaderState {
+        LoaderState {
+            seed: self.seed,
+            files: self.files.len() as u64,
+            epoch: self.epoch,
+            opened: self.next_file as u64,
+            offset: self.buffer_offset,
+            buffered: self.buffered as u64,
+            taken: self.taken as u64,
+        }
+    }
+
+    /// Picks up from a state by reading the buffer it was at again.
+    fn restore(&mut self, state: LoaderState) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if state.files != self.files.len() as u64 {
+            return Err(invalid(format!(
+                "loader state is of a dataset of {} files, not {}",
+                state.files,
+                self.files.len()
+            )));
+        }
+        self.seed = state.seed;
+        if state.epoch == 0 {
+            return Ok(());
+        }
+        if state.opened == 0 || state.opened > state.files {
+            return Err(invalid(format!(
+                "loader state has {} of {} files opened",
+                state.opened, state.files
+            )));
         }
+        self.epoch = state.epoch;
+        self.shuffle_files();
+        self.next_file = state.opened as usize;
+        self.in_file = true;
+        self.file = None;
+        self.file_offset = state.offset;
+        self.file_end = self.file_range(self.next_file - 1)?.end;
+        self.read_buffer(state.buffered as usize)?;
+        let taken = (state.taken as usize).min(self.buffer.len());
+        self.buffer.truncate(self.buffer.len() - taken);
+        self.taken = taken;
+        Ok(())
     }
 
+    /// The next sample read, or `None` if it was skipped, such as for not passing the
+    /// filters. The caller counts the attempt, so that a dataset where no sample is let
+    /// through gives short batches instead of reading forever.
     fn next(&mut self) -> Option<PackedSample> {
         if self.buffer.is_empty() {
-            self.fill_buffer().expect("failed to read from dataset file");
+            self.fill_buffer()
+                .expect("failed to read from dataset file");
         }
-        self.buffer.pop()
+        let sample = self.buffer.pop()?;
+        self.taken += 1;
+        let skipped = (self.options.skip_adjudicated
+            && sample.adjudication() != Adjudication::None)
+            || (self.options.missing_eval == MissingEval::Skip && sample.eval().is_none())
+            || (!self.options.filters.is_empty() && !self.passes_filters(&sample));
+        (!skipped).then_some(sample)
+    }
+
+    /// Samples which can't be unpacked pass, so the batch reports them.
+    fn passes_filters(&self, sample: &PackedSample) -> bool {
+        let Ok(entry) = Entry::from_packed(sample) else {
+            return true;
+        };
+        let entry = entry.to_side_to_move(self.options.eval_perspective.unwrap_or_default());
+        self.options
+            .filters
+            .iter()
+            .all(|filter| filter.matches(&entry))
     }
 
     fn fill_buffer(&mut self) -> io::Result<()> {
-        unsafe { self.buffer.set_len(BUFFER_SIZE) };
-        let mut buf_size = self.file.read(bytemuck::cast_slice_mut(&mut self.buffer))?;
-        if buf_size == 0 {
-            self.file.rewind()?;
-            buf_size = self.file.read(bytemuck::cast_slice_mut(&mut self.buffer))?;
+        // Bounded so that a dataset of empty files errors out instead of spinning forever.
+        for _ in 0..=self.files.len() {
+            if self.read_buffer(self.share.buffer_size())? != 0 {
+                return Ok(());
+            }
+            self.open_next_file()?;
         }
-        self.buffer.resize(buf_size / mem::size_of::<PackedSample>(), Default::default());
-        self.buffer.shuffle(&mut rand::rng());
+        Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "dataset is empty",
+        ))
+    }
+
+    /// Reads up to `size` of the next samples of the last file opened into the buffer and
+    /// shuffles them, returning how many were read. The shuffle only depends on the seed
+    /// and where the samples are in the dataset, so a restored state shuffles them the
+    /// same way.
+    fn read_buffer(&mut self, size: usize) -> io::Result<usize> {
+        if !self.in_file {
+            return Ok(0);
+        }
+        let (file, _) = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let permit = FilePermit::acquire();
+                let mut file = File::open(&self.files[self.next_file - 1])?;
+                file.seek(SeekFrom::Start(self.file_offset))?;
+                self.file.insert((file, permit))
+            }
+        };
+        let size = size.min(
+            (self.file_end.saturating_sub(self.file_offset) / mem::size_of::<PackedSample>() as u64)
+                as usize,
+        );
+        // A buffer from before the loader's share shrank is given back.
+        if self.buffer.capacity() > size {
+            self.buffer = Vec::with_capacity(size);
+        }
+        self.buffer.clear();
+        self.buffer.reserve_exact(size);
+        unsafe { self.buffer.set_len(size) };
+        let samples = read_samples(file, &mut self.buffer)?;
+        self.buffer.truncate(samples);
+        if self.share.limits_files() {
+            self.file = None;
+        }
+        self.in_file = samples != 0;
+        self.buffer_offset = self.file_offset;
+        self.file_offset += (samples * mem::size_of::<PackedSample>()) as u64;
+        self.buffered = samples;
+        self.taken = 0;
+        self.buffer.shuffle(&mut seeded_rng(&[
+            self.seed,
+            self.epoch,
+            self.next_file as u64,
+            self.buffer_offset,
+        ]));
+        Ok(samples)
+    }
+
+    /// Moves on to the next file, starting a new epoch in a new random file order
+    /// once every file has been read.
+    fn open_next_file(&mut self) -> io::Result<()> {
+        if self.next_file == self.files.len() {
+            self.epoch += 1;
+            self.shuffle_files();
+            self.next_file = 0;
+        }
+        // Opened by the next read, so that waiting for a file happens in one place.
+        let range = self.file_range(self.next_file)?;
+        self.in_file = true;
+        self.file = None;
+        self.file_offset = range.start;
+        self.file_end = range.end;
+        self.next_file += 1;
         Ok(())
     }
+
+    /// Byte range of the samples of a file in the loader's partition.
+    fn file_range(&self, file: usize) -> io::Result<Range<u64>> {
+        let Some(partition) = self.options.partition else {
+            return Ok(0..u64::MAX);
+        };
+        let sample_size = mem::size_of::<PackedSample>() as u64;
+        let samples = partition.range(fs::metadata(&self.files[file])?.len() / sample_size);
+        Ok(samples.start * sample_size..samples.end * sample_size)
+    }
+
+    /// Whether any of the files has a sample in the loader's partition of it.
+    fn has_samples(&self) -> io::Result<bool> {
+        let sample_size = mem::size_of::<PackedSample>() as u64;
+        for file in 0..self.files.len() {
+            let len = fs::metadata(&self.files[file])?.len();
+            let range = self.file_range(file)?;
+            if range.end.min(len) - range.start.min(len) >= sample_size {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Puts the files in the order of the current epoch.
+    fn shuffle_files(&mut self) {
+        self.files.sort();
+        self.files
+            .shuffle(&mut seeded_rng(&[self.seed, self.epoch]));
+    }
+}
+
+/// A random number generator seeded from several values, which draws the same numbers
+/// for the same values on every run and platform.
+fn seeded_rng(values: &[u64]) -> Xoshiro256PlusPlus {
+    let seed = values.iter().fold(0, |seed: u64, value| {
+        (seed ^ value)
+            .wrapping_mul(0x9E3779B97F4A7C15)
+            .rotate_left(31)
+    });
+    Xoshiro256PlusPlus::seed_from_u64(seed)
+}
+
+/// Fills `buffer` with whole samples from `file`, returning how many were read. Short
+/// reads are retried so that samples never straddle two reads, and a partial sample at
+/// the end of a truncated file is dropped.
+pub(crate) fn read_samples(file: &mut File, buffer: &mut [PackedSample]) -> io::Result<usize> {
+    let bytes: &mut [u8] = bytemuck::cast_slice_mut(buffer);
+    let mut filled = 0;
+    while filled < bytes.len() {
+        match file.read(&mut bytes[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled / mem::size_of::<PackedSample>())
 }
 
 /*
@@ -146,3 +1007,119 @@ impl BatchLoader {
     }
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dama::{Outcome, Position};
+    use dataformat::Sample;
+
+    /// A dataset file whose samples are told apart by their evals, from 0 upwards.
+    fn write_dataset(name: &str, samples: i16) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("dataloader-{}-{}.bin", name, std::process::id()));
+        let packed: Vec<PackedSample> = (0..samples)
+            .map(|eval| {
+                Sample {
+                    position: Position::new_initial(),
+                    outcome: Outcome::Draw,
+                    eval: Some(eval),
+                }
+                .pack()
+                .unwrap()
+            })
+            .collect();
+        fs::write(&path, bytemuck::cast_slice(&packed)).unwrap();
+        path
+    }
+
+    fn load_evals(loader: &mut BatchLoader, batches: usize) -> Vec<f32> {
+        (0..batches)
+            .flat_map(|_| loader.load().evals().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn partitions_split_files_evenly() {
+        for samples in [0, 1, 7, 100, u64::MAX] {
+            for world_size in 1..=5 {
+                let mut start = 0;
+                for rank in 0..world_size {
+                    let range = Partition { rank, world_size }.range(samples);
+                    assert_eq!(range.start, start);
+                    assert!(range.end - range.start - samples / world_size as u64 <= 1);
+                    start = range.end;
+                }
+                assert_eq!(start, samples);
+            }
+        }
+    }
+
+    #[test]
+    fn state_words_round_trip() {
+        let path = write_dataset("words", 10);
+        let mut loader = BatchLoader::from_path(&path, 4).unwrap();
+        loader.load();
+        let state = loader.state();
+        assert_eq!(LoaderState::from_words(state.to_words()), state);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn restored_state_loads_the_same_samples() {
+        let path = write_dataset("restore", 100);
+        let options = LoaderOptions {
+            seed: Some(7),
+            ..LoaderOptions::default()
+        };
+        // Enough batches for the loader to go through several epochs.
+        let mut loader = BatchLoader::with_options(&path, 16, options.clone()).unwrap();
+        load_evals(&mut loader, 9);
+        let state = loader.state();
+        let expected = load_evals(&mut loader, 15);
+
+        let mut restored = BatchLoader::with_options(&path, 16, options).unwrap();
+        restored.restore(state).unwrap();
+        assert_eq!(restored.state(), state);
+        assert_eq!(load_evals(&mut restored, 15), expected);
+
+        // Going back to the state of the same loader works the same way.
+        loader.restore(state).unwrap();
+        assert_eq!(load_evals(&mut loader, 15), expected);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn empty_partitions_are_rejected() {
+        let path = write_dataset("partition", 2);
+        let options = |rank| LoaderOptions {
+            partition: Some(Partition {
+                rank,
+                world_size: 4,
+            }),
+            ..LoaderOptions::default()
+        };
+        assert!(BatchLoader::with_options(&path, 4, options(1)).is_ok());
+        let err = BatchLoader::with_options(&path, 4, options(0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn filters_letting_nothing_through_give_empty_batches() {
+        let path = write_dataset("filtered", 100);
+        let options = LoaderOptions {
+            filters: vec!["eval>1000".parse().unwrap()],
+            ..LoaderOptions::default()
+        };
+        let mut loader = BatchLoader::with_options(&path, 16, options).unwrap();
+        assert!(loader.load().evals().is_empty());
+
+        // New filters are picked up once the batches queued up with the old ones are used.
+        loader.set_filters(vec!["eval<50".parse().unwrap()]);
+        let evals = load_evals(&mut loader, 64);
+        assert!(!evals.is_empty());
+        assert!(evals.iter().all(|&eval| eval < 50.0));
+        fs::remove_file(path).unwrap();
+    }
+}
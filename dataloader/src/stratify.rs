@@ -0,0 +1,121 @@
+use crate::{batch::Entry, wdl::MAX_PHASE};
+use dama::Outcome;
+use std::{fmt, str::FromStr};
+
+/// A target makeup for every batch, parsed from `<key>:<share>,<share>...` such as
+/// `outcome:1,1,1` or `phase:1,2,1`. Each batch takes its share of samples from each
+/// stratum, so the mix of targets stays the same from one batch to the next.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stratification {
+    key: StratumKey,
+    /// Share of each stratum, summing to 1.
+    shares: Vec<f64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StratumKey {
+    /// Losses, draws and wins from the side to move's perspective.
+    Outcome,
+    /// Equal ranges of [`wdl::phase`](crate::wdl::phase), from endgames to openings.
+    Phase,
+}
+
+impl Stratification {
+    #[inline]
+    pub fn strata(&self) -> usize {
+        self.shares.len()
+    }
+
+    /// Index of the stratum a sample belongs to.
+    #[inline]
+    pub(crate) fn stratum(&self, entry: &Entry) -> usize {
+        match self.key {
+            StratumKey::Outcome => match entry.outcome {
+                Outcome::Winner(winner) if winner == entry.side_to_move => 2,
+                Outcome::Winner(_) => 0,
+                Outcome::Draw => 1,
+            },
+            StratumKey::Phase => (entry.phase() as usize * self.strata() / (MAX_PHASE as usize + 1)).min(self.strata() - 1),
+        }
+    }
+
+    /// Samples of each stratum making up a batch of `capacity` samples, the rounding
+    /// going to the strata with the largest remainders.
+    pub(crate) fn counts(&self, capacity: usize) -> Vec<usize> {
+        let exact: Vec<f64> = self.shares.iter().map(|share| share * capacity as f64).collect();
+        let mut counts: Vec<usize> = exact.iter().map(|count| count.floor() as usize).collect();
+        let mut by_remainder: Vec<usize> = (0..counts.len()).collect();
+        by_remainder.sort_by(|&a, &b| (exact[b] - exact[b].floor()).total_cmp(&(exact[a] - exact[a].floor())));
+        let missing = capacity - counts.iter().sum::<usize>();
+        for &stratum in by_remainder.iter().take(missing) {
+            counts[stratum] += 1;
+        }
+        counts
+    }
+}
+
+impl fmt::Display for Stratification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = match self.key {
+            StratumKey::Outcome => "outcome",
+            StratumKey::Phase => "phase",
+        };
+        let shares: Vec<_> = self.shares.iter().map(|share| share.to_string()).collect();
+        write!(f, "{}:{}", key, shares.join(","))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidStratificationError(String);
+
+impl fmt::Display for InvalidStratificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidStratificationError {}
+
+impl FromStr for Stratification {
+    type Err = InvalidStratificationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = InvalidStratificationError;
+        let (key, shares) = s
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("stratification `{}` must be of the form `<key>:<share>,<share>...`", s.trim())))?;
+        let key = match key.trim() {
+            "outcome" => StratumKey::Outcome,
+            "phase" => StratumKey::Phase,
+            key => return Err(invalid(format!("unknown stratification key `{}`, expected `outcome` or `phase`", key))),
+        };
+        let shares = shares
+            .split(',')
+            .map(|share| {
+                share
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|share| share.is_finite() && *share >= 0.0)
+                    .ok_or_else(|| invalid(format!("invalid share `{}`", share.trim())))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let total: f64 = shares.iter().sum();
+        if total <= 0.0 {
+            return Err(invalid("stratification shares must not all be zero".to_string()));
+        }
+        match key {
+            StratumKey::Outcome if shares.len() != 3 => {
+                return Err(invalid("outcome stratification takes three shares, for losses, draws and wins".to_string()));
+            }
+            StratumKey::Phase if shares.len() > MAX_PHASE as usize + 1 => {
+                return Err(invalid(format!("phase stratification takes at most {} shares", MAX_PHASE + 1)));
+            }
+            _ => {}
+        }
+        Ok(Stratification {
+            key,
+            shares: shares.iter().map(|share| share / total).collect(),
+        })
+    }
+}
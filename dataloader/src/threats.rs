@@ -0,0 +1,119 @@
+use dama::{Color, Piece, SquareSet, SquareSets};
+use dataformat::PieceSets;
+
+/// Squares attacked by any of `color`'s pieces of type `piece`, whether they hold a
+/// piece of either side or not.
+pub fn attacked(pieces: &PieceSets, color: Color, piece: Piece) -> SquareSet {
+    let occupied = pieces.occupied();
+    let attackers = pieces.pieces(piece) & pieces.colored(color);
+    if piece == Piece::Pawn {
+        return SquareSet::all_pawn_attacks(color, attackers);
+    }
+    let mut attacked = SquareSet::EMPTY;
+    for square in attackers {
+        attacked |= match piece {
+            Piece::Knight => SquareSet::knight_moves(square),
+            Piece::Bishop => SquareSet::bishop_moves(square, occupied),
+            Piece::Rook => SquareSet::rook_moves(square, occupied),
+            Piece::Queen => SquareSet::queen_moves(square, occupied),
+            Piece::King => SquareSet::king_moves(square),
+            Piece::Pawn => unreachable!("pawn attacks are taken all at once"),
+        };
+    }
+    attacked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feature::FeatureSet;
+    use dama::{Position, Square};
+
+    fn pieces(fen: &str) -> (PieceSets, Color) {
+        let position = Position::from_fen(fen).unwrap();
+        (PieceSets::of(&position), position.side_to_move())
+    }
+
+    fn squares(squares: &[Square]) -> SquareSet {
+        squares
+            .iter()
+            .fold(SquareSet::EMPTY, |set, &square| set.with_square(square))
+    }
+
+    #[test]
+    fn sliders_stop_at_the_first_piece() {
+        let (pieces, _) = pieces("4k3/8/8/8/8/8/1p6/R3K3 w - - 0 1");
+        use Square::*;
+        assert_eq!(
+            attacked(&pieces, Color::White, Piece::Rook),
+            squares(&[A2, A3, A4, A5, A6, A7, A8, B1, C1, D1, E1])
+        );
+        assert_eq!(
+            attacked(&pieces, Color::White, Piece::King),
+            squares(&[D1, D2, E2, F2, F1])
+        );
+        assert_eq!(
+            attacked(&pieces, Color::Black, Piece::Pawn),
+            squares(&[A1, C1])
+        );
+    }
+
+    #[test]
+    fn pawn_attacks_dont_wrap_around() {
+        let (pieces, _) = pieces("4k3/8/7p/8/8/P7/8/4K3 w - - 0 1");
+        assert_eq!(
+            attacked(&pieces, Color::White, Piece::Pawn),
+            squares(&[Square::B4])
+        );
+        assert_eq!(
+            attacked(&pieces, Color::Black, Piece::Pawn),
+            squares(&[Square::G5])
+        );
+    }
+
+    #[test]
+    fn initial_position_has_32_threats_per_side() {
+        let (pieces, side_to_move) =
+            pieces("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let mut board = 0;
+        let mut threats = 0;
+        FeatureSet::Chess768Threats.active_features_of(&pieces, side_to_move, |stm, nstm| {
+            assert!(stm < 1536 && nstm < 1536);
+            if stm < 768 {
+                board += 1;
+            } else {
+                threats += 1;
+            }
+        });
+        // Per side: 8 squares in front of the pawns, 6 for the knights, 4 for the
+        // bishops and rooks each, 5 for the queen and king each.
+        assert_eq!(board, 32);
+        assert_eq!(threats, 64);
+    }
+
+    #[test]
+    fn threat_features_are_mirrored_for_black() {
+        let (pieces, side_to_move) = pieces("4k3/8/8/3n4/8/8/8/4K3 b - - 0 1");
+        let mut features = Vec::new();
+        FeatureSet::Chess768Threats.active_features_of(&pieces, side_to_move, |stm, nstm| {
+            features.push((stm, nstm))
+        });
+        // The knight on d5 attacks the same squares from both perspectives, mirrored
+        // vertically for black, the side to move, and as "their" knight for white.
+        use Square::*;
+        let attacks = [C7, E7, B6, F6, B4, F4, C3, E3];
+        for square in attacks {
+            let own = 768 + Piece::Knight as u32 * 64 + square.flip_vertical() as u32;
+            let their = 768 + (6 + Piece::Knight as u32) * 64 + square as u32;
+            assert!(features.contains(&(own, their)), "{:?}", square);
+        }
+        let own_knights = 768 + Piece::Knight as u32 * 64..768 + (Piece::Knight as u32 + 1) * 64;
+        let knight_threats = features
+            .iter()
+            .filter(|(stm, _)| own_knights.contains(stm))
+            .count();
+        assert_eq!(knight_threats, attacks.len());
+        // Both kings, the knight and their 5 + 5 + 8 attacked squares.
+        assert_eq!(features.len(), 3 + 18);
+    }
+}
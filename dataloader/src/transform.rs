@@ -0,0 +1,145 @@
+use dama::{Outcome, Position};
+use dataformat::Sample;
+use rand::{Rng, RngCore};
+use std::{fmt, mem, sync::Arc};
+
+/// Custom curation of the samples of a dataset, applied as they're loaded after the
+/// built-in filters and exclusions. A transform can leave a sample out, change it or
+/// add more samples made from it, and is registered through
+/// [`LoaderOptions::transforms`](crate::loader::LoaderOptions::transforms), so that
+/// it can be written outside of this crate.
+///
+/// Samples are seen with their evals from the side to move's perspective, whatever the
+/// dataset's. Samples added past the end of a batch are dropped rather than held over,
+/// so that a restored [`LoaderState`](crate::loader::LoaderState) loads the same ones.
+pub trait SampleTransform: fmt::Debug + Send + Sync {
+    /// Pushes what `sample` becomes to `output`: nothing to leave it out, one sample to
+    /// keep or change it, or several to add more. `rng` is seeded from where the sample
+    /// was read, so a restored state draws the same numbers.
+    fn apply(&self, sample: Sample, rng: &mut dyn RngCore, output: &mut Vec<Sample>);
+}
+
+/// Runs `sample` through each transform in turn, each applied to every sample the
+/// previous one output.
+pub(crate) fn apply_all(
+    transforms: &[Arc<dyn SampleTransform>],
+    sample: Sample,
+    rng: &mut dyn RngCore,
+) -> Vec<Sample> {
+    let mut samples = vec![sample];
+    let mut output = Vec::new();
+    for transform in transforms {
+        for sample in samples.drain(..) {
+            transform.apply(sample, rng, &mut output);
+        }
+        mem::swap(&mut samples, &mut output);
+    }
+    samples
+}
+
+/// Keeps the samples a function returns `true` for, the shortest way to plug in a
+/// custom filter.
+pub struct FilterFn<F>(pub F);
+
+impl<F: Fn(&Sample) -> bool + Send + Sync> SampleTransform for FilterFn<F> {
+    #[inline]
+    fn apply(&self, sample: Sample, _rng: &mut dyn RngCore, output: &mut Vec<Sample>) {
+        if (self.0)(&sample) {
+            output.push(sample);
+        }
+    }
+}
+
+impl<F> fmt::Debug for FilterFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FilterFn")
+    }
+}
+
+/// Leaves out samples whose eval is further from zero than this many centipawns, which
+/// say little once a game is decided. Samples without an eval are kept.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaxAbsEval(pub i16);
+
+impl SampleTransform for MaxAbsEval {
+    #[inline]
+    fn apply(&self, sample: Sample, _rng: &mut dyn RngCore, output: &mut Vec<Sample>) {
+        if sample.eval.is_none_or(|eval| eval.unsigned_abs() <= self.0.unsigned_abs()) {
+            output.push(sample);
+        }
+    }
+}
+
+/// Clamps evals to this many centipawns either side of zero, for datasets whose mate
+/// scores would otherwise pull the eval targets to the ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClampEval(pub i16);
+
+impl SampleTransform for ClampEval {
+    #[inline]
+    fn apply(&self, mut sample: Sample, _rng: &mut dyn RngCore, output: &mut Vec<Sample>) {
+        let limit = self.0.saturating_abs();
+        sample.eval = sample.eval.map(|eval| eval.clamp(-limit, limit));
+        output.push(sample);
+    }
+}
+
+/// Adds a copy of samples with the colors swapped and the board flipped from top to
+/// bottom, each with this chance, keeping the original as well. Unlike the colors
+/// mirror of an [`Augmentation`](crate::augment::Augmentation), which stands in for the
+/// sample, the copy comes on top of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorFlip {
+    pub probability: f64,
+}
+
+impl SampleTransform for ColorFlip {
+    fn apply(&self, sample: Sample, rng: &mut dyn RngCore, output: &mut Vec<Sample>) {
+        let flipped = (rng.random::<f64>() < self.probability)
+            .then(|| flip_colors(&sample.position))
+            .flatten();
+        if let Some(position) = flipped {
+            output.push(Sample {
+                position,
+                outcome: match sample.outcome {
+                    Outcome::Winner(winner) => Outcome::Winner(!winner),
+                    Outcome::Draw => Outcome::Draw,
+                },
+                // From the side to move's perspective, which changes along with the colors.
+                eval: sample.eval,
+            });
+        }
+        output.push(sample);
+    }
+}
+
+/// The position with the colors swapped and the board flipped from top to bottom, made
+/// through its FEN. `None` for positions the FEN of which doesn't read back, such as
+/// Chess960 ones with castling rights.
+fn flip_colors(position: &Position) -> Option<Position> {
+    let fen = position.fen().to_string();
+    let fields: Vec<_> = fen.split_whitespace().collect();
+    let [board, side, castling, en_passant, rest @ ..] = fields.as_slice() else {
+        return None;
+    };
+    let swap_case = |s: &str| -> String {
+        s.chars()
+            .map(|c| if c.is_ascii_uppercase() { c.to_ascii_lowercase() } else { c.to_ascii_uppercase() })
+            .collect()
+    };
+    let board = board.split('/').rev().map(swap_case).collect::<Vec<_>>().join("/");
+    let side = if *side == "w" { "b" } else { "w" };
+    let mut castling: Vec<char> = swap_case(castling).chars().collect();
+    castling.sort_by_key(|c| (c.is_ascii_lowercase(), *c != 'K' && *c != 'k'));
+    let castling: String = castling.into_iter().collect();
+    let en_passant = match en_passant.as_bytes() {
+        [file, rank] => format!("{}{}", *file as char, (b'1' + b'8' - rank) as char),
+        _ => en_passant.to_string(),
+    };
+    let fen = [board.as_str(), side, &castling, &en_passant]
+        .into_iter()
+        .chain(rest.iter().copied())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Position::from_fen(&fen).ok()
+}
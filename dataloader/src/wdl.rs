@@ -0,0 +1,91 @@
+use dama::{Piece, Position};
+use dataformat::PieceSets;
+use std::{fmt::Write as _, fs, io, path::Path};
+
+/// Phase of a position with all non-pawn pieces on the board.
+pub const MAX_PHASE: u32 = 24;
+
+/// Maps evaluations to expected scores with a logistic model whose slope depends on
+/// the game phase: `score = sigmoid(eval * (eval_coefficient + phase_coefficient * phase))`,
+/// with evals in centipawns and the phase going from 0 in pawn endings to 1 in the opening.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WdlModel {
+    pub eval_coefficient: f32,
+    pub phase_coefficient: f32,
+}
+
+impl Default for WdlModel {
+    /// The common 400 centipawn scale, independent of the phase.
+    fn default() -> Self {
+        WdlModel {
+            eval_coefficient: 1.0 / 400.0,
+            phase_coefficient: 0.0,
+        }
+    }
+}
+
+impl WdlModel {
+    #[inline]
+    pub fn expected_score(&self, eval: f32, phase: f32) -> f32 {
+        let logit = eval * (self.eval_coefficient + self.phase_coefficient * phase);
+        1.0 / (1.0 + (-logit).exp())
+    }
+
+    /// Reads a model written by [`WdlModel::save`].
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut model = WdlModel::default();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("invalid line `{}` in WDL model", line)))?;
+            let value = value
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| invalid(format!("invalid value in `{}`", line)))?;
+            match key.trim() {
+                "eval_coefficient" => model.eval_coefficient = value,
+                "phase_coefficient" => model.phase_coefficient = value,
+                key => return Err(invalid(format!("unknown WDL model parameter `{}`", key))),
+            }
+        }
+        Ok(model)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut contents = String::new();
+        writeln!(contents, "# score = sigmoid(eval * (eval_coefficient + phase_coefficient * phase))").unwrap();
+        writeln!(contents, "eval_coefficient = {}", self.eval_coefficient).unwrap();
+        writeln!(contents, "phase_coefficient = {}", self.phase_coefficient).unwrap();
+        fs::write(path, contents)
+    }
+}
+
+/// Game phase from the non-pawn material left, from 0 with only kings and pawns to
+/// [`MAX_PHASE`] with the starting material or more.
+pub fn phase(position: &Position) -> u32 {
+    let phase = position.pieces(Piece::Knight).count()
+        + position.pieces(Piece::Bishop).count()
+        + 2 * position.pieces(Piece::Rook).count()
+        + 4 * position.pieces(Piece::Queen).count();
+    phase.min(MAX_PHASE)
+}
+
+/// [`phase`] of the pieces of a position read from a packed sample.
+pub fn phase_of(pieces: &PieceSets) -> u32 {
+    let phase = pieces.pieces(Piece::Knight).count()
+        + pieces.pieces(Piece::Bishop).count()
+        + 2 * pieces.pieces(Piece::Rook).count()
+        + 4 * pieces.pieces(Piece::Queen).count();
+    phase.min(MAX_PHASE)
+}
+
+/// [`phase`] scaled to go from 0 to 1.
+#[inline]
+pub fn phase_fraction(position: &Position) -> f32 {
+    phase(position) as f32 / MAX_PHASE as f32
+}
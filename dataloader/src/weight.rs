@@ -0,0 +1,109 @@
+use crate::{batch::Entry, filter::SampleFilter};
+use dataformat::Adjudication;
+use std::{fmt, str::FromStr};
+
+/// Settings giving each sample a weight which scales its share of the loss, so the
+/// trainer doesn't have to work out which samples to trust on its own.
+#[derive(Clone, Debug)]
+pub struct SampleWeighting {
+    /// Plies over which the weight rises linearly from 0 to 1 from the start of the
+    /// game, to play down positions mostly decided by the opening book. Unset, all plies
+    /// weigh the same.
+    pub ply_ramp: Option<u32>,
+    /// How much a disagreement between the eval score and the outcome lowers the weight,
+    /// from 0 ignoring it to 1 zeroing samples whose evaluation predicts the opposite
+    /// outcome with certainty.
+    pub eval_agreement: f32,
+    /// Weight of samples from games decided by adjudication.
+    pub adjudicated: f32,
+    /// Factors applied to the samples matching each rule, on top of the others.
+    pub rules: Vec<WeightRule>,
+}
+
+impl Default for SampleWeighting {
+    fn default() -> Self {
+        Self {
+            ply_ramp: None,
+            eval_agreement: 0.0,
+            adjudicated: 1.0,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl SampleWeighting {
+    /// Weight of a sample given its eval score and outcome targets, both from the side
+    /// to move's perspective.
+    #[inline]
+    pub(crate) fn weight(&self, entry: &Entry, eval_score: f32, outcome: f32) -> f32 {
+        let mut weight = 1.0;
+        if let Some(ramp) = self.ply_ramp {
+            weight *= (entry.ply() as f32 / ramp.max(1) as f32).min(1.0);
+        }
+        weight *= 1.0 - self.eval_agreement * (eval_score - outcome).abs();
+        if entry.adjudication != Adjudication::None {
+            weight *= self.adjudicated;
+        }
+        for rule in &self.rules {
+            if rule.matches(entry) {
+                weight *= rule.weight;
+            }
+        }
+        weight.max(0.0)
+    }
+}
+
+/// A factor applied to the weight of matching samples, parsed from
+/// `<filter>:<weight>` such as `ply<16:0.5` or `abs-eval>=1000:0.25`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WeightRule {
+    filter: SampleFilter,
+    weight: f32,
+}
+
+impl WeightRule {
+    /// Samples without an evaluation never match rules on it.
+    #[inline]
+    pub(crate) fn matches(&self, entry: &Entry) -> bool {
+        self.filter.matches(entry)
+    }
+}
+
+impl fmt::Display for WeightRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.filter, self.weight)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidWeightRuleError(String);
+
+impl fmt::Display for InvalidWeightRuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidWeightRuleError {}
+
+impl FromStr for WeightRule {
+    type Err = InvalidWeightRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = InvalidWeightRuleError;
+        let (condition, weight) = s
+            .rsplit_once(':')
+            .ok_or_else(|| invalid(format!("weight rule `{}` must be of the form `<field><op><value>:<weight>`", s)))?;
+        let weight: f32 = weight
+            .trim()
+            .parse()
+            .map_err(|_| invalid(format!("invalid weight `{}`", weight.trim())))?;
+        if weight < 0.0 || !weight.is_finite() {
+            return Err(invalid(format!("weight `{}` must be a finite, non-negative number", weight)));
+        }
+        let filter = condition
+            .parse()
+            .map_err(|err| invalid(format!("invalid weight rule `{}`: {}", s, err)))?;
+        Ok(WeightRule { filter, weight })
+    }
+}
@@ -0,0 +1,368 @@
+use anyhow::Context;
+use dama::{Color, Position};
+use dataformat::Sample;
+use dataloader::wdl::{self, WdlModel};
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::Rng;
+use std::{path::PathBuf, process::Stdio, time::Duration};
+use tokio::process::Command;
+
+use crate::{
+    io::DatasetSource,
+    logging, manifest,
+    protocol::ProtocolKind,
+    selfplay::{Engine, Go},
+};
+
+/// Plies covered by each row of the ply table, the last row holding every later ply.
+const PLY_BAND: u32 = 20;
+const PLY_BANDS: usize = 11;
+/// Phases covered by each row of the phase table.
+const PHASE_BAND: u32 = 5;
+const PHASE_BANDS: usize = 5;
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Dataset to analyze, a file, a directory of shards or `-` for stdin."))]
+    dataset: DatasetSource,
+    #[clap(
+        long("wdl-model"),
+        help(
+            "WDL model fitted by `fit-wdl` turning evals into expected scores, a 400 cp sigmoid is used by default."
+        )
+    )]
+    wdl_model: Option<PathBuf>,
+    #[clap(long("limit"), help("Maximum number of samples to analyze."))]
+    limit: Option<u64>,
+    #[clap(
+        long("contradiction"),
+        default_value_t = 0.9,
+        help(
+            "Expected score beyond which a lost game, or below one minus which a won game, contradicts the eval."
+        )
+    )]
+    contradiction: f32,
+    #[clap(
+        long("engine"),
+        help(
+            "Command of a UCI engine that re-evaluates a random subset of the samples as a reference."
+        )
+    )]
+    engine: Option<String>,
+    #[clap(long("nodes"), help("Node limit of each reference search."))]
+    nodes: Option<u64>,
+    #[clap(long("depth"), help("Depth limit of each reference search."))]
+    depth: Option<u32>,
+    #[clap(
+        long("reference-samples"),
+        default_value_t = 1000,
+        help("Number of samples evaluated by the reference engine.")
+    )]
+    reference_samples: usize,
+    #[clap(
+        long("disagreement"),
+        default_value_t = 300,
+        help("Centipawn difference from the reference eval beyond which a sample is flagged.")
+    )]
+    disagreement: i32,
+}
+
+/// Running statistics of the eval scores and outcomes of a group of samples.
+#[derive(Clone, Copy, Debug, Default)]
+struct LabelStats {
+    samples: u64,
+    sum_score: f64,
+    sum_outcome: f64,
+    sum_score_sq: f64,
+    sum_outcome_sq: f64,
+    sum_product: f64,
+    sum_loss: f64,
+    contradictions: u64,
+}
+
+impl LabelStats {
+    fn add(&mut self, score: f32, outcome: f32, contradiction: bool) {
+        let (score, outcome) = (score as f64, outcome as f64);
+        let clamped = score.clamp(1e-9, 1.0 - 1e-9);
+        self.samples += 1;
+        self.sum_score += score;
+        self.sum_outcome += outcome;
+        self.sum_score_sq += score * score;
+        self.sum_outcome_sq += outcome * outcome;
+        self.sum_product += score * outcome;
+        self.sum_loss -= outcome * clamped.ln() + (1.0 - outcome) * (1.0 - clamped).ln();
+        self.contradictions += contradiction as u64;
+    }
+
+    /// Pearson correlation between the eval scores and the outcomes, if neither is constant.
+    fn correlation(&self) -> Option<f64> {
+        let n = self.samples as f64;
+        let covariance = n * self.sum_product - self.sum_score * self.sum_outcome;
+        let score_var = n * self.sum_score_sq - self.sum_score * self.sum_score;
+        let outcome_var = n * self.sum_outcome_sq - self.sum_outcome * self.sum_outcome;
+        (score_var > 0.0 && outcome_var > 0.0)
+            .then(|| covariance / (score_var.sqrt() * outcome_var.sqrt()))
+    }
+
+    fn print_row(&self, label: &str) {
+        if self.samples == 0 {
+            logging::report!("{:<10} {:>10}", label, 0);
+            return;
+        }
+        let n = self.samples as f64;
+        let correlation = match self.correlation() {
+            Some(correlation) => format!("{:.3}", correlation),
+            None => "-".to_string(),
+        };
+        logging::report!(
+            "{:<10} {:>10} {:>11} {:>10.3} {:>12.3} {:>8.4} {:>14.2}%",
+            label,
+            self.samples,
+            correlation,
+            self.sum_score / n,
+            self.sum_outcome / n,
+            self.sum_loss / n,
+            100.0 * self.contradictions as f64 / n
+        );
+    }
+}
+
+fn print_header(label: &str) {
+    logging::report!(
+        "{:<10} {:>10} {:>11} {:>10} {:>12} {:>8} {:>15}",
+        label,
+        "samples",
+        "correlation",
+        "mean score",
+        "mean outcome",
+        "loss",
+        "contradictions"
+    );
+}
+
+/// A sample kept for the reference engine, with its index in the dataset.
+struct ReferenceSample {
+    index: u64,
+    sample: Sample,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    if !(0.5..=1.0).contains(&args.contradiction) {
+        anyhow::bail!("--contradiction must be between 0.5 and 1");
+    }
+    if args.engine.is_some() && args.nodes.is_none() && args.depth.is_none() {
+        anyhow::bail!("--engine requires a search limit with --nodes or --depth");
+    }
+    let model = match &args.wdl_model {
+        Some(path) => WdlModel::load(path)
+            .with_context(|| format!("failed to read WDL model `{}`", path.display()))?,
+        None => WdlModel::default(),
+    };
+    let perspective = manifest::perspective_of(&args.dataset)?;
+
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} positions analyzed",
+                )
+                .unwrap(),
+            )
+            .with_message("analyzing..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let mut total = LabelStats::default();
+    let mut by_ply = [LabelStats::default(); PLY_BANDS];
+    let mut by_phase = [LabelStats::default(); PHASE_BANDS];
+    let mut reference = Vec::new();
+    let mut rng = rand::rng();
+    let mut reader = args.dataset.open()?;
+    let mut index = 0u64;
+    let mut evaluated = 0u64;
+    while args.limit.is_none_or(|limit| index < limit)
+        && let Some(packed) = reader.read_sample()?
+    {
+        index += 1;
+        progress.inc(1);
+        let Some(eval) = packed.eval() else {
+            continue;
+        };
+        let mut sample = packed
+            .unpack()
+            .with_context(|| format!("failed to unpack sample #{}", index - 1))?;
+        // Scores and reference evals are from the side to move's point of view.
+        let side_to_move = sample.position.side_to_move();
+        let eval = perspective.to_side_to_move(eval, side_to_move);
+        sample.eval = Some(eval);
+        let phase = wdl::phase(&sample.position);
+        let score = model.expected_score(eval as f32, phase as f32 / wdl::MAX_PHASE as f32);
+        let outcome = match sample.outcome.winner() {
+            Some(color) if color == side_to_move => 1.0,
+            Some(_) => 0.0,
+            None => 0.5,
+        };
+        let contradiction = (score >= args.contradiction && outcome == 0.0)
+            || (score <= 1.0 - args.contradiction && outcome == 1.0);
+
+        let ply_band = (ply(&sample.position) / PLY_BAND).min(PLY_BANDS as u32 - 1);
+        let phase_band = (phase / PHASE_BAND).min(PHASE_BANDS as u32 - 1);
+        for stats in [
+            &mut total,
+            &mut by_ply[ply_band as usize],
+            &mut by_phase[phase_band as usize],
+        ] {
+            stats.add(score, outcome, contradiction);
+        }
+
+        // Reservoir sampling keeps a uniform subset without knowing the dataset's length.
+        if args.engine.is_some() {
+            let kept = ReferenceSample {
+                index: index - 1,
+                sample,
+            };
+            if reference.len() < args.reference_samples {
+                reference.push(kept);
+            } else {
+                let slot = rng.random_range(0..=evaluated) as usize;
+                if slot < args.reference_samples {
+                    reference[slot] = kept;
+                }
+            }
+        }
+        evaluated += 1;
+    }
+    progress.finish_and_clear();
+
+    if evaluated == 0 {
+        anyhow::bail!("`{}` has no evaluated samples to analyze", args.dataset);
+    }
+
+    print_header("ply");
+    for (band, stats) in by_ply.iter().enumerate() {
+        let start = band as u32 * PLY_BAND;
+        let label = if band == PLY_BANDS - 1 {
+            format!("{}+", start)
+        } else {
+            format!("{}-{}", start, start + PLY_BAND - 1)
+        };
+        stats.print_row(&label);
+    }
+    logging::report!();
+    print_header("phase");
+    for (band, stats) in by_phase.iter().enumerate() {
+        let start = band as u32 * PHASE_BAND;
+        let end = if band == PHASE_BANDS - 1 {
+            wdl::MAX_PHASE
+        } else {
+            start + PHASE_BAND - 1
+        };
+        stats.print_row(&format!("{}-{}", start, end));
+    }
+    logging::report!();
+    print_header("");
+    total.print_row("total");
+
+    let mut fields = vec![
+        ("samples", index),
+        ("evaluated_samples", evaluated),
+        ("contradictions", total.contradictions),
+    ];
+    if let Some(command) = &args.engine {
+        let flagged = compare_with_engine(&args, command, &reference).await?;
+        fields.push(("reference_samples", reference.len() as u64));
+        fields.push(("flagged", flagged));
+    }
+
+    logging::summary(
+        &format!(
+            "{} samples analyzed, {} of {} evaluated samples contradict their outcome",
+            index, total.contradictions, evaluated
+        ),
+        &fields,
+    );
+    Ok(())
+}
+
+/// Re-evaluates the reference samples with an engine, printing those whose label
+/// disagrees with it and returning how many there were.
+async fn compare_with_engine(
+    args: &Args,
+    command: &str,
+    reference: &[ReferenceSample],
+) -> anyhow::Result<u64> {
+    let mut engine = Engine::new(
+        Command::new(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to start engine `{}`", command))?,
+        ProtocolKind::Uci.protocol(),
+    )
+    .await?;
+
+    let progress = logging::track(
+        ProgressBar::new(reference.len() as u64).with_style(
+            ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions ({eta})",
+            )
+            .unwrap()
+            .progress_chars("##-"),
+        )
+        .with_message("searching..."),
+    );
+
+    logging::report!();
+    let mut flagged = 0;
+    let mut total_difference = 0u64;
+    let mut compared = 0u64;
+    for ReferenceSample { index, sample } in reference {
+        engine.new_game().await?;
+        let go = Go {
+            nodes: args.nodes,
+            depth: args.depth,
+            movetime: None,
+        };
+        let (_, reference_eval) = engine.go(&sample.position, go).await?;
+        progress.inc(1);
+        // Mate scores aren't reported in centipawns and can't be compared.
+        let (Some(reference_eval), Some(eval)) = (reference_eval, sample.eval) else {
+            continue;
+        };
+        let difference = (eval as i32 - reference_eval).abs();
+        total_difference += difference as u64;
+        compared += 1;
+        if difference >= args.disagreement {
+            progress.suspend(|| {
+                logging::report!(
+                    "#{}: {} | eval: {} | reference eval: {} | outcome: {}",
+                    index,
+                    sample.position.fen(),
+                    eval,
+                    reference_eval,
+                    sample.outcome
+                )
+            });
+            flagged += 1;
+        }
+    }
+    progress.finish_and_clear();
+    engine.quit().await?;
+
+    if compared > 0 {
+        logging::report!(
+            "{} of {} reference positions disagree by {} cp or more, mean difference {:.1} cp",
+            flagged,
+            compared,
+            args.disagreement,
+            total_difference as f64 / compared as f64
+        );
+    }
+    Ok(flagged)
+}
+
+/// Number of plies played since the start of the game, derived from the move counter.
+fn ply(position: &Position) -> u32 {
+    let black = (position.side_to_move() == Color::Black) as u32;
+    2 * position.fullmove_number().saturating_sub(1) + black
+}
@@ -0,0 +1,318 @@
+use anyhow::Context;
+use dama::{Color, Outcome};
+use dataformat::{Adjudication, PackedSample};
+use indicatif::{ProgressBar, ProgressStyle};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use std::{
+    fs::File,
+    mem,
+    ops::Range,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    compression, digest,
+    io::DatasetSource,
+    logging, threads,
+    units::{ByteRate, ByteSize},
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Dataset to audit, an uncompressed file or directory of shards."))]
+    dataset: DatasetSource,
+    #[clap(
+        long("duplicates"),
+        help(
+            "Also counts repeated positions, by a 64-bit hash of each. Takes 8 bytes of memory per sample."
+        )
+    )]
+    duplicates: bool,
+    #[clap(
+        long("allow-invalid"),
+        help("Reports samples which can't be unpacked without failing.")
+    )]
+    allow_invalid: bool,
+}
+
+/// Samples scanned by one task, small enough to balance the load between threads and
+/// large enough for the progress updates not to matter.
+const CHUNK_SAMPLES: usize = 1 << 20;
+
+/// Counts gathered over a part of a dataset, added up across the parts.
+#[derive(Clone, Copy, Debug, Default)]
+struct AuditStats {
+    samples: u64,
+    invalid: u64,
+    /// Index into the dataset of the first sample which couldn't be unpacked.
+    first_invalid: Option<u64>,
+    /// Samples with an eval, which are summed up as stored, whatever their perspective.
+    evals: u64,
+    eval_sum: i64,
+    abs_eval_sum: u64,
+    min_eval: Option<i16>,
+    max_eval: Option<i16>,
+    white_wins: u64,
+    draws: u64,
+    black_wins: u64,
+    white_to_move: u64,
+    adjudicated: u64,
+}
+
+impl AuditStats {
+    fn add(&mut self, sample: &PackedSample, index: u64) {
+        self.samples += 1;
+        let Ok(unpacked) = sample.unpack() else {
+            self.invalid += 1;
+            self.first_invalid.get_or_insert(index);
+            return;
+        };
+        if sample.adjudication() != Adjudication::None {
+            self.adjudicated += 1;
+        }
+        let sample = unpacked;
+        if let Some(eval) = sample.eval {
+            self.evals += 1;
+            self.eval_sum += eval as i64;
+            self.abs_eval_sum += eval.unsigned_abs() as u64;
+            self.min_eval = Some(self.min_eval.map_or(eval, |min| min.min(eval)));
+            self.max_eval = Some(self.max_eval.map_or(eval, |max| max.max(eval)));
+        }
+        match sample.outcome {
+            Outcome::Winner(Color::White) => self.white_wins += 1,
+            Outcome::Winner(Color::Black) => self.black_wins += 1,
+            Outcome::Draw => self.draws += 1,
+        }
+        if sample.position.side_to_move() == Color::White {
+            self.white_to_move += 1;
+        }
+    }
+
+    fn merge(self, other: Self) -> Self {
+        AuditStats {
+            samples: self.samples + other.samples,
+            invalid: self.invalid + other.invalid,
+            first_invalid: lowest(self.first_invalid, other.first_invalid),
+            evals: self.evals + other.evals,
+            eval_sum: self.eval_sum + other.eval_sum,
+            abs_eval_sum: self.abs_eval_sum + other.abs_eval_sum,
+            min_eval: lowest(self.min_eval, other.min_eval),
+            max_eval: highest(self.max_eval, other.max_eval),
+            white_wins: self.white_wins + other.white_wins,
+            draws: self.draws + other.draws,
+            black_wins: self.black_wins + other.black_wins,
+            white_to_move: self.white_to_move + other.white_to_move,
+            adjudicated: self.adjudicated + other.adjudicated,
+        }
+    }
+}
+
+fn lowest<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    a.into_iter().chain(b).min()
+}
+
+fn highest<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    a.into_iter().chain(b).max()
+}
+
+/// A range of the samples of one of the mapped files.
+struct Chunk {
+    file: usize,
+    samples: Range<usize>,
+    /// Index into the whole dataset of the chunk's first sample.
+    offset: u64,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let files = args.dataset.files()?;
+    let mut maps = Vec::with_capacity(files.len());
+    for path in &files {
+        let mut file = File::open(path)
+            .with_context(|| format!("failed to open file `{}`", path.display()))?;
+        if compression::is_compressed(&mut file)? {
+            anyhow::bail!(
+                "`{}` is compressed, which can't be mapped into memory, decompress it first",
+                path.display()
+            );
+        }
+        // The files are only read, and datasets are not written to while they're audited.
+        let map = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("failed to map `{}` into memory", path.display()))?;
+        let trailing = map.len() % mem::size_of::<PackedSample>();
+        if trailing != 0 {
+            eprintln!(
+                "warning: `{}` ends with {} bytes of a partial sample",
+                path.display(),
+                trailing
+            );
+        }
+        maps.push(map);
+    }
+
+    let threads = threads::available();
+    tokio::task::spawn_blocking(move || audit(args, files, maps, threads)).await?
+}
+
+fn audit(args: Args, files: Vec<PathBuf>, maps: Vec<Mmap>, threads: usize) -> anyhow::Result<()> {
+    let samples: Vec<&[PackedSample]> = maps
+        .iter()
+        .map(|map| {
+            let len = map.len() - map.len() % mem::size_of::<PackedSample>();
+            bytemuck::cast_slice(&map[..len])
+        })
+        .collect();
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    for (file, samples) in samples.iter().enumerate() {
+        for start in (0..samples.len()).step_by(CHUNK_SAMPLES) {
+            let end = (start + CHUNK_SAMPLES).min(samples.len());
+            chunks.push(Chunk {
+                file,
+                samples: start..end,
+                offset: offset + start as u64,
+            });
+        }
+        offset += samples.len() as u64;
+    }
+    let total = offset;
+    let passes = if args.duplicates { 2 } else { 1 };
+
+    let progress = logging::track(
+        ProgressBar::new(total * passes)
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {human_pos}/{human_len} samples scanned, {per_sec}",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("auditing..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .context("failed to start the scanning threads")?;
+    let start = Instant::now();
+    let stats = pool.install(|| {
+        chunks
+            .par_iter()
+            .map(|chunk| {
+                let mut stats = AuditStats::default();
+                let range = chunk.samples.clone();
+                for (n, sample) in samples[chunk.file][range].iter().enumerate() {
+                    stats.add(sample, chunk.offset + n as u64);
+                }
+                progress.inc(chunk.samples.len() as u64);
+                stats
+            })
+            .reduce(AuditStats::default, AuditStats::merge)
+    });
+    let distinct = args.duplicates.then(|| {
+        pool.install(|| {
+            let mut hashes: Vec<u64> = chunks
+                .par_iter()
+                .flat_map_iter(|chunk| {
+                    progress.inc(chunk.samples.len() as u64);
+                    samples[chunk.file][chunk.samples.clone()]
+                        .iter()
+                        .map(|sample| digest::hash_bytes(&sample.position_key()))
+                })
+                .collect();
+            hashes.par_sort_unstable();
+            hashes.dedup();
+            hashes.len() as u64
+        })
+    });
+    let elapsed = start.elapsed();
+    progress.finish_and_clear();
+
+    print_stats(&stats);
+    if let Some(distinct) = distinct {
+        logging::report!(
+            "Distinct positions: {} ({} duplicates, {:.2}%)",
+            distinct,
+            total - distinct,
+            percent(total - distinct, total)
+        );
+    }
+    let bytes = total * mem::size_of::<PackedSample>() as u64;
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    logging::report!(
+        "Throughput: {:.0} samples/s, {} with {} threads",
+        (total * passes) as f64 / seconds,
+        ByteRate(((bytes * passes) as f64 / seconds) as u64),
+        threads
+    );
+
+    let mut fields = vec![
+        ("samples", stats.samples),
+        ("invalid", stats.invalid),
+        ("evals", stats.evals),
+        ("white_wins", stats.white_wins),
+        ("draws", stats.draws),
+        ("black_wins", stats.black_wins),
+        ("adjudicated", stats.adjudicated),
+        ("elapsed_ms", elapsed.as_millis() as u64),
+    ];
+    if let Some(distinct) = distinct {
+        fields.push(("distinct_positions", distinct));
+    }
+    logging::summary(
+        &format!(
+            "{} samples ({}) in {} files audited in {:.1}s",
+            total,
+            ByteSize(bytes),
+            files.len(),
+            elapsed.as_secs_f64()
+        ),
+        &fields,
+    );
+
+    if let Some(index) = stats.first_invalid {
+        let message = format!(
+            "{} samples can't be unpacked, the first is sample #{}",
+            stats.invalid, index
+        );
+        if !args.allow_invalid {
+            anyhow::bail!(message);
+        }
+        eprintln!("warning: {}", message);
+    }
+    Ok(())
+}
+
+fn print_stats(stats: &AuditStats) {
+    let valid = stats.samples - stats.invalid;
+    logging::report!("Samples: {} ({} invalid)", stats.samples, stats.invalid);
+    logging::report!(
+        "Outcomes: {:.2}% white wins, {:.2}% draws, {:.2}% black wins",
+        percent(stats.white_wins, valid),
+        percent(stats.draws, valid),
+        percent(stats.black_wins, valid)
+    );
+    logging::report!("White to move: {:.2}%", percent(stats.white_to_move, valid));
+    logging::report!("Adjudicated: {:.2}%", percent(stats.adjudicated, valid));
+    logging::report!(
+        "With eval: {} ({:.2}%)",
+        stats.evals,
+        percent(stats.evals, valid)
+    );
+    if let (Some(min), Some(max)) = (stats.min_eval, stats.max_eval) {
+        let evals = stats.evals as f64;
+        logging::report!(
+            "Evals: mean {:.1}, mean absolute {:.1}, from {} to {}",
+            stats.eval_sum as f64 / evals,
+            stats.abs_eval_sum as f64 / evals,
+            min,
+            max
+        );
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    part as f64 * 100.0 / whole.max(1) as f64
+}
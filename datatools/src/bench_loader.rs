@@ -0,0 +1,180 @@
+use anyhow::Context;
+use dataformat::PackedSample;
+use dataloader::{
+    batch::Batch,
+    feature::FeatureSet,
+    loader::{BatchLoader, LoaderOptions},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{io::DatasetSource, logging};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Dataset to load, an uncompressed file or a directory of shards."))]
+    dataset: PathBuf,
+    #[clap(long("batch-size"), default_value_t = 16384)]
+    batch_size: usize,
+    #[clap(
+        long("feature-set"),
+        default_value_t,
+        help("Feature set the batches are built with.")
+    )]
+    feature_set: FeatureSet,
+    #[clap(
+        long("batches"),
+        default_value_t = 200,
+        help("Number of batches loaded by each part of the benchmark.")
+    )]
+    batches: usize,
+}
+
+/// Time spent on each stage of turning stored samples into a batch, on a single thread.
+/// Unpacking is timed for comparison only, the loader decodes samples as it adds them.
+#[derive(Default)]
+struct Breakdown {
+    samples: u64,
+    read: Duration,
+    unpack: Duration,
+    add: Duration,
+}
+
+impl Breakdown {
+    fn per_sample(&self, duration: Duration) -> f64 {
+        duration.as_nanos() as f64 / self.samples.max(1) as f64
+    }
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    if args.batch_size == 0 {
+        anyhow::bail!("--batch-size must be at least 1");
+    }
+    if args.batches < 2 {
+        anyhow::bail!("--batches must be at least 2");
+    }
+    let options = LoaderOptions {
+        feature_set: args.feature_set,
+        ..LoaderOptions::default()
+    };
+
+    let progress = logging::track(
+        ProgressBar::new(2 * args.batches as u64)
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} batches",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("timing each stage..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let breakdown = time_stages(&args, &options, &progress)?;
+
+    progress.set_position(args.batches as u64);
+    progress.set_message("loading batches...");
+    let started = Instant::now();
+    let mut loader = BatchLoader::with_options(&args.dataset, args.batch_size, options)
+        .with_context(|| format!("failed to open dataset `{}`", args.dataset.display()))?;
+    // The first batch waits for the loader to fill its whole shuffle buffer.
+    loader.load();
+    let startup = started.elapsed();
+    progress.inc(1);
+    let started = Instant::now();
+    let mut samples = 0;
+    for _ in 1..args.batches {
+        samples += loader.load().len() as u64;
+        progress.inc(1);
+    }
+    let steady = started.elapsed();
+    progress.finish_and_clear();
+
+    let batches = args.batches - 1;
+    let samples_per_sec = samples as f64 / steady.as_secs_f64().max(1e-9);
+    let read = breakdown.per_sample(breakdown.read);
+    let unpack = breakdown.per_sample(breakdown.unpack);
+    let add = breakdown.per_sample(breakdown.add);
+    let total = read + add;
+
+    logging::report!("Batch size: {}", args.batch_size);
+    logging::report!("Feature set: {}", args.feature_set);
+    logging::report!(
+        "Startup: {:.2} s until the first batch",
+        startup.as_secs_f64()
+    );
+    logging::report!(
+        "Throughput: {:.0} samples/s, {:.2} batches/s",
+        samples_per_sec,
+        batches as f64 / steady.as_secs_f64().max(1e-9)
+    );
+    logging::report!(
+        "Per sample on one thread, over {} samples: read {:.0} ns, decode and add {:.0} ns (full unpack {:.0} ns)",
+        breakdown.samples,
+        read,
+        add,
+        unpack
+    );
+    logging::report!(
+        "Single thread limit: {:.0} samples/s",
+        1e9 / total.max(1e-9)
+    );
+
+    logging::summary(
+        &format!(
+            "{:.0} samples/s with batches of {}",
+            samples_per_sec, args.batch_size
+        ),
+        &[
+            ("samples_per_sec", samples_per_sec as u64),
+            ("startup_ms", startup.as_millis() as u64),
+            ("read_ns", read as u64),
+            ("unpack_ns", unpack as u64),
+            ("add_ns", add as u64),
+        ],
+    );
+    Ok(())
+}
+
+/// Times reading and adding samples to batches separately, the way the loader does it
+/// but without its shuffle buffer and thread, along with what fully unpacking them would
+/// cost.
+fn time_stages(
+    args: &Args,
+    options: &LoaderOptions,
+    progress: &ProgressBar,
+) -> anyhow::Result<Breakdown> {
+    let mut reader = DatasetSource::from_path(&args.dataset).open()?;
+    let mut packed = vec![PackedSample::default(); args.batch_size];
+    let mut batch = Batch::new(args.batch_size, args.feature_set);
+    let mut breakdown = Breakdown::default();
+    for _ in 0..args.batches {
+        let started = Instant::now();
+        let read = reader.read_samples(&mut packed)?;
+        breakdown.read += started.elapsed();
+        if read == 0 {
+            break;
+        }
+
+        let started = Instant::now();
+        for sample in &packed[..read] {
+            std::hint::black_box(sample.unpack().ok());
+        }
+        breakdown.unpack += started.elapsed();
+
+        let started = Instant::now();
+        batch.clear();
+        for sample in &packed[..read] {
+            let _ = batch.add_packed(sample, options);
+        }
+        breakdown.add += started.elapsed();
+
+        breakdown.samples += read as u64;
+        progress.inc(1);
+    }
+    Ok(breakdown)
+}
@@ -0,0 +1,254 @@
+use anyhow::Context;
+use core::str;
+use dama::{Position, SanMove, pgn};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::{io::DatasetSource, logging, manifest};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Input datasets or PGN files (detected by the `.pgn` extension)."))]
+    inputs: Vec<PathBuf>,
+    #[clap(short('o'), default_value("book.epd"))]
+    output: PathBuf,
+    #[clap(
+        long("plies"),
+        default_value_t = 8,
+        help("Number of plies played from PGN games before recording the book position.")
+    )]
+    plies: u32,
+    #[clap(
+        long("max-fullmove"),
+        default_value_t = 10,
+        help("Only dataset samples up to this fullmove number are considered.")
+    )]
+    max_fullmove: u32,
+    #[clap(
+        long("min-frequency"),
+        default_value_t = 2,
+        help("Minimum number of occurrences for a position to be included.")
+    )]
+    min_frequency: u32,
+    #[clap(
+        long("max-eval"),
+        help("Maximum absolute average evaluation, in centipawns, of included positions.")
+    )]
+    max_eval: Option<i32>,
+    #[clap(
+        long("limit"),
+        help("Maximum number of book entries, most frequent first.")
+    )]
+    limit: Option<usize>,
+}
+
+#[derive(Default)]
+struct Entry {
+    count: u32,
+    eval_sum: i64,
+    evals: u32,
+}
+
+impl Entry {
+    fn add(&mut self, eval: Option<i32>) {
+        self.count += 1;
+        if let Some(eval) = eval {
+            self.eval_sum += eval as i64;
+            self.evals += 1;
+        }
+    }
+
+    fn average_eval(&self) -> Option<i32> {
+        (self.evals > 0).then(|| (self.eval_sum / self.evals as i64) as i32)
+    }
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} positions collected",
+                )
+                .unwrap(),
+            )
+            .with_message("collecting book positions..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let mut entries = HashMap::<String, Entry>::new();
+    for input in &args.inputs {
+        if input.extension() == Some(OsStr::new("pgn")) {
+            collect_pgn(input, args.plies, &mut entries, &progress)?;
+        } else {
+            collect_dataset(input, args.max_fullmove, &mut entries, &progress)?;
+        }
+    }
+    progress.finish();
+
+    let mut book = entries
+        .into_iter()
+        .filter(|(_, entry)| entry.count >= args.min_frequency)
+        .filter(|(_, entry)| match (args.max_eval, entry.average_eval()) {
+            (Some(max_eval), Some(eval)) => eval.abs() <= max_eval,
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+    book.sort_unstable_by(|(a_epd, a), (b_epd, b)| b.count.cmp(&a.count).then(a_epd.cmp(b_epd)));
+    if let Some(limit) = args.limit {
+        book.truncate(limit);
+    }
+
+    let output = tokio::fs::File::create(&args.output)
+        .await
+        .with_context(|| format!("failed to open output path `{}`", args.output.display()))?;
+    let mut writer = BufWriter::new(output);
+    for (epd, entry) in &book {
+        let mut line = format!("{} c0 \"{}\";", epd, entry.count);
+        if let Some(eval) = entry.average_eval() {
+            line.push_str(&format!(" ce {};", eval));
+        }
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+    }
+    writer.flush().await?;
+
+    logging::summary(
+        &format!("{} book positions written", book.len()),
+        &[("positions_written", book.len() as u64)],
+    );
+
+    Ok(())
+}
+
+fn epd_key(position: &Position) -> String {
+    let fen = position.fen().to_string();
+    fen.split_whitespace().take(4).collect::<Vec<_>>().join(" ")
+}
+
+fn collect_dataset(
+    path: &Path,
+    max_fullmove: u32,
+    entries: &mut HashMap<String, Entry>,
+    progress: &ProgressBar,
+) -> anyhow::Result<()> {
+    let dataset = DatasetSource::from_path(path);
+    let perspective = manifest::perspective_of(&dataset)?;
+    let mut reader = dataset.open()?;
+    while let Some(packed) = reader.read_sample()? {
+        let mut sample = packed.unpack()?;
+        manifest::to_side_to_move(&mut sample, perspective);
+        if sample.position.fullmove_number() > max_fullmove {
+            continue;
+        }
+        entries
+            .entry(epd_key(&sample.position))
+            .or_default()
+            .add(sample.eval.map(i32::from));
+        progress.inc(1);
+    }
+    Ok(())
+}
+
+fn collect_pgn(
+    path: &Path,
+    plies: u32,
+    entries: &mut HashMap<String, Entry>,
+    progress: &ProgressBar,
+) -> anyhow::Result<()> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open input file `{}`", path.display()))?;
+    let mut reader = pgn::Reader::new(BufReader::new(file));
+    let mut visitor = BookVisitor {
+        plies,
+        ..Default::default()
+    };
+    loop {
+        match reader.visit_game(&mut visitor) {
+            Ok(true) => {
+                if let Some(position) = visitor.book_position.take() {
+                    entries
+                        .entry(epd_key(&position))
+                        .or_default()
+                        .add(visitor.book_eval.take());
+                    progress.inc(1);
+                }
+            }
+            Ok(false) => break,
+            Err(err) if !err.is_recoverable() => {
+                progress.println(format!("unrecoverable PGN error: {}", err));
+                break;
+            }
+            Err(err) => progress.println(format!("error while reading PGN: {}", err)),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct BookVisitor {
+    plies: u32,
+    played: u32,
+    position: Position,
+    book_position: Option<Position>,
+    book_eval: Option<i32>,
+}
+
+impl pgn::Visitor for BookVisitor {
+    type Error = anyhow::Error;
+
+    fn prepare(&mut self) {
+        self.position = Position::new_initial();
+        self.played = 0;
+        self.book_position = None;
+        self.book_eval = None;
+    }
+
+    fn visit_tag_pair(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        if name == "FEN" {
+            self.position = Position::from_fen(value)?;
+        }
+        Ok(())
+    }
+
+    fn enter_variation(&mut self) -> pgn::ControlFlow {
+        pgn::ControlFlow::Skip
+    }
+
+    fn visit_move(&mut self, _number: Option<u32>, mv: SanMove) -> anyhow::Result<()> {
+        if self.played >= self.plies {
+            self.played = self.plies + 1;
+            return Ok(());
+        }
+        self.position.play(&mv)?;
+        self.played += 1;
+        if self.played == self.plies {
+            self.book_position = Some(self.position.clone());
+        }
+        Ok(())
+    }
+
+    fn visit_comment(&mut self, comment: &[u8]) -> anyhow::Result<()> {
+        if self.played != self.plies || self.book_eval.is_some() {
+            return Ok(());
+        }
+        // Comment evaluations are given from the point of view of the side that just moved.
+        let comment = str::from_utf8(comment)?;
+        if let Some(info) = comment.split('/').next()
+            && !info.starts_with("+M")
+            && !info.starts_with("-M")
+            && let Ok(eval) = info.parse::<f64>()
+        {
+            self.book_eval = Some((-eval * 100.0).round() as i32);
+        }
+        Ok(())
+    }
+}
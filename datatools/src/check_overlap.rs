@@ -0,0 +1,99 @@
+use anyhow::Context;
+use dataloader::exclude::OverlapCheck;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{collections::HashSet, time::Duration};
+
+use crate::{io::DatasetSource, logging};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help(
+        "Dataset checked for contamination, usually the training data, a file, a directory of shards or `-` for stdin."
+    ))]
+    dataset: DatasetSource,
+    #[clap(
+        long("reference"),
+        help(
+            "Dataset whose positions shouldn't appear in the checked one, usually the validation set."
+        )
+    )]
+    reference: DatasetSource,
+    #[clap(
+        long("max-rate"),
+        help(
+            "Fails if a larger share of the samples hold positions of the reference, e.g. `0.001`, for stopping a pipeline before training on contaminated data."
+        )
+    )]
+    max_rate: Option<f64>,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} positions hashed",
+                )
+                .unwrap(),
+            )
+            .with_message("hashing the reference..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let mut hashes = HashSet::new();
+    for_each_hash(&args.reference, &progress, |hash| {
+        hashes.insert(hash);
+    })?;
+    progress.set_message("checking the dataset...");
+    progress.set_position(0);
+    let mut check = OverlapCheck::new(hashes);
+    for_each_hash(&args.dataset, &progress, |hash| check.add(hash))?;
+    progress.finish_and_clear();
+
+    let overlap = check.overlap();
+    logging::summary(
+        &format!(
+            "{} of {} samples ({:.3}%) hold {} of the {} positions of the reference",
+            overlap.contaminated,
+            overlap.samples,
+            overlap.rate() * 100.0,
+            overlap.shared_positions,
+            overlap.reference_positions
+        ),
+        &[
+            ("samples", overlap.samples),
+            ("contaminated", overlap.contaminated),
+            ("reference_positions", overlap.reference_positions),
+            ("shared_positions", overlap.shared_positions),
+        ],
+    );
+    if let Some(max_rate) = args.max_rate
+        && overlap.rate() > max_rate
+    {
+        anyhow::bail!(
+            "contamination rate of {:.3}% is above the maximum of {:.3}%",
+            overlap.rate() * 100.0,
+            max_rate * 100.0
+        );
+    }
+    Ok(())
+}
+
+/// Calls `f` with the Zobrist hash of every position of a dataset.
+fn for_each_hash(
+    dataset: &DatasetSource,
+    progress: &ProgressBar,
+    mut f: impl FnMut(u64),
+) -> anyhow::Result<()> {
+    let mut reader = dataset.open()?;
+    let mut index = 0u64;
+    while let Some(packed) = reader.read_sample()? {
+        let sample = packed
+            .unpack()
+            .with_context(|| format!("failed to unpack sample #{} of `{}`", index, dataset))?;
+        f(sample.position.hash());
+        index += 1;
+        progress.inc(1);
+    }
+    Ok(())
+}
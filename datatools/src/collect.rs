@@ -0,0 +1,450 @@
+use anyhow::Context;
+use dataformat::{
+    PackedSample,
+    block::{Handshake, HandshakeReply, SampleBlock, VERSION},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::seq::SliceRandom;
+use std::{
+    collections::HashSet,
+    fs,
+    io::{BufReader, BufWriter, Write},
+    mem,
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    digest,
+    io::{DatasetSink, SampleWriter},
+    logging, manifest,
+    shuffle::{ShuffleOptions, shuffle_sink},
+    status::{self, RecentErrors, StatusPage},
+    units::ByteSize,
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(
+        long("listen"),
+        help("Address to accept sample streams on, e.g. `0.0.0.0:7878`.")
+    )]
+    listen: String,
+    #[clap(
+        long("token-file"),
+        help(
+            "File holding the access token workers must present, read from the `DATATOOLS_TOKEN` environment variable if not given. It is sent in the clear, so only use it on trusted networks or through a tunnel."
+        )
+    )]
+    token_file: Option<PathBuf>,
+    #[clap(short('o'), help("Output file, compressed if it ends in `.zst`."))]
+    output: PathBuf,
+    #[clap(
+        long("shard-size"),
+        help("Writes the output as a directory of shards of this size, e.g. `4GiB`.")
+    )]
+    shard_size: Option<ByteSize>,
+    #[clap(short('a'), long("append"))]
+    append: bool,
+    #[clap(
+        long("no-dedup"),
+        help("Keeps every sample received instead of dropping repeated positions.")
+    )]
+    no_dedup: bool,
+    #[clap(
+        long("dedup-window"),
+        default_value_t = 1 << 25,
+        help("Positions remembered for dropping repeats, beyond which the oldest half is forgotten so memory stays bounded. Takes about 20 bytes per position.")
+    )]
+    dedup_window: usize,
+    #[clap(
+        long("buffer-samples"),
+        default_value_t = 1 << 22,
+        help("Samples gathered and shuffled together before being written, mixing samples from different workers.")
+    )]
+    buffer_samples: usize,
+    #[clap(
+        long("http"),
+        help("Address serving the collection progress as JSON, e.g. `127.0.0.1:8080`.")
+    )]
+    http: Option<String>,
+}
+
+#[derive(Default)]
+struct CollectStats {
+    samples_received: AtomicU64,
+    samples_written: AtomicU64,
+    duplicates: AtomicU64,
+    /// Samples dropped with the block they came in for holding one that can't be unpacked.
+    invalid_samples: AtomicU64,
+    active_connections: AtomicU64,
+    connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    recent_errors: RecentErrors,
+}
+
+impl CollectStats {
+    fn page(&self, started: Instant) -> StatusPage {
+        let elapsed = started.elapsed().as_secs_f64();
+        let written = self.samples_written.load(Ordering::Relaxed);
+        let errors = self.recent_errors.to_vec();
+        StatusPage::default()
+            .number(
+                "samples_received",
+                self.samples_received.load(Ordering::Relaxed),
+            )
+            .number("samples_written", written)
+            .number("duplicates", self.duplicates.load(Ordering::Relaxed))
+            .number(
+                "invalid_samples",
+                self.invalid_samples.load(Ordering::Relaxed),
+            )
+            .number(
+                "active_connections",
+                self.active_connections.load(Ordering::Relaxed),
+            )
+            .number("connections", self.connections.load(Ordering::Relaxed))
+            .number(
+                "rejected_connections",
+                self.rejected_connections.load(Ordering::Relaxed),
+            )
+            .number("elapsed_secs", format!("{:.1}", elapsed))
+            .number(
+                "samples_per_sec",
+                format!("{:.1}", written as f64 / elapsed.max(1e-3)),
+            )
+            .strings("recent_errors", errors.iter().map(String::as_str))
+    }
+}
+
+/// Environment variable the access token is read from when no token file is given.
+const TOKEN_VAR: &str = "DATATOOLS_TOKEN";
+
+/// Reads the access token from a file, or the [`TOKEN_VAR`] environment variable if none
+/// is given. Neither shows up in the process list or shell history like arguments do.
+pub fn read_token(file: Option<&Path>) -> anyhow::Result<String> {
+    let token = match file {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("failed to read token file `{}`", path.display()))?
+            .trim_end_matches(['\n', '\r'])
+            .to_string(),
+        None => std::env::var(TOKEN_VAR).with_context(|| {
+            format!(
+                "an access token must be given with --token-file or the `{}` environment variable",
+                TOKEN_VAR
+            )
+        })?,
+    };
+    if token.is_empty() {
+        anyhow::bail!("the access token is empty");
+    }
+    Ok(token)
+}
+
+enum Message {
+    Block(Vec<PackedSample>),
+    Finish,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    if args.buffer_samples == 0 {
+        anyhow::bail!("--buffer-samples must be at least 1");
+    }
+    if args.dedup_window < 2 {
+        anyhow::bail!("--dedup-window must be at least 2");
+    }
+    if args.output.as_os_str() == "-" {
+        anyhow::bail!("collected samples cannot be written to stdout");
+    }
+    let token = read_token(args.token_file.as_deref())?;
+    let sink = DatasetSink::from_path(&args.output, false).sharded(args.shard_size)?;
+    let writer = sink.create(args.append)?;
+
+    let started = Instant::now();
+    let stats = Arc::new(CollectStats::default());
+    let listener = TcpListener::bind(&args.listen)
+        .with_context(|| format!("failed to listen on `{}`", args.listen))?;
+    if let Some(addr) = &args.http {
+        let stats = stats.clone();
+        let addr = status::serve(addr, move || stats.page(started))?;
+        eprintln!("serving status on http://{}", addr);
+    }
+    eprintln!("accepting samples on {}", listener.local_addr()?);
+
+    let (block_send, block_recv) = mpsc::sync_channel(64);
+    let writer_thread = {
+        let stats = stats.clone();
+        let seen = (!args.no_dedup).then(|| RecentPositions::new(args.dedup_window));
+        let buffer_samples = args.buffer_samples;
+        thread::spawn(move || write_blocks(block_recv, writer, &stats, seen, buffer_samples))
+    };
+    {
+        let stats = stats.clone();
+        let block_send = block_send.clone();
+        thread::spawn(move || accept_streams(listener, block_send, stats, token));
+    }
+
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} samples written",
+                )
+                .unwrap(),
+            )
+            .with_message("collecting, press Ctrl-C to stop..."),
+    );
+    let mut ticker = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result.context("failed to listen for Ctrl-C")?;
+                break;
+            }
+            _ = ticker.tick() => {
+                progress.set_position(stats.samples_written.load(Ordering::Relaxed));
+                progress.tick();
+            }
+        }
+    }
+    progress.set_message("flushing...");
+
+    // Streams still open are cut off here, their workers see the connection drop.
+    block_send
+        .send(Message::Finish)
+        .expect("sample writing thread has disconnected");
+    let written = tokio::task::spawn_blocking(move || writer_thread.join())
+        .await?
+        .expect("sample writing thread has panicked")?;
+    progress.finish_and_clear();
+    shuffle_sink(&sink, &ShuffleOptions::default()).await?;
+    let sources = if args.append {
+        manifest::previous_sources(&sink)?
+    } else {
+        Vec::new()
+    };
+    let settings = [
+        ("dedup", (!args.no_dedup).to_string()),
+        ("dedup_window", args.dedup_window.to_string()),
+        (
+            "connections",
+            stats.connections.load(Ordering::Relaxed).to_string(),
+        ),
+    ];
+    manifest::write_for_sink(&sink, "collect", &settings, sources)?;
+
+    let received = stats.samples_received.load(Ordering::Relaxed);
+    let duplicates = stats.duplicates.load(Ordering::Relaxed);
+    let invalid = stats.invalid_samples.load(Ordering::Relaxed);
+    let connections = stats.connections.load(Ordering::Relaxed);
+    logging::summary(
+        &format!(
+            "{} samples written to `{}` out of {} received over {} connections, {} duplicates and {} invalid samples dropped",
+            written, sink, received, connections, duplicates, invalid
+        ),
+        &[
+            ("samples_written", written),
+            ("samples_received", received),
+            ("duplicates", duplicates),
+            ("invalid_samples", invalid),
+            ("connections", connections),
+            (
+                "rejected_connections",
+                stats.rejected_connections.load(Ordering::Relaxed),
+            ),
+        ],
+    );
+    Ok(())
+}
+
+fn accept_streams(
+    listener: TcpListener,
+    block_send: SyncSender<Message>,
+    stats: Arc<CollectStats>,
+    token: String,
+) {
+    let token = Arc::new(token);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                stats
+                    .recent_errors
+                    .record(format!("failed to accept connection: {}", err));
+                continue;
+            }
+        };
+        let block_send = block_send.clone();
+        let stats = stats.clone();
+        let token = token.clone();
+        thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
+            stats.active_connections.fetch_add(1, Ordering::Relaxed);
+            if let Err(err) = receive_stream(stream, &block_send, &stats, &token, &peer) {
+                stats.recent_errors.record(format!("{}: {:#}", peer, err));
+            }
+            stats.active_connections.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Checks a worker's handshake, then forwards its blocks to the writer until it ends
+/// the stream.
+fn receive_stream(
+    stream: TcpStream,
+    block_send: &SyncSender<Message>,
+    stats: &CollectStats,
+    token: &str,
+    peer: &str,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(300)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let handshake = Handshake::read_from(&mut reader).context("invalid handshake")?;
+    let reply = if handshake.version != VERSION {
+        HandshakeReply::UnsupportedVersion
+    } else if !constant_time_eq(handshake.token.as_bytes(), token.as_bytes()) {
+        HandshakeReply::InvalidToken
+    } else {
+        HandshakeReply::Accepted
+    };
+    writer.write_all(&[reply as u8])?;
+    writer.flush()?;
+    if reply != HandshakeReply::Accepted {
+        stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+        anyhow::bail!("connection rejected: {:?}", reply);
+    }
+    stats.connections.fetch_add(1, Ordering::Relaxed);
+
+    let mut accepted = 0u64;
+    while let Some(block) = SampleBlock::read_from(&mut reader).context("invalid block")? {
+        let len = block.samples.len() as u64;
+        stats.samples_received.fetch_add(len, Ordering::Relaxed);
+        // A worker sending a sample that can't be unpacked is broken or sends another
+        // format, so nothing of that block is trusted.
+        if let Some(err) = block
+            .samples
+            .iter()
+            .find_map(|sample| sample.unpack().err())
+        {
+            stats.invalid_samples.fetch_add(len, Ordering::Relaxed);
+            stats.recent_errors.record(format!(
+                "{}: dropped a block of {} samples holding one that can't be unpacked: {}",
+                peer, len, err
+            ));
+            continue;
+        }
+        block_send
+            .send(Message::Block(block.samples))
+            .map_err(|_| anyhow::anyhow!("collector is shutting down"))?;
+        accepted += len;
+    }
+    writer.write_all(&accepted.to_le_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Hashes of the positions received lately, for dropping repeats without holding every
+/// position of a long-running collection. Once a window's worth of hashes is held, the
+/// older half is forgotten, so repeats are caught across at least half a window.
+struct RecentPositions {
+    current: HashSet<u64>,
+    previous: HashSet<u64>,
+    /// Hashes held in `current` before it replaces `previous`.
+    generation: usize,
+}
+
+impl RecentPositions {
+    fn new(window: usize) -> Self {
+        RecentPositions {
+            current: HashSet::new(),
+            previous: HashSet::new(),
+            generation: window / 2,
+        }
+    }
+
+    /// Records a position, returning whether it is new to the window.
+    fn insert(&mut self, hash: u64) -> bool {
+        if self.previous.contains(&hash) || !self.current.insert(hash) {
+            return false;
+        }
+        if self.current.len() >= self.generation {
+            self.previous = mem::take(&mut self.current);
+        }
+        true
+    }
+}
+
+/// Gathers blocks into a buffer that is shuffled and written whenever it fills up,
+/// returning the number of samples written once told to finish. Repeated positions are
+/// dropped if `seen` is given.
+fn write_blocks(
+    block_recv: Receiver<Message>,
+    mut writer: SampleWriter,
+    stats: &CollectStats,
+    mut seen: Option<RecentPositions>,
+    buffer_samples: usize,
+) -> anyhow::Result<u64> {
+    let mut buffer = Vec::with_capacity(buffer_samples);
+    let mut flush = |buffer: &mut Vec<PackedSample>| -> anyhow::Result<()> {
+        buffer.shuffle(&mut rand::rng());
+        for sample in buffer.iter() {
+            writer.write_sample(sample)?;
+        }
+        stats
+            .samples_written
+            .fetch_add(buffer.len() as u64, Ordering::Relaxed);
+        buffer.clear();
+        Ok(())
+    };
+
+    while let Ok(Message::Block(samples)) = block_recv.recv() {
+        for sample in samples {
+            if let Some(seen) = &mut seen
+                && !seen.insert(digest::hash_bytes(&sample.position_key()))
+            {
+                stats.duplicates.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            buffer.push(sample);
+            if buffer.len() == buffer_samples {
+                flush(&mut buffer)?;
+            }
+        }
+    }
+    flush(&mut buffer)?;
+    Ok(writer.finish()?)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_positions_forget_the_oldest_half() {
+        let mut seen = RecentPositions::new(4);
+        assert!(seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(!seen.insert(1));
+        assert!(seen.insert(3));
+        assert!(seen.insert(4));
+        // 1 and 2 were forgotten when 3 and 4 filled the window.
+        assert!(!seen.insert(3));
+        assert!(seen.insert(1));
+        assert!(seen.current.len() + seen.previous.len() <= 4);
+    }
+}
@@ -0,0 +1,139 @@
+use clap_complete::Shell;
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(
+        required_unless_present("help_markdown"),
+        help("Shell to write a completion script for.")
+    )]
+    shell: Option<Shell>,
+    #[clap(
+        long("help-markdown"),
+        conflicts_with("shell"),
+        help("Writes the help of every command as Markdown instead, for documentation.")
+    )]
+    help_markdown: bool,
+}
+
+/// Writes the completion script or the Markdown help of `command`, the whole CLI, to
+/// stdout.
+pub fn run(args: Args, mut command: clap::Command) -> anyhow::Result<()> {
+    let name = command.get_name().to_string();
+    // Written whole, as clap_complete panics on write errors such as a closed pipe.
+    let output = match args.shell {
+        Some(shell) => {
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut command, name, &mut script);
+            script
+        }
+        None => {
+            command.build();
+            let mut markdown = String::new();
+            write_markdown(&mut markdown, &command, &name, 1);
+            markdown.into_bytes()
+        }
+    };
+    io::stdout().lock().write_all(&output)?;
+    Ok(())
+}
+
+/// Appends the help of a command and then of each of its subcommands, one heading level
+/// further down. Global options are only listed with the top-level command.
+fn write_markdown(out: &mut String, command: &clap::Command, path: &str, level: usize) {
+    writeln!(out, "{} `{}`\n", "#".repeat(level), path).unwrap();
+    if let Some(about) = command.get_long_about().or(command.get_about()) {
+        writeln!(out, "{}\n", about).unwrap();
+    }
+    let usage = command.clone().render_usage().to_string();
+    let usage = usage.trim_start_matches("Usage:").trim();
+    writeln!(out, "```\n{}\n```\n", usage).unwrap();
+
+    let positionals: Vec<_> = command
+        .get_positionals()
+        .filter(|arg| !arg.is_hide_set())
+        .collect();
+    if !positionals.is_empty() {
+        writeln!(out, "Arguments:\n").unwrap();
+        for arg in positionals {
+            let names = arg
+                .get_value_names()
+                .map(|names| {
+                    names
+                        .iter()
+                        .map(|name| format!("<{}>", name))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .unwrap_or_else(|| format!("<{}>", arg.get_id()));
+            write_arg(out, &names, arg);
+        }
+        writeln!(out).unwrap();
+    }
+
+    let options: Vec<_> = command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+        .filter(|arg| level == 1 || !arg.is_global_set())
+        .collect();
+    if !options.is_empty() {
+        writeln!(out, "Options:\n").unwrap();
+        for arg in options {
+            let mut names: Vec<_> = arg
+                .get_short()
+                .map(|short| format!("-{}", short))
+                .into_iter()
+                .collect();
+            if let Some(long) = arg.get_long() {
+                names.push(format!("--{}", long));
+            }
+            let mut names = names.join(", ");
+            if arg.get_action().takes_values() {
+                for value in arg.get_value_names().unwrap_or_default() {
+                    write!(names, " <{}>", value).unwrap();
+                }
+            }
+            write_arg(out, &names, arg);
+        }
+        writeln!(out).unwrap();
+    }
+
+    for subcommand in command.get_subcommands() {
+        if subcommand.is_hide_set() || subcommand.get_name() == "help" {
+            continue;
+        }
+        write_markdown(
+            out,
+            subcommand,
+            &format!("{} {}", path, subcommand.get_name()),
+            level + 1,
+        );
+    }
+}
+
+fn write_arg(out: &mut String, names: &str, arg: &clap::Arg) {
+    write!(out, "- `{}`", names).unwrap();
+    if let Some(help) = arg.get_long_help().or(arg.get_help()) {
+        write!(out, ": {}", help).unwrap();
+    }
+    let values: Vec<_> = arg
+        .get_possible_values()
+        .iter()
+        .map(|value| value.get_name().to_string())
+        .collect();
+    if !values.is_empty() && arg.get_action().takes_values() {
+        write!(out, " Possible values: `{}`.", values.join("`, `")).unwrap();
+    }
+    let defaults: Vec<_> = arg
+        .get_default_values()
+        .iter()
+        .map(|value| value.to_string_lossy())
+        .collect();
+    if !defaults.is_empty() && arg.get_action().takes_values() {
+        write!(out, " Default: `{}`.", defaults.join(" ")).unwrap();
+    }
+    writeln!(out).unwrap();
+}
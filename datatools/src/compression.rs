@@ -0,0 +1,135 @@
+use dataformat::PackedSample;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, IntoInnerError, Read, Seek, Write},
+    mem,
+    path::Path,
+};
+
+/// Magic number at the start of every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Magic number at the start of every chunk of a Stockfish binpack file.
+const BINPACK_MAGIC: [u8; 4] = *b"BINP";
+
+/// Compression level used when none is given.
+pub const DEFAULT_LEVEL: i32 = 3;
+/// Compression level used for temporary files, where speed matters more than ratio.
+pub const FAST_LEVEL: i32 = 1;
+
+/// Checks whether a file starts with a zstd frame. The file is rewound afterwards.
+pub fn is_compressed(file: &mut File) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    file.rewind()?;
+    let result = file.read_exact(&mut magic);
+    file.rewind()?;
+    match result {
+        Ok(()) => Ok(magic == ZSTD_MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Names the format of a file of training data other than packed samples, recognized
+/// from its first bytes once decompressed, so it isn't read as samples. The file is
+/// rewound afterwards.
+pub fn foreign_format(file: &mut File) -> io::Result<Option<&'static str>> {
+    let compressed = is_compressed(file)?;
+    let mut magic = Vec::with_capacity(BINPACK_MAGIC.len());
+    reader(file.try_clone()?, compressed)?
+        .take(BINPACK_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    file.rewind()?;
+    Ok((magic == BINPACK_MAGIC).then_some("Stockfish binpack"))
+}
+
+pub fn has_zstd_extension(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "zst")
+}
+
+/// Opens a buffered reader over a file, decompressing it if `compressed` is set.
+pub fn reader(file: File, compressed: bool) -> io::Result<Box<dyn Read + Send>> {
+    let reader = BufReader::new(file);
+    Ok(if compressed {
+        Box::new(zstd::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    })
+}
+
+/// Opens a buffered reader over any byte stream, decompressing it if it starts with a
+/// zstd frame. Unlike [`is_compressed`] this works on streams that can't be rewound.
+pub fn auto_reader(reader: impl Read + Send + 'static) -> io::Result<Box<dyn Read + Send>> {
+    let mut reader = BufReader::new(reader);
+    Ok(if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    })
+}
+
+/// A buffered writer that optionally compresses everything written to it.
+/// [`Writer::finish`] must be called to flush the last frame.
+pub enum Writer<W: Write> {
+    Plain(BufWriter<W>),
+    Zstd(zstd::Encoder<'static, BufWriter<W>>),
+}
+
+impl<W: Write> Writer<W> {
+    /// Creates a writer, compressing with the given level if there is one.
+    pub fn new(inner: W, level: Option<i32>) -> io::Result<Self> {
+        let writer = BufWriter::new(inner);
+        Ok(match level {
+            Some(level) => Writer::Zstd(zstd::Encoder::new(writer, level)?),
+            None => Writer::Plain(writer),
+        })
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        let writer = match self {
+            Writer::Plain(writer) => writer,
+            Writer::Zstd(encoder) => encoder.finish()?,
+        };
+        writer.into_inner().map_err(IntoInnerError::into_error)
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Plain(writer) => writer.write(buf),
+            Writer::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Plain(writer) => writer.flush(),
+            Writer::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Reads as many samples as fit into `samples`, returning how many were read.
+/// Fewer samples are only returned once the reader is exhausted.
+pub fn read_samples(reader: &mut impl Read, samples: &mut [PackedSample]) -> io::Result<usize> {
+    let buf: &mut [u8] = bytemuck::cast_slice_mut(samples);
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    if filled % mem::size_of::<PackedSample>() != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "input ends in the middle of a sample",
+        ));
+    }
+    Ok(filled / mem::size_of::<PackedSample>())
+}
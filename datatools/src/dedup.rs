@@ -0,0 +1,216 @@
+use anyhow::Context;
+use dataformat::PackedSample;
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::Rng;
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fs::File,
+    io::{BufReader, BufWriter, Seek, Write},
+    mem,
+    time::Duration,
+};
+
+use crate::{compression, digest, logging};
+
+/// How to resolve duplicate positions carrying different evaluations.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keeps the first occurrence of a position, in input order.
+    #[default]
+    KeepFirst,
+    /// Keeps the first occurrence with the mean of all known evaluations.
+    Average,
+}
+
+/// What to do with positions that occur several times.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Repeats {
+    /// Keeps a single sample of every position.
+    Dedup(ConflictPolicy),
+    /// Keeps every sample of positions occurring up to `max_frequency` times, and each
+    /// sample of a more frequent position with probability
+    /// `(max_frequency / frequency)^strength`.
+    Downweight { max_frequency: u64, strength: f64 },
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DedupStats {
+    pub positions_read: u64,
+    pub positions_written: u64,
+    pub duplicates: u64,
+    pub conflicts: u64,
+    /// Positions occurring more than the maximum frequency when down-weighting.
+    pub frequent_positions: u64,
+    /// Samples of frequent positions dropped when down-weighting.
+    pub downweighted: u64,
+}
+
+/// Removes or thins out repeated positions from datasets that don't fit in memory.
+///
+/// Samples are first partitioned by the hash of their position into temporary bucket
+/// files, each small enough to be deduplicated in memory. Samples keep their relative
+/// order within a bucket, so "first" means first in the order they were pushed.
+pub struct Deduplicator {
+    buckets: Vec<BufWriter<File>>,
+    repeats: Repeats,
+    positions_read: u64,
+}
+
+/// Target number of samples held in memory while resolving a bucket.
+const BUCKET_SIZE: u64 = 1 << 22;
+const MAX_BUCKETS: u64 = 1024;
+
+impl Deduplicator {
+    pub fn new(expected_positions: u64, repeats: Repeats) -> anyhow::Result<Self> {
+        let buckets = expected_positions
+            .div_ceil(BUCKET_SIZE)
+            .clamp(1, MAX_BUCKETS);
+        let buckets = (0..buckets)
+            .map(|_| {
+                tempfile::tempfile()
+                    .map(BufWriter::new)
+                    .context("failed to create temporary bucket file")
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Deduplicator {
+            buckets,
+            repeats,
+            positions_read: 0,
+        })
+    }
+
+    pub fn push(&mut self, sample: &PackedSample) -> anyhow::Result<()> {
+        let hash = digest::hash_bytes(&sample.position_key());
+        let bucket = (hash % self.buckets.len() as u64) as usize;
+        self.buckets[bucket].write_all(bytemuck::bytes_of(sample))?;
+        self.positions_read += 1;
+        Ok(())
+    }
+
+    /// Resolves every bucket and writes the samples kept, grouped by bucket.
+    pub fn finish(self, writer: &mut impl Write) -> anyhow::Result<DedupStats> {
+        let progress = logging::track(
+            ProgressBar::new(self.buckets.len() as u64)
+                .with_style(
+                    ProgressStyle::with_template(
+                        "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} buckets done.",
+                    )
+                    .unwrap()
+                    .progress_chars("##-"),
+                )
+                .with_message("removing duplicates..."),
+        );
+        progress.enable_steady_tick(Duration::from_millis(50));
+
+        let mut stats = DedupStats {
+            positions_read: self.positions_read,
+            ..DedupStats::default()
+        };
+        let mut samples = Vec::new();
+        for bucket in self.buckets {
+            let mut file = bucket.into_inner().map_err(|err| err.into_error())?;
+            file.rewind()?;
+            let positions = file.metadata()?.len() / mem::size_of::<PackedSample>() as u64;
+            samples.resize(positions as usize, PackedSample::default());
+            compression::read_samples(&mut BufReader::new(file), &mut samples)?;
+
+            let kept = match self.repeats {
+                Repeats::Dedup(policy) => resolve(&samples, policy, &mut stats),
+                Repeats::Downweight {
+                    max_frequency,
+                    strength,
+                } => downweight(&samples, max_frequency, strength, &mut stats),
+            };
+            for sample in kept {
+                writer.write_all(bytemuck::bytes_of(&sample))?;
+                stats.positions_written += 1;
+            }
+            progress.inc(1);
+        }
+        progress.finish();
+        Ok(stats)
+    }
+}
+
+fn resolve(
+    samples: &[PackedSample],
+    policy: ConflictPolicy,
+    stats: &mut DedupStats,
+) -> Vec<PackedSample> {
+    struct Group {
+        sample: PackedSample,
+        eval_sum: i64,
+        evals: i64,
+    }
+
+    let mut groups: Vec<Group> = Vec::new();
+    let mut index = HashMap::new();
+    for sample in samples {
+        match index.entry(sample.position_key()) {
+            Entry::Vacant(entry) => {
+                entry.insert(groups.len());
+                groups.push(Group {
+                    sample: *sample,
+                    eval_sum: sample.eval().unwrap_or(0) as i64,
+                    evals: sample.eval().is_some() as i64,
+                });
+            }
+            Entry::Occupied(entry) => {
+                let group = &mut groups[*entry.get()];
+                stats.duplicates += 1;
+                if sample.eval() != group.sample.eval() {
+                    stats.conflicts += 1;
+                }
+                if let Some(eval) = sample.eval() {
+                    group.eval_sum += eval as i64;
+                    group.evals += 1;
+                }
+            }
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|group| {
+            let mut sample = group.sample;
+            if policy == ConflictPolicy::Average && group.evals > 0 {
+                let mean = (group.eval_sum as f64 / group.evals as f64).round();
+                sample.set_eval(Some(mean as i16));
+            }
+            sample
+        })
+        .collect()
+}
+
+fn downweight(
+    samples: &[PackedSample],
+    max_frequency: u64,
+    strength: f64,
+    stats: &mut DedupStats,
+) -> Vec<PackedSample> {
+    let mut frequencies: HashMap<_, u64> = HashMap::new();
+    for sample in samples {
+        *frequencies.entry(sample.position_key()).or_default() += 1;
+    }
+    stats.frequent_positions += frequencies
+        .values()
+        .filter(|&&frequency| frequency > max_frequency)
+        .count() as u64;
+
+    let mut rng = rand::rng();
+    samples
+        .iter()
+        .filter(|sample| {
+            let frequency = frequencies[&sample.position_key()];
+            if frequency <= max_frequency {
+                return true;
+            }
+            let keep = rng.random_bool((max_frequency as f64 / frequency as f64).powf(strength));
+            if !keep {
+                stats.downweighted += 1;
+            }
+            keep
+        })
+        .copied()
+        .collect()
+}
@@ -0,0 +1,69 @@
+use dataformat::PackedSample;
+use std::ops::AddAssign;
+
+/// An order-independent digest of a multiset of samples: two sequences of samples have
+/// the same digest (with overwhelming probability) iff they are permutations of each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SampleDigest {
+    count: u64,
+    sum: u64,
+    sum_squares: u64,
+    xor: u64,
+}
+
+impl SampleDigest {
+    #[inline]
+    pub fn add(&mut self, sample: &PackedSample) {
+        let hash = hash_sample(sample);
+        self.count += 1;
+        self.sum = self.sum.wrapping_add(hash);
+        self.sum_squares = self.sum_squares.wrapping_add(hash.wrapping_mul(hash));
+        self.xor ^= hash;
+    }
+
+    #[inline]
+    pub fn extend<'a>(&mut self, samples: impl IntoIterator<Item = &'a PackedSample>) {
+        for sample in samples {
+            self.add(sample);
+        }
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl AddAssign for SampleDigest {
+    #[inline]
+    fn add_assign(&mut self, rhs: Self) {
+        self.count += rhs.count;
+        self.sum = self.sum.wrapping_add(rhs.sum);
+        self.sum_squares = self.sum_squares.wrapping_add(rhs.sum_squares);
+        self.xor ^= rhs.xor;
+    }
+}
+
+/// Hashes the raw bytes of a sample.
+#[inline]
+pub fn hash_sample(sample: &PackedSample) -> u64 {
+    hash_bytes(bytemuck::bytes_of(sample))
+}
+
+#[inline]
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash = 0x9e3779b97f4a7c15u64 ^ bytes.len() as u64;
+    for chunk in bytes.chunks(8) {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        hash = mix(hash ^ u64::from_le_bytes(word));
+    }
+    hash
+}
+
+#[inline]
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
@@ -0,0 +1,122 @@
+use anyhow::Context;
+use dama::{Color, Outcome};
+use dataloader::wdl;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    io::DatasetSource,
+    logging, manifest,
+    npz::{Element, NpyWriter},
+    predicate::{self, Predicate},
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Dataset to export, a file, a directory of shards or `-` for stdin."))]
+    input: DatasetSource,
+    #[clap(
+        short('o'),
+        help("Output directory, with a `.npy` file for each column.")
+    )]
+    output: PathBuf,
+    #[clap(
+        short('f'),
+        long("filter"),
+        help("Only exports samples satisfying the predicate.")
+    )]
+    filters: Vec<Predicate>,
+}
+
+/// The columns of an exported dataset, one value per sample in each, loaded with
+/// `numpy.load`.
+struct Columns {
+    /// From the side to move's perspective, NaN without an eval.
+    eval: NpyWriter<f32>,
+    /// 1 for a win of the side to move, 0 for a draw and -1 for a loss.
+    outcome: NpyWriter<i8>,
+    phase: NpyWriter<u8>,
+    pieces: NpyWriter<u8>,
+    /// 0 with white to move, 1 with black to move.
+    stm: NpyWriter<u8>,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    fs::create_dir_all(&args.output)
+        .with_context(|| format!("failed to create directory `{}`", args.output.display()))?;
+    let mut columns = Columns {
+        eval: create_column(&args.output, "eval")?,
+        outcome: create_column(&args.output, "outcome")?,
+        phase: create_column(&args.output, "phase")?,
+        pieces: create_column(&args.output, "pieces")?,
+        stm: create_column(&args.output, "stm")?,
+    };
+
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} samples exported",
+                )
+                .unwrap(),
+            )
+            .with_message("exporting columns..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let perspective = manifest::perspective_of(&args.input)?;
+    let mut reader = args.input.open()?;
+    let mut index = 0u64;
+    while let Some(packed) = reader.read_sample()? {
+        let mut sample = packed
+            .unpack()
+            .with_context(|| format!("failed to unpack sample #{}", index))?;
+        index += 1;
+        manifest::to_side_to_move(&mut sample, perspective);
+        if !predicate::matches_all(&args.filters, &sample) {
+            continue;
+        }
+        let side_to_move = sample.position.side_to_move();
+        columns
+            .eval
+            .push(sample.eval.map_or(f32::NAN, |eval| eval as f32))?;
+        columns.outcome.push(match sample.outcome {
+            Outcome::Winner(winner) if winner == side_to_move => 1,
+            Outcome::Winner(_) => -1,
+            Outcome::Draw => 0,
+        })?;
+        columns.phase.push(wdl::phase(&sample.position) as u8)?;
+        columns
+            .pieces
+            .push(sample.position.occupied().count() as u8)?;
+        columns.stm.push((side_to_move == Color::Black) as u8)?;
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+
+    let exported = columns.eval.finish()?;
+    columns.outcome.finish()?;
+    columns.phase.finish()?;
+    columns.pieces.finish()?;
+    columns.stm.finish()?;
+
+    logging::summary(
+        &format!(
+            "{} of {} samples exported to `{}`",
+            exported,
+            index,
+            args.output.display()
+        ),
+        &[("samples", index), ("samples_exported", exported)],
+    );
+    Ok(())
+}
+
+fn create_column<T: Element>(directory: &Path, name: &str) -> anyhow::Result<NpyWriter<T>> {
+    let path = directory.join(format!("{}.npy", name));
+    NpyWriter::create(&path).with_context(|| format!("failed to create `{}`", path.display()))
+}
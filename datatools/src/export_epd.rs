@@ -0,0 +1,159 @@
+use anyhow::Context;
+use dataformat::{PackedSample, Sample};
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::SeedableRng;
+use rand_xoshiro::Xoshiro128PlusPlus;
+use std::{io::SeekFrom, mem, path::PathBuf, time::Duration};
+use tokio::{
+    fs::File,
+    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter},
+};
+
+use crate::{
+    io::DatasetSource,
+    logging, manifest,
+    predicate::{self, Predicate},
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Dataset to export, a file, a directory of shards or `-` for stdin."))]
+    input: DatasetSource,
+    #[clap(short('o'), help("Output EPD file, defaults to stdout."))]
+    output: Option<PathBuf>,
+    #[clap(
+        short('s'),
+        long("samples"),
+        help("Exports this many randomly chosen samples instead of the whole file.")
+    )]
+    samples: Option<u64>,
+    #[clap(short('S'), long("seed"))]
+    seed: Option<u64>,
+    #[clap(
+        short('f'),
+        long("filter"),
+        help("Only exports samples satisfying the predicate.")
+    )]
+    filters: Vec<Predicate>,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let positions = args.input.count()?;
+    let perspective = manifest::perspective_of(&args.input)?;
+
+    let output: Box<dyn AsyncWrite + Unpin> = match &args.output {
+        Some(path) => Box::new(
+            File::create(path)
+                .await
+                .with_context(|| format!("failed to open output path `{}`", path.display()))?,
+        ),
+        None => {
+            logging::reserve_stdout();
+            Box::new(io::stdout())
+        }
+    };
+    let mut writer = BufWriter::new(output);
+
+    let progress = logging::track(
+        ProgressBar::new(args.samples.unwrap_or(positions).min(positions))
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions exported.",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("exporting positions..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let indices = args.samples.map(|samples| {
+        let mut rng = if let Some(seed) = args.seed {
+            Xoshiro128PlusPlus::seed_from_u64(seed)
+        } else {
+            Xoshiro128PlusPlus::from_os_rng()
+        };
+        let mut indices = rand::seq::index::sample(
+            &mut rng,
+            positions as usize,
+            samples.min(positions) as usize,
+        )
+        .into_iter()
+        .map(|index| index as u64)
+        .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices
+    });
+
+    let mut exported = 0;
+    if let (Some(path), Some(indices)) = (args.input.plain_file()?, &indices) {
+        // Uncompressed files are sampled by seeking straight to each chosen sample.
+        let step = mem::size_of::<PackedSample>() as u64;
+        let mut input = File::open(path)
+            .await
+            .with_context(|| format!("failed to open file `{}`", path.display()))?;
+        for &index in indices {
+            input.seek(SeekFrom::Start(index * step)).await?;
+            let mut packed = PackedSample::default();
+            input
+                .read_exact(bytemuck::bytes_of_mut(&mut packed))
+                .await?;
+            let mut sample = packed.unpack()?;
+            manifest::to_side_to_move(&mut sample, perspective);
+            if predicate::matches_all(&args.filters, &sample) {
+                write_epd(&mut writer, &sample).await?;
+                exported += 1;
+            }
+            progress.inc(1);
+        }
+    } else {
+        let mut reader = args.input.open()?;
+        let mut indices = indices.map(|indices| indices.into_iter().peekable());
+        let mut index = 0;
+        while let Some(packed) = reader.read_sample()? {
+            let chosen = indices
+                .as_mut()
+                .is_none_or(|indices| indices.next_if_eq(&index).is_some());
+            index += 1;
+            if !chosen {
+                continue;
+            }
+
+            let mut sample = packed.unpack()?;
+            manifest::to_side_to_move(&mut sample, perspective);
+            if predicate::matches_all(&args.filters, &sample) {
+                write_epd(&mut writer, &sample).await?;
+                exported += 1;
+            }
+            progress.inc(1);
+            if indices
+                .as_mut()
+                .is_some_and(|indices| indices.peek().is_none())
+            {
+                break;
+            }
+        }
+    }
+    writer.flush().await?;
+    progress.finish();
+
+    logging::summary(
+        &format!("{} positions exported", exported),
+        &[("positions_exported", exported)],
+    );
+
+    Ok(())
+}
+
+/// Writes the sample as an EPD record, with `ce` holding the evaluation from the
+/// side to move's point of view and `c0` holding the game result.
+async fn write_epd(writer: &mut (impl AsyncWrite + Unpin), sample: &Sample) -> anyhow::Result<()> {
+    let fen = sample.position.fen().to_string();
+    let mut epd = fen.split_whitespace().take(4).collect::<Vec<_>>().join(" ");
+    if let Some(eval) = sample.eval {
+        epd.push_str(&format!(" ce {};", eval));
+    }
+    epd.push_str(&format!(" c0 \"{}\";\n", sample.outcome));
+    writer.write_all(epd.as_bytes()).await?;
+    Ok(())
+}
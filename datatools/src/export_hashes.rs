@@ -0,0 +1,62 @@
+use anyhow::Context;
+use dataloader::exclude;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+use crate::{io::DatasetSource, logging};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help(
+        "Dataset whose positions are listed, a file, a directory of shards or `-` for stdin."
+    ))]
+    input: DatasetSource,
+    #[clap(
+        short('o'),
+        help("Output file, one hexadecimal Zobrist hash per line.")
+    )]
+    output: PathBuf,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} positions hashed",
+                )
+                .unwrap(),
+            )
+            .with_message("hashing positions..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let mut hashes = HashSet::new();
+    let mut reader = args.input.open()?;
+    let mut index = 0u64;
+    while let Some(packed) = reader.read_sample()? {
+        let sample = packed
+            .unpack()
+            .with_context(|| format!("failed to unpack sample #{}", index))?;
+        hashes.insert(sample.position.hash());
+        index += 1;
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+
+    let mut hashes: Vec<u64> = hashes.into_iter().collect();
+    hashes.sort_unstable();
+    exclude::write_hashes(&args.output, &hashes)
+        .with_context(|| format!("failed to write `{}`", args.output.display()))?;
+
+    logging::summary(
+        &format!(
+            "{} distinct positions of {} samples written to `{}`",
+            hashes.len(),
+            index,
+            args.output.display()
+        ),
+        &[("samples", index), ("positions", hashes.len() as u64)],
+    );
+    Ok(())
+}
@@ -1,215 +1,1064 @@
 use anyhow::Context;
-use core::str;
-use dama::{Outcome, Position, SanMove, pgn};
-use dataformat::{PackedSample, Sample};
+use dama::pgn;
+use dataformat::{
+    EvalOverflow, EvalPerspective, PackedSample, Sample,
+    manifest::{EVAL_PERSPECTIVE_SETTING, Source},
+};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use notify::{
+    EventKind, RecursiveMode, Watcher,
+    event::{AccessKind, AccessMode, ModifyKind, RenameMode},
+};
+use pgnextract::{EvalSign, ExtractError, Extractor, LenientReader, eval::SIGN_CHECK_GAMES};
+use rand::Rng;
 use std::{
-    fs::{File, OpenOptions},
-    io::{BufReader, BufWriter, Write},
-    mem,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    iter,
     path::{Path, PathBuf},
-    sync::mpsc,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc,
+    },
     thread,
     time::Duration,
 };
 
-use crate::shuffle::shuffle;
+use crate::{
+    compression,
+    io::{DatasetSink, SampleWriter},
+    logging, manifest,
+    predicate::{self, Placement, Predicate},
+    shuffle::{ShuffleOptions, shuffle_sink},
+    status::{self, GenerationStatus, WorkerState},
+    units::ByteSize,
+};
 
 #[derive(clap::Args)]
 pub struct Args {
-    #[clap(help("Input PGN files."))]
+    #[clap(help("Input PGN files, or `-` for stdin, which may also be zstd-compressed."))]
     inputs: Vec<PathBuf>,
-    #[clap(short('o'), default_value("output.bin"))]
+    #[clap(
+        short('o'),
+        default_value("output.bin"),
+        help("Output dataset, compressed if it ends in `.zst`, or `-` for stdout.")
+    )]
     output: PathBuf,
     #[clap(short('a'), long("append"))]
     append: bool,
+    #[clap(
+        long("shard-size"),
+        help("Writes the output as a directory of shards of this size, e.g. `4GiB`.")
+    )]
+    shard_size: Option<ByteSize>,
+    #[clap(
+        long("dry-run"),
+        help(
+            "Parses the input games and reports what would be written, without touching the output."
+        )
+    )]
+    dry_run: bool,
+    #[clap(
+        long("http"),
+        help(
+            "Address serving a JSON status page with the games read, positions per second and reader health, e.g. `127.0.0.1:8080`."
+        )
+    )]
+    http: Option<String>,
+    #[clap(
+        long("filter"),
+        help(
+            "Only keeps samples satisfying the predicate, e.g. `material=KRPvKR`, `phase>=16` or `contains!=Q on d1`, taking the same filters as routes."
+        )
+    )]
+    filters: Vec<Predicate>,
+    #[clap(
+        long("must-contain"),
+        help(
+            "Only keeps positions with the piece on one of the squares, e.g. `K on g1` for white's king or `k on b8/c8` for black's. It's the same as `--filter 'contains=K on g1'`."
+        )
+    )]
+    must_contain: Vec<Placement>,
+    #[clap(
+        long("route"),
+        help(
+            "Sends samples matching comma-separated filters to another output, e.g. `phase<=8 => endgames.bin`. Each sample goes to the first route it matches, or to the main output if none."
+        )
+    )]
+    routes: Vec<Route>,
+    #[clap(
+        long("eval-band-quota"),
+        help(
+            "Caps the share of each output taken by samples matching comma-separated filters, e.g. `abs-eval>300:10%`. Samples over the cap are held back until enough others make room, and those still held at the end are dropped."
+        )
+    )]
+    quotas: Vec<Quota>,
+    #[clap(
+        long("eval-perspective"),
+        default_value_t,
+        help(
+            "Whose point of view the evals are written from, `side-to-move` or `white`. It's recorded in the manifest so the loader can turn them back."
+        )
+    )]
+    eval_perspective: EvalPerspective,
+    #[clap(
+        long("eval-sign"),
+        value_enum,
+        default_value_t,
+        help("Whose point of view the evals in PGN comments are given from.")
+    )]
+    eval_sign: EvalSign,
+    #[clap(
+        long("validate-eval-sign"),
+        help(
+            "Checks the sign of each input's evals against the game results. Input files are read the way their first games agree with, other inputs are only warned about."
+        )
+    )]
+    validate_eval_sign: bool,
+    #[clap(
+        long("no-eval"),
+        conflicts_with("validate_eval_sign"),
+        help(
+            "Writes every quiet position without an eval, for games without eval comments. The loader trains them on their outcome."
+        )
+    )]
+    no_eval: bool,
+    #[clap(
+        long("eval-overflow"),
+        default_value_t,
+        help(
+            "What to do with evals past ±32767 centipawns, which samples can't hold: `clamp` them, `drop-eval` to keep the position without one, `skip` the position or report the game as an `error`."
+        )
+    )]
+    eval_overflow: EvalOverflow,
+    #[clap(
+        long("lenient"),
+        help(
+            "Splits the input into games at their tag pairs before parsing them, so that a malformed game is skipped without taking the games after it down with it."
+        )
+    )]
+    lenient: bool,
+    #[clap(
+        long("watch"),
+        conflicts_with_all(["inputs", "dry_run"]),
+        help(
+            "Directory to extract PGN files from as they appear, until Ctrl-C. Files count as new once written and closed or moved in, and those already there are extracted first unless the manifest of the output appended to lists them."
+        )
+    )]
+    watch: Option<PathBuf>,
+}
+
+/// Samples matching every filter of a route are written to its output instead of the
+/// main one, parsed from `<filter>[,<filter>...] => <output>`.
+#[derive(Clone, Debug)]
+struct Route {
+    filters: Vec<Predicate>,
+    output: PathBuf,
+    /// The filters as given, recorded in the output's manifest.
+    spec: String,
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (spec, output) = s
+            .rsplit_once("=>")
+            .context("route must be of the form `<filter>[,<filter>...] => <output>`")?;
+        let filters = spec
+            .split(',')
+            .map(str::parse)
+            .collect::<anyhow::Result<Vec<Predicate>>>()?;
+        let output = output.trim();
+        if output.is_empty() {
+            anyhow::bail!("route `{}` has no output", s);
+        }
+        Ok(Route {
+            filters,
+            output: PathBuf::from(output),
+            spec: spec.trim().to_string(),
+        })
+    }
+}
+
+/// A cap on the share of an output's samples matching every filter, parsed from
+/// `<filter>[,<filter>...]:<percent>%`. Each sample counts towards the first quota it
+/// matches.
+#[derive(Clone, Debug)]
+struct Quota {
+    filters: Vec<Predicate>,
+    share: f64,
+    /// The quota as given, recorded in the outputs' manifests.
+    spec: String,
+}
+
+impl FromStr for Quota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (spec, percent) = s
+            .rsplit_once(':')
+            .context("quota must be of the form `<filter>[,<filter>...]:<percent>%`")?;
+        let filters = spec
+            .split(',')
+            .map(str::parse)
+            .collect::<anyhow::Result<Vec<Predicate>>>()?;
+        let percent = percent.trim();
+        let share = percent
+            .strip_suffix('%')
+            .unwrap_or(percent)
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|percent| (0.0..=100.0).contains(percent))
+            .with_context(|| format!("invalid percentage `{}` in quota", percent))?
+            / 100.0;
+        Ok(Quota {
+            filters,
+            share,
+            spec: s.trim().to_string(),
+        })
+    }
+}
+
+/// Samples of a quota held back by each output, beyond which they are sampled down.
+const QUOTA_RESERVOIR: usize = 1 << 20;
+
+/// How far an output is into one of the quotas. Samples over it wait in a reservoir,
+/// which keeps a uniform random subset of them once full.
+#[derive(Default)]
+struct QuotaAccount {
+    share: f64,
+    written: u64,
+    /// Samples held back so far, including those evicted from the reservoir.
+    held: u64,
+    /// Samples held back and written later.
+    released: u64,
+    reservoir: Vec<PackedSample>,
+}
+
+impl QuotaAccount {
+    /// Whether one more sample of the quota may be written to an output holding
+    /// `positions` samples.
+    fn has_room(&self, positions: u64) -> bool {
+        (self.written + 1) as f64 <= self.share * (positions + 1) as f64
+    }
+
+    fn hold(&mut self, sample: PackedSample, rng: &mut impl Rng) {
+        self.held += 1;
+        if self.reservoir.len() < QUOTA_RESERVOIR {
+            self.reservoir.push(sample);
+        } else {
+            let slot = rng.random_range(0..self.held) as usize;
+            if slot < QUOTA_RESERVOIR {
+                self.reservoir[slot] = sample;
+            }
+        }
+    }
+
+    /// Samples held back which haven't been written, all of them once reading is over.
+    fn dropped(&self) -> u64 {
+        self.held - self.released
+    }
+}
+
+/// One of the datasets written, with the main output first and then one per route.
+struct Output {
+    sink: DatasetSink,
+    writer: Option<SampleWriter>,
+    positions: u64,
+    filters: Option<String>,
+    quotas: Vec<QuotaAccount>,
+}
+
+impl Output {
+    /// Writes a sample counting towards the given quota, or holds it back if the quota
+    /// is used up, then writes the held samples the output has room for.
+    fn push(
+        &mut self,
+        quota: Option<usize>,
+        sample: PackedSample,
+        rng: &mut impl Rng,
+    ) -> anyhow::Result<()> {
+        match quota {
+            Some(quota) if !self.quotas[quota].has_room(self.positions) => {
+                self.quotas[quota].hold(sample, rng);
+                return Ok(());
+            }
+            Some(quota) => self.quotas[quota].written += 1,
+            None => {}
+        }
+        self.write(&sample)?;
+        while let Some(quota) = self
+            .quotas
+            .iter()
+            .position(|account| !account.reservoir.is_empty() && account.has_room(self.positions))
+        {
+            let account = &mut self.quotas[quota];
+            let sample = account.reservoir.pop().unwrap();
+            account.written += 1;
+            account.released += 1;
+            self.write(&sample)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sample: &PackedSample) -> anyhow::Result<()> {
+        self.positions += 1;
+        if let Some(writer) = &mut self.writer {
+            writer.write_sample(sample)?;
+        }
+        Ok(())
+    }
 }
 
 pub async fn run(args: Args) -> anyhow::Result<()> {
-    let output_file = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .truncate(!args.append)
-        .append(args.append)
-        .open(&args.output)
-        .with_context(|| format!("failed to open output path `{}`", args.output.display()))?;
+    if args.inputs.iter().filter(|path| is_stdin(path)).count() > 1 {
+        anyhow::bail!("standard input can only be given once");
+    }
+    let mut outputs = Vec::new();
+    let paths = iter::once((&args.output, None)).chain(
+        args.routes
+            .iter()
+            .map(|route| (&route.output, Some(route.spec.clone()))),
+    );
+    for (path, filters) in paths {
+        let sink = DatasetSink::from_path(path, false).sharded(args.shard_size)?;
+        if outputs.iter().any(|output: &Output| output.sink == sink) {
+            anyhow::bail!("`{}` is given as more than one output", sink);
+        }
+        if args.append
+            && let Some(previous) = manifest::previous(&sink)?
+        {
+            let perspective = previous
+                .eval_perspective()
+                .with_context(|| format!("invalid manifest for `{}`", sink))?;
+            if perspective != args.eval_perspective {
+                anyhow::bail!(
+                    "`{}` holds evals from the {} perspective, append with `--eval-perspective {}`",
+                    sink,
+                    perspective,
+                    perspective
+                );
+            }
+        }
+        let writer = if args.dry_run {
+            None
+        } else {
+            Some(sink.create(args.append)?)
+        };
+        outputs.push(Output {
+            sink,
+            writer,
+            positions: 0,
+            filters,
+            quotas: args
+                .quotas
+                .iter()
+                .map(|quota| QuotaAccount {
+                    share: quota.share,
+                    ..QuotaAccount::default()
+                })
+                .collect(),
+        });
+    }
+    let routed = !args.routes.is_empty();
+    let routes = Arc::new(args.routes.clone());
+    let quotas = Arc::new(args.quotas.clone());
+
+    let status = Arc::new(GenerationStatus::new(
+        args.inputs.iter().map(|path| input_name(path)),
+    ));
+    if let Some(addr) = &args.http {
+        let status = status.clone();
+        let addr = status::serve(addr, move || status.page())?;
+        eprintln!("serving status on http://{}", addr);
+    }
+
+    // Files already extracted into the output, which a watched directory may still hold.
+    let extracted: HashSet<String> = if args.append && args.watch.is_some() {
+        manifest::previous_sources(&outputs[0].sink)?
+            .into_iter()
+            .map(|source| source.path)
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    let mut eval_signs = Vec::with_capacity(args.inputs.len());
+    for path in &args.inputs {
+        let eval_sign = if args.validate_eval_sign && !is_stdin(path) {
+            check_eval_sign(path, args.eval_sign)?
+        } else {
+            args.eval_sign
+        };
+        eval_signs.push(eval_sign);
+    }
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    tokio::spawn(watch_interrupt(interrupted.clone()));
+    let recovered = Arc::new(AtomicU64::new(0));
+    let filters: Arc<Vec<Predicate>> = Arc::new(
+        args.filters
+            .iter()
+            .cloned()
+            .chain(
+                args.must_contain
+                    .iter()
+                    .map(|&placement| Predicate::Contains(true, placement)),
+            )
+            .collect(),
+    );
+    let filtered = Arc::new(AtomicU64::new(0));
 
     let (send, recv) = mpsc::channel();
-    let reader_progress = MultiProgress::new();
-    let _reader_threads = args
-        .inputs
-        .iter()
-        .map(|path| -> Result<_, anyhow::Error> {
-            let file = File::open(path)
-                .with_context(|| format!("failed to open input file `{}`", path.display()))?;
-            let path = path.clone();
-            let send = send.clone();
-            let progress = reader_progress.clone();
-            Ok(thread::spawn(move || {
-                read_games(&path, file, send, progress)
-            }))
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+    let reader_progress = logging::track_multi(MultiProgress::new());
+    let _reader_threads =
+        args.inputs
+            .iter()
+            .enumerate()
+            .map(|(worker, path)| -> Result<_, anyhow::Error> {
+                let input: Box<dyn Read + Send> = if is_stdin(path) {
+                    compression::auto_reader(io::stdin())?
+                } else {
+                    Box::new(File::open(path).with_context(|| {
+                        format!("failed to open input file `{}`", path.display())
+                    })?)
+                };
+                let name = input_name(path);
+                let send = send.clone();
+                let progress = reader_progress.clone();
+                let status = status.clone();
+                let reader = GameReader {
+                    extractor: Extractor::default()
+                        .with_eval_sign(eval_signs[worker])
+                        .with_eval_overflow(args.eval_overflow)
+                        .without_evals(args.no_eval),
+                    filters: filters.clone(),
+                    filtered: filtered.clone(),
+                    routes: routes.clone(),
+                    quotas: quotas.clone(),
+                    check_sign: args.validate_eval_sign,
+                    lenient: args.lenient,
+                    recovered: recovered.clone(),
+                    interrupted: interrupted.clone(),
+                };
+                Ok(thread::spawn(move || {
+                    read_games(&name, input, send, reader, progress, worker, &status)
+                }))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+    let watch_thread = args.watch.clone().map(|directory| {
+        let watch = Watch {
+            directory,
+            extracted,
+            eval_sign: args.eval_sign,
+            no_eval: args.no_eval,
+            eval_overflow: args.eval_overflow,
+            reader: GameReader {
+                extractor: Extractor::default(),
+                filters: filters.clone(),
+                filtered: filtered.clone(),
+                routes: routes.clone(),
+                quotas: quotas.clone(),
+                check_sign: args.validate_eval_sign,
+                lenient: args.lenient,
+                recovered: recovered.clone(),
+                // Files are read to the end once started, so that each one is extracted
+                // whole.
+                interrupted: Arc::new(AtomicBool::new(false)),
+            },
+            interrupted: interrupted.clone(),
+        };
+        let send = send.clone();
+        let progress = reader_progress.clone();
+        let status = status.clone();
+        thread::spawn(move || watch_directory(watch, send, progress, &status))
+    });
     drop(send);
 
-    let mut writer = BufWriter::new(&output_file);
-    let mut positions_written = 0;
-    while let Ok(sample) = recv.recv() {
-        positions_written += 1;
-        writer.write_all(bytemuck::bytes_of(&sample))?;
+    let mut rng = rand::rng();
+    while let Ok((route, quota, mut sample)) = recv.recv() {
+        // Games give evals from the side to move's perspective, and routes and quotas
+        // match them so.
+        if let Some(eval) = sample.eval()
+            && let Ok(side_to_move) = sample.side_to_move()
+        {
+            sample.set_eval(Some(
+                args.eval_perspective.from_side_to_move(eval, side_to_move),
+            ));
+        }
+        status.add_positions(1);
+        outputs[route].push(quota, sample, &mut rng)?;
+    }
+
+    let positions_written: u64 = outputs.iter().map(|output| output.positions).sum();
+    let recovered = recovered.load(Ordering::Relaxed);
+    if recovered > 0 {
+        eprintln!("{} malformed games were skipped", recovered);
+    }
+    let filtered = filtered.load(Ordering::Relaxed);
+    if filtered > 0 {
+        eprintln!("{} positions were left out by the filters", filtered);
+    }
+    let quota_dropped: u64 = outputs
+        .iter()
+        .flat_map(|output| &output.quotas)
+        .map(QuotaAccount::dropped)
+        .sum();
+    if quota_dropped > 0 {
+        eprintln!("{} positions over their quota were dropped", quota_dropped);
+    }
+    let watched = match watch_thread {
+        Some(thread) => thread.join().expect("failed to join the watching thread")?,
+        None => Vec::new(),
+    };
+    // Watching only ever ends with an interruption, which leaves no file half read.
+    let interrupted = interrupted.load(Ordering::Relaxed) && args.watch.is_none();
+    if interrupted {
+        eprintln!(
+            "interrupted after {} games, keeping the {} positions read from them",
+            status.games(),
+            positions_written
+        );
+    }
+    if args.dry_run {
+        for output in &outputs {
+            eprintln!(
+                "{} positions would be written to `{}`",
+                output.positions, output.sink
+            );
+        }
+        logging::summary(
+            &format!(
+                "{} positions would be written to {} outputs",
+                positions_written,
+                outputs.len()
+            ),
+            &[("positions", positions_written)],
+        );
+        return Ok(());
+    }
+
+    for output in &mut outputs {
+        let writer = output
+            .writer
+            .take()
+            .expect("outputs have writers outside dry runs");
+        writer
+            .finish()
+            .with_context(|| format!("failed to write to `{}`", output.sink))?;
+        if routed {
+            eprintln!(
+                "{} positions written to `{}`",
+                output.positions, output.sink
+            );
+        }
     }
-    writer.flush()?;
-    drop(writer);
+    logging::summary(
+        &format!(
+            "{} positions written{}",
+            positions_written,
+            if interrupted {
+                " before the interruption"
+            } else {
+                ""
+            }
+        ),
+        &[
+            ("positions_written", positions_written),
+            ("quota_dropped", quota_dropped),
+            ("games", status.games()),
+            ("games_recovered", recovered),
+            ("positions_filtered", filtered),
+            ("interrupted", interrupted as u64),
+        ],
+    );
 
-    println!("{} positions written", positions_written);
+    for output in &outputs {
+        shuffle_sink(&output.sink, &ShuffleOptions::default()).await?;
 
-    shuffle(output_file.into(), None).await
+        let mut sources = if args.append {
+            manifest::previous_sources(&output.sink)?
+        } else {
+            Vec::new()
+        };
+        for path in &args.inputs {
+            sources.push(if is_stdin(path) {
+                Source {
+                    path: input_name(path),
+                    hash: None,
+                }
+            } else {
+                manifest::file_source(path)?
+            });
+        }
+        sources.extend(watched.iter().cloned());
+        let mut settings: Vec<_> = match &output.filters {
+            Some(filters) => vec![("filters", filters.clone())],
+            // The main output holds whatever no route took.
+            None if routed => vec![("filters", "unrouted".to_string())],
+            None => vec![],
+        };
+        settings.push((EVAL_PERSPECTIVE_SETTING, args.eval_perspective.to_string()));
+        if args.no_eval {
+            settings.push(("no_eval", "true".to_string()));
+        }
+        if args.eval_overflow != EvalOverflow::Clamp {
+            settings.push(("eval_overflow", args.eval_overflow.to_string()));
+        }
+        if !filters.is_empty() {
+            let filters: Vec<_> = filters.iter().map(Predicate::to_string).collect();
+            settings.push(("sample_filters", filters.join("; ")));
+        }
+        if !args.quotas.is_empty() {
+            let quotas: Vec<_> = args
+                .quotas
+                .iter()
+                .map(|quota| quota.spec.as_str())
+                .collect();
+            settings.push(("eval_band_quotas", quotas.join("; ")));
+            let dropped: u64 = output.quotas.iter().map(QuotaAccount::dropped).sum();
+            settings.push(("quota_dropped", dropped.to_string()));
+        }
+        if args.lenient {
+            settings.push(("lenient", "true".to_string()));
+            settings.push(("games_recovered", recovered.to_string()));
+        }
+        if interrupted {
+            settings.push(("interrupted", "true".to_string()));
+        }
+        manifest::write_for_sink(&output.sink, "extract", &settings, sources)?;
+    }
+    Ok(())
+}
+
+/// What the files of a watched directory are read with.
+struct Watch {
+    directory: PathBuf,
+    /// Paths of the files the output already holds the samples of.
+    extracted: HashSet<String>,
+    eval_sign: EvalSign,
+    no_eval: bool,
+    eval_overflow: EvalOverflow,
+    /// Reader whose settings the reader of each file starts with.
+    reader: GameReader,
+    /// Set on Ctrl-C, stopping the watch once the file being read is done.
+    interrupted: Arc<AtomicBool>,
+}
+
+/// How often the watch checks whether it was interrupted while no file appears.
+const WATCH_POLL: Duration = Duration::from_millis(200);
+
+/// Extracts the PGN files already in a directory and those appearing in it until
+/// interrupted, one at a time, returning them as sources for the manifest.
+fn watch_directory(
+    watch: Watch,
+    send: mpsc::Sender<(usize, Option<usize>, PackedSample)>,
+    progress: MultiProgress,
+    status: &GenerationStatus,
+) -> anyhow::Result<Vec<Source>> {
+    let (events_send, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(events_send)?;
+    watcher
+        .watch(&watch.directory, RecursiveMode::NonRecursive)
+        .with_context(|| format!("failed to watch `{}`", watch.directory.display()))?;
+    // Listed once the watch started, so that no file is missed in between.
+    let mut pending: Vec<PathBuf> = fs::read_dir(&watch.directory)
+        .with_context(|| format!("failed to read directory `{}`", watch.directory.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    pending.retain(|path| is_pgn(path) && path.is_file());
+    pending.sort();
+    eprintln!(
+        "watching `{}` for PGN files, press Ctrl-C to stop",
+        watch.directory.display()
+    );
+
+    // Modification times of the files extracted, as some writers close a file more than
+    // once.
+    let mut seen = HashMap::new();
+    let mut sources = Vec::new();
+    loop {
+        for path in pending.drain(..) {
+            let name = input_name(&path);
+            if watch.extracted.contains(&name) {
+                continue;
+            }
+            let modified = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            if let Some(&previous) = seen.get(&path) {
+                if previous != modified {
+                    eprintln!(
+                        "warning: `{}` was written again after it was extracted, skipping it",
+                        name
+                    );
+                }
+                continue;
+            }
+            seen.insert(path.clone(), modified);
+            // The file may be gone already, which only loses that one.
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(err) => {
+                    eprintln!("warning: failed to open `{}`: {}", name, err);
+                    continue;
+                }
+            };
+            let eval_sign = if watch.reader.check_sign {
+                check_eval_sign(&path, watch.eval_sign)?
+            } else {
+                watch.eval_sign
+            };
+            let reader = GameReader {
+                extractor: Extractor::default()
+                    .with_eval_sign(eval_sign)
+                    .with_eval_overflow(watch.eval_overflow)
+                    .without_evals(watch.no_eval),
+                filters: watch.reader.filters.clone(),
+                filtered: watch.reader.filtered.clone(),
+                routes: watch.reader.routes.clone(),
+                quotas: watch.reader.quotas.clone(),
+                recovered: watch.reader.recovered.clone(),
+                interrupted: watch.reader.interrupted.clone(),
+                ..watch.reader
+            };
+            let worker = status.add_worker(name.clone());
+            read_games(
+                &name,
+                Box::new(file),
+                send.clone(),
+                reader,
+                progress.clone(),
+                worker,
+                status,
+            );
+            sources.push(manifest::file_source(&path)?);
+        }
+        if watch.interrupted.load(Ordering::Relaxed) {
+            return Ok(sources);
+        }
+        match events.recv_timeout(WATCH_POLL) {
+            Ok(event) => {
+                let event = event
+                    .with_context(|| format!("failed to watch `{}`", watch.directory.display()))?;
+                // Files being written are left until they're closed.
+                let complete = matches!(
+                    event.kind,
+                    EventKind::Access(AccessKind::Close(AccessMode::Write))
+                        | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                );
+                if complete {
+                    pending.extend(event.paths.into_iter().filter(|path| is_pgn(path)));
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(sources),
+        }
+    }
+}
+
+fn is_pgn(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "pgn")
+}
+
+/// Asks the readers to stop after their current game on the first Ctrl-C, so what was
+/// read so far is still written out, shuffled and recorded, and exits on the second.
+async fn watch_interrupt(interrupted: Arc<AtomicBool>) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    eprintln!("interrupted, finishing the output, press Ctrl-C again to abort");
+    interrupted.store(true, Ordering::Relaxed);
+    if tokio::signal::ctrl_c().await.is_ok() {
+        std::process::exit(130);
+    }
+}
+
+pub(crate) fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// How an input is named in progress bars, errors and the status page.
+pub(crate) fn input_name(path: &Path) -> String {
+    if is_stdin(path) {
+        "<stdin>".to_string()
+    } else {
+        path.display().to_string()
+    }
+}
+
+/// Reads the first games of an input file to tell whose point of view its evals are
+/// from, returning `default` when they don't clearly tell.
+fn check_eval_sign(path: &Path, default: EvalSign) -> anyhow::Result<EvalSign> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open input file `{}`", path.display()))?;
+    let mut extractor = Extractor::default();
+    let mut reader = pgn::Reader::new(BufReader::new(file));
+    for _ in 0..SIGN_CHECK_GAMES {
+        match reader.visit_game(&mut extractor) {
+            Ok(true) => {
+                extractor.take_samples();
+            }
+            Ok(false) => break,
+            Err(err) if !err.is_recoverable() => break,
+            Err(_) => {}
+        }
+    }
+
+    let votes = extractor.votes();
+    match votes.verdict() {
+        Some(sign) => {
+            if sign != default {
+                eprintln!(
+                    "warning: evals in `{}` agree with the results when read from the {} point of view ({} to {}), reading them so",
+                    path.display(),
+                    sign.name(),
+                    votes.mover.max(votes.white),
+                    votes.mover.min(votes.white)
+                );
+            }
+            Ok(sign)
+        }
+        None => {
+            eprintln!(
+                "warning: can't tell whose point of view the evals in `{}` are from ({} agree with the mover, {} with White), reading them from the {} point of view",
+                path.display(),
+                votes.mover,
+                votes.white,
+                default.name()
+            );
+            Ok(default)
+        }
+    }
 }
 
 fn read_games(
-    path: &Path,
-    file: File,
-    send: mpsc::Sender<PackedSample>,
+    name: &str,
+    input: Box<dyn Read + Send>,
+    send: mpsc::Sender<(usize, Option<usize>, PackedSample)>,
+    mut game_reader: GameReader,
     multi_progress: MultiProgress,
+    worker: usize,
+    status: &GenerationStatus,
 ) {
-    let progress = ProgressBar::new_spinner()
-        .with_message(format!("reading games from `{}...`", path.display()))
-        .with_style(
-            ProgressStyle::with_template(
-                "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} games read",
-            )
-            .unwrap(),
-        );
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_message(format!("reading games from `{}...`", name))
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} games read",
+                )
+                .unwrap(),
+            ),
+    );
     progress.enable_steady_tick(Duration::from_millis(100));
     multi_progress.add(progress.clone());
 
-    let mut visitor = GameVisitor::default();
-    let mut reader = pgn::Reader::new(BufReader::new(file));
+    let mut reader = if game_reader.lenient {
+        PgnReader::Lenient(LenientReader::new(BufReader::new(input)))
+    } else {
+        PgnReader::Strict(pgn::Reader::new(BufReader::new(input)))
+    };
     loop {
-        match reader.visit_game(&mut visitor) {
+        if game_reader.interrupted.load(Ordering::Relaxed) {
+            progress.finish();
+            status.set_state(worker, WorkerState::Stopped);
+            break;
+        }
+        match reader.visit_game(&mut game_reader.extractor) {
             Ok(true) => {
-                for sample in visitor.take_buffer() {
-                    send.send(sample).expect("failed to send sample");
+                for sample in game_reader.extractor.take_samples() {
+                    if !predicate::matches_all(&game_reader.filters, &sample) {
+                        game_reader.filtered.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    let route = game_reader.route(&sample);
+                    let quota = game_reader.quota(&sample);
+                    match sample.pack() {
+                        Ok(packed) => send
+                            .send((route, quota, packed))
+                            .expect("failed to send sample"),
+                        Err(err) => {
+                            progress.println(format!("error while packing sample: {}", err));
+                            status.recent_errors.record(format!("{}: {}", name, err));
+                        }
+                    }
                 }
+                status.game_finished(worker);
             }
             Ok(false) => {
                 progress.finish();
+                status.set_state(worker, WorkerState::Finished);
                 break;
             }
             Err(err) if !err.is_recoverable() => {
                 progress.finish();
                 eprintln!("unrecoverable PGN error: {}", err);
+                status.set_state(worker, WorkerState::Failed);
+                status
+                    .recent_errors
+                    .record(format!("{}: unrecoverable PGN error: {}", name, err));
                 break;
             }
             Err(pgn::Error::Parse(err)) => {
                 progress.println(format!("parsing error while reading PGN: {}", err));
+                status
+                    .recent_errors
+                    .record(format!("{}: parsing error: {}", name, err));
             }
             Err(pgn::Error::Visitor(err)) => {
-                progress.println(format!("error while reading PGN: {:#}", err));
+                progress.println(format!("error while reading PGN: {}", err));
+                status.recent_errors.record(format!("{}: {}", name, err));
             }
         }
         progress.inc(1);
     }
-}
 
-#[derive(Default)]
-struct GameVisitor {
-    buffer: Vec<PackedSample>,
-    skip: bool,
-    position: Position,
-    outcome: Option<Outcome>,
-    eval: Option<i16>,
+    if let PgnReader::Lenient(reader) = &reader {
+        game_reader
+            .recovered
+            .fetch_add(reader.recovered(), Ordering::Relaxed);
+    }
 
-    positions_written: u32,
-    positions_seen: u32,
-    games_read: u32,
-    games_skipped: u32,
+    // Inputs which couldn't be checked up front, like stdin, are only checked once read.
+    let eval_sign = game_reader.extractor.eval_sign();
+    if game_reader.check_sign
+        && let Some(sign) = game_reader.extractor.votes().verdict()
+        && sign != eval_sign
+    {
+        let message = format!(
+            "{}: evals agree with the results when read from the {} point of view, but were read from the {} one",
+            name,
+            sign.name(),
+            eval_sign.name()
+        );
+        progress.println(format!("warning: {}", message));
+        status.recent_errors.record(message);
+    }
 }
 
-impl pgn::Visitor for GameVisitor {
-    type Error = anyhow::Error;
-
-    fn prepare(&mut self) {
-        self.position = Position::new_initial();
-        self.eval = None;
-    }
+/// Reads the games of an input either as dama does or with a [`LenientReader`].
+enum PgnReader {
+    Strict(pgn::Reader<BufReader<Box<dyn Read + Send>>>),
+    Lenient(LenientReader<BufReader<Box<dyn Read + Send>>>),
+}
 
-    fn visit_tag_pair(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
-        match name {
-            "FEN" => self.position = Position::from_fen(value)?,
-            "Result" if value == "*" => self.outcome = None,
-            "Result" => self.outcome = Some(value.parse()?),
-            "Termination" if value != "normal" => self.skip = true,
-            _ => {}
+impl PgnReader {
+    fn visit_game(&mut self, extractor: &mut Extractor) -> Result<bool, pgn::Error<ExtractError>> {
+        match self {
+            PgnReader::Strict(reader) => reader.visit_game(extractor),
+            PgnReader::Lenient(reader) => reader.visit_game(extractor),
         }
-        Ok(())
     }
+}
 
-    fn enter_game(&mut self) -> pgn::ControlFlow {
-        if self.skip || self.outcome.is_none() {
-            self.games_skipped += 1;
-            pgn::ControlFlow::Skip
-        } else {
-            self.games_read += 1;
-            pgn::ControlFlow::Continue
-        }
+/// The games of one input, whose samples are sent to the output of the first route they
+/// match.
+struct GameReader {
+    extractor: Extractor,
+    /// Filters every sample has to match to be kept, adding those left out to `filtered`.
+    filters: Arc<Vec<Predicate>>,
+    filtered: Arc<AtomicU64>,
+    routes: Arc<Vec<Route>>,
+    quotas: Arc<Vec<Quota>>,
+    /// Whether the evals are checked against the game results.
+    check_sign: bool,
+    /// Whether the input is read with a [`LenientReader`], adding the malformed games it
+    /// skipped to `recovered`.
+    lenient: bool,
+    recovered: Arc<AtomicU64>,
+    /// Set on Ctrl-C, stopping the reader before its next game.
+    interrupted: Arc<AtomicBool>,
+}
+
+impl GameReader {
+    /// Index of the output a sample goes to.
+    fn route(&self, sample: &Sample) -> usize {
+        self.routes
+            .iter()
+            .position(|route| predicate::matches_all(&route.filters, sample))
+            .map_or(0, |route| route + 1)
     }
 
-    fn enter_variation(&mut self) -> pgn::ControlFlow {
-        pgn::ControlFlow::Skip
+    /// Index of the quota a sample counts towards, if any.
+    fn quota(&self, sample: &Sample) -> Option<usize> {
+        self.quotas
+            .iter()
+            .position(|quota| predicate::matches_all(&quota.filters, sample))
     }
+}
 
-    fn visit_move(&mut self, _number: Option<u32>, mv: SanMove) -> anyhow::Result<()> {
-        if !self.position.is_in_check() && !mv.is_capture() {
-            if let Some(eval) = self.eval {
-                self.write(eval)?;
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::DatasetSource;
+    use clap::Parser;
+    use dama::{Color, Outcome};
 
-        self.position
-            .play(&mv)
-            .with_context(|| format!("position: '{}', move: '{}'", self.position.fen(), mv))?;
-        self.eval = None;
-        self.positions_seen += 1;
+    const GAME: &str = r#"[Event "?"]
+[Result "1-0"]
 
-        Ok(())
+1. e4 {+0.30/10 0.1s} e5 {-0.20/10 0.1s} 2. Qh5 {book} Nc6 {+0.50/12 0.1s}
+3. Bc4 {-0.10/12 0.1s} Nf6 {+1.00/12 0.1s} 4. Qxf7# {+M1/1 0.1s} 1-0
+"#;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        args: Args,
     }
 
-    fn visit_comment(&mut self, comment: &[u8]) -> anyhow::Result<()> {
-        let comment = str::from_utf8(comment)?;
-        if comment == "book" {
-            return Ok(());
-        }
+    #[tokio::test]
+    async fn extracted_samples_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let pgn = dir.path().join("games.pgn");
+        fs::write(&pgn, GAME).unwrap();
 
-        if let Some(info) = comment.split('/').next() {
-            if !info.starts_with("+M") && !info.starts_with("-M") {
-                if let Ok(eval) = info.parse::<f64>() {
-                    self.eval = Some((-eval * 100.0).round() as i16);
-                }
-            }
-        }
+        // 2... Nc6 follows a book move and 4. Qxf7# is a capture.
+        for (output, options, perspective, expected) in [
+            ("out.bin", &[][..], "side-to-move", [-50, -30, 10, 20]),
+            (
+                "out",
+                &["--shard-size", "64", "--eval-perspective", "white"][..],
+                "white",
+                [-50, -10, 20, 30],
+            ),
+        ] {
+            let output = dir.path().join(output);
+            let cli = Cli::parse_from(
+                [
+                    "extract",
+                    pgn.to_str().unwrap(),
+                    "-o",
+                    output.to_str().unwrap(),
+                ]
+                .into_iter()
+                .chain(options.iter().copied()),
+            );
+            run(cli.args).await.unwrap();
 
-        Ok(())
-    }
-}
+            let dataset = DatasetSource::from_path(&output);
+            let mut reader = dataset.open().unwrap();
+            let mut evals = Vec::new();
+            while let Some(packed) = reader.read_sample().unwrap() {
+                let sample = packed.unpack().unwrap();
+                assert_eq!(sample.outcome, Outcome::Winner(Color::White));
+                evals.push(sample.eval.unwrap());
+            }
+            evals.sort();
+            assert_eq!(evals, expected);
 
-impl GameVisitor {
-    fn write(&mut self, eval: i16) -> anyhow::Result<()> {
-        let sample = Sample {
-            position: self.position.clone(),
-            outcome: self
-                .outcome
-                .ok_or(anyhow::Error::msg("game has no outcome"))?,
-            eval: Some(eval),
+            let manifest = manifest::check(&dataset).unwrap().unwrap();
+            assert_eq!(manifest.samples(), 4);
+            assert_eq!(
+                manifest.setting(EVAL_PERSPECTIVE_SETTING),
+                Some(perspective)
+            );
         }
-        .pack()?;
-        self.buffer.push(sample);
-        self.positions_written += 1;
-        Ok(())
-    }
-
-    fn take_buffer(&mut self) -> Vec<PackedSample> {
-        mem::take(&mut self.buffer)
     }
 }
@@ -0,0 +1,128 @@
+use anyhow::Context;
+use dama::Position;
+use dataformat::Sample;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::time::Duration;
+
+use crate::{
+    io::DatasetSource,
+    logging, manifest,
+    predicate::{self, MaterialSignature, Predicate},
+};
+
+#[derive(clap::Args)]
+#[command(group(
+    clap::ArgGroup::new("query")
+        .required(true)
+        .multiple(true)
+        .args(["fen", "material", "filters"])
+))]
+pub struct Args {
+    #[clap(help("Dataset to search, a file, a directory of shards or `-` for stdin."))]
+    file: DatasetSource,
+    #[clap(
+        long("fen"),
+        help("Matches samples with the same position as the given FEN, ignoring move counters.")
+    )]
+    fen: Option<String>,
+    #[clap(long("exact"), help("Also compare move counters when matching by FEN."))]
+    exact: bool,
+    #[clap(
+        long("material"),
+        help("Matches samples with the given material signature, e.g. `KRPvKR`.")
+    )]
+    material: Option<MaterialSignature>,
+    #[clap(
+        short('f'),
+        long("filter"),
+        help("Matches samples satisfying the predicate, e.g. `eval>=300` or `pieces<=5`.")
+    )]
+    filters: Vec<Predicate>,
+    #[clap(short('n'), long("limit"), help("Stops after this many matches."))]
+    limit: Option<u64>,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let fen = args
+        .fen
+        .as_deref()
+        .map(Position::from_fen)
+        .transpose()
+        .context("invalid FEN")?
+        .map(|position| fen_key(&position, args.exact));
+
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} positions searched",
+                )
+                .unwrap(),
+            )
+            .with_message("searching..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let perspective = manifest::perspective_of(&args.file)?;
+    let mut reader = args.file.open()?;
+    let mut index = 0u64;
+    let mut matches = 0u64;
+    while let Some(packed) = reader.read_sample()? {
+        let mut sample = packed
+            .unpack()
+            .with_context(|| format!("failed to unpack sample #{}", index))?;
+        manifest::to_side_to_move(&mut sample, perspective);
+
+        let fen_matches = fen
+            .as_ref()
+            .is_none_or(|fen| *fen == fen_key(&sample.position, args.exact));
+        let material_matches = args
+            .material
+            .as_ref()
+            .is_none_or(|material| *material == MaterialSignature::of(&sample.position));
+
+        let is_match =
+            fen_matches && material_matches && predicate::matches_all(&args.filters, &sample);
+        if is_match {
+            progress.suspend(|| print_match(index, &sample));
+            matches += 1;
+        }
+
+        index += 1;
+        progress.inc(1);
+        if is_match && args.limit.is_some_and(|limit| matches >= limit) {
+            break;
+        }
+    }
+    progress.finish_and_clear();
+
+    logging::summary(
+        &format!("{} matches found in {} positions", matches, index),
+        &[("matches", matches), ("positions_searched", index)],
+    );
+
+    Ok(())
+}
+
+fn print_match(index: u64, sample: &Sample) {
+    let eval = match sample.eval {
+        Some(eval) => eval.to_string(),
+        None => "none".to_string(),
+    };
+    logging::report!(
+        "#{}: {} | eval: {} | outcome: {}",
+        index,
+        sample.position.fen(),
+        eval,
+        sample.outcome
+    );
+}
+
+fn fen_key(position: &Position, exact: bool) -> String {
+    let fen = position.fen().to_string();
+    if exact {
+        fen
+    } else {
+        fen.split_whitespace().take(4).collect::<Vec<_>>().join(" ")
+    }
+}
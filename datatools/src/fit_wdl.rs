@@ -0,0 +1,251 @@
+use anyhow::Context;
+use dataloader::wdl::{self, MAX_PHASE, WdlModel};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{path::PathBuf, time::Duration};
+
+use crate::{io::DatasetSource, logging, manifest};
+
+/// Width in centipawns of the buckets samples are grouped into.
+const EVAL_BUCKET: i32 = 10;
+/// The fit runs on evals in pawns to keep the Newton steps well conditioned.
+const EVAL_UNIT: f64 = 100.0;
+const MAX_ITERATIONS: usize = 100;
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Dataset to fit the model on, a file, a directory of shards or `-` for stdin."))]
+    dataset: DatasetSource,
+    #[clap(short('o'), help("Output file for the fitted WDL model."))]
+    output: PathBuf,
+    #[clap(
+        long("max-eval"),
+        default_value_t = 2000,
+        help(
+            "Samples evaluated beyond this many centipawns, such as mate scores, are ignored. Must be a multiple of 10."
+        )
+    )]
+    max_eval: i32,
+    #[clap(long("limit"), help("Maximum number of samples to fit the model on."))]
+    limit: Option<u64>,
+}
+
+/// Counts and total scores of the samples, bucketed by eval and phase.
+struct Histogram {
+    max_eval: i32,
+    counts: Vec<f64>,
+    scores: Vec<f64>,
+}
+
+impl Histogram {
+    fn new(max_eval: i32) -> Self {
+        let len = Self::eval_buckets(max_eval) * (MAX_PHASE as usize + 1);
+        Histogram {
+            max_eval,
+            counts: vec![0.0; len],
+            scores: vec![0.0; len],
+        }
+    }
+
+    fn eval_buckets(max_eval: i32) -> usize {
+        (2 * (max_eval / EVAL_BUCKET) + 1) as usize
+    }
+
+    fn add(&mut self, eval: i32, phase: u32, score: f64) {
+        let eval_bucket = ((eval + self.max_eval) as f64 / EVAL_BUCKET as f64).round() as usize;
+        let index = phase as usize * Self::eval_buckets(self.max_eval) + eval_bucket;
+        self.counts[index] += 1.0;
+        self.scores[index] += score;
+    }
+
+    /// Every non-empty bucket as `(eval, phase fraction, count, total score)`.
+    fn buckets(&self) -> impl Iterator<Item = (f64, f64, f64, f64)> + '_ {
+        let eval_buckets = Self::eval_buckets(self.max_eval);
+        self.counts
+            .iter()
+            .zip(&self.scores)
+            .enumerate()
+            .filter(|(_, (count, _))| **count > 0.0)
+            .map(move |(index, (&count, &score))| {
+                let eval = (index % eval_buckets) as i32 * EVAL_BUCKET - self.max_eval;
+                let phase = (index / eval_buckets) as f64 / MAX_PHASE as f64;
+                (eval as f64, phase, count, score)
+            })
+    }
+
+    /// Mean cross entropy between the outcomes and the model's expected scores.
+    fn loss(&self, model: &WdlModel) -> f64 {
+        let (mut loss, mut total) = (0.0, 0.0);
+        for (eval, phase, count, score) in self.buckets() {
+            let p =
+                (model.expected_score(eval as f32, phase as f32) as f64).clamp(1e-9, 1.0 - 1e-9);
+            loss -= score * p.ln() + (count - score) * (1.0 - p).ln();
+            total += count;
+        }
+        loss / total
+    }
+
+    /// Fits the logistic model by maximum likelihood with Newton's method.
+    fn fit(&self) -> anyhow::Result<WdlModel> {
+        let (mut a, mut b) = (EVAL_UNIT / 400.0, 0.0);
+        for _ in 0..MAX_ITERATIONS {
+            let (mut g0, mut g1) = (0.0, 0.0);
+            let (mut h00, mut h01, mut h11) = (0.0, 0.0, 0.0);
+            for (eval, phase, count, score) in self.buckets() {
+                let x0 = eval / EVAL_UNIT;
+                let x1 = x0 * phase;
+                let p = 1.0 / (1.0 + (-(a * x0 + b * x1)).exp());
+                let residual = count * p - score;
+                let weight = count * p * (1.0 - p);
+                g0 += residual * x0;
+                g1 += residual * x1;
+                h00 += weight * x0 * x0;
+                h01 += weight * x0 * x1;
+                h11 += weight * x1 * x1;
+            }
+
+            let det = h00 * h11 - h01 * h01;
+            if det.abs() < 1e-12 {
+                anyhow::bail!("not enough distinct evals and phases to fit a WDL model");
+            }
+            let step_a = (h11 * g0 - h01 * g1) / det;
+            let step_b = (h00 * g1 - h01 * g0) / det;
+            a -= step_a;
+            b -= step_b;
+            if step_a.abs() < 1e-10 && step_b.abs() < 1e-10 {
+                break;
+            }
+        }
+        Ok(WdlModel {
+            eval_coefficient: (a / EVAL_UNIT) as f32,
+            phase_coefficient: (b / EVAL_UNIT) as f32,
+        })
+    }
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    // The buckets are centered on multiples of their width from -max_eval to max_eval.
+    if args.max_eval < EVAL_BUCKET || args.max_eval % EVAL_BUCKET != 0 {
+        anyhow::bail!(
+            "--max-eval must be a positive multiple of {} centipawns",
+            EVAL_BUCKET
+        );
+    }
+
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} positions read",
+                )
+                .unwrap(),
+            )
+            .with_message("collecting..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let perspective = manifest::perspective_of(&args.dataset)?;
+    let mut histogram = Histogram::new(args.max_eval);
+    let mut reader = args.dataset.open()?;
+    let mut index = 0u64;
+    let mut used = 0u64;
+    while args.limit.is_none_or(|limit| used < limit)
+        && let Some(packed) = reader.read_sample()?
+    {
+        index += 1;
+        progress.inc(1);
+        // Samples without a usable eval are skipped before paying for a full unpack.
+        let Some(eval) = packed.eval() else {
+            continue;
+        };
+        if (eval as i32).abs() > args.max_eval {
+            continue;
+        }
+        let sample = packed
+            .unpack()
+            .with_context(|| format!("failed to unpack sample #{}", index - 1))?;
+        let side_to_move = sample.position.side_to_move();
+        let eval = perspective.to_side_to_move(eval, side_to_move) as i32;
+        let score = match sample.outcome.winner() {
+            Some(color) if color == side_to_move => 1.0,
+            Some(_) => 0.0,
+            None => 0.5,
+        };
+        histogram.add(eval, wdl::phase(&sample.position), score);
+        used += 1;
+    }
+    progress.finish_and_clear();
+
+    if used == 0 {
+        anyhow::bail!(
+            "`{}` has no evaluated samples to fit a WDL model on",
+            args.dataset
+        );
+    }
+    let model = histogram.fit()?;
+    model
+        .save(&args.output)
+        .with_context(|| format!("failed to write file `{}`", args.output.display()))?;
+
+    let scale_at = |phase: f32| 1.0 / (model.eval_coefficient + model.phase_coefficient * phase);
+    logging::report!("Endgame scale: {:.1} cp", scale_at(0.0));
+    logging::report!("Opening scale: {:.1} cp", scale_at(1.0));
+    logging::report!("Loss: {:.6}", histogram.loss(&model));
+    logging::report!(
+        "Loss with a 400 cp scale: {:.6}",
+        histogram.loss(&WdlModel::default())
+    );
+
+    logging::summary(
+        &format!(
+            "WDL model fitted on {} of {} samples written to `{}`",
+            used,
+            index,
+            args.output.display()
+        ),
+        &[("samples", used), ("samples_read", index)],
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_add_rounds_to_buckets() {
+        let mut histogram = Histogram::new(100);
+        histogram.add(-100, 0, 1.0);
+        histogram.add(100, MAX_PHASE, 0.0);
+        histogram.add(96, MAX_PHASE, 1.0);
+        histogram.add(-4, 12, 0.5);
+        histogram.add(4, 12, 0.5);
+        let buckets: Vec<_> = histogram.buckets().collect();
+        assert_eq!(
+            buckets,
+            [
+                (-100.0, 0.0, 1.0, 1.0),
+                (0.0, 0.5, 2.0, 1.0),
+                (100.0, 1.0, 2.0, 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn fit_recovers_model() {
+        let model = WdlModel {
+            eval_coefficient: 1.0 / 300.0,
+            phase_coefficient: 1.0 / 600.0,
+        };
+        let mut histogram = Histogram::new(1000);
+        for eval in (-1000..=1000).step_by(EVAL_BUCKET as usize) {
+            for phase in 0..=MAX_PHASE {
+                let score = model.expected_score(eval as f32, phase as f32 / MAX_PHASE as f32);
+                histogram.add(eval, phase, score as f64);
+            }
+        }
+        let fitted = histogram.fit().unwrap();
+        assert!((fitted.eval_coefficient - model.eval_coefficient).abs() < 1e-6);
+        assert!((fitted.phase_coefficient - model.phase_coefficient).abs() < 1e-6);
+        assert!(histogram.loss(&fitted) <= histogram.loss(&WdlModel::default()));
+    }
+}
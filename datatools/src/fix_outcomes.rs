@@ -0,0 +1,150 @@
+use anyhow::Context;
+use dama::{Outcome, Position};
+use dataformat::PackedSample;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    io::{DatasetSource, SampleFiles},
+    logging, manifest,
+    tablebase::Tablebase,
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Uncompressed dataset, a file or a directory of shards, to fix in place."))]
+    file: DatasetSource,
+    #[clap(
+        long("syzygy"),
+        help("Directory of Syzygy tablebases used to relabel positions with few pieces.")
+    )]
+    syzygy: Option<PathBuf>,
+    #[clap(
+        long("check"),
+        help("Only reports mislabeled samples, leaving the file untouched.")
+    )]
+    check: bool,
+}
+
+const BLOCK_SIZE: u64 = 65536;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Reason {
+    Checkmate,
+    Stalemate,
+    InsufficientMaterial,
+    Tablebase,
+}
+
+#[derive(Default)]
+struct Stats {
+    checkmate: u64,
+    stalemate: u64,
+    insufficient_material: u64,
+    tablebase: u64,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let tablebase = args.syzygy.as_deref().map(Tablebase::open).transpose()?;
+
+    let mut files = SampleFiles::open(&args.file, !args.check)?;
+    let positions = files.len();
+
+    let progress = logging::track(
+        ProgressBar::new(positions)
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions checked.",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("checking outcomes..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let mut stats = Stats::default();
+    let mut block = vec![PackedSample::default(); BLOCK_SIZE as usize];
+    let mut offset = 0;
+    while offset < positions {
+        let len = (positions - offset).min(BLOCK_SIZE) as usize;
+        let block = &mut block[..len];
+        files.read(offset, block)?;
+
+        let mut modified = false;
+        for (n, packed) in block.iter_mut().enumerate() {
+            let mut sample = packed
+                .unpack()
+                .with_context(|| format!("failed to unpack sample #{}", offset + n as u64))?;
+            let Some((outcome, reason)) = expected_outcome(&sample.position, tablebase.as_ref())
+            else {
+                continue;
+            };
+            if outcome == sample.outcome {
+                continue;
+            }
+
+            match reason {
+                Reason::Checkmate => stats.checkmate += 1,
+                Reason::Stalemate => stats.stalemate += 1,
+                Reason::InsufficientMaterial => stats.insufficient_material += 1,
+                Reason::Tablebase => stats.tablebase += 1,
+            }
+            sample.outcome = outcome;
+            packed.repack(&sample)?;
+            modified = true;
+        }
+
+        if modified && !args.check {
+            files.write(offset, block)?;
+        }
+
+        offset += len as u64;
+        progress.inc(len as u64);
+    }
+    progress.finish();
+    if !args.check {
+        manifest::refresh(&args.file)?;
+    }
+
+    let total = stats.checkmate + stats.stalemate + stats.insufficient_material + stats.tablebase;
+    logging::summary(
+        &format!(
+            "{} mislabeled outcomes {} ({} checkmates, {} stalemates, {} insufficient material, {} tablebase)",
+            total,
+            if args.check { "found" } else { "fixed" },
+            stats.checkmate,
+            stats.stalemate,
+            stats.insufficient_material,
+            stats.tablebase,
+        ),
+        &[
+            ("mislabeled", total),
+            ("checkmate", stats.checkmate),
+            ("stalemate", stats.stalemate),
+            ("insufficient_material", stats.insufficient_material),
+            ("tablebase", stats.tablebase),
+        ],
+    );
+
+    Ok(())
+}
+
+fn expected_outcome(
+    position: &Position,
+    tablebase: Option<&Tablebase>,
+) -> Option<(Outcome, Reason)> {
+    if position.legal_moves().is_empty() {
+        return Some(if position.is_in_check() {
+            (Outcome::Winner(!position.side_to_move()), Reason::Checkmate)
+        } else {
+            (Outcome::Draw, Reason::Stalemate)
+        });
+    }
+    if position.is_insufficient_material() {
+        return Some((Outcome::Draw, Reason::InsufficientMaterial));
+    }
+    tablebase
+        .and_then(|tablebase| tablebase.probe_outcome(position))
+        .map(|outcome| (outcome, Reason::Tablebase))
+}
@@ -0,0 +1,289 @@
+use anyhow::Context;
+use dama::{Color, Outcome, Position, SanMove};
+use dataformat::{
+    EvalOverflow, EvalPerspective, FittedEval, Sample,
+    manifest::{EVAL_PERSPECTIVE_SETTING, Source},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use pgnextract::{MoveFilter, filter};
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    compression,
+    extract::{input_name, is_stdin},
+    io::DatasetSink,
+    logging, manifest,
+    shuffle::{ShuffleOptions, shuffle_sink},
+    units::ByteSize,
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help(
+        "Game exports of the Lichess API as NDJSON, requested with `evals=true`, or `-` for stdin, which may also be zstd-compressed."
+    ))]
+    inputs: Vec<PathBuf>,
+    #[clap(
+        short('o'),
+        default_value("output.bin"),
+        help("Output dataset, compressed if it ends in `.zst`, or `-` for stdout.")
+    )]
+    output: PathBuf,
+    #[clap(short('a'), long("append"))]
+    append: bool,
+    #[clap(
+        long("shard-size"),
+        help("Writes the output as a directory of shards of this size, e.g. `4GiB`.")
+    )]
+    shard_size: Option<ByteSize>,
+    #[clap(
+        long("no-eval"),
+        help(
+            "Writes every quiet position without an eval, including those of games Lichess never analysed. The loader trains them on their outcome."
+        )
+    )]
+    no_eval: bool,
+    #[clap(
+        long("eval-overflow"),
+        default_value_t,
+        help(
+            "What to do with evals past ±32767 centipawns, which samples can't hold: `clamp` them, `drop-eval` to keep the position without one, `skip` the position or report the game as an `error`."
+        )
+    )]
+    eval_overflow: EvalOverflow,
+}
+
+/// A game as exported by the Lichess API, with only the fields samples are made of.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LichessGame {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    variant: Option<String>,
+    status: String,
+    #[serde(default)]
+    winner: Option<String>,
+    #[serde(default)]
+    moves: String,
+    #[serde(default)]
+    initial_fen: Option<String>,
+    /// Server analysis of the position after each move, from white's point of view.
+    #[serde(default)]
+    analysis: Option<Vec<Analysis>>,
+}
+
+#[derive(Deserialize)]
+struct Analysis {
+    /// Eval in centipawns, missing when the engine found a mate.
+    #[serde(default)]
+    eval: Option<i32>,
+}
+
+#[derive(Default)]
+struct ImportStats {
+    games_read: u64,
+    games_skipped: u64,
+    games_failed: u64,
+    positions_written: u64,
+}
+
+/// Turns the games of the Lichess API into samples.
+struct Importer {
+    filters: Vec<Box<dyn MoveFilter + Send>>,
+    no_eval: bool,
+    eval_overflow: EvalOverflow,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let sink = DatasetSink::from_path(&args.output, false).sharded(args.shard_size)?;
+    let mut writer = sink.create(args.append)?;
+    let importer = Importer {
+        filters: filter::quiet(),
+        no_eval: args.no_eval,
+        eval_overflow: args.eval_overflow,
+    };
+
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} games read",
+                )
+                .unwrap(),
+            )
+            .with_message("importing games..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let mut stats = ImportStats::default();
+    for path in &args.inputs {
+        let input: Box<dyn Read + Send> = if is_stdin(path) {
+            compression::auto_reader(io::stdin())?
+        } else {
+            compression::auto_reader(
+                File::open(path)
+                    .with_context(|| format!("failed to open input file `{}`", path.display()))?,
+            )?
+        };
+        let name = input_name(path);
+        for (number, line) in BufReader::new(input).lines().enumerate() {
+            let line = line.with_context(|| format!("failed to read `{}`", name))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match importer.import(&line) {
+                Ok(Some(samples)) => {
+                    stats.games_read += 1;
+                    for sample in samples {
+                        writer
+                            .write_sample(&sample.pack()?)
+                            .with_context(|| format!("failed to write to `{}`", sink))?;
+                        stats.positions_written += 1;
+                    }
+                }
+                Ok(None) => stats.games_skipped += 1,
+                Err(err) => {
+                    stats.games_failed += 1;
+                    progress.println(format!("{}:{}: {:#}", name, number + 1, err));
+                }
+            }
+            progress.inc(1);
+        }
+    }
+    progress.finish();
+    writer
+        .finish()
+        .with_context(|| format!("failed to write to `{}`", sink))?;
+
+    logging::summary(
+        &format!(
+            "{} positions written from {} games, {} skipped and {} failed",
+            stats.positions_written, stats.games_read, stats.games_skipped, stats.games_failed
+        ),
+        &[
+            ("positions_written", stats.positions_written),
+            ("games", stats.games_read),
+            ("games_skipped", stats.games_skipped),
+            ("games_failed", stats.games_failed),
+        ],
+    );
+
+    shuffle_sink(&sink, &ShuffleOptions::default()).await?;
+    let mut sources = if args.append {
+        manifest::previous_sources(&sink)?
+    } else {
+        Vec::new()
+    };
+    for path in &args.inputs {
+        sources.push(if is_stdin(path) {
+            Source {
+                path: input_name(path),
+                hash: None,
+            }
+        } else {
+            manifest::file_source(path)?
+        });
+    }
+    let mut settings = vec![(
+        EVAL_PERSPECTIVE_SETTING,
+        EvalPerspective::SideToMove.to_string(),
+    )];
+    if args.no_eval {
+        settings.push(("no_eval", "true".to_string()));
+    }
+    if args.eval_overflow != EvalOverflow::Clamp {
+        settings.push(("eval_overflow", args.eval_overflow.to_string()));
+    }
+    manifest::write_for_sink(&sink, "import-lichess", &settings, sources)
+}
+
+impl Importer {
+    /// The samples of a game, the positions before the moves every filter keeps with the
+    /// eval of the analysis of the previous move, or `None` for games that are skipped:
+    /// variants, games which ended abnormally and, unless evals are left out, games
+    /// without analysis.
+    fn import(&self, line: &str) -> anyhow::Result<Option<Vec<Sample>>> {
+        let game: LichessGame = serde_json::from_str(line).context("invalid game")?;
+        let context = || format!("game {}", game.id);
+        if !matches!(
+            game.variant.as_deref(),
+            None | Some("standard" | "fromPosition")
+        ) {
+            return Ok(None);
+        }
+        let outcome = match (game.status.as_str(), game.winner.as_deref()) {
+            ("mate" | "resign", Some("white")) => Outcome::Winner(Color::White),
+            ("mate" | "resign", Some("black")) => Outcome::Winner(Color::Black),
+            ("draw" | "stalemate", None) => Outcome::Draw,
+            ("mate" | "resign" | "draw" | "stalemate", _) => {
+                return Err(anyhow::anyhow!(
+                    "`{}` game with winner {:?}",
+                    game.status,
+                    game.winner
+                ))
+                .with_context(context);
+            }
+            // Games lost on time, aborted or cut short by a rule violation.
+            _ => return Ok(None),
+        };
+        let analysis = match &game.analysis {
+            Some(analysis) => analysis.as_slice(),
+            None if self.no_eval => &[],
+            None => return Ok(None),
+        };
+
+        let mut position = match &game.initial_fen {
+            Some(fen) => Position::from_fen(fen).with_context(context)?,
+            None => Position::new_initial(),
+        };
+        let mut samples = Vec::new();
+        for (ply, san) in game.moves.split_whitespace().enumerate() {
+            let mv = SanMove::from_str(san)
+                .map_err(|_| anyhow::anyhow!("invalid move `{}`", san))
+                .with_context(context)?;
+            let white_eval = ply
+                .checked_sub(1)
+                .and_then(|previous| analysis.get(previous))
+                .and_then(|analysis| analysis.eval);
+            let (eval, dropped) = match white_eval.filter(|_| !self.no_eval) {
+                Some(eval) => {
+                    let eval = match position.side_to_move() {
+                        Color::White => eval,
+                        Color::Black => eval.saturating_neg(),
+                    };
+                    match self.eval_overflow.fit(eval).with_context(context)? {
+                        FittedEval::Eval(eval) => (Some(eval), false),
+                        FittedEval::NoEval => (None, true),
+                        FittedEval::Skip => (None, false),
+                    }
+                }
+                None => (None, false),
+            };
+            if (self.no_eval || eval.is_some() || dropped)
+                && self
+                    .filters
+                    .iter()
+                    .all(|filter| filter.keep(&position, &mv))
+            {
+                samples.push(Sample {
+                    position: position.clone(),
+                    outcome,
+                    eval,
+                });
+            }
+            position
+                .play(&mv)
+                .with_context(|| format!("illegal move `{}` in '{}'", san, position.fen()))
+                .with_context(context)?;
+        }
+        Ok(Some(samples))
+    }
+}
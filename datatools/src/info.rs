@@ -0,0 +1,174 @@
+use anyhow::Context;
+use dataformat::{PackedSample, manifest::Manifest};
+use std::{fs::File, io, mem, path::Path};
+
+use crate::{compression, io::DatasetSource, logging, manifest, units::ByteSize};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Dataset to describe, a file, a directory of shards or `-` for stdin."))]
+    dataset: DatasetSource,
+    #[clap(
+        long("shards"),
+        help("Also describes every shard of a sharded dataset.")
+    )]
+    shards: bool,
+    #[clap(
+        long("verify"),
+        help("Checks the dataset's files against the hashes recorded in its manifest.")
+    )]
+    verify: bool,
+}
+
+/// What is known about a single file of a dataset.
+struct FileInfo {
+    size: u64,
+    compressed: bool,
+    samples: u64,
+    /// Bytes after the last whole sample, which only a truncated file has.
+    trailing_bytes: u64,
+}
+
+impl FileInfo {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::open(path)
+            .with_context(|| format!("failed to open file `{}`", path.display()))?;
+        let size = file.metadata()?.len();
+        let compressed = compression::is_compressed(&mut file)?;
+        let data_size = if compressed {
+            io::copy(&mut compression::reader(file, true)?, &mut io::sink())
+                .with_context(|| format!("failed to decompress `{}`", path.display()))?
+        } else {
+            size
+        };
+        let sample_size = mem::size_of::<PackedSample>() as u64;
+        Ok(FileInfo {
+            size,
+            compressed,
+            samples: data_size / sample_size,
+            trailing_bytes: data_size % sample_size,
+        })
+    }
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    if args.dataset == DatasetSource::Stdin {
+        let samples = args.dataset.count()?;
+        logging::summary(
+            &format!("<stdin>: {} samples", samples),
+            &[("samples", samples)],
+        );
+        return Ok(());
+    }
+
+    let manifest = manifest::check(&args.dataset)?;
+    let files = args.dataset.files()?;
+    if args.verify {
+        let manifest = manifest
+            .as_ref()
+            .with_context(|| format!("`{}` has no manifest to verify against", args.dataset))?;
+        manifest
+            .verify_files(&files)
+            .with_context(|| format!("`{}` doesn't match its manifest", args.dataset))?;
+    }
+    let mut infos = Vec::with_capacity(files.len());
+    for path in &files {
+        infos.push(FileInfo::read(path)?);
+    }
+
+    let size: u64 = infos.iter().map(|info| info.size).sum();
+    let samples: u64 = infos.iter().map(|info| info.samples).sum();
+    let compressed = infos.iter().filter(|info| info.compressed).count();
+    let compression = match compressed {
+        0 => "none".to_string(),
+        n if n == infos.len() => "zstd".to_string(),
+        n => format!("zstd for {} of {} files", n, infos.len()),
+    };
+
+    logging::report!("Dataset: {}", args.dataset);
+    match &args.dataset {
+        DatasetSource::Shards(_) => logging::report!("Layout: directory of {} shards", files.len()),
+        _ => logging::report!("Layout: single file"),
+    }
+    logging::report!(
+        "Format: packed samples, {} bytes each, no header",
+        mem::size_of::<PackedSample>()
+    );
+    logging::report!("Compression: {}", compression);
+    logging::report!("Size on disk: {}", ByteSize(size));
+    logging::report!("Samples: {}", samples);
+    if compressed > 0 && size > 0 {
+        let raw = samples * mem::size_of::<PackedSample>() as u64;
+        logging::report!("Compression ratio: {:.2}", raw as f64 / size as f64);
+    }
+
+    if let Some(manifest) = &manifest {
+        print_manifest(manifest, args.verify);
+    }
+
+    if args.shards && matches!(args.dataset, DatasetSource::Shards(_)) {
+        logging::report!();
+        for (path, info) in files.iter().zip(&infos) {
+            logging::report!(
+                "{}: {} samples, {}{}",
+                path.file_name().unwrap_or_default().to_string_lossy(),
+                info.samples,
+                ByteSize(info.size),
+                if info.compressed { ", zstd" } else { "" },
+            );
+        }
+    }
+
+    for (path, info) in files.iter().zip(&infos) {
+        if info.trailing_bytes != 0 {
+            eprintln!(
+                "warning: `{}` ends with {} bytes of a partial sample",
+                path.display(),
+                info.trailing_bytes
+            );
+        }
+    }
+
+    logging::summary(
+        &format!("{} samples in {} files", samples, files.len()),
+        &[
+            ("samples", samples),
+            ("files", files.len() as u64),
+            ("compressed_files", compressed as u64),
+            ("size_bytes", size),
+        ],
+    );
+
+    Ok(())
+}
+
+fn print_manifest(manifest: &Manifest, verified: bool) {
+    logging::report!();
+    logging::report!(
+        "Manifest: {} ({:016x})",
+        if verified { "verified" } else { "sizes match" },
+        manifest.dataset_hash()
+    );
+    for (key, value) in &manifest.generation {
+        logging::report!("  {}: {}", key, value);
+    }
+    for source in &manifest.sources {
+        match source.hash {
+            Some(hash) => logging::report!("  source: {} ({:016x})", source.path, hash),
+            None => logging::report!("  source: {}", source.path),
+        }
+    }
+    for step in &manifest.history {
+        let sources: Vec<_> = step
+            .sources
+            .iter()
+            .map(|hash| format!("{:016x}", hash))
+            .collect();
+        logging::report!(
+            "  history: {:016x} <- [{}] by `{}`",
+            step.hash,
+            sources.join(", "),
+            step.command_line
+        );
+    }
+}
@@ -0,0 +1,647 @@
+use anyhow::Context;
+use dataformat::{PackedSample, shard};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    compression, logging,
+    throttle::{Throttle, Throttled},
+    units::{ByteRate, ByteSize},
+};
+
+/// A dataset samples are read from.
+///
+/// Every kind of source may be zstd-compressed, which is detected from its contents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DatasetSource {
+    /// A single file of packed samples.
+    File(PathBuf),
+    /// A directory of dataset files, read one after the other in name order.
+    Shards(PathBuf),
+    /// Standard input, given as `-`.
+    Stdin,
+}
+
+impl DatasetSource {
+    pub fn from_path(path: &Path) -> Self {
+        if path.as_os_str() == "-" {
+            DatasetSource::Stdin
+        } else if path.is_dir() {
+            DatasetSource::Shards(path.to_path_buf())
+        } else {
+            DatasetSource::File(path.to_path_buf())
+        }
+    }
+
+    /// Opens a reader over every sample in the dataset.
+    pub fn open(&self) -> anyhow::Result<SampleReader> {
+        Ok(match self {
+            DatasetSource::Stdin => SampleReader {
+                pending: VecDeque::new(),
+                current: Some(compression::auto_reader(io::stdin())?),
+            },
+            _ => SampleReader {
+                pending: self.files()?.into(),
+                current: None,
+            },
+        })
+    }
+
+    /// The number of samples in the dataset, if it can be known without reading it,
+    /// which is the case for uncompressed files and shards.
+    pub fn len(&self) -> anyhow::Result<Option<u64>> {
+        if *self == DatasetSource::Stdin {
+            return Ok(None);
+        }
+        let mut positions = 0;
+        for path in self.files()? {
+            let mut file = open_file(&path)?;
+            if compression::is_compressed(&mut file)? {
+                return Ok(None);
+            }
+            positions += file.metadata()?.len() / mem::size_of::<PackedSample>() as u64;
+        }
+        Ok(Some(positions))
+    }
+
+    /// The number of samples in the dataset, decoding it if that's the only way to know.
+    pub fn count(&self) -> anyhow::Result<u64> {
+        if let Some(positions) = self.len()? {
+            return Ok(positions);
+        }
+        let bytes = io::copy(&mut self.open()?, &mut io::sink())
+            .with_context(|| format!("failed to read `{}`", self))?;
+        Ok(bytes / mem::size_of::<PackedSample>() as u64)
+    }
+
+    /// The path of the dataset if it is a single uncompressed file, which allows random
+    /// access to samples and modifying them in place.
+    pub fn plain_file(&self) -> anyhow::Result<Option<&Path>> {
+        let DatasetSource::File(path) = self else {
+            return Ok(None);
+        };
+        let compressed = compression::is_compressed(&mut open_file(path)?)?;
+        Ok((!compressed).then_some(path.as_path()))
+    }
+
+    /// Fails on files holding training data in another format than packed samples, which
+    /// would otherwise be read as garbage samples.
+    pub fn check_format(&self) -> anyhow::Result<()> {
+        if *self == DatasetSource::Stdin {
+            return Ok(());
+        }
+        for path in self.files()? {
+            if let Some(format) = compression::foreign_format(&mut open_file(&path)?)? {
+                anyhow::bail!(
+                    "`{}` is a {} file, only datasets of packed samples are supported",
+                    path.display(),
+                    format
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The files making up the dataset, in the order their samples are read.
+    pub fn files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        match self {
+            DatasetSource::File(path) => Ok(vec![path.clone()]),
+            DatasetSource::Shards(dir) => shard::shard_files(dir)
+                .with_context(|| format!("failed to read directory `{}`", dir.display())),
+            DatasetSource::Stdin => anyhow::bail!("standard input is not a file"),
+        }
+    }
+}
+
+fn open_file(path: &Path) -> anyhow::Result<File> {
+    File::open(path).with_context(|| format!("failed to open file `{}`", path.display()))
+}
+
+impl FromStr for DatasetSource {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(DatasetSource::from_path(Path::new(s)))
+    }
+}
+
+impl fmt::Display for DatasetSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatasetSource::File(path) | DatasetSource::Shards(path) => {
+                write!(f, "{}", path.display())
+            }
+            DatasetSource::Stdin => write!(f, "<stdin>"),
+        }
+    }
+}
+
+/// Reads the samples of a [`DatasetSource`] in order, opening shards as it goes.
+pub struct SampleReader {
+    pending: VecDeque<PathBuf>,
+    current: Option<Box<dyn Read + Send>>,
+}
+
+impl SampleReader {
+    /// Reads up to `samples.len()` samples, returning how many were read.
+    /// Fewer samples are only returned once the dataset is exhausted.
+    pub fn read_samples(&mut self, samples: &mut [PackedSample]) -> io::Result<usize> {
+        compression::read_samples(self, samples)
+    }
+
+    /// Reads the next sample, or `None` at the end of the dataset.
+    pub fn read_sample(&mut self) -> io::Result<Option<PackedSample>> {
+        let mut sample = [PackedSample::default()];
+        Ok((self.read_samples(&mut sample)? == 1).then_some(sample[0]))
+    }
+}
+
+impl Read for SampleReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(reader) = &mut self.current {
+                match reader.read(buf)? {
+                    0 => self.current = None,
+                    n => return Ok(n),
+                }
+            }
+            let Some(path) = self.pending.pop_front() else {
+                return Ok(0);
+            };
+            let file = File::open(&path).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to open file `{}`: {}", path.display(), err),
+                )
+            })?;
+            self.current = Some(compression::auto_reader(file)?);
+        }
+    }
+}
+
+/// The uncompressed files of a dataset, a single file or shards, opened for random
+/// access to its samples by index, for reading them out of order or modifying them in
+/// place.
+pub struct SampleFiles {
+    files: Vec<File>,
+    /// The index of the first sample of every file, followed by the number of samples.
+    starts: Vec<u64>,
+}
+
+impl SampleFiles {
+    pub fn open(source: &DatasetSource, write: bool) -> anyhow::Result<Self> {
+        if *source == DatasetSource::Stdin {
+            anyhow::bail!("samples of standard input can't be accessed out of order");
+        }
+        let mut files = Vec::new();
+        let mut starts = vec![0];
+        for path in source.files()? {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(write)
+                .open(&path)
+                .with_context(|| format!("failed to open file `{}`", path.display()))?;
+            if compression::is_compressed(&mut file)? {
+                anyhow::bail!("`{}` must be uncompressed", path.display());
+            }
+            let bytes = file.metadata()?.len();
+            let step = mem::size_of::<PackedSample>() as u64;
+            if bytes % step != 0 {
+                anyhow::bail!(
+                    "size of `{}` is not a multiple of the sample size",
+                    path.display()
+                );
+            }
+            starts.push(starts.last().unwrap() + bytes / step);
+            files.push(file);
+        }
+        Ok(SampleFiles { files, starts })
+    }
+
+    /// The number of samples in the dataset.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        *self.starts.last().unwrap()
+    }
+
+    /// Reads `samples.len()` consecutive samples starting at `index`.
+    pub fn read(&mut self, index: u64, samples: &mut [PackedSample]) -> io::Result<()> {
+        self.for_each_file(index, samples.len(), |file, range| {
+            file.read_exact(bytemuck::cast_slice_mut(&mut samples[range]))
+        })
+    }
+
+    /// Overwrites `samples.len()` consecutive samples starting at `index`.
+    pub fn write(&mut self, index: u64, samples: &[PackedSample]) -> io::Result<()> {
+        self.for_each_file(index, samples.len(), |file, range| {
+            file.write_all(bytemuck::cast_slice(&samples[range]))
+        })
+    }
+
+    /// Calls `f` on every file holding some of the `len` samples starting at `index`,
+    /// positioned at the first of them, with the range of the samples it holds.
+    fn for_each_file(
+        &mut self,
+        index: u64,
+        len: usize,
+        mut f: impl FnMut(&mut File, Range<usize>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        if index + len as u64 > self.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "samples {}..{} are out of range of a dataset of {}",
+                    index,
+                    index + len as u64,
+                    self.len()
+                ),
+            ));
+        }
+        let step = mem::size_of::<PackedSample>() as u64;
+        let mut done = 0;
+        while done < len {
+            let position = index + done as u64;
+            let file = self.starts.partition_point(|&start| start <= position) - 1;
+            let count = ((self.starts[file + 1] - position) as usize).min(len - done);
+            let handle = &mut self.files[file];
+            handle.seek(SeekFrom::Start((position - self.starts[file]) * step))?;
+            f(handle, done..done + count)?;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+/// A destination for samples: a file, compressed if requested or if its name ends in
+/// `.zst`, a directory of fixed-size shards, or standard output, given as `-`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DatasetSink {
+    File {
+        path: PathBuf,
+        compress: bool,
+    },
+    Shards {
+        dir: PathBuf,
+        /// Uncompressed size of every shard but the last, in samples.
+        shard_samples: u64,
+        compress: bool,
+    },
+    Stdout,
+}
+
+impl DatasetSink {
+    pub fn from_path(path: &Path, compress: bool) -> Self {
+        if path.as_os_str() == "-" {
+            DatasetSink::Stdout
+        } else {
+            DatasetSink::File {
+                path: path.to_path_buf(),
+                compress: compress || compression::has_zstd_extension(path),
+            }
+        }
+    }
+
+    /// Turns a file sink into a directory of shards holding `shard_size` bytes of
+    /// uncompressed samples each, if a shard size is given.
+    pub fn sharded(self, shard_size: Option<ByteSize>) -> anyhow::Result<Self> {
+        let Some(shard_size) = shard_size else {
+            return Ok(self);
+        };
+        let shard_samples = shard_size.bytes() / mem::size_of::<PackedSample>() as u64;
+        if shard_samples == 0 {
+            anyhow::bail!("shards must be large enough to hold at least one sample");
+        }
+        match self {
+            DatasetSink::File { path, compress } => Ok(DatasetSink::Shards {
+                dir: path,
+                shard_samples,
+                compress,
+            }),
+            DatasetSink::Shards { .. } => Ok(self),
+            DatasetSink::Stdout => anyhow::bail!("standard output cannot be sharded"),
+        }
+    }
+
+    /// The dataset written to the sink, to read it back. Nothing can be read back from
+    /// standard output.
+    pub fn source(&self) -> Option<DatasetSource> {
+        match self {
+            DatasetSink::File { path, .. } => Some(DatasetSource::File(path.clone())),
+            DatasetSink::Shards { dir, .. } => Some(DatasetSource::Shards(dir.clone())),
+            DatasetSink::Stdout => None,
+        }
+    }
+
+    /// Every file holding samples written to the sink, in order.
+    pub fn files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        match self {
+            DatasetSink::File { path, .. } => Ok(vec![path.clone()]),
+            DatasetSink::Shards { dir, .. } => DatasetSource::Shards(dir.clone()).files(),
+            DatasetSink::Stdout => Ok(Vec::new()),
+        }
+    }
+
+    /// Opens the sink for writing, truncating it unless `append` is set. Appending to
+    /// a compressed file adds a new zstd frame, which decoders read as a continuation,
+    /// and appending to shards starts a new shard after the existing ones.
+    pub fn create(&self, append: bool) -> anyhow::Result<SampleWriter> {
+        self.create_with_limit(append, None)
+    }
+
+    /// Like [`DatasetSink::create`], writing to disk no faster than `io_limit` if set.
+    pub fn create_with_limit(
+        &self,
+        append: bool,
+        io_limit: Option<ByteRate>,
+    ) -> anyhow::Result<SampleWriter> {
+        let mut writer = SampleWriter {
+            writer: None,
+            shards: None,
+            bytes_written: 0,
+            throttle: io_limit.map(Throttle::new),
+        };
+        match self {
+            DatasetSink::File { path, compress } => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(!append)
+                    .append(append)
+                    .open(path)
+                    .with_context(|| format!("failed to open output path `{}`", path.display()))?;
+                let file = Throttled::new(file, writer.throttle.clone());
+                writer.writer = Some(compressed_writer(Box::new(file), *compress)?);
+            }
+            DatasetSink::Shards {
+                dir,
+                shard_samples,
+                compress,
+            } => {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("failed to create directory `{}`", dir.display()))?;
+                let existing = self.files()?;
+                if !append {
+                    for path in &existing {
+                        fs::remove_file(path).with_context(|| {
+                            format!("failed to remove old shard `{}`", path.display())
+                        })?;
+                    }
+                }
+                writer.shards = Some(ShardRotation {
+                    dir: dir.clone(),
+                    shard_bytes: shard_samples * mem::size_of::<PackedSample>() as u64,
+                    compress: *compress,
+                    next_index: if append {
+                        shard::next_shard_index(&existing)
+                    } else {
+                        0
+                    },
+                    current_bytes: 0,
+                });
+            }
+            DatasetSink::Stdout => {
+                logging::reserve_stdout();
+                writer.writer = Some(compressed_writer(Box::new(io::stdout()), false)?);
+            }
+        }
+        Ok(writer)
+    }
+}
+
+impl fmt::Display for DatasetSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatasetSink::File { path, .. } | DatasetSink::Shards { dir: path, .. } => {
+                write!(f, "{}", path.display())
+            }
+            DatasetSink::Stdout => write!(f, "<stdout>"),
+        }
+    }
+}
+
+fn compressed_writer(
+    output: Box<dyn Write + Send>,
+    compress: bool,
+) -> io::Result<compression::Writer<Box<dyn Write + Send>>> {
+    compression::Writer::new(output, compress.then_some(compression::DEFAULT_LEVEL))
+}
+
+/// Writes samples to a [`DatasetSink`]. [`SampleWriter::finish`] must be called once
+/// every sample has been written.
+pub struct SampleWriter {
+    writer: Option<compression::Writer<Box<dyn Write + Send>>>,
+    shards: Option<ShardRotation>,
+    bytes_written: u64,
+    throttle: Option<Throttle>,
+}
+
+/// Where the next shard goes and how much room is left in the current one.
+struct ShardRotation {
+    dir: PathBuf,
+    shard_bytes: u64,
+    compress: bool,
+    next_index: usize,
+    current_bytes: u64,
+}
+
+impl SampleWriter {
+    #[inline]
+    pub fn write_sample(&mut self, sample: &PackedSample) -> io::Result<()> {
+        self.write_all(bytemuck::bytes_of(sample))
+    }
+
+    #[inline]
+    pub fn write_samples(&mut self, samples: &[PackedSample]) -> io::Result<()> {
+        self.write_all(bytemuck::cast_slice(samples))
+    }
+
+    /// Flushes everything written so far, returning the number of samples written.
+    pub fn finish(self) -> io::Result<u64> {
+        if let Some(writer) = self.writer {
+            writer.finish()?.flush()?;
+        }
+        Ok(self.bytes_written / mem::size_of::<PackedSample>() as u64)
+    }
+
+    /// Finishes the current shard once it is full and opens the next one, returning how
+    /// many bytes may still be written to the current output.
+    fn rotate(&mut self) -> io::Result<u64> {
+        let Some(shards) = &mut self.shards else {
+            return Ok(u64::MAX);
+        };
+        if self.writer.is_some() && shards.current_bytes < shards.shard_bytes {
+            return Ok(shards.shard_bytes - shards.current_bytes);
+        }
+        if let Some(writer) = self.writer.take() {
+            writer.finish()?.flush()?;
+        }
+        let path = shards
+            .dir
+            .join(shard::shard_file_name(shards.next_index, shards.compress));
+        let file = File::create(&path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to create shard `{}`: {}", path.display(), err),
+            )
+        })?;
+        let file = Throttled::new(file, self.throttle.clone());
+        self.writer = Some(compressed_writer(Box::new(file), shards.compress)?);
+        shards.next_index += 1;
+        shards.current_bytes = 0;
+        Ok(shards.shard_bytes)
+    }
+}
+
+impl Write for SampleWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let room = self.rotate()?;
+        let len = buf.len().min(room.try_into().unwrap_or(usize::MAX));
+        let written = self
+            .writer
+            .as_mut()
+            .expect("sample writer has an output after rotating")
+            .write(&buf[..len])?;
+        if let Some(shards) = &mut self.shards {
+            shards.current_bytes += written as u64;
+        }
+        self.bytes_written += written as u64;
+        Ok(written)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(count: usize, seed: u8) -> Vec<PackedSample> {
+        (0..count)
+            .map(|n| {
+                let mut sample: PackedSample = bytemuck::Zeroable::zeroed();
+                bytemuck::bytes_of_mut(&mut sample)[0] = seed.wrapping_add(n as u8);
+                sample
+            })
+            .collect()
+    }
+
+    fn write(sink: &DatasetSink, append: bool, samples: &[PackedSample]) {
+        let mut writer = sink.create(append).unwrap();
+        writer.write_samples(samples).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn shards_keep_other_files_and_skip_used_indices() {
+        let dir = tempfile::tempdir().unwrap();
+        let shard_bytes = 4 * mem::size_of::<PackedSample>() as u64;
+        let sink = DatasetSink::File {
+            path: dir.path().join("out"),
+            compress: false,
+        }
+        .sharded(Some(ByteSize(shard_bytes)))
+        .unwrap();
+        let out = dir.path().join("out");
+        fs::create_dir(&out).unwrap();
+        fs::write(out.join("notes.bin"), "not samples").unwrap();
+        fs::write(
+            out.join(shard::shard_file_name(2, false)),
+            bytemuck::cast_slice::<_, u8>(&samples(4, 0)),
+        )
+        .unwrap();
+
+        write(&sink, true, &samples(6, 100));
+        let names: Vec<_> = sink
+            .files()
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            ["shard-0002.bin", "shard-0003.bin", "shard-0004.bin"]
+        );
+        let read = |path: &str| fs::read(out.join(path)).unwrap();
+        assert_eq!(
+            read("shard-0002.bin"),
+            bytemuck::cast_slice::<_, u8>(&samples(4, 0))
+        );
+        assert_eq!(
+            read("shard-0004.bin").len(),
+            2 * mem::size_of::<PackedSample>()
+        );
+
+        write(&sink, false, &samples(1, 0));
+        assert_eq!(sink.files().unwrap(), [out.join("shard-0000.bin")]);
+        assert!(out.join("notes.bin").is_file());
+    }
+
+    fn bytes(samples: &[PackedSample]) -> &[u8] {
+        bytemuck::cast_slice(samples)
+    }
+
+    #[test]
+    fn sample_files_span_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let shard_bytes = 4 * mem::size_of::<PackedSample>() as u64;
+        let sink = DatasetSink::from_path(&dir.path().join("out"), false)
+            .sharded(Some(ByteSize(shard_bytes)))
+            .unwrap();
+        let mut expected = samples(10, 0);
+        write(&sink, false, &expected);
+
+        let source = sink.source().unwrap();
+        let mut files = SampleFiles::open(&source, true).unwrap();
+        assert_eq!(files.len(), 10);
+        let mut read = vec![PackedSample::default(); 5];
+        files.read(2, &mut read).unwrap();
+        assert_eq!(bytes(&read), bytes(&expected[2..7]));
+
+        let replaced = samples(3, 200);
+        files.write(3, &replaced).unwrap();
+        expected[3..6].copy_from_slice(&replaced);
+        let mut all = vec![PackedSample::default(); 10];
+        files.read(0, &mut all).unwrap();
+        assert_eq!(bytes(&all), bytes(&expected));
+        assert!(files.read(8, &mut read).is_err());
+    }
+
+    #[test]
+    fn shards_read_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let shard_bytes = 4 * mem::size_of::<PackedSample>() as u64;
+        for compress in [false, true] {
+            let sink = DatasetSink::from_path(&dir.path().join("out"), compress)
+                .sharded(Some(ByteSize(shard_bytes)))
+                .unwrap();
+            let expected = samples(10, 0);
+            write(&sink, false, &expected[..6]);
+            write(&sink, true, &expected[6..]);
+            assert!(sink.files().unwrap().len() > 1);
+
+            let mut reader = sink.source().unwrap().open().unwrap();
+            let mut read = vec![PackedSample::default(); 11];
+            assert_eq!(reader.read_samples(&mut read).unwrap(), 10);
+            assert_eq!(bytes(&read[..10]), bytes(&expected));
+        }
+    }
+}
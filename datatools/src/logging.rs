@@ -0,0 +1,210 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+use std::{
+    fmt::Write as _,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Progress bars on stderr and human readable summaries.
+    #[default]
+    Plain,
+    /// One JSON object per line on stdout, for progress updates and summaries.
+    Json,
+}
+
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct LogOptions {
+    #[clap(
+        long("log-format"),
+        global(true),
+        value_enum,
+        default_value_t,
+        help("Format of progress updates and summaries.")
+    )]
+    format: LogFormat,
+    #[clap(
+        long("quiet"),
+        global(true),
+        help("Hides progress updates, only printing final summaries.")
+    )]
+    quiet: bool,
+}
+
+struct Logger {
+    options: LogOptions,
+    tracked: Mutex<Vec<Tracked>>,
+}
+
+struct Tracked {
+    // Kept alive until its final state has been reported, however fast it finishes.
+    progress: ProgressBar,
+    last_position: Option<u64>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+const JSON_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sets up logging for the whole process, must be called before any progress bar is created.
+pub fn init(options: LogOptions) {
+    let logger = Logger {
+        options,
+        tracked: Mutex::new(Vec::new()),
+    };
+    if LOGGER.set(logger).is_err() {
+        panic!("logging was initialized twice");
+    }
+    if options.format == LogFormat::Json && !options.quiet {
+        thread::spawn(|| {
+            loop {
+                thread::sleep(JSON_PROGRESS_INTERVAL);
+                report_progress();
+            }
+        });
+    }
+}
+
+/// Reports the final state of every progress bar still being tracked.
+pub fn finish() {
+    report_progress();
+}
+
+fn options() -> LogOptions {
+    LOGGER
+        .get()
+        .map(|logger| logger.options)
+        .unwrap_or_default()
+}
+
+/// Moves everything logged to stdout over to stderr, for commands that write their
+/// output to stdout.
+pub fn reserve_stdout() {
+    STDOUT_RESERVED.store(true, Ordering::Relaxed);
+}
+
+fn emit(line: &str) {
+    if STDOUT_RESERVED.load(Ordering::Relaxed) {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Prints a line of a command's report for people to read, to stdout in plain mode and
+/// to stderr in JSON mode, where stdout only carries JSON objects.
+pub fn report_line(line: &str) {
+    match options().format {
+        LogFormat::Plain => emit(line),
+        LogFormat::Json => eprintln!("{}", line),
+    }
+}
+
+/// Formats and prints a line of a command's report like [`println!`], through
+/// [`report_line`].
+macro_rules! report {
+    () => {
+        $crate::logging::report_line("")
+    };
+    ($($arg:tt)*) => {
+        $crate::logging::report_line(&format!($($arg)*))
+    };
+}
+pub(crate) use report;
+
+/// Registers a progress bar, hiding it unless progress is logged as plain text.
+/// In JSON mode its state is then periodically printed to stdout instead.
+pub fn track(progress: ProgressBar) -> ProgressBar {
+    let options = options();
+    if options.format == LogFormat::Plain && !options.quiet {
+        return progress;
+    }
+
+    progress.set_draw_target(ProgressDrawTarget::hidden());
+    if let Some(logger) = LOGGER.get()
+        && !options.quiet
+    {
+        logger.tracked.lock().unwrap().push(Tracked {
+            progress: progress.clone(),
+            last_position: None,
+        });
+    }
+    progress
+}
+
+/// Hides a group of progress bars unless progress is logged as plain text.
+/// Bars added to it must still be registered with [`track`] to be reported.
+pub fn track_multi(progress: MultiProgress) -> MultiProgress {
+    let options = options();
+    if options.format != LogFormat::Plain || options.quiet {
+        progress.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    progress
+}
+
+/// Prints the final summary of a command, as `message` in plain mode
+/// or as a JSON object holding `fields` in JSON mode.
+pub fn summary(message: &str, fields: &[(&str, u64)]) {
+    match options().format {
+        LogFormat::Plain => emit(message),
+        LogFormat::Json => {
+            let mut line = format!(r#"{{"type":"summary","message":{}"#, json_string(message));
+            for (name, value) in fields {
+                write!(line, r#",{}:{}"#, json_string(name), value).unwrap();
+            }
+            line.push('}');
+            emit(&line);
+        }
+    }
+}
+
+fn report_progress() {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let mut tracked = logger.tracked.lock().unwrap();
+    tracked.retain_mut(|tracked| {
+        let progress = &tracked.progress;
+        let position = progress.position();
+        let finished = progress.is_finished();
+        if tracked.last_position != Some(position) || finished {
+            tracked.last_position = Some(position);
+            let length = progress
+                .length()
+                .map_or_else(|| "null".to_string(), |len| len.to_string());
+            emit(&format!(
+                r#"{{"type":"progress","message":{},"position":{},"length":{},"elapsed_secs":{:.1},"finished":{}}}"#,
+                json_string(&progress.message()),
+                position,
+                length,
+                progress.elapsed().as_secs_f64(),
+                finished,
+            ));
+        }
+        !finished
+    });
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            ch if ch.is_control() => write!(escaped, "\\u{:04x}", ch as u32).unwrap(),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
@@ -0,0 +1,170 @@
+use anyhow::Context;
+use core::str;
+use dama::{Color, Outcome, Position, SanMove, pgn};
+use pgnextract::{CutechessEval, EvalParser, LenientReader};
+use std::{collections::HashSet, fs::File, io::BufReader, path::Path};
+
+/// How opening positions are picked from lost games.
+pub(crate) struct LossBookOptions {
+    /// Only games lost by the player of this name, as given by the `White` and `Black`
+    /// tags, or the loser of every decisive game.
+    pub player: Option<String>,
+    /// Plies before the losing mistake the opening is taken at.
+    pub plies: u32,
+    /// Eval in centipawns, from the loser's point of view, at or below which a game
+    /// counts as lost.
+    pub lost_eval: i32,
+}
+
+#[derive(Default)]
+pub(crate) struct LossBook {
+    pub positions: Vec<Position>,
+    pub games: u64,
+    /// Lost games without a collapse of the eval to find the mistake by.
+    pub skipped: u64,
+}
+
+/// Reads the games of a PGN lost by a player and takes an opening position some plies
+/// before each loser's mistake, the last of its moves before the evals of the comments
+/// fall to `lost_eval` and stay there until the end of the game.
+pub(crate) fn load(path: &Path, options: &LossBookOptions) -> anyhow::Result<LossBook> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open loss book `{}`", path.display()))?;
+    let mut reader = LenientReader::new(BufReader::new(file));
+    let mut visitor = LossVisitor::default();
+    let mut book = LossBook::default();
+    let mut seen = HashSet::new();
+    loop {
+        match reader.visit_game(&mut visitor) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) if !err.is_recoverable() => {
+                return Err(anyhow::anyhow!("{}", err))
+                    .with_context(|| format!("failed to read loss book `{}`", path.display()));
+            }
+            Err(err) => {
+                eprintln!("warning: skipped game of `{}`: {}", path.display(), err);
+                continue;
+            }
+        }
+        let Some(loser) = visitor.loser(options.player.as_deref()) else {
+            continue;
+        };
+        book.games += 1;
+        let Some(mistake) = visitor.mistake(loser, options.lost_eval) else {
+            book.skipped += 1;
+            continue;
+        };
+        let position = &visitor.positions[mistake.saturating_sub(options.plies as usize)];
+        if seen.insert(epd_key(position)) {
+            book.positions.push(position.clone());
+        }
+    }
+    Ok(book)
+}
+
+/// The position without its move counters, which book positions are started from anew.
+fn epd_key(position: &Position) -> String {
+    let fen = position.fen().to_string();
+    fen.split_whitespace().take(4).collect::<Vec<_>>().join(" ")
+}
+
+#[derive(Default)]
+struct LossVisitor {
+    white: String,
+    black: String,
+    outcome: Option<Outcome>,
+    position: Position,
+    /// The position before each ply.
+    positions: Vec<Position>,
+    /// The eval of the comment after each ply, from white's point of view.
+    evals: Vec<Option<i32>>,
+}
+
+impl LossVisitor {
+    /// The side that lost the game, if it was played by `player`.
+    fn loser(&self, player: Option<&str>) -> Option<Color> {
+        let Some(Outcome::Winner(winner)) = self.outcome else {
+            return None;
+        };
+        let loser = !winner;
+        let name = match loser {
+            Color::White => &self.white,
+            Color::Black => &self.black,
+        };
+        player.is_none_or(|player| player == name).then_some(loser)
+    }
+
+    /// The ply of the loser's last move before its evals collapse for good.
+    fn mistake(&self, loser: Color, lost_eval: i32) -> Option<usize> {
+        let mut collapse = None;
+        for (ply, eval) in self.evals.iter().enumerate() {
+            let Some(eval) = eval else {
+                continue;
+            };
+            let eval = if loser == Color::White { *eval } else { -eval };
+            if eval > lost_eval {
+                collapse = None;
+            } else if collapse.is_none() {
+                collapse = Some(ply);
+            }
+        }
+        // The collapse either follows the loser's own move or the winner's reply to it.
+        let ply = collapse?;
+        if self.positions[ply].side_to_move() == loser {
+            Some(ply)
+        } else {
+            ply.checked_sub(1)
+        }
+    }
+}
+
+impl pgn::Visitor for LossVisitor {
+    type Error = anyhow::Error;
+
+    fn prepare(&mut self) {
+        self.white.clear();
+        self.black.clear();
+        self.outcome = None;
+        self.position = Position::new_initial();
+        self.positions.clear();
+        self.evals.clear();
+    }
+
+    fn visit_tag_pair(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        match name {
+            "White" => self.white = value.to_string(),
+            "Black" => self.black = value.to_string(),
+            "Result" => self.outcome = value.parse().ok(),
+            "FEN" => self.position = Position::from_fen(value)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn enter_variation(&mut self) -> pgn::ControlFlow {
+        pgn::ControlFlow::Skip
+    }
+
+    fn visit_move(&mut self, _number: Option<u32>, mv: SanMove) -> anyhow::Result<()> {
+        self.positions.push(self.position.clone());
+        self.evals.push(None);
+        self.position.play(&mv)?;
+        Ok(())
+    }
+
+    fn visit_comment(&mut self, comment: &[u8]) -> anyhow::Result<()> {
+        let Some(last) = self.evals.last_mut() else {
+            return Ok(());
+        };
+        // Comment evaluations are given from the point of view of the side that just moved.
+        if let Some(eval) = CutechessEval.parse(str::from_utf8(comment)?.trim()) {
+            let eval = (eval * 100.0).round() as i32;
+            *last = Some(match self.position.side_to_move() {
+                Color::White => -eval,
+                Color::Black => eval,
+            });
+        }
+        Ok(())
+    }
+}
@@ -1,24 +1,99 @@
+mod analyze_labels;
+mod audit;
+mod bench_loader;
+mod book_build;
+mod check_overlap;
+mod collect;
+mod completions;
+mod compression;
+mod dedup;
+mod digest;
+mod export;
+mod export_epd;
+mod export_hashes;
 mod extract;
+mod find;
+mod fit_wdl;
+mod import_lichess;
+mod info;
+mod io;
+mod fix_outcomes;
+mod logging;
+mod loss_book;
+mod manifest;
 mod show;
 mod merge;
+mod npz;
+mod pipeline;
+mod predicate;
+mod protocol;
+mod push;
+mod quantize;
+mod rebalance;
 mod selfplay;
 mod shuffle;
-use clap::{Parser, Subcommand};
+mod status;
+mod tablebase;
+mod tb_relabel;
+mod threads;
+mod throttle;
+mod units;
+use clap::{CommandFactory, Parser, Subcommand};
 
 #[derive(Subcommand)]
 enum Command {
     #[clap(about("Extracts positions from labeled PGN files"))]
     Extract(extract::Args),
+    #[clap(about("Imports games exported by the Lichess API as NDJSON, with their server analysis as evals"))]
+    ImportLichess(import_lichess::Args),
     #[clap(about("Shuffles data files"))]
     Shuffle(shuffle::Args),
     #[clap(about(
         "Runs games with the specified UCI compliant engine, outputting the resulting data to a file"
     ))]
-    Selfplay(selfplay::Args),
+    Selfplay(Box<selfplay::Args>),
     #[clap(about("Merges two or more data files"))]
     Merge(merge::Args),
     #[clap(about("Shows some samples from a dataset, used for debugging"))]
     Show(show::Args),
+    #[clap(about("Searches a dataset for samples matching a position or material signature"))]
+    Find(find::Args),
+    #[clap(about("Validates outcome labels against terminal positions and tablebases, fixing them in place"))]
+    FixOutcomes(fix_outcomes::Args),
+    #[clap(about("Exports samples as EPD records with eval and outcome opcodes"))]
+    ExportEpd(export_epd::Args),
+    #[clap(about("Exports the eval, outcome, phase, piece count and side to move of every sample as numpy arrays"))]
+    Export(export::Args),
+    #[clap(about("Builds an EPD opening book from datasets or PGN files, usable with `selfplay --book`"))]
+    BookBuild(book_build::Args),
+    #[clap(about("Describes a dataset: its layout, compression, size and sample count"))]
+    Info(info::Args),
+    #[clap(about("Quantizes a trained float network into the engine's integer format"))]
+    Quantize(quantize::Args),
+    #[clap(about("Fits a model mapping evals and game phase to expected scores, used by the dataloader"))]
+    FitWdl(fit_wdl::Args),
+    #[clap(about("Reports how well evals agree with outcomes by ply and phase, and with a reference engine"))]
+    AnalyzeLabels(analyze_labels::Args),
+    #[clap(about("Collects samples streamed by remote workers with `push`, deduplicating and shuffling them"))]
+    Collect(collect::Args),
+    #[clap(about("Streams a dataset to a `collect` server"))]
+    Push(push::Args),
+    #[clap(about("Runs the steps of a pipeline file in order, resuming after the last completed step"))]
+    Run(pipeline::Args),
+    #[clap(about("Measures how fast the dataloader turns a dataset into batches, stage by stage"))]
+    BenchLoader(bench_loader::Args),
+    #[clap(about("Reports the distribution of endgame classes and material, resampling a dataset to cap classes"))]
+    Rebalance(rebalance::Args),
+    #[clap(about("Overwrites the eval and outcome of positions covered by Syzygy tablebases with exact values"))]
+    TbRelabel(tb_relabel::Args),
+    #[clap(about("Lists the Zobrist hashes of a dataset's positions, for the dataloader to exclude them"))]
+    ExportHashes(export_hashes::Args),
+    #[clap(about("Reports how many samples of a dataset hold positions of another, such as the validation set"))]
+    CheckOverlap(check_overlap::Args),
+    #[clap(about("Validates a dataset and reports its statistics and duplicates, scanning memory-mapped shards in parallel"))]
+    Audit(audit::Args),
+    #[clap(about("Writes a shell completion script for datatools, or the help of every command as Markdown"))]
+    Completions(completions::Args),
 }
 
 #[derive(Parser)]
@@ -26,17 +101,53 @@ enum Command {
 struct Options {
     #[command(subcommand)]
     command: Command,
+    #[clap(flatten)]
+    log: logging::LogOptions,
+    #[clap(flatten)]
+    threads: threads::ThreadOptions,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     let options = Options::parse();
-    match options.command {
+    logging::init(options.log);
+    // Threads blocking on I/O or waiting on each other aren't capped, since they barely
+    // use a core and capping them could leave commands waiting forever.
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads::init(options.threads))
+        .enable_all()
+        .build()?;
+    runtime.block_on(run_command(options.command))?;
+    logging::finish();
+    Ok(())
+}
+
+async fn run_command(command: Command) -> anyhow::Result<()> {
+    match command {
         Command::Extract(args) => extract::run(args).await?,
+        Command::ImportLichess(args) => import_lichess::run(args).await?,
         Command::Shuffle(args) => shuffle::run(args).await?,
-        Command::Selfplay(args) => selfplay::run(args).await?,
+        Command::Selfplay(args) => selfplay::run(*args).await?,
         Command::Merge(args) => merge::run(args).await?,
         Command::Show(args) => show::run(args).await?,
+        Command::Find(args) => find::run(args).await?,
+        Command::FixOutcomes(args) => fix_outcomes::run(args).await?,
+        Command::ExportEpd(args) => export_epd::run(args).await?,
+        Command::Export(args) => export::run(args).await?,
+        Command::BookBuild(args) => book_build::run(args).await?,
+        Command::Info(args) => info::run(args).await?,
+        Command::Quantize(args) => quantize::run(args).await?,
+        Command::FitWdl(args) => fit_wdl::run(args).await?,
+        Command::AnalyzeLabels(args) => analyze_labels::run(args).await?,
+        Command::Collect(args) => collect::run(args).await?,
+        Command::Push(args) => push::run(args).await?,
+        Command::Run(args) => pipeline::run(args).await?,
+        Command::BenchLoader(args) => bench_loader::run(args).await?,
+        Command::Rebalance(args) => rebalance::run(args).await?,
+        Command::TbRelabel(args) => tb_relabel::run(args).await?,
+        Command::ExportHashes(args) => export_hashes::run(args).await?,
+        Command::CheckOverlap(args) => check_overlap::run(args).await?,
+        Command::Audit(args) => audit::run(args).await?,
+        Command::Completions(args) => completions::run(args, Options::command())?,
     }
     Ok(())
 }
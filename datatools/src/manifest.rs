@@ -0,0 +1,256 @@
+use anyhow::Context;
+use dataformat::{
+    EvalPerspective, Sample,
+    manifest::{self, COMMAND_LINE_SETTING, EVAL_PERSPECTIVE_SETTING, FileEntry, Manifest, Source},
+};
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::io::{DatasetSink, DatasetSource};
+
+/// Describes the current contents of dataset files, hashing every one of them.
+pub fn describe_files(files: &[PathBuf]) -> anyhow::Result<Vec<FileEntry>> {
+    files
+        .iter()
+        .map(|path| {
+            let name = path
+                .file_name()
+                .with_context(|| format!("`{}` is not a file", path.display()))?
+                .to_string_lossy()
+                .into_owned();
+            let bytes = path
+                .metadata()
+                .with_context(|| format!("failed to read metadata of `{}`", path.display()))?
+                .len();
+            let hash = manifest::hash_file(path)
+                .with_context(|| format!("failed to hash `{}`", path.display()))?;
+            let samples = DatasetSource::File(path.clone()).count()?;
+            Ok(FileEntry {
+                name,
+                samples,
+                bytes,
+                hash,
+            })
+        })
+        .collect()
+}
+
+/// Writes the manifest of a freshly written sink, recording the command that wrote it
+/// with its settings and inputs, and how the datasets among the inputs were made in
+/// turn. Standard output gets no manifest.
+///
+/// Unless the settings give an eval perspective, the output keeps the one of the
+/// datasets it was made from.
+pub fn write_for_sink(
+    sink: &DatasetSink,
+    command: &str,
+    settings: &[(&str, String)],
+    sources: Vec<Source>,
+) -> anyhow::Result<()> {
+    let Some(path) = manifest_path(sink) else {
+        return Ok(());
+    };
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let mut generation = vec![
+        ("command".to_string(), command.to_string()),
+        (COMMAND_LINE_SETTING.to_string(), manifest::command_line()),
+        (
+            "datatools_version".to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        ),
+        ("created_unix".to_string(), created.to_string()),
+    ];
+    generation.extend(
+        settings
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone())),
+    );
+    if !settings
+        .iter()
+        .any(|(key, _)| *key == EVAL_PERSPECTIVE_SETTING)
+        && let Some(perspective) = eval_perspective(&sources)?
+        && perspective != EvalPerspective::SideToMove
+    {
+        generation.push((
+            EVAL_PERSPECTIVE_SETTING.to_string(),
+            perspective.to_string(),
+        ));
+    }
+    let history =
+        manifest::history_of(&sources).context("failed to read the history of the inputs")?;
+    let manifest = Manifest {
+        generation,
+        files: describe_files(&sink.files()?)?,
+        sources,
+        history,
+    };
+    manifest
+        .save(&path)
+        .with_context(|| format!("failed to write manifest `{}`", path.display()))
+}
+
+/// The eval perspective shared by the datasets among `sources`, or `None` if none of
+/// them is a dataset with a manifest. Datasets mixing perspectives are an error, their
+/// evals would silently contradict each other.
+pub fn eval_perspective(sources: &[Source]) -> anyhow::Result<Option<EvalPerspective>> {
+    let mut found: Option<(EvalPerspective, &str)> = None;
+    for source in sources {
+        let path = Path::new(&source.path);
+        let Some(manifest) = Manifest::find(path)
+            .with_context(|| format!("failed to read the manifest of `{}`", source.path))?
+        else {
+            continue;
+        };
+        let perspective = manifest
+            .eval_perspective()
+            .with_context(|| format!("invalid manifest for `{}`", source.path))?;
+        match found {
+            Some((first, first_path)) if first != perspective => anyhow::bail!(
+                "`{}` holds evals from the {} perspective but `{}` from the {} perspective",
+                first_path,
+                first,
+                source.path,
+                perspective
+            ),
+            Some(_) => {}
+            None => found = Some((perspective, &source.path)),
+        }
+    }
+    Ok(found.map(|(perspective, _)| perspective))
+}
+
+/// The perspective of a dataset's evals as given by its manifest, the side to move's
+/// for datasets without one.
+pub fn perspective_of(dataset: &DatasetSource) -> anyhow::Result<EvalPerspective> {
+    let Some(path) = dataset_path(dataset) else {
+        return Ok(EvalPerspective::SideToMove);
+    };
+    let Some(manifest) = Manifest::find(path)
+        .with_context(|| format!("failed to read the manifest of `{}`", dataset))?
+    else {
+        return Ok(EvalPerspective::SideToMove);
+    };
+    manifest
+        .eval_perspective()
+        .with_context(|| format!("invalid manifest for `{}`", dataset))
+}
+
+/// Turns the eval of a sample read from a dataset of the given perspective into the side
+/// to move's, which filters and printed samples use.
+pub fn to_side_to_move(sample: &mut Sample, perspective: EvalPerspective) {
+    let side_to_move = sample.position.side_to_move();
+    sample.eval = sample
+        .eval
+        .map(|eval| perspective.to_side_to_move(eval, side_to_move));
+}
+
+/// Inputs recorded in the manifest of an existing sink, to carry them over when appending.
+pub fn previous_sources(sink: &DatasetSink) -> anyhow::Result<Vec<Source>> {
+    Ok(previous(sink)?.map_or_else(Vec::new, |manifest| manifest.sources))
+}
+
+/// The manifest of an existing sink, if it has one.
+pub fn previous(sink: &DatasetSink) -> anyhow::Result<Option<Manifest>> {
+    let Some(path) = manifest_path(sink).filter(|path| path.is_file()) else {
+        return Ok(None);
+    };
+    Manifest::load(&path)
+        .map(Some)
+        .with_context(|| format!("failed to read manifest `{}`", path.display()))
+}
+
+fn manifest_path(sink: &DatasetSink) -> Option<PathBuf> {
+    match sink {
+        DatasetSink::File { path, .. } => Some(Manifest::path_for(path)),
+        DatasetSink::Shards { dir, .. } => Some(dir.join(manifest::MANIFEST_FILE_NAME)),
+        DatasetSink::Stdout => None,
+    }
+}
+
+/// Reads the manifest of a dataset, if it has one, and checks that the dataset's files
+/// match it.
+pub fn check(dataset: &DatasetSource) -> anyhow::Result<Option<Manifest>> {
+    let Some(path) = dataset_path(dataset) else {
+        return Ok(None);
+    };
+    let Some(manifest) = Manifest::find(path)
+        .with_context(|| format!("failed to read the manifest of `{}`", dataset))?
+    else {
+        return Ok(None);
+    };
+    manifest
+        .check_files(&dataset.files()?)
+        .with_context(|| format!("`{}` doesn't match its manifest", dataset))?;
+    Ok(Some(manifest))
+}
+
+/// The number of samples in a dataset if it can be known without decoding it: from the
+/// size of its files if they are uncompressed, or else from a manifest matching them.
+pub fn known_samples(dataset: &DatasetSource) -> anyhow::Result<Option<u64>> {
+    if let Some(samples) = dataset.len()? {
+        return Ok(Some(samples));
+    }
+    // A manifest that doesn't match the files says nothing about their samples.
+    Ok(check(dataset)
+        .ok()
+        .flatten()
+        .map(|manifest| manifest.samples()))
+}
+
+/// Identifies a dataset used as an input, by its manifest's hash if it has one.
+pub fn dataset_source(dataset: &DatasetSource) -> anyhow::Result<Source> {
+    let hash = match (dataset, check(dataset)?) {
+        (_, Some(manifest)) => Some(manifest.dataset_hash()),
+        (DatasetSource::Stdin, None) => None,
+        (_, None) => Some(
+            Manifest {
+                files: describe_files(&dataset.files()?)?,
+                ..Manifest::default()
+            }
+            .dataset_hash(),
+        ),
+    };
+    Ok(Source {
+        path: dataset.to_string(),
+        hash,
+    })
+}
+
+/// Identifies a plain input file, such as a PGN file, by the hash of its contents.
+pub fn file_source(path: &Path) -> anyhow::Result<Source> {
+    let hash = manifest::hash_file(path)
+        .with_context(|| format!("failed to hash `{}`", path.display()))?;
+    Ok(Source {
+        path: path.display().to_string(),
+        hash: Some(hash),
+    })
+}
+
+/// Updates the file descriptions in a dataset's manifest after it was modified in
+/// place, keeping how it was generated.
+pub fn refresh(dataset: &DatasetSource) -> anyhow::Result<()> {
+    let Some(path) = dataset_path(dataset) else {
+        return Ok(());
+    };
+    let manifest_path = Manifest::path_for(path);
+    if !manifest_path.is_file() {
+        return Ok(());
+    }
+    let mut manifest = Manifest::load(&manifest_path)
+        .with_context(|| format!("failed to read manifest `{}`", manifest_path.display()))?;
+    manifest.files = describe_files(&dataset.files()?)?;
+    manifest
+        .save(&manifest_path)
+        .with_context(|| format!("failed to write manifest `{}`", manifest_path.display()))
+}
+
+fn dataset_path(dataset: &DatasetSource) -> Option<&Path> {
+    match dataset {
+        DatasetSource::File(path) | DatasetSource::Shards(path) => Some(path),
+        DatasetSource::Stdin => None,
+    }
+}
@@ -1,49 +1,546 @@
-use anyhow::Context;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::{path::PathBuf, time::Duration};
-use tokio::{
-    fs::{File, OpenOptions},
-    io,
+use dataformat::PackedSample;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use rand::Rng;
+use std::{
+    io::{self, Read, Write},
+    mem,
+    path::PathBuf,
+    time::Duration,
 };
 
-use crate::shuffle::shuffle;
+use crate::{
+    compression,
+    dedup::{ConflictPolicy, DedupStats, Deduplicator, Repeats},
+    io::{DatasetSink, DatasetSource, SampleFiles, SampleReader},
+    logging, manifest,
+    shuffle::{ShuffleOptions, shuffle_sink},
+    throttle::Throttle,
+    units::{ByteRate, ByteSize},
+};
 
 #[derive(clap::Args)]
 pub struct Args {
-    #[clap(help("Input data files"))]
-    inputs: Vec<PathBuf>,
-    #[clap(short('o'))]
+    #[clap(help(
+        "Input datasets: files, plain or zstd-compressed, directories of shards or `-`."
+    ))]
+    inputs: Vec<DatasetSource>,
+    #[clap(
+        short('o'),
+        help("Output file, compressed if it ends in `.zst`, or `-` for stdout.")
+    )]
     output: PathBuf,
+    #[clap(
+        long("shard-size"),
+        help("Writes the output as a directory of shards of this size, e.g. `4GiB`.")
+    )]
+    shard_size: Option<ByteSize>,
+    #[clap(
+        long("no-shuffle"),
+        help("Interleaves already shuffled inputs in one pass instead of reshuffling the output.")
+    )]
+    no_shuffle: bool,
+    #[clap(
+        long("weights"),
+        value_delimiter(','),
+        help("Relative share of each input in the output (e.g. `3,1`), defaults to their sizes.")
+    )]
+    weights: Option<Vec<f64>>,
+    #[clap(
+        long("dedup"),
+        conflicts_with("no_shuffle"),
+        help("Drops duplicate positions across all inputs.")
+    )]
+    dedup: bool,
+    #[clap(
+        long("conflict-policy"),
+        value_enum,
+        default_value_t,
+        requires("dedup"),
+        help("How to resolve duplicates with different evaluations.")
+    )]
+    conflict_policy: ConflictPolicy,
+    #[clap(
+        long("max-frequency"),
+        conflicts_with_all(["no_shuffle", "dedup"]),
+        help("Randomly drops samples of positions occurring more than this many times across all inputs, flattening the skew toward common positions.")
+    )]
+    max_frequency: Option<u64>,
+    #[clap(
+        long("frequency-strength"),
+        default_value_t = 1.0,
+        requires("max_frequency"),
+        help(
+            "How strongly frequent positions are thinned out, from 0 for not at all to 1 for `--max-frequency` samples left of each on average."
+        )
+    )]
+    frequency_strength: f64,
+    #[clap(
+        short('a'),
+        long("append"),
+        conflicts_with_all(["no_shuffle", "dedup", "max_frequency"]),
+        help("Appends to an existing shuffled output, mixing new samples in without a reshuffle.")
+    )]
+    append: bool,
+    #[clap(
+        long("dry-run"),
+        help("Reads the inputs and reports what would be written, without touching the output.")
+    )]
+    dry_run: bool,
+    #[clap(
+        long("io-limit"),
+        help("Caps the rate at which the output is written and shuffled, e.g. `100M/s`.")
+    )]
+    io_limit: Option<ByteRate>,
+}
+
+impl Args {
+    /// What to do with repeated positions, if they're looked for at all.
+    fn repeats(&self) -> anyhow::Result<Option<Repeats>> {
+        if self.dedup {
+            return Ok(Some(Repeats::Dedup(self.conflict_policy)));
+        }
+        let Some(max_frequency) = self.max_frequency else {
+            return Ok(None);
+        };
+        if max_frequency == 0 {
+            anyhow::bail!("--max-frequency must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&self.frequency_strength) {
+            anyhow::bail!("--frequency-strength must be between 0 and 1");
+        }
+        Ok(Some(Repeats::Downweight {
+            max_frequency,
+            strength: self.frequency_strength,
+        }))
+    }
 }
 
 pub async fn run(args: Args) -> anyhow::Result<()> {
-    let mut output_file = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .truncate(true)
-        .open(&args.output)
-        .await
-        .with_context(|| format!("failed to open output path `{}`", args.output.display()))?;
-
-    let progress = ProgressBar::new(args.inputs.len() as u64)
-        .with_style(
-            ProgressStyle::with_template(
-                "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} files merged",
-            )
-            .unwrap()
-            .progress_chars("##-"),
+    let repeats = args.repeats()?;
+    let progress = logging::track_multi(MultiProgress::new());
+    let mut inputs = Vec::with_capacity(args.inputs.len());
+    let mut sources = Vec::with_capacity(args.inputs.len());
+    for source in &args.inputs {
+        inputs.push(Input::open(source)?);
+        sources.push(manifest::dataset_source(source)?);
+    }
+    // Checked before any work is done, the manifest is only written at the end.
+    manifest::eval_perspective(&sources)?;
+    let sink = DatasetSink::from_path(&args.output, false).sharded(args.shard_size)?;
+
+    // Inputs that are copied whole are counted as they're read, decoding compressed ones
+    // just to count them would read them twice.
+    let counts = match &args.weights {
+        Some(weights) => Some(weighted_counts(&count(&mut inputs)?, weights)?),
+        None if args.no_shuffle || repeats.is_some() || args.dry_run => Some(count(&mut inputs)?),
+        None => None,
+    };
+
+    let body = if args.append {
+        existing_positions(&sink)?
+    } else {
+        0
+    };
+    if args.dry_run
+        && let Some(counts) = &counts
+    {
+        return dry_run(&sink, inputs, counts.clone(), body, repeats, &progress);
+    }
+    if args.append {
+        sources.splice(0..0, manifest::previous_sources(&sink)?);
+    }
+
+    let readers = inputs
+        .into_iter()
+        .enumerate()
+        .map(|(index, input)| input.reader(&progress, counts.as_ref().map(|counts| counts[index])))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut writer = sink.create_with_limit(args.append, args.io_limit)?;
+    if args.no_shuffle
+        && let Some(counts) = &counts
+    {
+        interleave(readers, counts.clone(), &mut writer)?;
+    } else if let (Some(repeats), Some(counts)) = (repeats, counts) {
+        let stats = dedup(readers, counts, &mut writer, repeats)?;
+        if args.dedup {
+            logging::summary(
+                &format!(
+                    "{} positions read, {} duplicates removed ({} with conflicting evals), {} positions written",
+                    stats.positions_read,
+                    stats.duplicates,
+                    stats.conflicts,
+                    stats.positions_written
+                ),
+                &[
+                    ("positions_read", stats.positions_read),
+                    ("duplicates", stats.duplicates),
+                    ("conflicts", stats.conflicts),
+                    ("positions_written", stats.positions_written),
+                ],
+            );
+        } else {
+            logging::summary(
+                &format!(
+                    "{} positions read, {} samples of {} frequent positions dropped, {} positions written",
+                    stats.positions_read,
+                    stats.downweighted,
+                    stats.frequent_positions,
+                    stats.positions_written
+                ),
+                &[
+                    ("positions_read", stats.positions_read),
+                    ("downweighted", stats.downweighted),
+                    ("frequent_positions", stats.frequent_positions),
+                    ("positions_written", stats.positions_written),
+                ],
+            );
+        }
+    } else {
+        concatenate(readers, &mut writer)?;
+    }
+    let appended = writer.finish()?;
+
+    if !args.no_shuffle {
+        if let Some(source) = sink.source()
+            && body > 0
+        {
+            mix_appended(&source, body, appended, args.io_limit.map(Throttle::new))?;
+        } else {
+            shuffle_sink(&sink, &ShuffleOptions::with_io_limit(args.io_limit)).await?;
+        }
+    }
+
+    let mut settings = vec![
+        ("dedup", args.dedup.to_string()),
+        ("shuffled", (!args.no_shuffle).to_string()),
+    ];
+    if args.dedup {
+        settings.push(("conflict_policy", format!("{:?}", args.conflict_policy)));
+    }
+    if let Some(max_frequency) = args.max_frequency {
+        settings.push(("max_frequency", max_frequency.to_string()));
+        settings.push(("frequency_strength", args.frequency_strength.to_string()));
+    }
+    if let Some(weights) = &args.weights {
+        let weights: Vec<_> = weights.iter().map(f64::to_string).collect();
+        settings.push(("weights", weights.join(",")));
+    }
+    manifest::write_for_sink(&sink, "merge", &settings, sources)
+}
+
+/// Reads and, with `--dedup` or `--max-frequency`, thins out the inputs without writing
+/// anything, then reports how many positions the output would hold.
+fn dry_run(
+    sink: &DatasetSink,
+    inputs: Vec<Input>,
+    counts: Vec<u64>,
+    existing: u64,
+    repeats: Option<Repeats>,
+    progress: &MultiProgress,
+) -> anyhow::Result<()> {
+    let readers = inputs
+        .into_iter()
+        .zip(&counts)
+        .map(|(input, &count)| input.reader(progress, Some(count)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (positions, duplicates) = if let Some(repeats) = repeats {
+        let stats = dedup(readers, counts, &mut io::sink(), repeats)?;
+        (
+            stats.positions_written,
+            stats.duplicates + stats.downweighted,
         )
-        .with_message("merging files...");
+    } else {
+        (counts.iter().sum(), 0)
+    };
+
+    logging::summary(
+        &format!(
+            "{} positions would be written to `{}` ({} already there, {} repeated samples removed)",
+            positions, sink, existing, duplicates
+        ),
+        &[
+            ("positions", positions),
+            ("existing_positions", existing),
+            ("duplicates", duplicates),
+        ],
+    );
+    Ok(())
+}
+
+struct Input {
+    source: DatasetSource,
+    /// The number of samples, if known without decoding the input.
+    positions: Option<u64>,
+}
+
+impl Input {
+    fn open(source: &DatasetSource) -> anyhow::Result<Self> {
+        source.check_format()?;
+        Ok(Input {
+            source: source.clone(),
+            positions: manifest::known_samples(source)?,
+        })
+    }
+
+    /// The number of samples in the input, decoding it if it's compressed and has no
+    /// manifest to tell.
+    fn count(&mut self) -> anyhow::Result<u64> {
+        if let Some(positions) = self.positions {
+            return Ok(positions);
+        }
+        if self.source == DatasetSource::Stdin {
+            anyhow::bail!(
+                "standard input can't be counted before it is read, which --weights, --no-shuffle, --dedup, --max-frequency and --dry-run need"
+            );
+        }
+        let positions = self.source.count()?;
+        self.positions = Some(positions);
+        Ok(positions)
+    }
+
+    /// Opens a reader over the first `positions` samples, or all of them, with its own
+    /// progress bar.
+    fn reader(
+        self,
+        multi_progress: &MultiProgress,
+        positions: Option<u64>,
+    ) -> anyhow::Result<InputReader> {
+        let progress = logging::track(
+            ProgressBar::new(positions.or(self.positions).unwrap_or(0))
+                .with_style(
+                    ProgressStyle::with_template(
+                        "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions read",
+                    )
+                    .unwrap()
+                    .progress_chars("##-"),
+                )
+                .with_message(format!("reading `{}`...", self.source)),
+        );
+        progress.enable_steady_tick(Duration::from_millis(50));
+        multi_progress.add(progress.clone());
+        Ok(InputReader {
+            reader: self
+                .source
+                .open()?
+                .take(positions.map_or(u64::MAX, |positions| {
+                    positions * mem::size_of::<PackedSample>() as u64
+                })),
+            source: self.source,
+            progress,
+        })
+    }
+}
+
+struct InputReader {
+    source: DatasetSource,
+    reader: io::Take<SampleReader>,
+    progress: ProgressBar,
+}
+
+impl InputReader {
+    /// Reads up to `samples.len()` samples, returning how many were read.
+    fn read(&mut self, samples: &mut [PackedSample]) -> io::Result<usize> {
+        let read = compression::read_samples(&mut self.reader, samples)?;
+        self.progress.inc(read as u64);
+        if read < samples.len() {
+            self.progress.finish();
+        }
+        Ok(read)
+    }
+}
+
+const COPY_BUFFER_SIZE: usize = 8192;
+
+fn concatenate(readers: Vec<InputReader>, writer: &mut impl Write) -> anyhow::Result<()> {
+    let mut buffer = vec![PackedSample::default(); COPY_BUFFER_SIZE];
+    for mut reader in readers {
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(bytemuck::cast_slice(&buffer[..read]))?;
+        }
+    }
+    Ok(())
+}
+
+/// Counts the samples of every input.
+fn count(inputs: &mut [Input]) -> anyhow::Result<Vec<u64>> {
+    inputs.iter_mut().map(Input::count).collect()
+}
+
+/// Number of samples to take from each input so that the output mixes them in the
+/// proportions given by `weights`, using as much of the inputs as those proportions allow.
+fn weighted_counts(sizes: &[u64], weights: &[f64]) -> anyhow::Result<Vec<u64>> {
+    if weights.len() != sizes.len() {
+        anyhow::bail!(
+            "expected {} weights, one per input, but {} were given",
+            sizes.len(),
+            weights.len()
+        );
+    }
+    if let Some(weight) = weights.iter().find(|w| !w.is_finite() || **w <= 0.0) {
+        anyhow::bail!("invalid weight `{}`, weights must be positive", weight);
+    }
+
+    let weight_sum: f64 = weights.iter().sum();
+    let total = sizes
+        .iter()
+        .zip(weights)
+        .map(|(&size, &weight)| size as f64 * weight_sum / weight)
+        .fold(f64::INFINITY, f64::min);
+    Ok(sizes
+        .iter()
+        .zip(weights)
+        .map(|(&size, &weight)| ((total * weight / weight_sum) as u64).min(size))
+        .collect())
+}
+
+/// Writes a uniformly random interleaving of the inputs, keeping the order of samples
+/// within each input. Shuffled inputs therefore produce a shuffled output.
+fn interleave(
+    mut readers: Vec<InputReader>,
+    mut remaining: Vec<u64>,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut positions: u64 = remaining.iter().sum();
+    let mut rng = rand::rng();
+    while positions > 0 {
+        let mut rand = rng.random_range(0..positions);
+        let idx = remaining
+            .iter()
+            .position(|&count| {
+                if rand < count {
+                    true
+                } else {
+                    rand -= count;
+                    false
+                }
+            })
+            .expect("sample index out of range");
+
+        let mut sample = [PackedSample::default()];
+        if readers[idx].read(&mut sample)? == 0 {
+            anyhow::bail!("unexpected end of input `{}`", readers[idx].source);
+        }
+        writer.write_all(bytemuck::cast_slice(&sample))?;
+        remaining[idx] -= 1;
+        positions -= 1;
+        if remaining[idx] == 0 {
+            readers[idx].progress.finish();
+        }
+    }
+    Ok(())
+}
+
+fn dedup(
+    readers: Vec<InputReader>,
+    counts: Vec<u64>,
+    writer: &mut impl Write,
+    repeats: Repeats,
+) -> anyhow::Result<DedupStats> {
+    let mut deduplicator = Deduplicator::new(counts.iter().sum(), repeats)?;
+    let mut buffer = vec![PackedSample::default(); COPY_BUFFER_SIZE];
+    for mut reader in readers {
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            for sample in &buffer[..read] {
+                deduplicator.push(sample)?;
+            }
+        }
+    }
+    deduplicator.finish(writer)
+}
+
+/// Number of samples in a dataset that is about to be appended to.
+fn existing_positions(sink: &DatasetSink) -> anyhow::Result<u64> {
+    match sink {
+        DatasetSink::File { path, .. } if path.is_dir() => anyhow::bail!(
+            "`{}` is a directory of shards, give `--shard-size` to append to it",
+            path.display()
+        ),
+        DatasetSink::File {
+            path,
+            compress: true,
+        } => anyhow::bail!("cannot append to compressed dataset `{}`", path.display()),
+        DatasetSink::Shards {
+            dir,
+            compress: true,
+            ..
+        } => anyhow::bail!("cannot append to compressed shards in `{}`", dir.display()),
+        DatasetSink::File { path, .. } | DatasetSink::Shards { dir: path, .. }
+            if !path.exists() =>
+        {
+            return Ok(0);
+        }
+        DatasetSink::Stdout => anyhow::bail!("cannot append to standard output"),
+        _ => {}
+    }
+    let source = sink.source().expect("files and shards can be read back");
+    Ok(SampleFiles::open(&source, false)?.len())
+}
+
+const MIX_CHUNK_SIZE: usize = 1 << 20;
+
+/// Mixes the `appended` samples at the end of the file into the `body` samples before
+/// them, continuing a Fisher-Yates shuffle from where the body ends. If the body was
+/// uniformly shuffled, so is the result, at the cost of one random access per new sample.
+fn mix_appended(
+    source: &DatasetSource,
+    body: u64,
+    appended: u64,
+    throttle: Option<Throttle>,
+) -> anyhow::Result<()> {
+    let progress = logging::track(
+        ProgressBar::new(appended)
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions mixed.",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("mixing appended positions..."),
+    );
     progress.enable_steady_tick(Duration::from_millis(50));
-    for input_path in &args.inputs {
-        let mut input_file = File::open(input_path)
-            .await
-            .with_context(|| format!("failed to open input file `{}`", input_path.display()))?;
-        io::copy(&mut input_file, &mut output_file).await?;
-        progress.inc(1);
+
+    let step = mem::size_of::<PackedSample>() as u64;
+    let end = body + appended;
+    let mut files = SampleFiles::open(source, true)?;
+    let mut rng = rand::rng();
+    let mut chunk = vec![PackedSample::default(); MIX_CHUNK_SIZE];
+    let mut start = body;
+    while start < end {
+        let chunk = &mut chunk[..(end - start).min(MIX_CHUNK_SIZE as u64) as usize];
+        files.read(start, chunk)?;
+
+        for k in 0..chunk.len() {
+            let j = rng.random_range(0..=start + k as u64);
+            if j >= start {
+                chunk.swap(k, (j - start) as usize);
+            } else {
+                let mut other = [PackedSample::default()];
+                files.read(j, &mut other)?;
+                files.write(j, &chunk[k..k + 1])?;
+                if let Some(throttle) = &throttle {
+                    throttle.consume(step);
+                }
+                chunk[k] = other[0];
+            }
+            progress.inc(1);
+        }
+
+        files.write(start, chunk)?;
+        if let Some(throttle) = &throttle {
+            throttle.consume(chunk.len() as u64 * step);
+        }
+        start += chunk.len() as u64;
     }
     progress.finish();
-
-    shuffle(output_file, None).await
+    Ok(())
 }
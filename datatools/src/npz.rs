@@ -0,0 +1,318 @@
+use anyhow::Context;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::Path,
+};
+
+/// A float array read from a `.npy` file, in C order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Array {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const METHOD_STORED: u16 = 0;
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Reads every array of an uncompressed `.npz` archive, as written by `numpy.savez`,
+/// keyed by name without the `.npy` extension.
+pub fn read_npz(path: &Path) -> anyhow::Result<HashMap<String, Array>> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    let mut arrays = HashMap::new();
+    let mut offset = 0;
+    while offset + 30 <= bytes.len() && u32_at(&bytes, offset) == Some(LOCAL_HEADER_SIGNATURE) {
+        // The loop condition makes sure the whole fixed-size header is there.
+        let flags = u16_at(&bytes, offset + 6).unwrap();
+        let method = u16_at(&bytes, offset + 8).unwrap();
+        let mut size = u32_at(&bytes, offset + 18).unwrap() as u64;
+        let name_len = u16_at(&bytes, offset + 26).unwrap() as usize;
+        let extra_len = u16_at(&bytes, offset + 28).unwrap() as usize;
+        let name_start = offset + 30;
+        let extra_start = name_start + name_len;
+        let data_start = extra_start + extra_len;
+        let name = bytes
+            .get(name_start..extra_start)
+            .context("truncated zip entry name")?;
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        if flags & 0x8 != 0 {
+            anyhow::bail!(
+                "entry `{}` uses a data descriptor, which is not supported",
+                name
+            );
+        }
+        if method != METHOD_STORED {
+            anyhow::bail!(
+                "entry `{}` is compressed, save the arrays with `numpy.savez` instead of `savez_compressed`",
+                name
+            );
+        }
+        if size == u32::MAX as u64 {
+            let extra = bytes
+                .get(extra_start..data_start)
+                .with_context(|| format!("truncated zip entry `{}`", name))?;
+            size = zip64_size(extra)
+                .with_context(|| format!("entry `{}` lacks its zip64 size", name))?;
+        }
+
+        let data_end = usize::try_from(size)
+            .ok()
+            .and_then(|size| data_start.checked_add(size))
+            .filter(|&end| end <= bytes.len())
+            .with_context(|| format!("truncated zip entry `{}`", name))?;
+        let data = &bytes[data_start..data_end];
+        let array = read_npy(data).with_context(|| format!("failed to parse array `{}`", name))?;
+        arrays.insert(name.trim_end_matches(".npy").to_string(), array);
+        offset = data_end;
+    }
+    if arrays.is_empty() {
+        anyhow::bail!("`{}` is not an npz archive", path.display());
+    }
+    Ok(arrays)
+}
+
+/// Finds the compressed size in a zip64 extended information extra field.
+fn zip64_size(mut extra: &[u8]) -> Option<u64> {
+    while extra.len() >= 4 {
+        let id = u16_at(extra, 0)?;
+        let len = u16_at(extra, 2)? as usize;
+        let field = extra.get(4..4 + len)?;
+        if id == ZIP64_EXTRA_ID && field.len() >= 16 {
+            // The uncompressed size comes first, then the compressed size.
+            return Some(u64::from_le_bytes(field[8..16].try_into().unwrap()));
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+/// Parses a little endian `float32` or `float64` `.npy` array.
+fn read_npy(bytes: &[u8]) -> anyhow::Result<Array> {
+    if !bytes.starts_with(NPY_MAGIC) || bytes.len() < 10 {
+        anyhow::bail!("not an npy array");
+    }
+    let major = bytes[6];
+    let (header_len, header_start) = if major == 1 {
+        (u16_at(bytes, 8).map(usize::from), 10)
+    } else {
+        (u32_at(bytes, 8).map(|len| len as usize), 12)
+    };
+    let header_end = header_len
+        .map(|len| header_start + len)
+        .filter(|&end| end <= bytes.len())
+        .context("truncated npy header")?;
+    let header =
+        std::str::from_utf8(&bytes[header_start..header_end]).context("npy header is not text")?;
+    let data = &bytes[header_end..];
+
+    if header_value(header, "fortran_order").is_some_and(|order| order.starts_with("True")) {
+        anyhow::bail!("arrays in Fortran order are not supported");
+    }
+    let descr = header_value(header, "descr").context("npy header lacks `descr`")?;
+    let shape = header_value(header, "shape").context("npy header lacks `shape`")?;
+    let shape = shape
+        .trim_start_matches('(')
+        .split(')')
+        .next()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse::<usize>().context("invalid npy shape"))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let len = shape
+        .iter()
+        .try_fold(1usize, |len, &dim| len.checked_mul(dim))
+        .context("npy shape is too large")?;
+    let bytes_of = |size: usize| len.checked_mul(size).context("npy shape is too large");
+
+    let data = if descr.starts_with("'<f4'") {
+        data.get(..bytes_of(4)?)
+            .context("truncated npy data")?
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect()
+    } else if descr.starts_with("'<f8'") {
+        data.get(..bytes_of(8)?)
+            .context("truncated npy data")?
+            .chunks_exact(8)
+            .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()) as f32)
+            .collect()
+    } else {
+        anyhow::bail!(
+            "unsupported npy element type {}",
+            descr.split(',').next().unwrap_or(descr)
+        );
+    };
+    Ok(Array { shape, data })
+}
+
+/// An element type of the arrays written by [`NpyWriter`].
+pub trait Element: Copy {
+    /// The numpy type string, such as `<f4`.
+    const DESCR: &'static str;
+
+    fn write_to(self, writer: &mut impl Write) -> io::Result<()>;
+}
+
+macro_rules! impl_element {
+    ($($ty:ty => $descr:literal),*) => {
+        $(impl Element for $ty {
+            const DESCR: &'static str = $descr;
+
+            fn write_to(self, writer: &mut impl Write) -> io::Result<()> {
+                writer.write_all(&self.to_le_bytes())
+            }
+        })*
+    };
+}
+
+impl_element!(f32 => "<f4", i8 => "|i1", u8 => "|u1", u16 => "<u2");
+
+/// Length of the headers written by [`NpyWriter`], room enough for any length.
+const NPY_HEADER_LEN: usize = 128;
+
+/// Writes a one-dimensional `.npy` array value by value, for arrays whose length isn't
+/// known up front. Its header is rewritten with the length once finished.
+pub struct NpyWriter<T> {
+    writer: BufWriter<File>,
+    len: u64,
+    element: PhantomData<T>,
+}
+
+impl<T: Element> NpyWriter<T> {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&npy_header(T::DESCR, 0))?;
+        Ok(Self {
+            writer,
+            len: 0,
+            element: PhantomData,
+        })
+    }
+
+    pub fn push(&mut self, value: T) -> io::Result<()> {
+        self.len += 1;
+        value.write_to(&mut self.writer)
+    }
+
+    /// Writes the final header, returning the array's length.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&npy_header(T::DESCR, self.len))?;
+        self.writer.flush()?;
+        Ok(self.len)
+    }
+}
+
+/// A version 1.0 header of a one-dimensional array, padded to [`NPY_HEADER_LEN`] bytes.
+fn npy_header(descr: &str, len: u64) -> Vec<u8> {
+    let dictionary = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({},), }}",
+        descr, len
+    );
+    let mut header = NPY_MAGIC.to_vec();
+    header.extend_from_slice(&[1, 0]);
+    header.extend_from_slice(&(NPY_HEADER_LEN as u16 - 10).to_le_bytes());
+    header.extend_from_slice(dictionary.as_bytes());
+    header.resize(NPY_HEADER_LEN - 1, b' ');
+    header.push(b'\n');
+    header
+}
+
+/// Finds the text following `'key':` in an npy header dictionary.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{}':", key))? + key.len() + 3;
+    Some(header[start..].trim_start())
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An archive of one stored entry, like those written by `numpy.savez`.
+    fn archive(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut bytes = LOCAL_HEADER_SIGNATURE.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[20, 0, 0, 0]);
+        bytes.extend_from_slice(&METHOD_STORED.to_le_bytes());
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn array(values: &[f32]) -> Vec<u8> {
+        let mut bytes = npy_header("<f4", values.len() as u64);
+        for value in values {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn read(bytes: &[u8]) -> anyhow::Result<HashMap<String, Array>> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), bytes).unwrap();
+        read_npz(file.path())
+    }
+
+    #[test]
+    fn reads_stored_arrays() {
+        let arrays = read(&archive("out.bias.npy", &array(&[0.5, -1.0]))).unwrap();
+        assert_eq!(
+            arrays["out.bias"],
+            Array {
+                shape: vec![2],
+                data: vec![0.5, -1.0],
+            }
+        );
+    }
+
+    #[test]
+    fn corrupt_archives_are_errors() {
+        let bytes = archive("out.bias.npy", &array(&[0.5, -1.0]));
+        for len in 0..bytes.len() {
+            assert!(read(&bytes[..len]).is_err(), "truncated to {} bytes", len);
+        }
+
+        // Sizes past the end of the archive.
+        let mut huge_entry = bytes.clone();
+        huge_entry[18..22].copy_from_slice(&(u32::MAX - 1).to_le_bytes());
+        assert!(read(&huge_entry).is_err());
+        let mut huge_extra = bytes.clone();
+        huge_extra[28..30].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(read(&huge_extra).is_err());
+        let zip64 = {
+            let mut bytes = bytes.clone();
+            bytes[18..22].copy_from_slice(&u32::MAX.to_le_bytes());
+            bytes
+        };
+        assert!(read(&zip64).is_err());
+
+        // A shape whose element count overflows.
+        let mut npy = array(&[0.5, -1.0]);
+        let header = String::from_utf8_lossy(&npy[10..NPY_HEADER_LEN])
+            .replace("(2,)", "(9999999999999, 9999999999)");
+        npy[10..NPY_HEADER_LEN].copy_from_slice(&header.as_bytes()[..NPY_HEADER_LEN - 10]);
+        let err = read(&archive("out.bias.npy", &npy)).unwrap_err();
+        assert!(format!("{:#}", err).contains("too large"), "{:#}", err);
+    }
+}
@@ -0,0 +1,428 @@
+use anyhow::Context;
+use clap::{Arg, CommandFactory, Parser};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{Options, digest, logging};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Pipeline file describing the steps to run."))]
+    pipeline: PathBuf,
+    #[clap(
+        long("from"),
+        help("Runs the pipeline again from this step, even if it already completed.")
+    )]
+    from: Option<String>,
+    #[clap(
+        long("restart"),
+        conflicts_with("from"),
+        help("Runs every step again, ignoring the steps completed by previous runs.")
+    )]
+    restart: bool,
+    #[clap(
+        long("dry-run"),
+        help("Prints the command of every step that would run, without running them.")
+    )]
+    dry_run: bool,
+}
+
+/// A pipeline step, with variables substituted and shared options added.
+struct Step {
+    name: String,
+    command: String,
+    args: Vec<String>,
+}
+
+impl Step {
+    /// Identifies what the step does, so that a step edited since it completed runs again.
+    fn fingerprint(&self) -> u64 {
+        let mut bytes = self.command.clone().into_bytes();
+        for arg in &self.args {
+            bytes.push(0);
+            bytes.extend_from_slice(arg.as_bytes());
+        }
+        digest::hash_bytes(&bytes)
+    }
+
+    fn command_line(&self) -> String {
+        let mut line = format!("datatools {}", self.command);
+        for arg in &self.args {
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                write!(line, " {:?}", arg).unwrap();
+            } else {
+                write!(line, " {}", arg).unwrap();
+            }
+        }
+        line
+    }
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let text = fs::read_to_string(&args.pipeline)
+        .with_context(|| format!("failed to read pipeline `{}`", args.pipeline.display()))?;
+    let pipeline = Pipeline::parse(&text)
+        .with_context(|| format!("invalid pipeline `{}`", args.pipeline.display()))?;
+    let steps = pipeline.resolve()?;
+    // Every step is parsed before the first one runs, so that a typo in the last step
+    // doesn't surface hours into the pipeline.
+    let mut commands = Vec::with_capacity(steps.len());
+    for step in &steps {
+        let options = Options::try_parse_from(
+            ["datatools", step.command.as_str()]
+                .into_iter()
+                .chain(step.args.iter().map(String::as_str)),
+        )
+        .with_context(|| format!("invalid arguments for step `{}`", step.name))?;
+        commands.push(options.command);
+    }
+
+    if let Some(from) = &args.from
+        && !steps.iter().any(|step| step.name == *from)
+    {
+        anyhow::bail!("the pipeline has no step named `{}`", from);
+    }
+    let state_path = args.pipeline.with_extension("state");
+    let mut completed = if args.restart {
+        HashMap::new()
+    } else {
+        read_state(&state_path)?
+    };
+
+    let mut run = 0;
+    let mut skipped = 0;
+    let mut rerun = false;
+    for (index, (step, command)) in steps.iter().zip(commands).enumerate() {
+        rerun |= args.from.as_ref() == Some(&step.name);
+        if !rerun && completed.get(&step.name) == Some(&step.fingerprint()) {
+            eprintln!(
+                "[{}/{}] {}: already completed, skipping",
+                index + 1,
+                steps.len(),
+                step.name
+            );
+            skipped += 1;
+            continue;
+        }
+        // Later steps read what this one writes, so they can't be trusted anymore.
+        rerun = true;
+        eprintln!(
+            "[{}/{}] {}: {}",
+            index + 1,
+            steps.len(),
+            step.name,
+            step.command_line()
+        );
+        if args.dry_run {
+            continue;
+        }
+
+        Box::pin(crate::run_command(command))
+            .await
+            .with_context(|| format!("step `{}` failed", step.name))?;
+        completed.insert(step.name.clone(), step.fingerprint());
+        write_state(&state_path, &steps, &completed)?;
+        run += 1;
+    }
+
+    logging::summary(
+        &format!("{} steps run, {} already completed", run, skipped),
+        &[("steps_run", run), ("steps_skipped", skipped)],
+    );
+    Ok(())
+}
+
+/// Reads the fingerprints of the steps completed by previous runs.
+fn read_state(path: &Path) -> anyhow::Result<HashMap<String, u64>> {
+    if !path.is_file() {
+        return Ok(HashMap::new());
+    }
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read pipeline state `{}`", path.display()))?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (name, fingerprint) = line
+                .rsplit_once(' ')
+                .and_then(|(name, hash)| Some((name, u64::from_str_radix(hash, 16).ok()?)))
+                .with_context(|| {
+                    format!(
+                        "invalid line `{}` in pipeline state `{}`",
+                        line,
+                        path.display()
+                    )
+                })?;
+            Ok((name.to_string(), fingerprint))
+        })
+        .collect()
+}
+
+fn write_state(
+    path: &Path,
+    steps: &[Step],
+    completed: &HashMap<String, u64>,
+) -> anyhow::Result<()> {
+    let mut text = String::new();
+    for step in steps {
+        if let Some(fingerprint) = completed.get(&step.name) {
+            writeln!(text, "{} {:016x}", step.name, fingerprint).unwrap();
+        }
+    }
+    fs::write(path, text)
+        .with_context(|| format!("failed to write pipeline state `{}`", path.display()))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawStep {
+    name: Option<String>,
+    command: Option<String>,
+    args: Vec<toml::Value>,
+}
+
+/// A pipeline as written in its file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Pipeline {
+    vars: HashMap<String, toml::Value>,
+    options: toml::Table,
+    steps: Vec<RawStep>,
+}
+
+impl Pipeline {
+    /// Parses a pipeline file, written in TOML:
+    ///
+    /// ```toml
+    /// [vars]
+    /// work = "/data/work"
+    ///
+    /// # Added to every step whose command has the option.
+    /// [options]
+    /// shard-size = "4GiB"
+    ///
+    /// [[steps]]
+    /// name = "extract"
+    /// command = "extract"
+    /// args = ["games.pgn", "-o", "${work}/extracted.bin"]
+    /// ```
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let pipeline: Pipeline = toml::from_str(text)?;
+        for (name, value) in &pipeline.vars {
+            if value.is_array() || value.is_table() {
+                anyhow::bail!("variable `{}` must be a single value", name);
+            }
+        }
+        Ok(pipeline)
+    }
+
+    /// Substitutes variables and adds the shared options to every step.
+    fn resolve(&self) -> anyhow::Result<Vec<Step>> {
+        if self.steps.is_empty() {
+            anyhow::bail!("the pipeline has no steps");
+        }
+        let cli = Options::command();
+        // Options of the whole process, which steps can't change since they run in it.
+        let global: Vec<&str> = cli
+            .get_arguments()
+            .filter(|arg| arg.is_global_set())
+            .filter_map(Arg::get_long)
+            .collect();
+        if let Some(key) = self
+            .options
+            .keys()
+            .find(|key| global.contains(&key.as_str()))
+        {
+            anyhow::bail!(
+                "`{}` can't be a shared option, pass `--{}` to `datatools run` instead",
+                key,
+                key
+            );
+        }
+        let mut steps: Vec<Step> = Vec::with_capacity(self.steps.len());
+        for (index, raw) in self.steps.iter().enumerate() {
+            let command = raw
+                .command
+                .clone()
+                .with_context(|| format!("step {} has no command", index + 1))?;
+            let name = raw
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("{}-{}", index + 1, command));
+            if name.contains(char::is_whitespace) {
+                anyhow::bail!("step name `{}` contains whitespace", name);
+            }
+            if steps.iter().any(|step| step.name == name) {
+                anyhow::bail!("two steps are named `{}`", name);
+            }
+            if command == "run" {
+                anyhow::bail!("step `{}` cannot run another pipeline", name);
+            }
+            let subcommand = cli
+                .find_subcommand(&command)
+                .with_context(|| format!("step `{}` has unknown command `{}`", name, command))?;
+
+            let mut args = raw
+                .args
+                .iter()
+                .map(|arg| self.substitute(&to_arg(arg)?))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .with_context(|| format!("invalid arguments for step `{}`", name))?;
+            if let Some(flag) = global
+                .iter()
+                .find(|flag| args.iter().any(|arg| is_flag(arg, flag)))
+            {
+                anyhow::bail!(
+                    "step `{}` sets `--{}`, which only applies to the whole pipeline, pass it to `datatools run` instead",
+                    name,
+                    flag
+                );
+            }
+            for (key, value) in &self.options {
+                let accepted = subcommand
+                    .get_arguments()
+                    .any(|arg| arg.get_long() == Some(key.as_str()));
+                let given = args.iter().any(|arg| is_flag(arg, key));
+                if !accepted || given {
+                    continue;
+                }
+                let flag = format!("--{}", key);
+                match value {
+                    toml::Value::Boolean(true) => args.push(flag),
+                    toml::Value::Boolean(false) => {}
+                    value => {
+                        args.push(flag);
+                        args.push(self.substitute(&to_arg(value)?)?);
+                    }
+                }
+            }
+            steps.push(Step {
+                name,
+                command,
+                args,
+            });
+        }
+        Ok(steps)
+    }
+
+    /// Replaces every `${name}` in `text` with the variable's value.
+    fn substitute(&self, text: &str) -> anyhow::Result<String> {
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("${") {
+            result.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .with_context(|| format!("unterminated variable in `{}`", text))?;
+            let name = &rest[start + 2..start + end];
+            let value = self
+                .vars
+                .get(name)
+                .with_context(|| format!("undefined variable `{}` in `{}`", name, text))?;
+            result.push_str(&to_arg(value)?);
+            rest = &rest[start + end + 1..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+}
+
+/// A value of the pipeline file as a command line argument, arrays being joined with
+/// commas.
+fn to_arg(value: &toml::Value) -> anyhow::Result<String> {
+    Ok(match value {
+        toml::Value::String(string) => string.clone(),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(to_arg)
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .join(","),
+        toml::Value::Table(_) => anyhow::bail!("a table can't be an argument"),
+        value => value.to_string(),
+    })
+}
+
+/// Whether `arg` is the long option `--<long>`, with its value attached or not.
+fn is_flag(arg: &str, long: &str) -> bool {
+    arg.strip_prefix("--")
+        .and_then(|rest| rest.strip_prefix(long))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIPELINE: &str = r#"
+[vars]
+work = "/data/work"
+
+[options]
+seed = 7
+shard-size = "4GiB"
+
+[[steps]]
+name = "shuffle"
+command = "shuffle"
+args = [
+    "${work}/extracted.bin", # Written by an earlier run.
+    "-o", "${work}/shuffled",
+]
+
+[[steps]]
+command = "info"
+args = ["${work}/shuffled"]
+"#;
+
+    #[test]
+    fn resolve_substitutes_and_adds_options() {
+        let steps = Pipeline::parse(PIPELINE).unwrap().resolve().unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].name, "shuffle");
+        assert_eq!(
+            steps[0].args,
+            [
+                "/data/work/extracted.bin",
+                "-o",
+                "/data/work/shuffled",
+                "--seed",
+                "7",
+                "--shard-size",
+                "4GiB"
+            ]
+        );
+        // `info` has neither shared option, and its step is named after its position.
+        assert_eq!(steps[1].name, "2-info");
+        assert_eq!(steps[1].args, ["/data/work/shuffled"]);
+    }
+
+    #[test]
+    fn options_given_by_a_step_are_kept() {
+        let text = PIPELINE.replace(r#""-o","#, r#""--seed=3", "-o","#);
+        let steps = Pipeline::parse(&text).unwrap().resolve().unwrap();
+        assert!(steps[0].args.contains(&"--seed=3".to_string()));
+        assert!(!steps[0].args.contains(&"--seed".to_string()));
+    }
+
+    #[test]
+    fn global_options_are_rejected() {
+        let text = PIPELINE.replace(r#""-o","#, r#""--threads", "4", "-o","#);
+        assert!(Pipeline::parse(&text).unwrap().resolve().is_err());
+        let text = PIPELINE.replace("seed = 7", "quiet = true");
+        assert!(Pipeline::parse(&text).unwrap().resolve().is_err());
+    }
+
+    #[test]
+    fn invalid_pipelines_are_rejected() {
+        assert!(Pipeline::parse("[stages]\nname = \"a\"").is_err());
+        assert!(Pipeline::parse("[[steps]]\ncommand = \"info\"\nflags = []").is_err());
+        assert!(Pipeline::parse("[vars]\nwork = [\"a\"]").is_err());
+        assert!(Pipeline::parse("[[steps]]\nargs = [\"a\"").is_err());
+        let undefined = "[[steps]]\ncommand = \"info\"\nargs = [\"${nowhere}\"]";
+        assert!(Pipeline::parse(undefined).unwrap().resolve().is_err());
+    }
+}
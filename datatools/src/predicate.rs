@@ -0,0 +1,305 @@
+use anyhow::Context;
+use dama::{Color, Outcome, Piece, Position, Square, SquareSet};
+use dataformat::Sample;
+use dataloader::wdl;
+use std::{fmt, str::FromStr};
+
+/// A condition on a single sample, parsed from `<field><op><value>` expressions such as
+/// `eval>=-200`, `pieces<=6`, `phase<=8`, `outcome=draw`, `material=KRvKR` or
+/// `contains=K on g1`.
+///
+/// Evals are compared from the side to move's perspective, samples of datasets holding
+/// them from white's are turned into it with [`manifest::to_side_to_move`] first.
+///
+/// [`manifest::to_side_to_move`]: crate::manifest::to_side_to_move
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    Eval(Comparison, i32),
+    AbsEval(Comparison, i32),
+    Pieces(Comparison, u32),
+    /// Non-pawn material as counted by [`wdl::phase`], from 0 to 24.
+    Phase(Comparison, u32),
+    Outcome(bool, Outcome),
+    Material(bool, MaterialSignature),
+    Contains(bool, Placement),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Predicate {
+    pub fn matches(&self, sample: &Sample) -> bool {
+        match self {
+            Predicate::Eval(cmp, value) => sample
+                .eval
+                .is_some_and(|eval| cmp.compare(eval as i32, *value)),
+            Predicate::AbsEval(cmp, value) => sample
+                .eval
+                .is_some_and(|eval| cmp.compare((eval as i32).abs(), *value)),
+            Predicate::Pieces(cmp, value) => {
+                cmp.compare(sample.position.occupied().count(), *value)
+            }
+            Predicate::Phase(cmp, value) => cmp.compare(wdl::phase(&sample.position), *value),
+            Predicate::Outcome(equal, outcome) => (sample.outcome == *outcome) == *equal,
+            Predicate::Material(equal, signature) => {
+                (MaterialSignature::of(&sample.position) == *signature) == *equal
+            }
+            Predicate::Contains(equal, placement) => placement.matches(&sample.position) == *equal,
+        }
+    }
+}
+
+pub fn matches_all(predicates: &[Predicate], sample: &Sample) -> bool {
+    predicates.iter().all(|predicate| predicate.matches(sample))
+}
+
+impl Comparison {
+    #[inline]
+    pub fn compare<T: Ord>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            Comparison::Less => lhs < rhs,
+            Comparison::LessOrEqual => lhs <= rhs,
+            Comparison::Equal => lhs == rhs,
+            Comparison::NotEqual => lhs != rhs,
+            Comparison::GreaterOrEqual => lhs >= rhs,
+            Comparison::Greater => lhs > rhs,
+        }
+    }
+
+    fn is_equality(self) -> Option<bool> {
+        match self {
+            Comparison::Equal => Some(true),
+            Comparison::NotEqual => Some(false),
+            _ => None,
+        }
+    }
+}
+
+const OPERATORS: [(&str, Comparison); 6] = [
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("!=", Comparison::NotEqual),
+    ("=", Comparison::Equal),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+impl FromStr for Predicate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (position, op, cmp) = OPERATORS
+            .iter()
+            .filter_map(|&(op, cmp)| s.find(op).map(|position| (position, op, cmp)))
+            .min_by_key(|&(position, op, _)| (position, usize::MAX - op.len()))
+            .with_context(|| format!("missing comparison operator in filter `{}`", s))?;
+
+        let field = s[..position].trim();
+        let value = s[position + op.len()..].trim();
+        let equality = || {
+            cmp.is_equality()
+                .with_context(|| format!("field `{}` only supports `=` and `!=`", field))
+        };
+
+        Ok(match field {
+            "eval" => Predicate::Eval(cmp, value.parse().context("invalid eval value")?),
+            "abs-eval" => Predicate::AbsEval(cmp, value.parse().context("invalid eval value")?),
+            "pieces" => Predicate::Pieces(cmp, value.parse().context("invalid piece count")?),
+            "phase" => Predicate::Phase(cmp, value.parse().context("invalid phase")?),
+            "outcome" => Predicate::Outcome(equality()?, parse_outcome(value)?),
+            "material" => Predicate::Material(equality()?, value.parse()?),
+            "contains" => Predicate::Contains(equality()?, value.parse()?),
+            _ => anyhow::bail!("unknown filter field `{}`", field),
+        })
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let equality = |equal: bool| if equal { "=" } else { "!=" };
+        match self {
+            Predicate::Eval(cmp, value) => write!(f, "eval{}{}", cmp, value),
+            Predicate::AbsEval(cmp, value) => write!(f, "abs-eval{}{}", cmp, value),
+            Predicate::Pieces(cmp, value) => write!(f, "pieces{}{}", cmp, value),
+            Predicate::Phase(cmp, value) => write!(f, "phase{}{}", cmp, value),
+            Predicate::Outcome(equal, outcome) => {
+                write!(f, "outcome{}{}", equality(*equal), outcome)
+            }
+            Predicate::Material(equal, signature) => {
+                write!(f, "material{}{}", equality(*equal), signature)
+            }
+            Predicate::Contains(equal, placement) => {
+                write!(f, "contains{}{}", equality(*equal), placement)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (op, _) = OPERATORS
+            .iter()
+            .find(|(_, cmp)| cmp == self)
+            .expect("every comparison has an operator");
+        f.write_str(op)
+    }
+}
+
+fn parse_outcome(value: &str) -> anyhow::Result<Outcome> {
+    Ok(match value {
+        "white" => Outcome::Winner(Color::White),
+        "black" => Outcome::Winner(Color::Black),
+        "draw" => Outcome::Draw,
+        _ => value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid outcome `{}`", value))?,
+    })
+}
+
+const SIGNATURE_PIECES: [(Piece, char); 6] = [
+    (Piece::King, 'K'),
+    (Piece::Queen, 'Q'),
+    (Piece::Rook, 'R'),
+    (Piece::Bishop, 'B'),
+    (Piece::Knight, 'N'),
+    (Piece::Pawn, 'P'),
+];
+
+/// Piece counts for both sides, written as e.g. `KRPvKR` with white's pieces first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MaterialSignature {
+    counts: [[u8; 6]; 2],
+}
+
+impl MaterialSignature {
+    pub fn of(position: &Position) -> Self {
+        let mut signature = MaterialSignature::default();
+        for color in Color::all() {
+            for piece in Piece::all() {
+                signature.counts[color as usize][piece as usize] =
+                    (position.pieces(piece) & position.colored(color)).count() as u8;
+            }
+        }
+        signature
+    }
+}
+
+impl FromStr for MaterialSignature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (white, black) = s
+            .split_once('v')
+            .context("material signature must be of the form `<white>v<black>`")?;
+
+        let mut signature = MaterialSignature::default();
+        for (color, pieces) in [(Color::White, white), (Color::Black, black)] {
+            for ch in pieces.chars() {
+                let (piece, _) = SIGNATURE_PIECES
+                    .iter()
+                    .find(|(_, symbol)| *symbol == ch.to_ascii_uppercase())
+                    .with_context(|| format!("invalid piece `{}` in material signature", ch))?;
+                signature.counts[color as usize][*piece as usize] += 1;
+            }
+        }
+        Ok(signature)
+    }
+}
+
+impl fmt::Display for MaterialSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (n, color) in [Color::White, Color::Black].into_iter().enumerate() {
+            if n != 0 {
+                write!(f, "v")?;
+            }
+            for (piece, symbol) in SIGNATURE_PIECES {
+                for _ in 0..self.counts[color as usize][piece as usize] {
+                    write!(f, "{}", symbol)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A piece standing on any of a set of squares, written as e.g. `K on g1` with white's
+/// pieces in uppercase and black's in lowercase, or `k on b8/c8` for several squares.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Placement {
+    color: Color,
+    piece: Piece,
+    squares: SquareSet,
+}
+
+impl Placement {
+    pub fn matches(&self, position: &Position) -> bool {
+        !(position.pieces(self.piece) & position.colored(self.color) & self.squares).is_empty()
+    }
+}
+
+impl FromStr for Placement {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (piece, squares) = s
+            .split_once(" on ")
+            .context("piece placement must be of the form `<piece> on <square>[/<square>...]`")?;
+        let mut chars = piece.trim().chars();
+        let (Some(symbol), None) = (chars.next(), chars.next()) else {
+            anyhow::bail!(
+                "invalid piece `{}` in placement, expected e.g. `K` or `k`",
+                piece.trim()
+            );
+        };
+        let (piece, _) = SIGNATURE_PIECES
+            .iter()
+            .find(|(_, signature)| *signature == symbol.to_ascii_uppercase())
+            .with_context(|| format!("invalid piece `{}` in placement", symbol))?;
+        let color = if symbol.is_ascii_uppercase() {
+            Color::White
+        } else {
+            Color::Black
+        };
+
+        let mut set = SquareSet::EMPTY;
+        for square in squares.split('/') {
+            let square = square.trim();
+            set |= SquareSet::from(
+                square
+                    .parse::<Square>()
+                    .map_err(|_| anyhow::anyhow!("invalid square `{}` in placement", square))?,
+            );
+        }
+        Ok(Placement {
+            color,
+            piece: *piece,
+            squares: set,
+        })
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, symbol) = SIGNATURE_PIECES
+            .iter()
+            .find(|(piece, _)| *piece == self.piece)
+            .expect("every piece has a symbol");
+        let symbol = match self.color {
+            Color::White => *symbol,
+            Color::Black => symbol.to_ascii_lowercase(),
+        };
+        let squares: Vec<_> = self
+            .squares
+            .into_iter()
+            .map(|square| square.to_string())
+            .collect();
+        write!(f, "{} on {}", symbol, squares.join("/"))
+    }
+}
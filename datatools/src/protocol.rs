@@ -0,0 +1,416 @@
+use anyhow::Context;
+use dama::{Move, Position, SanMove, ToMove, UciMove};
+
+use crate::selfplay::{Go, Search};
+
+/// The protocols engines can be driven with.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProtocolKind {
+    #[default]
+    Uci,
+    /// The Chess Engine Communication Protocol of xboard and WinBoard, version 2.
+    Xboard,
+}
+
+impl ProtocolKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ProtocolKind::Uci => "uci",
+            ProtocolKind::Xboard => "xboard",
+        }
+    }
+
+    pub(crate) fn protocol(self) -> Box<dyn Protocol> {
+        match self {
+            ProtocolKind::Uci => Box::new(Uci),
+            ProtocolKind::Xboard => Box::new(Xboard::default()),
+        }
+    }
+}
+
+/// What an engine said while starting up.
+#[derive(Default)]
+pub(crate) struct Handshake {
+    /// Names of the options the engine declares.
+    pub(crate) options: Vec<String>,
+    /// Messages printed by the engine, often about the files it loaded.
+    pub(crate) messages: Vec<String>,
+    /// Commands to answer the engine with before reading on.
+    pub(crate) replies: Vec<String>,
+}
+
+/// What the engine said about a search so far.
+#[derive(Default)]
+pub(crate) struct SearchInfo {
+    eval: Option<i32>,
+    /// Best line of each MultiPV index, `None` for those without a centipawn score.
+    lines: Vec<Option<(Move, i32)>>,
+    depth: Option<u32>,
+    nodes: Option<u64>,
+    time_ms: Option<u64>,
+}
+
+impl SearchInfo {
+    fn finish(self, best_move: Move) -> Search {
+        Search {
+            best_move,
+            eval: self.eval,
+            lines: self.lines.into_iter().flatten().collect(),
+            depth: self.depth,
+            nodes: self.nodes,
+            time_ms: self.time_ms,
+        }
+    }
+}
+
+/// The commands an engine is driven with and how its answers are read. The driver does
+/// the talking, so protocols only turn each step into lines and read lines back.
+pub(crate) trait Protocol: Send {
+    /// Commands starting the conversation, answered by the lines given to
+    /// [`Protocol::read_handshake`].
+    fn handshake(&self) -> Vec<String>;
+
+    /// Reads a line of the engine's answer to the handshake, returning whether it was
+    /// the last one.
+    fn read_handshake(&mut self, line: &str, handshake: &mut Handshake) -> anyhow::Result<bool>;
+
+    /// Commands sent once after the handshake, knowing the options the engine declares.
+    fn configure(&self, options: &[String]) -> Vec<String>;
+
+    /// Command asking the engine to answer once it has caught up with every command
+    /// before it, the answer being recognized by [`Protocol::is_pong`].
+    fn ping(&mut self) -> String;
+
+    fn is_pong(&self, line: &str) -> bool;
+
+    fn set_option(&self, name: &str, value: &str) -> String;
+
+    fn new_game(&self) -> Vec<String>;
+
+    /// Command searching this many lines, `None` if the protocol can't.
+    fn multipv(&self, lines: u32) -> Option<String>;
+
+    /// Commands setting up the position to search.
+    fn position(&self, position: &Position) -> Vec<String>;
+
+    /// Commands starting a search within the limits.
+    fn go(&self, go: &Go) -> anyhow::Result<Vec<String>>;
+
+    /// Reads a line the engine printed while searching, returning the search once the
+    /// engine gave its move.
+    fn read_search(&self, line: &str, position: &Position, info: &mut SearchInfo) -> anyhow::Result<Option<Search>>;
+
+    fn quit(&self) -> String;
+}
+
+/// The Universal Chess Interface.
+pub(crate) struct Uci;
+
+impl Protocol for Uci {
+    fn handshake(&self) -> Vec<String> {
+        vec!["uci".to_string()]
+    }
+
+    fn read_handshake(&mut self, line: &str, handshake: &mut Handshake) -> anyhow::Result<bool> {
+        let line = line.trim();
+        if line == "uciok" {
+            return Ok(true);
+        }
+        if let Some(name) = uci_option_name(line) {
+            handshake.options.push(name.to_string());
+        } else if let Some(message) = line.strip_prefix("info string ") {
+            handshake.messages.push(message.to_string());
+        }
+        Ok(false)
+    }
+
+    fn configure(&self, options: &[String]) -> Vec<String> {
+        // Searches never use `go ponder`, but some engines think on the opponent's time
+        // when the option is left on.
+        if options.iter().any(|option| option.eq_ignore_ascii_case("Ponder")) {
+            vec![self.set_option("Ponder", "false")]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn ping(&mut self) -> String {
+        "isready".to_string()
+    }
+
+    fn is_pong(&self, line: &str) -> bool {
+        line.trim() == "readyok"
+    }
+
+    fn set_option(&self, name: &str, value: &str) -> String {
+        format!("setoption name {} value {}", name, value)
+    }
+
+    fn new_game(&self) -> Vec<String> {
+        vec!["ucinewgame".to_string()]
+    }
+
+    fn multipv(&self, lines: u32) -> Option<String> {
+        Some(self.set_option("MultiPV", &lines.to_string()))
+    }
+
+    fn position(&self, position: &Position) -> Vec<String> {
+        vec![format!("position fen {}", position.fen())]
+    }
+
+    fn go(&self, go: &Go) -> anyhow::Result<Vec<String>> {
+        let mut cmd = String::from("go");
+        if let Some(depth) = go.depth {
+            cmd.push_str(&format!(" depth {}", depth));
+        }
+        if let Some(nodes) = go.nodes {
+            cmd.push_str(&format!(" nodes {}", nodes));
+        }
+        if let Some(movetime) = go.movetime {
+            cmd.push_str(&format!(" movetime {}", movetime));
+        }
+        Ok(vec![cmd])
+    }
+
+    fn read_search(&self, line: &str, position: &Position, info: &mut SearchInfo) -> anyhow::Result<Option<Search>> {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("bestmove") => {
+                let mv = parts.next().context("invalid 'bestmove' usage")?;
+                let mv = mv.parse::<UciMove>()?.to_move(position)?;
+                return Ok(Some(std::mem::take(info).finish(mv)));
+            }
+            Some("info") => {}
+            _ => return Ok(None),
+        }
+        let mut multipv = 1;
+        // `None` for bounds, which leave the previous score in place.
+        let mut score = None;
+        let mut first_move = None;
+        while let Some(part) = parts.next() {
+            match part {
+                "multipv" => {
+                    multipv = parts
+                        .next()
+                        .context("MultiPV index not present")?
+                        .parse::<usize>()?;
+                }
+                "score" => match parts.next() {
+                    Some("cp") => {
+                        let info_eval = parts
+                            .next()
+                            .context("centipawn score not present")?
+                            .parse::<i32>()?;
+                        if !matches!(parts.clone().next(), Some("upperbound" | "lowerbound")) {
+                            score = Some(Some(info_eval));
+                        }
+                    }
+                    _ => score = Some(None),
+                },
+                // Only used for reporting, so values that don't parse are skipped.
+                "depth" => info.depth = parts.next().and_then(|value| value.parse().ok()).or(info.depth),
+                "nodes" => info.nodes = parts.next().and_then(|value| value.parse().ok()).or(info.nodes),
+                "time" => info.time_ms = parts.next().and_then(|value| value.parse().ok()).or(info.time_ms),
+                "pv" => {
+                    first_move = parts.next();
+                    break;
+                }
+                "string" => break,
+                _ => {}
+            }
+        }
+        let Some(score) = score else {
+            return Ok(None);
+        };
+        if multipv == 1 {
+            info.eval = score;
+        }
+        if multipv == 0 {
+            return Ok(None);
+        }
+        if info.lines.len() < multipv {
+            info.lines.resize(multipv, None);
+        }
+        info.lines[multipv - 1] = match (score, first_move) {
+            (Some(score), Some(mv)) => Some((mv.parse::<UciMove>()?.to_move(position)?, score)),
+            _ => None,
+        };
+        Ok(None)
+    }
+
+    fn quit(&self) -> String {
+        "quit".to_string()
+    }
+}
+
+/// The name in an `option name <name> type <type> ...` declaration, which may contain
+/// spaces.
+fn uci_option_name(line: &str) -> Option<&str> {
+    let declaration = line.trim().strip_prefix("option name ")?;
+    let name = match declaration.find(" type ") {
+        Some(end) => &declaration[..end],
+        None => declaration,
+    };
+    Some(name.trim())
+}
+
+/// Scores at least this far from zero are mates in xboard's thinking output.
+const XBOARD_MATE_SCORE: i32 = 100_000;
+
+/// Search time given to xboard engines limited by depth alone, so that their clock
+/// never cuts a search short.
+const XBOARD_UNTIMED_SECONDS: u32 = 86_400;
+
+/// The Chess Engine Communication Protocol version 2, spoken by xboard engines. Engines
+/// must support the `setboard` and `ping` features, which every modern one does.
+#[derive(Default)]
+pub(crate) struct Xboard {
+    setboard: bool,
+    ping: bool,
+    /// Number of the last `ping` sent.
+    pings: u32,
+}
+
+impl Protocol for Xboard {
+    fn handshake(&self) -> Vec<String> {
+        vec!["xboard".to_string(), "protover 2".to_string()]
+    }
+
+    fn read_handshake(&mut self, line: &str, handshake: &mut Handshake) -> anyhow::Result<bool> {
+        let Some(features) = line.trim().strip_prefix("feature ") else {
+            if let Some(message) = line.trim().strip_prefix('#') {
+                handshake.messages.push(message.trim().to_string());
+            }
+            return Ok(false);
+        };
+        let mut done = false;
+        for (name, value) in xboard_features(features) {
+            match name {
+                "setboard" => self.setboard = value == "1",
+                "ping" => self.ping = value == "1",
+                "option" => {
+                    let name = value.split(" -").next().unwrap_or(value).trim();
+                    handshake.options.push(name.to_string());
+                }
+                "myname" => handshake.messages.push(format!("name {}", value)),
+                "done" => done = value == "1",
+                _ => {}
+            }
+            // Features which change how the engine talks are turned down.
+            let accepted = !matches!(name, "san" | "usermove" | "sigint" | "sigterm") || value == "0";
+            handshake.replies.push(format!("{} {}", if accepted { "accepted" } else { "rejected" }, name));
+        }
+        if done && !(self.setboard && self.ping) {
+            anyhow::bail!("xboard engines must support the `setboard` and `ping` features");
+        }
+        Ok(done)
+    }
+
+    fn configure(&self, _options: &[String]) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn ping(&mut self) -> String {
+        self.pings += 1;
+        format!("ping {}", self.pings)
+    }
+
+    fn is_pong(&self, line: &str) -> bool {
+        line.trim().strip_prefix("pong ").is_some_and(|n| n.trim() == self.pings.to_string())
+    }
+
+    fn set_option(&self, name: &str, value: &str) -> String {
+        format!("option {}={}", name, value)
+    }
+
+    fn new_game(&self) -> Vec<String> {
+        // `force` keeps the engine from moving on its own, `post` has it print its
+        // thinking and `easy` turns pondering off.
+        ["new", "force", "post", "easy"].map(str::to_string).to_vec()
+    }
+
+    fn multipv(&self, lines: u32) -> Option<String> {
+        (lines == 1).then(String::new)
+    }
+
+    fn position(&self, position: &Position) -> Vec<String> {
+        vec!["force".to_string(), format!("setboard {}", position.fen())]
+    }
+
+    fn go(&self, go: &Go) -> anyhow::Result<Vec<String>> {
+        let mut commands = Vec::new();
+        if let Some(depth) = go.depth {
+            commands.push(format!("sd {}", depth));
+        }
+        match (go.nodes, go.movetime) {
+            // With `nps`, the engine searches the nodes it would in the time given.
+            (Some(nodes), _) => commands.extend([format!("nps {}", nodes), "st 1".to_string()]),
+            // `st` only takes whole seconds.
+            (None, Some(movetime)) => commands.push(format!("st {}", movetime.div_ceil(1000))),
+            (None, None) if go.depth.is_some() => commands.push(format!("st {}", XBOARD_UNTIMED_SECONDS)),
+            (None, None) => anyhow::bail!("xboard engines need a depth, node or time limit"),
+        }
+        commands.push("go".to_string());
+        Ok(commands)
+    }
+
+    fn read_search(&self, line: &str, position: &Position, info: &mut SearchInfo) -> anyhow::Result<Option<Search>> {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("move") => {
+                let mv = parts.next().context("invalid 'move' usage")?;
+                let mv = xboard_move(mv, position)?;
+                return Ok(Some(std::mem::take(info).finish(mv)));
+            }
+            Some("resign") => anyhow::bail!("engine resigned instead of moving"),
+            Some(depth) if depth.starts_with(|c: char| c.is_ascii_digit()) => {
+                // Thinking output: `<depth> <score> <centiseconds> <nodes> <pv>`.
+                let score = parts.next().and_then(|score| score.parse::<i32>().ok());
+                let time = parts.next().and_then(|time| time.parse::<u64>().ok());
+                let nodes = parts.next().and_then(|nodes| nodes.parse::<u64>().ok());
+                let (Some(score), Some(time), Some(nodes)) = (score, time, nodes) else {
+                    return Ok(None);
+                };
+                info.depth = depth.trim_end_matches(|c: char| !c.is_ascii_digit()).parse().ok().or(info.depth);
+                info.time_ms = Some(10 * time);
+                info.nodes = Some(nodes);
+                info.eval = (score.abs() < XBOARD_MATE_SCORE).then_some(score);
+                let first_move = parts.next().and_then(|mv| xboard_move(mv, position).ok());
+                info.lines = vec![first_move.zip(info.eval)];
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn quit(&self) -> String {
+        "quit".to_string()
+    }
+}
+
+/// Splits the `name=value` pairs of a `feature` line, whose values may be quoted.
+fn xboard_features(mut features: &str) -> Vec<(&str, &str)> {
+    let mut pairs = Vec::new();
+    while let Some((name, rest)) = features.trim_start().split_once('=') {
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => rest.split_once(' ').unwrap_or((rest, "")),
+        };
+        pairs.push((name.trim(), value));
+        features = rest;
+    }
+    pairs
+}
+
+/// A move given in coordinate notation, or in SAN by engines which insist on it.
+fn xboard_move(mv: &str, position: &Position) -> anyhow::Result<Move> {
+    if let Ok(uci) = mv.parse::<UciMove>()
+        && let Ok(mv) = uci.to_move(position)
+    {
+        return Ok(mv);
+    }
+    let san = mv
+        .parse::<SanMove>()
+        .with_context(|| format!("invalid move `{}`", mv))?;
+    Ok(san.to_move(position)?)
+}
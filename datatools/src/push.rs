@@ -0,0 +1,111 @@
+use anyhow::Context;
+use dataformat::{
+    PackedSample,
+    block::{Handshake, HandshakeReply, SampleBlock},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    io::{BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{collect, io::DatasetSource, logging};
+
+/// Samples sent in each block.
+const BLOCK_SAMPLES: usize = 4096;
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Dataset to send, a file, a directory of shards or `-` for stdin."))]
+    dataset: DatasetSource,
+    #[clap(
+        long("to"),
+        help("Address of the `collect` server, e.g. `example.org:7878`.")
+    )]
+    to: String,
+    #[clap(
+        long("token-file"),
+        help(
+            "File holding the access token expected by the server, read from the `DATATOOLS_TOKEN` environment variable if not given."
+        )
+    )]
+    token_file: Option<PathBuf>,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let token = collect::read_token(args.token_file.as_deref())?;
+    let stream = TcpStream::connect(&args.to)
+        .with_context(|| format!("failed to connect to `{}`", args.to))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    Handshake::new(&token).write_to(&mut writer)?;
+    writer.flush()?;
+    let mut reply = [0];
+    reader
+        .read_exact(&mut reply)
+        .context("server closed the connection during the handshake")?;
+    match HandshakeReply::from_byte(reply[0]) {
+        Some(HandshakeReply::Accepted) => {}
+        Some(HandshakeReply::InvalidToken) => anyhow::bail!("the server rejected the access token"),
+        Some(HandshakeReply::UnsupportedVersion) => {
+            anyhow::bail!("the server doesn't support this version of the protocol")
+        }
+        None => anyhow::bail!("invalid handshake reply from the server"),
+    }
+
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] {msg} {human_pos} samples sent",
+                )
+                .unwrap(),
+            )
+            .with_message("sending..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let mut input = args.dataset.open()?;
+    let mut block = SampleBlock {
+        samples: vec![PackedSample::default(); BLOCK_SAMPLES],
+    };
+    let mut sent = 0u64;
+    loop {
+        block.samples.resize(BLOCK_SAMPLES, PackedSample::default());
+        let read = input.read_samples(&mut block.samples)?;
+        if read == 0 {
+            break;
+        }
+        block.samples.truncate(read);
+        block.write_to(&mut writer)?;
+        sent += read as u64;
+        progress.set_position(sent);
+    }
+    SampleBlock::write_end(&mut writer)?;
+    writer.flush()?;
+
+    let mut accepted = [0; 8];
+    reader
+        .read_exact(&mut accepted)
+        .context("server closed the connection before acknowledging the samples")?;
+    let accepted = u64::from_le_bytes(accepted);
+    progress.finish_and_clear();
+    if accepted < sent {
+        eprintln!(
+            "warning: the server dropped {} samples, in blocks holding samples it couldn't unpack",
+            sent - accepted
+        );
+    }
+
+    logging::summary(
+        &format!(
+            "{} samples sent to `{}`, {} acknowledged",
+            sent, args.to, accepted
+        ),
+        &[("samples_sent", sent), ("samples_acknowledged", accepted)],
+    );
+    Ok(())
+}
@@ -0,0 +1,342 @@
+use anyhow::Context;
+use dataloader::feature::FeatureSet;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use trainer::network::Network;
+
+use crate::{io::DatasetSource, logging, npz};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help(
+        "Float network written by the trainer, or an `.npz` dump of a PyTorch model with `ft.weight`, `ft.bias`, `out.weight` and `out.bias` arrays."
+    ))]
+    input: PathBuf,
+    #[clap(short('o'), help("Output file for the quantized network."))]
+    output: PathBuf,
+    #[clap(
+        long("ft-scale"),
+        default_value_t = 255,
+        help("Scale of the feature transformer weights and of a fully activated neuron.")
+    )]
+    ft_scale: i32,
+    #[clap(
+        long("output-scale"),
+        default_value_t = 64,
+        help("Scale of the output weights.")
+    )]
+    output_scale: i32,
+    #[clap(
+        long("eval-scale"),
+        default_value_t = 400.0,
+        help("Centipawns per unit of network output, used to report errors in centipawns.")
+    )]
+    eval_scale: f32,
+    #[clap(long("output-weights"), value_enum, default_value_t)]
+    output_weights: WeightType,
+    #[clap(long("ft-layout"), value_enum, default_value_t)]
+    ft_layout: Layout,
+    #[clap(
+        long("verify"),
+        help(
+            "Dataset whose positions are evaluated with both networks to measure the quantization error."
+        )
+    )]
+    verify: Option<DatasetSource>,
+    #[clap(long("verify-samples"), default_value_t = 10_000)]
+    verify_samples: u64,
+}
+
+impl Args {
+    /// The scale of the output bias, which is that of the output weights applied to fully
+    /// activated neurons.
+    fn output_bias_scale(&self) -> anyhow::Result<i32> {
+        if self.ft_scale <= 0 {
+            anyhow::bail!("--ft-scale must be positive");
+        }
+        if self.output_scale <= 0 {
+            anyhow::bail!("--output-scale must be positive");
+        }
+        self.ft_scale
+            .checked_mul(self.output_scale)
+            .context("--ft-scale times --output-scale must fit in a 32-bit integer")
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeightType {
+    I8,
+    #[default]
+    I16,
+}
+
+impl WeightType {
+    fn range(self) -> (i32, i32) {
+        match self {
+            WeightType::I8 => (i8::MIN as i32, i8::MAX as i32),
+            WeightType::I16 => (i16::MIN as i32, i16::MAX as i32),
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// The weights of every neuron for one feature are contiguous, as added to accumulators.
+    #[default]
+    FeatureMajor,
+    /// The weights of every feature for one neuron are contiguous, as stored by PyTorch.
+    NeuronMajor,
+}
+
+/// A network quantized to integers, with the feature transformer in feature-major order.
+struct QuantizedNetwork {
+    hidden: usize,
+    ft_scale: i32,
+    output_bias_scale: i32,
+    feature_weights: Vec<i16>,
+    feature_biases: Vec<i16>,
+    output_weights: Vec<i32>,
+    output_bias: i32,
+}
+
+impl QuantizedNetwork {
+    /// Quantizes a network, returning it along with how many weights had to be clipped.
+    fn new(network: &Network, args: &Args, output_bias_scale: i32) -> (Self, u64) {
+        let mut clipped = 0;
+        let mut quantize = |value: f32, scale: i32, (min, max): (i32, i32)| {
+            let scaled = (value * scale as f32).round();
+            if scaled < min as f32 || scaled > max as f32 {
+                clipped += 1;
+            }
+            scaled.clamp(min as f32, max as f32) as i32
+        };
+        let i16_range = WeightType::I16.range();
+        let feature_weights = network
+            .feature_weights()
+            .iter()
+            .map(|&weight| quantize(weight, args.ft_scale, i16_range) as i16)
+            .collect();
+        let feature_biases = network
+            .feature_biases()
+            .iter()
+            .map(|&bias| quantize(bias, args.ft_scale, i16_range) as i16)
+            .collect();
+        let output_weights = network
+            .output_weights()
+            .iter()
+            .map(|&weight| quantize(weight, args.output_scale, args.output_weights.range()))
+            .collect();
+        let output_bias = quantize(
+            network.output_bias(),
+            output_bias_scale,
+            (i32::MIN, i32::MAX),
+        );
+        let quantized = QuantizedNetwork {
+            hidden: network.hidden(),
+            ft_scale: args.ft_scale,
+            output_bias_scale,
+            feature_weights,
+            feature_biases,
+            output_weights,
+            output_bias,
+        };
+        (quantized, clipped)
+    }
+
+    /// Evaluates a position the way the engine does, returning the output as a float.
+    fn evaluate(&self, stm: &[u32], non_stm: &[u32]) -> f32 {
+        let mut output = self.output_bias as i64;
+        for (features, weights) in [
+            (stm, &self.output_weights[..self.hidden]),
+            (non_stm, &self.output_weights[self.hidden..]),
+        ] {
+            let mut accumulator: Vec<i32> = self.feature_biases.iter().map(|&b| b as i32).collect();
+            for &feature in features {
+                let row = feature as usize * self.hidden;
+                for (acc, &weight) in accumulator
+                    .iter_mut()
+                    .zip(&self.feature_weights[row..row + self.hidden])
+                {
+                    *acc += weight as i32;
+                }
+            }
+            for (&acc, &weight) in accumulator.iter().zip(weights) {
+                output += acc.clamp(0, self.ft_scale) as i64 * weight as i64;
+            }
+        }
+        output as f32 / self.output_bias_scale as f32
+    }
+
+    fn write(&self, writer: &mut impl Write, args: &Args) -> anyhow::Result<()> {
+        match args.ft_layout {
+            Layout::FeatureMajor => {
+                for weight in &self.feature_weights {
+                    writer.write_all(&weight.to_le_bytes())?;
+                }
+            }
+            Layout::NeuronMajor => {
+                let features = self.feature_weights.len() / self.hidden;
+                for neuron in 0..self.hidden {
+                    for feature in 0..features {
+                        let weight = self.feature_weights[feature * self.hidden + neuron];
+                        writer.write_all(&weight.to_le_bytes())?;
+                    }
+                }
+            }
+        }
+        for bias in &self.feature_biases {
+            writer.write_all(&bias.to_le_bytes())?;
+        }
+        for &weight in &self.output_weights {
+            match args.output_weights {
+                WeightType::I8 => writer.write_all(&(weight as i8).to_le_bytes())?,
+                WeightType::I16 => writer.write_all(&(weight as i16).to_le_bytes())?,
+            }
+        }
+        writer.write_all(&self.output_bias.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    let output_bias_scale = args.output_bias_scale()?;
+    let network = load_network(&args.input)?;
+    let (quantized, clipped) = QuantizedNetwork::new(&network, &args, output_bias_scale);
+    if clipped > 0 {
+        eprintln!(
+            "warning: {} weights were out of range for their type and have been clipped",
+            clipped
+        );
+    }
+
+    let file = File::create(&args.output)
+        .with_context(|| format!("failed to create file `{}`", args.output.display()))?;
+    let mut writer = BufWriter::new(file);
+    quantized.write(&mut writer, &args)?;
+    writer.flush()?;
+
+    let Some(dataset) = &args.verify else {
+        logging::summary(
+            &format!(
+                "network with {} hidden neurons quantized to `{}`",
+                network.hidden(),
+                args.output.display()
+            ),
+            &[
+                ("hidden", network.hidden() as u64),
+                ("clipped_weights", clipped),
+            ],
+        );
+        return Ok(());
+    };
+
+    // Networks don't record their feature set, but every feature set has its own number
+    // of inputs.
+    let feature_set = FeatureSet::ALL
+        .into_iter()
+        .find(|set| set.feature_count() == network.feature_count())
+        .with_context(|| {
+            format!(
+                "no feature set has the {} inputs of the network",
+                network.feature_count()
+            )
+        })?;
+    let mut reader = dataset.open()?;
+    let mut samples = 0u64;
+    let mut total_error = 0.0f64;
+    let mut max_error = 0.0f32;
+    let mut stm = Vec::new();
+    let mut non_stm = Vec::new();
+    while samples < args.verify_samples
+        && let Some(packed) = reader.read_sample()?
+    {
+        let Ok(sample) = packed.unpack() else {
+            continue;
+        };
+        stm.clear();
+        non_stm.clear();
+        feature_set.active_features(&sample.position, |s, n| {
+            stm.push(s);
+            non_stm.push(n);
+        });
+        let float = network.evaluate(&stm, &non_stm) * args.eval_scale;
+        let quantized = quantized.evaluate(&stm, &non_stm) * args.eval_scale;
+        let error = (float - quantized).abs();
+        total_error += error as f64;
+        max_error = max_error.max(error);
+        samples += 1;
+    }
+    if samples == 0 {
+        anyhow::bail!("`{}` holds no samples to verify the network on", dataset);
+    }
+
+    let mean_error = total_error / samples as f64;
+    logging::summary(
+        &format!(
+            "network quantized to `{}`, mean error {:.3} cp and max error {:.3} cp over {} positions",
+            args.output.display(),
+            mean_error,
+            max_error,
+            samples
+        ),
+        &[
+            ("hidden", network.hidden() as u64),
+            ("clipped_weights", clipped),
+            ("positions", samples),
+            ("mean_error_millicp", (mean_error * 1000.0).round() as u64),
+            (
+                "max_error_millicp",
+                (max_error as f64 * 1000.0).round() as u64,
+            ),
+        ],
+    );
+    Ok(())
+}
+
+/// Loads a float network from the trainer's format or from an `.npz` dump.
+fn load_network(path: &Path) -> anyhow::Result<Network> {
+    if path.extension().is_none_or(|ext| ext != "npz") {
+        return Network::load(path);
+    }
+
+    let mut arrays = npz::read_npz(path)?;
+    let mut take = |name: &str| {
+        arrays
+            .remove(name)
+            .with_context(|| format!("`{}` has no `{}` array", path.display(), name))
+    };
+    let ft_weight = take("ft.weight")?;
+    let ft_bias = take("ft.bias")?;
+    let out_weight = take("out.weight")?;
+    let out_bias = take("out.bias")?;
+
+    // PyTorch stores linear layers as (outputs, inputs), the transpose of feature-major.
+    let [hidden, feature_count] = ft_weight.shape[..] else {
+        anyhow::bail!("`ft.weight` must be a matrix");
+    };
+    let mut feature_weights = vec![0.0; hidden * feature_count];
+    for neuron in 0..hidden {
+        for feature in 0..feature_count {
+            feature_weights[feature * hidden + neuron] =
+                ft_weight.data[neuron * feature_count + feature];
+        }
+    }
+    let output_bias = *out_bias.data.first().context("`out.bias` is empty")?;
+    Network::from_layers(
+        feature_count,
+        hidden,
+        &feature_weights,
+        &ft_bias.data,
+        &out_weight.data,
+        output_bias,
+    )
+    .with_context(|| {
+        format!(
+            "`{}` doesn't hold a (features->N)x2->1 network",
+            path.display()
+        )
+    })
+}
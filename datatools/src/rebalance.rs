@@ -0,0 +1,333 @@
+use anyhow::Context;
+use dama::{Piece, Position};
+use dataloader::wdl;
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::Rng;
+use std::{collections::HashMap, path::PathBuf, str::FromStr, time::Duration};
+
+use crate::{
+    io::{DatasetSink, DatasetSource},
+    logging, manifest,
+    predicate::MaterialSignature,
+    units::ByteSize,
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Dataset to rebalance, a file or a directory of shards."))]
+    dataset: DatasetSource,
+    #[clap(
+        short('o'),
+        help(
+            "Output file for the resampled dataset, only the distribution is reported without one."
+        )
+    )]
+    output: Option<PathBuf>,
+    #[clap(
+        long("shard-size"),
+        requires("output"),
+        help("Writes the output as a directory of shards of this size, e.g. `4GiB`.")
+    )]
+    shard_size: Option<ByteSize>,
+    #[clap(
+        long("cap"),
+        help(
+            "Caps the share of a class in the output, e.g. `pawn=5%`. Classes are pawn, minor, rook, queen, mixed-endgame and middlegame."
+        )
+    )]
+    caps: Vec<Cap>,
+    #[clap(
+        long("top"),
+        default_value_t = 20,
+        help("Number of most common material signatures reported.")
+    )]
+    top: usize,
+}
+
+/// Phase up to which positions mixing different kinds of pieces count as endgames.
+const ENDGAME_PHASE: u32 = 8;
+
+/// A coarse grouping of positions by the pieces left on the board.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Class {
+    /// Kings and pawns only.
+    Pawn,
+    /// Knights and bishops, besides pawns.
+    Minor,
+    /// Rooks, besides pawns.
+    Rook,
+    /// Queens, besides pawns.
+    Queen,
+    /// Any other mix of pieces, up to [`ENDGAME_PHASE`].
+    MixedEndgame,
+    Middlegame,
+}
+
+impl Class {
+    const ALL: [Class; 6] = [
+        Class::Pawn,
+        Class::Minor,
+        Class::Rook,
+        Class::Queen,
+        Class::MixedEndgame,
+        Class::Middlegame,
+    ];
+
+    fn of(position: &Position) -> Self {
+        let minors =
+            position.pieces(Piece::Knight).count() + position.pieces(Piece::Bishop).count();
+        let rooks = position.pieces(Piece::Rook).count();
+        let queens = position.pieces(Piece::Queen).count();
+        match (minors, rooks, queens) {
+            (0, 0, 0) => Class::Pawn,
+            (_, 0, 0) => Class::Minor,
+            (0, _, 0) => Class::Rook,
+            (0, 0, _) => Class::Queen,
+            _ if wdl::phase(position) <= ENDGAME_PHASE => Class::MixedEndgame,
+            _ => Class::Middlegame,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Class::Pawn => "pawn",
+            Class::Minor => "minor",
+            Class::Rook => "rook",
+            Class::Queen => "queen",
+            Class::MixedEndgame => "mixed-endgame",
+            Class::Middlegame => "middlegame",
+        }
+    }
+}
+
+/// A maximum share of the output for a class, parsed from `<class>=<percent>`.
+#[derive(Clone, Copy, Debug)]
+struct Cap {
+    class: Class,
+    share: f64,
+}
+
+impl FromStr for Cap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, percent) = s
+            .split_once('=')
+            .context("cap must be of the form `<class>=<percent>`")?;
+        let class = Class::ALL
+            .into_iter()
+            .find(|candidate| candidate.name() == class.trim())
+            .with_context(|| format!("unknown class `{}`", class.trim()))?;
+        let percent = percent.trim().trim_end_matches('%');
+        let percent: f64 = percent
+            .parse()
+            .with_context(|| format!("invalid percentage `{}`", percent))?;
+        if !(0.0..=100.0).contains(&percent) {
+            anyhow::bail!("percentage `{}` must be between 0 and 100", percent);
+        }
+        Ok(Cap {
+            class,
+            share: percent / 100.0,
+        })
+    }
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    if args.dataset == DatasetSource::Stdin {
+        anyhow::bail!("the dataset is read twice, so it cannot come from stdin");
+    }
+    if args
+        .output
+        .as_ref()
+        .is_some_and(|output| output.as_os_str() == "-")
+    {
+        anyhow::bail!("the distribution is printed to stdout, so the output must be a file");
+    }
+    let mut caps = [None; Class::ALL.len()];
+    for cap in &args.caps {
+        caps[cap.class as usize] = Some(cap.share);
+    }
+
+    let mut class_counts = [0u64; Class::ALL.len()];
+    let mut signatures: HashMap<MaterialSignature, u64> = HashMap::new();
+    let progress = spinner("counting classes...", "positions counted");
+    let mut reader = args.dataset.open()?;
+    let mut index = 0u64;
+    while let Some(packed) = reader.read_sample()? {
+        let sample = packed
+            .unpack()
+            .with_context(|| format!("failed to unpack sample #{}", index))?;
+        class_counts[Class::of(&sample.position) as usize] += 1;
+        *signatures
+            .entry(MaterialSignature::of(&sample.position))
+            .or_default() += 1;
+        index += 1;
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+
+    let keep = keep_probabilities(&class_counts, &caps)?;
+    print_distribution(&class_counts, &keep, &signatures, args.top);
+
+    let Some(output) = &args.output else {
+        logging::summary(
+            &format!("{} positions in `{}`", index, args.dataset),
+            &[("positions", index)],
+        );
+        return Ok(());
+    };
+
+    let sink = DatasetSink::from_path(output, false).sharded(args.shard_size)?;
+    let mut writer = sink.create(false)?;
+    let progress = spinner("resampling...", "positions resampled");
+    let mut reader = args.dataset.open()?;
+    let mut rng = rand::rng();
+    let mut kept_counts = [0u64; Class::ALL.len()];
+    let mut index = 0u64;
+    while let Some(packed) = reader.read_sample()? {
+        let sample = packed
+            .unpack()
+            .with_context(|| format!("failed to unpack sample #{}", index))?;
+        let class = Class::of(&sample.position) as usize;
+        if rng.random_bool(keep[class]) {
+            writer.write_sample(&packed)?;
+            kept_counts[class] += 1;
+        }
+        index += 1;
+        progress.inc(1);
+    }
+    let written = writer.finish()?;
+    progress.finish_and_clear();
+
+    let settings: Vec<_> = args
+        .caps
+        .iter()
+        .map(|cap| (cap.class.name(), format!("{}%", cap.share * 100.0)))
+        .collect();
+    manifest::write_for_sink(
+        &sink,
+        "rebalance",
+        &settings,
+        vec![manifest::dataset_source(&args.dataset)?],
+    )?;
+
+    let mut fields = vec![("positions_read", index), ("positions_written", written)];
+    fields.extend(
+        Class::ALL
+            .iter()
+            .map(|class| (class.name(), kept_counts[*class as usize])),
+    );
+    logging::summary(
+        &format!("{} of {} positions written to `{}`", written, index, sink),
+        &fields,
+    );
+    Ok(())
+}
+
+fn spinner(message: &'static str, unit: &str) -> ProgressBar {
+    let progress = logging::track(
+        ProgressBar::new_spinner()
+            .with_style(
+                ProgressStyle::with_template(&format!(
+                    "{{spinner}} [{{elapsed_precise:.yellow}}] {{msg}} {{human_pos}} {}",
+                    unit
+                ))
+                .unwrap(),
+            )
+            .with_message(message),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+    progress
+}
+
+/// Probability of keeping a sample of each class so that capped classes make up at
+/// most their share of the output, while keeping as many samples as possible.
+fn keep_probabilities(
+    counts: &[u64; Class::ALL.len()],
+    caps: &[Option<f64>; Class::ALL.len()],
+) -> anyhow::Result<[f64; Class::ALL.len()]> {
+    let mut capped: Vec<usize> = (0..counts.len())
+        .filter(|&class| caps[class].is_some() && counts[class] > 0)
+        .collect();
+    loop {
+        let uncapped: u64 = (0..counts.len())
+            .filter(|class| !capped.contains(class))
+            .map(|class| counts[class])
+            .sum();
+        let share: f64 = capped.iter().map(|&class| caps[class].unwrap()).sum();
+        let total = if uncapped > 0 {
+            if share >= 1.0 {
+                anyhow::bail!("the caps add up to 100% or more, leaving no room for other classes");
+            }
+            uncapped as f64 / (1.0 - share)
+        } else {
+            // Only capped classes are left, the most constrained one sets the output size.
+            capped
+                .iter()
+                .map(|&class| counts[class] as f64 / caps[class].unwrap())
+                .fold(f64::INFINITY, f64::min)
+        };
+
+        // Classes already below their cap are kept whole, which leaves less room for
+        // the others.
+        let before = capped.len();
+        capped.retain(|&class| caps[class].unwrap() * total < counts[class] as f64);
+        if capped.len() == before {
+            let mut keep = [1.0; Class::ALL.len()];
+            for class in capped {
+                keep[class] = caps[class].unwrap() * total / counts[class] as f64;
+            }
+            return Ok(keep);
+        }
+    }
+}
+
+fn print_distribution(
+    counts: &[u64; Class::ALL.len()],
+    keep: &[f64; Class::ALL.len()],
+    signatures: &HashMap<MaterialSignature, u64>,
+    top: usize,
+) {
+    let total: u64 = counts.iter().sum();
+    let kept: f64 = counts
+        .iter()
+        .zip(keep)
+        .map(|(&count, keep)| count as f64 * keep)
+        .sum();
+    let percent = |count: f64, total: f64| 100.0 * count / total.max(1.0);
+
+    logging::report!(
+        "{:<16} {:>12} {:>8} {:>8}",
+        "Class",
+        "Positions",
+        "Share",
+        "Output"
+    );
+    for class in Class::ALL {
+        let count = counts[class as usize];
+        logging::report!(
+            "{:<16} {:>12} {:>7.2}% {:>7.2}%",
+            class.name(),
+            count,
+            percent(count as f64, total as f64),
+            percent(count as f64 * keep[class as usize], kept),
+        );
+    }
+
+    let mut signatures: Vec<_> = signatures.iter().collect();
+    signatures.sort_by(|a, b| {
+        b.1.cmp(a.1)
+            .then_with(|| a.0.to_string().cmp(&b.0.to_string()))
+    });
+    logging::report!();
+    logging::report!("{:<24} {:>12} {:>8}", "Material", "Positions", "Share");
+    for (signature, &count) in signatures.into_iter().take(top) {
+        logging::report!(
+            "{:<24} {:>12} {:>7.2}%",
+            signature.to_string(),
+            count,
+            percent(count as f64, total as f64)
+        );
+    }
+    logging::report!();
+}
@@ -1,31 +1,83 @@
 use anyhow::Context;
-use dama::{Color, Move, Outcome, Position, ToMove, UciMove};
-use dataformat::{PackedSample, Sample};
+use dama::{Color, Move, Outcome, Position};
+use dataformat::{Adjudication, EvalOverflow, FittedEval, PackedSample, Sample};
 use indicatif::{ProgressBar, ProgressStyle};
-use rand::{Rng, seq::IndexedRandom};
+use rand::{
+    Rng, SeedableRng,
+    seq::{IndexedRandom, SliceRandom},
+};
+use rand_xoshiro::Xoshiro256PlusPlus;
 use std::{
-    fmt::Write,
-    path::PathBuf,
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
     process::Stdio,
-    time::{Duration, Instant},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
 };
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    fs,
+    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
     process::{self, Command},
-    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    sync::mpsc::{
+        Receiver, Sender, UnboundedReceiver, UnboundedSender, channel, unbounded_channel,
+    },
 };
 
-use crate::shuffle::shuffle;
+use crate::{
+    io::{DatasetSink, SampleWriter},
+    logging,
+    loss_book::{self, LossBookOptions},
+    manifest,
+    protocol::{Handshake, Protocol, ProtocolKind, SearchInfo},
+    shuffle::{ShuffleOptions, shuffle_sink},
+    status::{self, GenerationStatus, WorkerState},
+    tablebase::Tablebase,
+    units::{ByteRate, ByteSize},
+};
 
 #[derive(clap::Args)]
 pub struct Args {
-    #[clap(short('o'), help("Output data file"))]
+    #[clap(
+        short('o'),
+        help("Output data file, compressed if it ends in `.zst`, or `-` for stdout")
+    )]
     output: PathBuf,
     #[clap(short('a'), long("append"))]
     append: bool,
+    #[clap(
+        long("shard-size"),
+        help("Writes the output as a directory of shards of this size, e.g. `4GiB`")
+    )]
+    shard_size: Option<ByteSize>,
+    #[clap(
+        long("compress"),
+        help(
+            "Compresses the output with zstd as it is written, implied by a `.zst` output extension"
+        )
+    )]
+    compress: bool,
     #[clap(short('c'), long("command"))]
     command: String,
+    #[clap(
+        long("option"),
+        value_parser(parse_option),
+        help("Option set on the engine as `Name=Value`, checked against the options it declares")
+    )]
+    options: Vec<(String, String)>,
+    #[clap(
+        long("protocol"),
+        value_enum,
+        default_value_t,
+        help(
+            "Protocol the engine speaks, `xboard` for engines only speaking CECP, which need `--depth`, `--nodes` or `--movetime` and can't be used with `--multipv-noise`"
+        )
+    )]
+    protocol: ProtocolKind,
     #[clap(long("games"))]
     games: u32,
     #[clap(long("concurrency"), default_value_t = 1)]
@@ -34,26 +86,677 @@ pub struct Args {
     nodes: Option<u64>,
     #[clap(long("depth"))]
     depth: Option<u32>,
+    #[clap(long("movetime"), help("Time limit of each search in milliseconds"))]
+    movetime: Option<u64>,
+    #[clap(
+        long("movetime-jitter"),
+        requires("movetime"),
+        help(
+            "Varies the time limit of each search at random by up to this fraction of `--movetime`, below 1, for engines whose search depends on the time they get"
+        )
+    )]
+    movetime_jitter: Option<f64>,
+    #[clap(
+        long("lag"),
+        help(
+            "Waits a random delay of up to this many milliseconds before each search, taken off its time limit like lag off a clock"
+        )
+    )]
+    lag: Option<u64>,
+    #[clap(
+        long("node-odds"),
+        requires("nodes"),
+        help(
+            "Multiplies the node limit of White's and Black's searches, given as `white,black`, e.g. `10,1`, for games with mistakes for the stronger side to punish"
+        )
+    )]
+    node_odds: Option<NodeOdds>,
     #[clap(long("min-random-moves"))]
     min_random_moves: u32,
     #[clap(long("max-random-moves"))]
     max_random_moves: u32,
+    #[clap(
+        long("book"),
+        help(
+            "EPD file of opening positions, each played once in a random order before any is played again, before the random moves of each game. Appending continues the order of the last run"
+        )
+    )]
+    book: Option<PathBuf>,
+    #[clap(
+        long("loss-book"),
+        conflicts_with("book"),
+        help(
+            "PGN of games the engine lost, with cutechess eval comments, whose positions a few plies before each losing mistake are played as the book"
+        )
+    )]
+    loss_book: Option<PathBuf>,
+    #[clap(
+        long("loss-book-player"),
+        requires("loss_book"),
+        help(
+            "Only takes the games of the loss book lost by the player of this name, as given by the White and Black tags, instead of those of every loser"
+        )
+    )]
+    loss_book_player: Option<String>,
+    #[clap(
+        long("loss-book-plies"),
+        default_value_t = 6,
+        help("Plies before the losing mistake the openings of the loss book are taken at")
+    )]
+    loss_book_plies: u32,
+    #[clap(
+        long("loss-book-eval"),
+        default_value_t = -200,
+        allow_hyphen_values(true),
+        help("Eval in centipawns, from the loser's point of view, at or below which a game of the loss book counts as lost. The mistake is the loser's last move before the evals stay there")
+    )]
+    loss_book_eval: i32,
+    #[clap(
+        long("random-halfmove-clock"),
+        help(
+            "Starts each game with a halfmove clock picked at random up to this many plies, below 100, for training the fifty-move rule"
+        )
+    )]
+    random_halfmove_clock: Option<u32>,
+    #[clap(
+        long("drop-castling"),
+        help(
+            "Removes each castling right of the opening position with this probability, between 0 and 1, so that positions without them aren't only reached after the king moved"
+        )
+    )]
+    drop_castling: Option<f64>,
+    #[clap(
+        long("dry-run"),
+        help(
+            "Checks the engine and book and reports what would be played, without touching the output"
+        )
+    )]
+    dry_run: bool,
+    #[clap(
+        long("http"),
+        help(
+            "Address serving a JSON status page with the games played, positions per second and worker health, e.g. `127.0.0.1:8080`."
+        )
+    )]
+    http: Option<String>,
+    #[clap(
+        long("resign-eval"),
+        help(
+            "Adjudicates a win once both engines, or the adjudicator, agree on an eval of at least this many centipawns for one side"
+        )
+    )]
+    resign_eval: Option<i32>,
+    #[clap(
+        long("resign-moves"),
+        default_value_t = 3,
+        help("Consecutive moves of each engine the resign eval must hold for")
+    )]
+    resign_moves: u32,
+    #[clap(
+        long("draw-eval"),
+        help(
+            "Adjudicates a draw once both engines, or the adjudicator, agree on an eval within this many centipawns of zero"
+        )
+    )]
+    draw_eval: Option<i32>,
+    #[clap(
+        long("draw-moves"),
+        default_value_t = 8,
+        help("Consecutive moves of each engine the draw eval must hold for")
+    )]
+    draw_moves: u32,
+    #[clap(
+        long("draw-after"),
+        default_value_t = 40,
+        help("Move number from which draws may be adjudicated")
+    )]
+    draw_after: u32,
+    #[clap(
+        long("trajectories"),
+        help(
+            "Writes how each game ended along with the adjudication evals of its last plies to this CSV file, for tuning the adjudication rules offline on real games"
+        )
+    )]
+    trajectories: Option<PathBuf>,
+    #[clap(
+        long("trajectory-plies"),
+        default_value_t = 32,
+        help(
+            "Number of plies at the end of each game whose evals are written with `--trajectories`"
+        )
+    )]
+    trajectory_plies: u32,
+    #[clap(
+        long("adjudicator"),
+        help(
+            "Engine searching every position of the games for resign and draw adjudication to go by its evals instead of the players', given like a line of the opponents file"
+        )
+    )]
+    adjudicator: Option<String>,
+    #[clap(
+        long("adjudicator-nodes"),
+        requires("adjudicator"),
+        help("Node limit of the adjudicator's searches, which otherwise get the players' limits")
+    )]
+    adjudicator_nodes: Option<u64>,
+    #[clap(
+        long("adjudicator-depth"),
+        requires("adjudicator"),
+        help("Depth limit of the adjudicator's searches, which otherwise get the players' limits")
+    )]
+    adjudicator_depth: Option<u32>,
+    #[clap(
+        long("syzygy"),
+        help(
+            "Directory of Syzygy tablebases, games reaching them are adjudicated with their result"
+        )
+    )]
+    syzygy: Option<PathBuf>,
+    #[clap(
+        long("io-limit"),
+        help("Caps the rate at which the output is written and shuffled, e.g. `100M/s`")
+    )]
+    io_limit: Option<ByteRate>,
+    #[clap(
+        long("multipv-noise"),
+        help(
+            "Runs the engines with MultiPV `k` early in each game and samples the move played from the `k` lines at temperature `temp` in centipawns, given as `k,temp`"
+        )
+    )]
+    multipv_noise: Option<MultiPvNoise>,
+    #[clap(
+        long("multipv-plies"),
+        default_value_t = 16,
+        requires("multipv_noise"),
+        help(
+            "Number of plies at the start of each game whose moves are sampled with `--multipv-noise`"
+        )
+    )]
+    multipv_plies: u32,
+    #[clap(
+        long("opponents"),
+        help(
+            "File of sparring engines, one command per line with options after ` -- ` as `Name=Value` and the program prefixed with `xboard:` for xboard engines, played against round-robin instead of the engine itself"
+        )
+    )]
+    opponents: Option<PathBuf>,
+    #[clap(
+        long("no-eval"),
+        help(
+            "Writes every quiet position without the engine's eval, including those it reports a mate in, for training on outcomes alone"
+        )
+    )]
+    no_eval: bool,
+    #[clap(
+        long("eval-overflow"),
+        default_value_t,
+        help(
+            "What to do with evals past ±32767 centipawns, which packed samples can't hold: `clamp` them, `drop-eval` to keep the position without one, `skip` the position or stop with an `error`"
+        )
+    )]
+    eval_overflow: EvalOverflow,
 }
 
-pub async fn run(args: Args) -> anyhow::Result<()> {
-    let mut output_file = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .truncate(!args.append)
-        .append(args.append)
-        .open(&args.output)
+/// A way to start an engine: a command with its arguments, the protocol it speaks and
+/// options to set once it is running.
+#[derive(Clone, Debug)]
+struct EngineConfig {
+    program: String,
+    args: Vec<String>,
+    protocol: ProtocolKind,
+    options: Vec<(String, String)>,
+}
+
+impl EngineConfig {
+    fn command(command: &str) -> Self {
+        EngineConfig {
+            program: command.to_string(),
+            args: vec![],
+            protocol: ProtocolKind::Uci,
+            options: vec![],
+        }
+    }
+
+    /// Parses `[<protocol>:]<program> [<arg>...] [-- <Name>=<Value>...]`, the protocol
+    /// being `uci` or `xboard`.
+    fn parse(line: &str) -> anyhow::Result<Self> {
+        let mut words = line.split_whitespace();
+        let program = words.next().context("missing engine command")?;
+        let (protocol, program) = match program.split_once(':') {
+            Some(("uci", program)) => (ProtocolKind::Uci, program),
+            Some(("xboard", program)) => (ProtocolKind::Xboard, program),
+            _ => (ProtocolKind::Uci, program),
+        };
+        let args = words
+            .by_ref()
+            .take_while(|&word| word != "--")
+            .map(str::to_string)
+            .collect();
+        let options = words.map(parse_option).collect::<anyhow::Result<_>>()?;
+        Ok(EngineConfig {
+            program: program.to_string(),
+            args,
+            protocol,
+            options,
+        })
+    }
+
+    async fn spawn(&self) -> anyhow::Result<Engine> {
+        let mut engine = Engine::new(
+            Command::new(&self.program)
+                .args(&self.args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("failed to start engine `{}`", self))?,
+            self.protocol.protocol(),
+        )
         .await
-        .with_context(|| format!("failed to open output path `{}`", args.output.display()))?;
+        .with_context(|| format!("failed to start engine `{}`", self))?;
+        for (name, value) in &self.options {
+            engine
+                .set_option(name, value)
+                .await
+                .with_context(|| format!("failed to configure engine `{}`", self))?;
+        }
+        Ok(engine)
+    }
+}
+
+fn parse_option(option: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = option.split_once('=').with_context(|| {
+        format!(
+            "engine option `{}` must be of the form `Name=Value`",
+            option
+        )
+    })?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+impl fmt::Display for EngineConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.protocol != ProtocolKind::Uci {
+            write!(f, "{}:", self.protocol.name())?;
+        }
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        if !self.options.is_empty() {
+            write!(f, " --")?;
+            for (name, value) in &self.options {
+                write!(f, " {}={}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sampling of the move played among an engine's best lines, parsed from `k,temp`.
+#[derive(Clone, Copy, Debug)]
+struct MultiPvNoise {
+    lines: u32,
+    temperature: f64,
+}
+
+impl FromStr for MultiPvNoise {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (lines, temperature) = s
+            .split_once(',')
+            .context("MultiPV noise must be of the form `k,temp`")?;
+        let lines: u32 = lines
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid number of lines `{}`", lines.trim()))?;
+        let temperature: f64 = temperature
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid temperature `{}`", temperature.trim()))?;
+        if lines < 2 {
+            anyhow::bail!("MultiPV noise needs at least 2 lines to choose from");
+        }
+        if temperature <= 0.0 {
+            anyhow::bail!("MultiPV noise temperature must be positive");
+        }
+        Ok(MultiPvNoise { lines, temperature })
+    }
+}
+
+impl MultiPvNoise {
+    /// Picks one of the lines with a probability proportional to
+    /// `exp((score - best) / temperature)`.
+    fn choose(&self, lines: &[(Move, i32)], rng: &mut impl Rng) -> Option<Move> {
+        let best = lines.iter().map(|&(_, score)| score).max()?;
+        let weights: Vec<f64> = lines
+            .iter()
+            .map(|&(_, score)| ((score - best) as f64 / self.temperature).exp())
+            .collect();
+        let mut pick = rng.random_range(0.0..weights.iter().sum::<f64>());
+        for (&(mv, _), weight) in lines.iter().zip(&weights) {
+            if pick < *weight {
+                return Some(mv);
+            }
+            pick -= weight;
+        }
+        lines.last().map(|&(mv, _)| mv)
+    }
+}
+
+/// Multipliers of the node limit for each side, parsed from `white,black`.
+#[derive(Clone, Copy, Debug)]
+struct NodeOdds {
+    white: f64,
+    black: f64,
+}
+
+impl FromStr for NodeOdds {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (white, black) = s
+            .split_once(',')
+            .context("node odds must be of the form `white,black`")?;
+        let parse = |multiplier: &str| -> anyhow::Result<f64> {
+            let multiplier = multiplier.trim();
+            multiplier
+                .parse()
+                .ok()
+                .filter(|multiplier: &f64| *multiplier > 0.0 && multiplier.is_finite())
+                .with_context(|| {
+                    format!(
+                        "invalid node multiplier `{}`, it must be positive",
+                        multiplier
+                    )
+                })
+        };
+        Ok(NodeOdds {
+            white: parse(white)?,
+            black: parse(black)?,
+        })
+    }
+}
+
+impl NodeOdds {
+    /// The node limit of `color`'s searches, at least one node.
+    fn nodes(&self, nodes: u64, color: Color) -> u64 {
+        let multiplier = match color {
+            Color::White => self.white,
+            Color::Black => self.black,
+        };
+        ((nodes as f64 * multiplier).round() as u64).max(1)
+    }
+}
+
+/// The time limit of the players' searches, with the noise added to it.
+#[derive(Clone, Copy, Debug, Default)]
+struct Timing {
+    movetime: Option<u64>,
+    /// Fraction of the movetime each search's limit may be off by, either way.
+    jitter: f64,
+    /// Longest delay before a search in milliseconds.
+    lag: u64,
+}
+
+impl Timing {
+    /// The delay before a search and the time limit of the search, at least a
+    /// millisecond.
+    fn pick(&self, rng: &mut impl Rng) -> (Duration, Option<u64>) {
+        let lag = if self.lag > 0 {
+            rng.random_range(0..=self.lag)
+        } else {
+            0
+        };
+        let movetime = self.movetime.map(|movetime| {
+            let factor = if self.jitter > 0.0 {
+                rng.random_range(1.0 - self.jitter..=1.0 + self.jitter)
+            } else {
+                1.0
+            };
+            ((movetime as f64 * factor).round() as u64)
+                .saturating_sub(lag)
+                .max(1)
+        });
+        (Duration::from_millis(lag), movetime)
+    }
+}
+
+/// Thresholds for ending games early, each disabled when unset.
+#[derive(Clone, Copy, Debug, Default)]
+struct AdjudicationRules {
+    resign_eval: Option<i32>,
+    resign_plies: u32,
+    draw_eval: Option<i32>,
+    draw_plies: u32,
+    draw_after: u32,
+}
+
+#[derive(Clone)]
+struct Settings {
+    engine: EngineConfig,
+    /// Engines the main one plays against, itself if there are no sparring engines.
+    opponents: Arc<Vec<EngineConfig>>,
+    /// Index of the next game across all workers, which picks its opponent and colors.
+    next_game: Arc<AtomicU64>,
+    nodes: Option<u64>,
+    depth: Option<u32>,
+    node_odds: Option<NodeOdds>,
+    timing: Timing,
+    min_random_moves: u32,
+    max_random_moves: u32,
+    book: Arc<Book>,
+    openings: Arc<PlayedOpenings>,
+    random_halfmove_clock: Option<u32>,
+    drop_castling: f64,
+    rules: AdjudicationRules,
+    /// Engine whose evals the rules go by instead of the players', with the limits of its
+    /// searches.
+    adjudicator: Option<EngineConfig>,
+    adjudicator_nodes: Option<u64>,
+    adjudicator_depth: Option<u32>,
+    adjudicator_movetime: Option<u64>,
+    tablebase: Option<Arc<Tablebase>>,
+    multipv_noise: Option<MultiPvNoise>,
+    multipv_plies: u32,
+    no_eval: bool,
+    eval_overflow: EvalOverflow,
+    /// Plies at the end of each game whose adjudication evals are sent along with its
+    /// outcome, if they are written out.
+    trajectory_plies: Option<u32>,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    if args.resign_eval.is_some() && args.resign_moves == 0 {
+        anyhow::bail!("--resign-moves must be at least 1");
+    }
+    if args.draw_eval.is_some() && args.draw_moves == 0 {
+        anyhow::bail!("--draw-moves must be at least 1");
+    }
+    if args.random_halfmove_clock.is_some_and(|plies| plies >= 100) {
+        anyhow::bail!(
+            "--random-halfmove-clock must be below 100, games would be drawn before they start"
+        );
+    }
+    if args
+        .drop_castling
+        .is_some_and(|probability| !(0.0..=1.0).contains(&probability))
+    {
+        anyhow::bail!("--drop-castling must be between 0 and 1");
+    }
+    let adjudicator = match &args.adjudicator {
+        Some(line) => Some(EngineConfig::parse(line).context("invalid --adjudicator")?),
+        None => None,
+    };
+    if adjudicator.is_some() && args.resign_eval.is_none() && args.draw_eval.is_none() {
+        anyhow::bail!("--adjudicator needs --resign-eval or --draw-eval to adjudicate by");
+    }
+    if args
+        .movetime_jitter
+        .is_some_and(|jitter| !(0.0..1.0).contains(&jitter))
+    {
+        anyhow::bail!("--movetime-jitter must be at least 0 and below 1");
+    }
+    let (adjudicator_nodes, adjudicator_depth, adjudicator_movetime) =
+        match (args.adjudicator_nodes, args.adjudicator_depth) {
+            (None, None) => (args.nodes, args.depth, args.movetime),
+            (nodes, depth) => (nodes, depth, None),
+        };
+    if adjudicator
+        .as_ref()
+        .is_some_and(|config| config.protocol == ProtocolKind::Xboard)
+        && adjudicator_nodes.is_none()
+        && adjudicator_depth.is_none()
+        && adjudicator_movetime.is_none()
+    {
+        anyhow::bail!(
+            "xboard engines need --adjudicator-depth or --adjudicator-nodes to limit their searches"
+        );
+    }
+    let sink = DatasetSink::from_path(&args.output, args.compress).sharded(args.shard_size)?;
+    let mut book_source = None;
+    let book = match &args.book {
+        Some(path) => {
+            let source = manifest::file_source(path)?;
+            let hash = source.hash.unwrap_or_default();
+            book_source = Some(source);
+            let rotation = if args.append {
+                previous_rotation(&sink, hash)?
+            } else {
+                None
+            };
+            let (seed, taken) = rotation.unwrap_or_else(|| (rand::random(), 0));
+            Book::new(load_book(path).await?, hash, seed, taken)
+        }
+        None => match &args.loss_book {
+            Some(path) => {
+                let source = manifest::file_source(path)?;
+                let hash = source.hash.unwrap_or_default();
+                book_source = Some(source);
+                let options = LossBookOptions {
+                    player: args.loss_book_player.clone(),
+                    plies: args.loss_book_plies,
+                    lost_eval: args.loss_book_eval,
+                };
+                let loss_book = loss_book::load(path, &options)?;
+                if loss_book.skipped > 0 {
+                    eprintln!(
+                        "warning: {} of {} lost games in `{}` have no eval collapse to find the mistake by",
+                        loss_book.skipped,
+                        loss_book.games,
+                        path.display()
+                    );
+                }
+                if loss_book.positions.is_empty() {
+                    anyhow::bail!(
+                        "loss book `{}` contains no positions before a losing mistake",
+                        path.display()
+                    );
+                }
+                let rotation = if args.append {
+                    previous_rotation(&sink, hash)?
+                } else {
+                    None
+                };
+                let (seed, taken) = rotation.unwrap_or_else(|| (rand::random(), 0));
+                Book::new(loss_book.positions, hash, seed, taken)
+            }
+            None => Book::new(Vec::new(), 0, 0, 0),
+        },
+    };
+    let engine = EngineConfig {
+        protocol: args.protocol,
+        options: args.options.clone(),
+        ..EngineConfig::command(&args.command)
+    };
+    let opponents = match &args.opponents {
+        Some(path) => load_opponents(path).await?,
+        None => vec![engine.clone()],
+    };
+    if std::iter::once(&engine)
+        .chain(&opponents)
+        .any(|config| config.protocol == ProtocolKind::Xboard)
+    {
+        if args.nodes.is_none() && args.depth.is_none() && args.movetime.is_none() {
+            anyhow::bail!(
+                "xboard engines need --depth, --nodes or --movetime to limit their searches"
+            );
+        }
+        if args.multipv_noise.is_some() {
+            anyhow::bail!(
+                "--multipv-noise can't be used with xboard engines, which search a single line"
+            );
+        }
+    }
+    let tablebase = match &args.syzygy {
+        Some(path) => Some(Arc::new(Tablebase::open(path)?)),
+        None => None,
+    };
+    let settings = Settings {
+        engine,
+        opponents: Arc::new(opponents),
+        next_game: Arc::new(AtomicU64::new(0)),
+        nodes: args.nodes,
+        depth: args.depth,
+        node_odds: args.node_odds,
+        timing: Timing {
+            movetime: args.movetime,
+            jitter: args.movetime_jitter.unwrap_or(0.0),
+            lag: args.lag.unwrap_or(0),
+        },
+        min_random_moves: args.min_random_moves,
+        max_random_moves: args.max_random_moves,
+        book: Arc::new(book),
+        openings: Arc::new(PlayedOpenings::default()),
+        random_halfmove_clock: args.random_halfmove_clock,
+        drop_castling: args.drop_castling.unwrap_or(0.0),
+        rules: AdjudicationRules {
+            resign_eval: args.resign_eval,
+            resign_plies: 2 * args.resign_moves,
+            draw_eval: args.draw_eval,
+            draw_plies: 2 * args.draw_moves,
+            draw_after: args.draw_after,
+        },
+        adjudicator,
+        adjudicator_nodes,
+        adjudicator_depth,
+        adjudicator_movetime,
+        tablebase,
+        multipv_noise: args.multipv_noise,
+        multipv_plies: args.multipv_plies,
+        no_eval: args.no_eval,
+        eval_overflow: args.eval_overflow,
+        trajectory_plies: args.trajectories.as_ref().map(|_| args.trajectory_plies),
+    };
+
+    if args.dry_run {
+        return dry_run(&args, &settings).await;
+    }
+    // Mistakes in the engine options would otherwise only show up as failed workers.
+    check_engines(&args, &settings).await?;
+
+    let writer = sink.create_with_limit(args.append, args.io_limit)?;
+    let trajectories = match &args.trajectories {
+        Some(path) => Some(
+            open_trajectories(path, args.append)
+                .await
+                .with_context(|| format!("failed to open trajectory file `{}`", path.display()))?,
+        ),
+        None => None,
+    };
+
+    let status = Arc::new(GenerationStatus::new(
+        (0..args.concurrency).map(|n| format!("worker {}", n)),
+    ));
+    if let Some(addr) = &args.http {
+        let status = status.clone();
+        let addr = status::serve(addr, move || status.page())?;
+        eprintln!("serving status on http://{}", addr);
+    }
 
     let games_per_task = args.games / args.concurrency;
     let games_rem = args.games % args.concurrency;
-    let (sample_send, sample_recv) = unbounded_channel();
+    let (sample_send, sample_recv) = channel(QUEUED_GAMES);
     let (outcome_send, outcome_recv) = unbounded_channel();
     for n in 0..args.concurrency {
         let rounds = if n < games_rem {
@@ -63,68 +766,378 @@ pub async fn run(args: Args) -> anyhow::Result<()> {
         };
         let sample_send = sample_send.clone();
         let outcome_send = outcome_send.clone();
-        let command = args.command.clone();
-        tokio::spawn(run_games(
-            sample_send,
-            outcome_send,
-            command,
-            rounds,
-            args.nodes,
-            args.depth,
-            args.min_random_moves,
-            args.max_random_moves,
-        ));
+        let settings = settings.clone();
+        let status = status.clone();
+        let worker = n as usize;
+        tokio::spawn(async move {
+            match run_games(sample_send, outcome_send, settings, rounds, worker, &status).await {
+                Ok(()) => status.set_state(worker, WorkerState::Finished),
+                Err(err) => {
+                    status.set_state(worker, WorkerState::Failed);
+                    status
+                        .recent_errors
+                        .record(format!("worker {}: {:#}", worker, err));
+                }
+            }
+        });
     }
     drop(outcome_send);
     drop(sample_send);
 
     tokio::try_join!(
-        show_progress(outcome_recv, args.games),
-        write_to_file(sample_recv, &mut output_file),
+        show_progress(outcome_recv, args.games, trajectories),
+        write_to_sink(sample_recv, writer, status.clone()),
     )?;
 
-    shuffle(output_file, None).await?;
+    report_searches(&args, &status);
+    let repeated = settings.openings.repeated.load(Ordering::Relaxed);
+    let book_rotation = settings.book.rotation();
+    if repeated > 0 {
+        eprintln!(
+            "warning: {} games replayed an opening already played, as no other was found in {} tries, consider more random moves or a larger book",
+            repeated, OPENING_TRIES
+        );
+    }
+
+    shuffle_sink(&sink, &ShuffleOptions::with_io_limit(args.io_limit)).await?;
+
+    let mut sources = if args.append {
+        manifest::previous_sources(&sink)?
+    } else {
+        Vec::new()
+    };
+    if let Some(book) = book_source {
+        sources.push(book);
+    }
+    if let Some(opponents) = &args.opponents {
+        sources.push(manifest::file_source(opponents)?);
+    }
+    let mut settings = vec![
+        ("engine", args.command.clone()),
+        ("games", args.games.to_string()),
+        ("min_random_moves", args.min_random_moves.to_string()),
+        ("max_random_moves", args.max_random_moves.to_string()),
+    ];
+    if !args.options.is_empty() {
+        let options: Vec<_> = args
+            .options
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        settings.push(("engine_options", options.join(" ")));
+    }
+    if args.protocol != ProtocolKind::Uci {
+        settings.push(("protocol", args.protocol.name().to_string()));
+    }
+    if let Some(nodes) = args.nodes {
+        settings.push(("nodes", nodes.to_string()));
+    }
+    if let Some(depth) = args.depth {
+        settings.push(("depth", depth.to_string()));
+    }
+    if let Some(movetime) = args.movetime {
+        settings.push(("movetime", movetime.to_string()));
+    }
+    if let Some(jitter) = args.movetime_jitter {
+        settings.push(("movetime_jitter", jitter.to_string()));
+    }
+    if let Some(lag) = args.lag {
+        settings.push(("lag", lag.to_string()));
+    }
+    if let Some(odds) = args.node_odds {
+        settings.push(("node_odds", format!("{},{}", odds.white, odds.black)));
+    }
+    if let Some(resign_eval) = args.resign_eval {
+        settings.push(("resign_eval", resign_eval.to_string()));
+        settings.push(("resign_moves", args.resign_moves.to_string()));
+    }
+    if let Some(draw_eval) = args.draw_eval {
+        settings.push(("draw_eval", draw_eval.to_string()));
+        settings.push(("draw_moves", args.draw_moves.to_string()));
+        settings.push(("draw_after", args.draw_after.to_string()));
+    }
+    if let Some(adjudicator) = &args.adjudicator {
+        settings.push(("adjudicator", adjudicator.clone()));
+        if let Some(nodes) = adjudicator_nodes {
+            settings.push(("adjudicator_nodes", nodes.to_string()));
+        }
+        if let Some(depth) = adjudicator_depth {
+            settings.push(("adjudicator_depth", depth.to_string()));
+        }
+        if let Some(movetime) = adjudicator_movetime {
+            settings.push(("adjudicator_movetime", movetime.to_string()));
+        }
+    }
+    if args.book.is_some() || args.loss_book.is_some() {
+        settings.push((BOOK_ROTATION_SETTING, book_rotation));
+    }
+    if args.loss_book.is_some() {
+        if let Some(player) = &args.loss_book_player {
+            settings.push(("loss_book_player", player.clone()));
+        }
+        settings.push(("loss_book_plies", args.loss_book_plies.to_string()));
+        settings.push(("loss_book_eval", args.loss_book_eval.to_string()));
+    }
+    if let Some(plies) = args.random_halfmove_clock {
+        settings.push(("random_halfmove_clock", plies.to_string()));
+    }
+    if let Some(probability) = args.drop_castling {
+        settings.push(("drop_castling", probability.to_string()));
+    }
+    if let Some(syzygy) = &args.syzygy {
+        settings.push(("syzygy", syzygy.display().to_string()));
+    }
+    if let Some(noise) = args.multipv_noise {
+        settings.push((
+            "multipv_noise",
+            format!("{},{}", noise.lines, noise.temperature),
+        ));
+        settings.push(("multipv_plies", args.multipv_plies.to_string()));
+    }
+    if args.no_eval {
+        settings.push(("no_eval", "true".to_string()));
+    }
+    if args.eval_overflow != EvalOverflow::Clamp {
+        settings.push(("eval_overflow", args.eval_overflow.to_string()));
+    }
+    if repeated > 0 {
+        settings.push(("repeated_openings", repeated.to_string()));
+    }
+    for (name, average) in [
+        ("avg_depth", &status.searches.depth),
+        ("avg_nodes", &status.searches.nodes),
+        ("avg_search_ms", &status.searches.time_ms),
+    ] {
+        if let Some(average) = average.get() {
+            settings.push((name, format!("{:.1}", average)));
+        }
+    }
+    manifest::write_for_sink(&sink, "selfplay", &settings, sources)
+}
+
+/// Starts the engine once to check that it speaks its protocol, then reports what would be played.
+async fn dry_run(args: &Args, settings: &Settings) -> anyhow::Result<()> {
+    if args.min_random_moves > args.max_random_moves {
+        anyhow::bail!("--min-random-moves must not be greater than --max-random-moves");
+    }
+    if args.concurrency == 0 {
+        anyhow::bail!("--concurrency must be at least 1");
+    }
+
+    check_engines(args, settings).await?;
 
+    logging::summary(
+        &format!(
+            "{} games would be played by {} concurrent engine pairs against {} opponents from {} book positions, {} `{}`",
+            args.games,
+            args.concurrency,
+            if args.opponents.is_some() {
+                settings.opponents.len()
+            } else {
+                0
+            },
+            settings.book.len(),
+            if args.append {
+                "appending to"
+            } else {
+                "writing to"
+            },
+            DatasetSink::from_path(&args.output, args.compress).sharded(args.shard_size)?
+        ),
+        &[
+            ("games", args.games as u64),
+            ("concurrency", args.concurrency as u64),
+            (
+                "opponents",
+                if args.opponents.is_some() {
+                    settings.opponents.len() as u64
+                } else {
+                    0
+                },
+            ),
+            ("book_positions", settings.book.len() as u64),
+        ],
+    );
     Ok(())
 }
 
-async fn write_to_file(
-    mut sample_recv: UnboundedReceiver<PackedSample>,
-    output_file: &mut File,
+/// Depth below which searches are too shallow to label positions with, unless asked for.
+const SHALLOW_DEPTH: f64 = 2.0;
+
+/// Reports how deep the engines actually searched, which the search limits alone don't
+/// tell when an engine ignores or misreads them.
+fn report_searches(args: &Args, status: &GenerationStatus) {
+    let searches = &status.searches;
+    let (Some(depth), nodes, time_ms) = (
+        searches.depth.get(),
+        searches.nodes.get(),
+        searches.time_ms.get(),
+    ) else {
+        if args.games > 0 {
+            eprintln!("warning: the engines didn't report the depth of their searches");
+        }
+        return;
+    };
+    if depth < SHALLOW_DEPTH && args.depth.is_none_or(|limit| limit as f64 >= SHALLOW_DEPTH) {
+        eprintln!(
+            "warning: the engines only searched to an average depth of {:.1}, check their search limits",
+            depth
+        );
+    }
+    logging::summary(
+        &format!(
+            "searched to an average depth of {:.1}, {:.0} nodes and {:.0} ms per move",
+            depth,
+            nodes.unwrap_or_default(),
+            time_ms.unwrap_or_default()
+        ),
+        &[
+            ("avg_depth", depth.round() as u64),
+            ("avg_nodes", nodes.unwrap_or_default().round() as u64),
+            ("avg_search_ms", time_ms.unwrap_or_default().round() as u64),
+        ],
+    );
+}
+
+/// Starts every engine once to check that it speaks its protocol and accepts its options, passing
+/// on what it had to say while starting up.
+async fn check_engines(args: &Args, settings: &Settings) -> anyhow::Result<()> {
+    let mut engines = vec![&settings.engine];
+    if args.opponents.is_some() {
+        engines.extend(settings.opponents.iter());
+    }
+    engines.extend(&settings.adjudicator);
+    for config in engines {
+        let mut engine = config.spawn().await?;
+        for message in &engine.messages {
+            eprintln!("{}: {}", config.program, message);
+        }
+        engine.quit().await?;
+    }
+    Ok(())
+}
+
+/// Games whose samples may wait for the writer before the workers block on it.
+const QUEUED_GAMES: usize = 1024;
+
+/// Writes the samples of every game on a blocking thread of its own, so compressing and
+/// writing them never holds up the workers driving the engines.
+async fn write_to_sink(
+    mut sample_recv: Receiver<Vec<PackedSample>>,
+    mut writer: SampleWriter,
+    status: Arc<GenerationStatus>,
 ) -> anyhow::Result<()> {
-    let mut writer = BufWriter::new(output_file);
-    let mut written = 0;
-    while let Some(sample) = sample_recv.recv().await {
-        writer.write_all(bytemuck::bytes_of(&sample)).await?;
-        written += 1;
-    }
-    println!("{} positions written", written);
-    writer.flush().await?;
+    let written = tokio::task::spawn_blocking(move || {
+        while let Some(samples) = sample_recv.blocking_recv() {
+            writer.write_samples(&samples)?;
+            status.add_positions(samples.len() as u64);
+        }
+        writer.finish()
+    })
+    .await??;
+    logging::summary(
+        &format!("{} positions written", written),
+        &[("positions_written", written)],
+    );
     anyhow::Result::<()>::Ok(())
 }
 
+/// How a game ended, either on the board or by adjudication.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Termination {
+    Checkmate,
+    Stalemate,
+    FiftyMoves,
+    Repetition,
+    InsufficientMaterial,
+    Resign,
+    DrawAdjudication,
+    Tablebase,
+}
+
+impl Termination {
+    const ALL: [Termination; 8] = [
+        Termination::Checkmate,
+        Termination::Stalemate,
+        Termination::FiftyMoves,
+        Termination::Repetition,
+        Termination::InsufficientMaterial,
+        Termination::Resign,
+        Termination::DrawAdjudication,
+        Termination::Tablebase,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Termination::Checkmate => "checkmate",
+            Termination::Stalemate => "stalemate",
+            Termination::FiftyMoves => "fifty_moves",
+            Termination::Repetition => "repetition",
+            Termination::InsufficientMaterial => "insufficient_material",
+            Termination::Resign => "resign",
+            Termination::DrawAdjudication => "draw_adjudication",
+            Termination::Tablebase => "tablebase",
+        }
+    }
+
+    /// How the samples of games ending this way are marked.
+    fn adjudication(self) -> Adjudication {
+        match self {
+            Termination::Resign => Adjudication::Resign,
+            Termination::DrawAdjudication => Adjudication::Draw,
+            Termination::Tablebase => Adjudication::Tablebase,
+            _ => Adjudication::None,
+        }
+    }
+}
+
 async fn show_progress(
-    mut outcome_recv: UnboundedReceiver<Outcome>,
+    mut outcome_recv: UnboundedReceiver<GameResult>,
     games: u32,
+    mut trajectories: Option<io::BufWriter<fs::File>>,
 ) -> anyhow::Result<()> {
-    let progress = ProgressBar::new(games as u64)
-        .with_style(
-            ProgressStyle::with_template(
-                "\
-            {spinner} [{elapsed_precise:.yellow}] [{bar:20}] \
-            running games... {pos}/{len} games finished {msg}",
+    let progress = logging::track(
+        ProgressBar::new(games as u64)
+            .with_style(
+                ProgressStyle::with_template(
+                    "\
+                {spinner} [{elapsed_precise:.yellow}] [{bar:20}] \
+                running games... {pos}/{len} games finished {msg}",
+                )
+                .unwrap()
+                .progress_chars("##-"),
             )
-            .unwrap()
-            .progress_chars("##-"),
-        )
-        .with_message("| 0W - 0B - 0D");
+            .with_message("| 0W - 0B - 0D"),
+    );
     progress.enable_steady_tick(Duration::from_millis(50));
 
     let mut white_win = 0;
     let mut black_win = 0;
     let mut draw = 0;
+    let mut terminations = [0u64; Termination::ALL.len()];
 
-    while let Some(outcome) = outcome_recv.recv().await {
+    while let Some(GameResult {
+        outcome,
+        termination,
+        plies,
+        trajectory,
+    }) = outcome_recv.recv().await
+    {
+        terminations[termination as usize] += 1;
+        if let (Some(file), Some(trajectory)) = (&mut trajectories, trajectory) {
+            let evals: Vec<_> = trajectory
+                .iter()
+                .map(|eval| eval.map_or("none".to_string(), |eval| eval.to_string()))
+                .collect();
+            let line = format!(
+                "{},{},{},{}\n",
+                termination.name(),
+                outcome,
+                plies,
+                evals.join(" ")
+            );
+            file.write_all(line.as_bytes()).await?;
+        }
         match outcome {
             Outcome::Winner(Color::White) => white_win += 1,
             Outcome::Winner(Color::Black) => black_win += 1,
@@ -134,95 +1147,284 @@ async fn show_progress(
         progress.set_message(format!("| {}W - {}B - {}D", white_win, black_win, draw));
     }
     progress.finish();
+    if let Some(file) = &mut trajectories {
+        file.flush().await?;
+    }
+
+    let counts: Vec<_> = Termination::ALL
+        .iter()
+        .map(|&termination| (termination.name(), terminations[termination as usize]))
+        .collect();
+    let breakdown: Vec<_> = counts
+        .iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(name, count)| format!("{} {}", count, name.replace('_', " ")))
+        .collect();
+    logging::summary(&format!("games ended by {}", breakdown.join(", ")), &counts);
 
     anyhow::Result::<()>::Ok(())
 }
 
+/// How a game ended, sent from the workers to the progress bar.
+struct GameResult {
+    outcome: Outcome,
+    termination: Termination,
+    plies: usize,
+    /// Adjudication evals of the last plies from white's point of view, `None` where
+    /// there was no centipawn eval, when trajectories are written.
+    trajectory: Option<Vec<Option<i32>>>,
+}
+
+/// Opens the CSV file trajectories are written to, writing its header unless it already
+/// holds the trajectories of the games appended to.
+async fn open_trajectories(path: &Path, append: bool) -> io::Result<io::BufWriter<fs::File>> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .await?;
+    let empty = file.metadata().await?.len() == 0;
+    let mut file = io::BufWriter::new(file);
+    if empty {
+        file.write_all(b"termination,outcome,plies,evals\n").await?;
+    }
+    Ok(file)
+}
+
 async fn run_games(
-    sample_sender: UnboundedSender<PackedSample>,
-    outcome_sender: UnboundedSender<Outcome>,
-    command: String,
+    sample_sender: Sender<Vec<PackedSample>>,
+    outcome_sender: UnboundedSender<GameResult>,
+    settings: Settings,
     games: u32,
-    nodes: Option<u64>,
-    depth: Option<u32>,
-    min_random_moves: u32,
-    max_random_moves: u32,
+    worker: usize,
+    status: &GenerationStatus,
 ) -> anyhow::Result<()> {
-    let mut engine_white = Engine::new(
-        Command::new(&command)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?,
-    )
-    .await?;
-    let mut engine_black = Engine::new(
-        Command::new(&command)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?,
-    )
-    .await?;
+    let mut engine = settings.engine.spawn().await?;
+    // Opponents are started the first time they are drawn and kept for later games.
+    let mut opponents: Vec<Option<Engine>> = settings.opponents.iter().map(|_| None).collect();
+    let mut adjudicator = match &settings.adjudicator {
+        Some(config) => Some(config.spawn().await?),
+        None => None,
+    };
 
     for _ in 0..games {
+        // Each opponent plays both colors in turn.
+        let index = settings.next_game.fetch_add(1, Ordering::Relaxed) as usize;
+        let opponent_index = index % opponents.len();
+        let main_plays_white = (index / opponents.len()).is_multiple_of(2);
+        let opponent = match &mut opponents[opponent_index] {
+            Some(opponent) => opponent,
+            slot => slot.insert(settings.opponents[opponent_index].spawn().await?),
+        };
+        let (engine_white, engine_black) = if main_plays_white {
+            (&mut engine, opponent)
+        } else {
+            (opponent, &mut engine)
+        };
         engine_white.new_game().await?;
         engine_black.new_game().await?;
+        if let Some(adjudicator) = &mut adjudicator {
+            adjudicator.new_game().await?;
+        }
 
-        let position = random_opening(
-            Position::new_initial(), 
-            min_random_moves, 
-            max_random_moves, 
-            &mut rand::rng()
+        let position = pick_opening(&settings, &mut rand::rng());
+        let position = scramble_rules(
+            position,
+            settings.random_halfmove_clock,
+            settings.drop_castling,
+            &mut rand::rng(),
         );
 
         let mut game = Game::from_position(position);
-        let outcome = loop {
-            if let Some(outcome) = game.outcome() {
-                break outcome;
+        let (outcome, termination) = loop {
+            if let Some(result) = game.outcome() {
+                break result;
+            }
+            if let Some(result) = game.adjudicate(&settings.rules, settings.tablebase.as_deref()) {
+                break result;
             }
 
-            let engine = match game.position().side_to_move() {
-                Color::White => &mut engine_white,
-                Color::Black => &mut engine_black,
+            // Searched before the players, so that the eval is of the position they move in.
+            let adjudicator_eval = match &mut adjudicator {
+                Some(adjudicator) => {
+                    let go = Go {
+                        nodes: settings.adjudicator_nodes,
+                        depth: settings.adjudicator_depth,
+                        movetime: settings.adjudicator_movetime,
+                    };
+                    Some(adjudicator.go(game.position(), go).await?.1)
+                }
+                None => None,
+            };
+
+            let side_to_move = game.position().side_to_move();
+            let engine = match side_to_move {
+                Color::White => &mut *engine_white,
+                Color::Black => &mut *engine_black,
+            };
+            let (lag, movetime) = settings.timing.pick(&mut rand::rng());
+            let go = Go {
+                nodes: settings.nodes.map(|nodes| {
+                    settings
+                        .node_odds
+                        .map_or(nodes, |odds| odds.nodes(nodes, side_to_move))
+                }),
+                depth: settings.depth,
+                movetime,
             };
-            let (mv, eval) = engine.go(game.position(), Go { nodes, depth }).await?;
-            game.play(&mv, eval);
+            let noise = settings
+                .multipv_noise
+                .filter(|_| game.plies() < settings.multipv_plies as usize);
+            engine
+                .set_multipv(noise.map_or(1, |noise| noise.lines))
+                .await?;
+            if !lag.is_zero() {
+                tokio::time::sleep(lag).await;
+            }
+            let search = engine.go_multipv(game.position(), go).await?;
+            status
+                .searches
+                .add(search.depth.map(u64::from), search.nodes, search.time_ms);
+            // Positions the engine already sees as decided are left to its best move.
+            let mv = match noise {
+                Some(noise) if search.eval.is_some() => noise
+                    .choose(&search.lines, &mut rand::rng())
+                    .unwrap_or(search.best_move),
+                _ => search.best_move,
+            };
+            game.play(&mv, search.eval, adjudicator_eval.unwrap_or(search.eval));
         };
-        outcome_sender.send(outcome)?;
+        outcome_sender.send(GameResult {
+            outcome,
+            termination,
+            plies: game.plies(),
+            trajectory: settings
+                .trajectory_plies
+                .map(|plies| game.white_evals(plies as usize).collect()),
+        })?;
+        status.game_finished(worker);
 
+        // A game's samples are sent together, which keeps the channel out of the way
+        // with many workers.
+        let mut samples = Vec::with_capacity(game.plies());
         for (pos, mv, eval) in game.history() {
             if pos.is_in_check() || pos.is_capture(&mv) {
                 continue;
             }
 
-            if let Some(eval) = eval {
-                let sample = Sample {
-                    position: pos.clone(),
-                    outcome,
-                    eval: Some(eval.clamp(i16::MIN as i32, i16::MAX as i32) as i16),
-                }
-                .pack()?;
-                sample_sender.send(sample)?;
+            let eval = match eval {
+                _ if settings.no_eval => None,
+                Some(eval) => match settings.eval_overflow.fit(eval)? {
+                    FittedEval::Eval(eval) => Some(eval),
+                    FittedEval::NoEval => None,
+                    FittedEval::Skip => continue,
+                },
+                None => continue,
+            };
+            let mut sample = Sample {
+                position: pos.clone(),
+                outcome,
+                eval,
             }
+            .pack()?;
+            sample.set_adjudication(termination.adjudication());
+            samples.push(sample);
+        }
+        if !samples.is_empty() {
+            sample_sender.send(samples).await?;
         }
     }
 
-    engine_white.quit().await?;
-    engine_black.quit().await?;
+    engine.quit().await?;
+    for opponent in opponents.iter_mut().flatten() {
+        opponent.quit().await?;
+    }
+    if let Some(adjudicator) = &mut adjudicator {
+        adjudicator.quit().await?;
+    }
 
     Ok(())
 }
 
+/// Hashes of the openings played so far by every worker, so that no two games start from
+/// the same position while others are left to pick.
+#[derive(Default)]
+struct PlayedOpenings {
+    hashes: Mutex<HashSet<u64>>,
+    /// Games which started from an opening already played.
+    repeated: AtomicU64,
+}
+
+/// Openings generated for a game before settling for one already played.
+const OPENING_TRIES: u32 = 16;
+
+/// A random opening from the book, or the initial position, that no game has started
+/// from yet if one is found within a few tries.
+fn pick_opening(settings: &Settings, rng: &mut impl Rng) -> Position {
+    let mut tries = 0;
+    loop {
+        let start_position = settings.book.next().unwrap_or_else(Position::new_initial);
+        let position = random_opening(
+            start_position,
+            settings.min_random_moves,
+            settings.max_random_moves,
+            rng,
+        );
+        tries += 1;
+        if settings
+            .openings
+            .hashes
+            .lock()
+            .unwrap()
+            .insert(position.hash())
+        {
+            break position;
+        }
+        if tries == OPENING_TRIES {
+            settings.openings.repeated.fetch_add(1, Ordering::Relaxed);
+            break position;
+        }
+    }
+}
+
+/// Randomizes the parts of an opening's rule state that random moves from the book
+/// rarely vary: the halfmove clock is picked up to `max_halfmove_clock` and each castling
+/// right is removed with probability `drop_castling`.
+fn scramble_rules(
+    position: Position,
+    max_halfmove_clock: Option<u32>,
+    drop_castling: f64,
+    rng: &mut impl Rng,
+) -> Position {
+    let mut fen = position.fen();
+    if let Some(max_halfmove_clock) = max_halfmove_clock {
+        fen.setup.halfmove_clock = rng.random_range(0..=max_halfmove_clock);
+    }
+    if drop_castling > 0.0 {
+        for color in [Color::White, Color::Black] {
+            let castling = &mut fen.setup.castling[color];
+            if rng.random_bool(drop_castling) {
+                castling.king_side = None;
+            }
+            if rng.random_bool(drop_castling) {
+                castling.queen_side = None;
+            }
+        }
+    }
+    fen.into_position().unwrap_or(position)
+}
+
 fn random_opening(
-    start_position: Position, 
-    min_random_moves: u32, 
-    max_random_moves: u32, 
-    rng: &mut impl Rng
+    start_position: Position,
+    min_random_moves: u32,
+    max_random_moves: u32,
+    rng: &mut impl Rng,
 ) -> Position {
     'outer: loop {
         let mut position = start_position.clone();
-        let plies = rng.random_range(
-            2 * min_random_moves..=2 * max_random_moves + 1
-        );
+        let plies = rng.random_range(2 * min_random_moves..=2 * max_random_moves + 1);
 
         for _ in 0..plies {
             let moves = position.legal_moves();
@@ -236,99 +1438,315 @@ fn random_opening(
     }
 }
 
-struct Engine {
+async fn load_opponents(path: &Path) -> anyhow::Result<Vec<EngineConfig>> {
+    let file = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to open opponents file `{}`", path.display()))?;
+
+    let mut opponents = Vec::new();
+    for (n, line) in file.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let opponent = EngineConfig::parse(line)
+            .with_context(|| format!("invalid engine in line {} of opponents file", n + 1))?;
+        opponents.push(opponent);
+    }
+
+    if opponents.is_empty() {
+        anyhow::bail!("opponents file `{}` lists no engines", path.display());
+    }
+    Ok(opponents)
+}
+
+/// Manifest setting recording where the book's rotation is, as `<book hash>:<seed>:<taken>`.
+const BOOK_ROTATION_SETTING: &str = "book_rotation";
+
+/// Opening positions, handed out in a random order drawn from a seed so that every one
+/// is played before any is played again. The seed and the positions taken so far are
+/// kept in the manifest, for runs appending to the dataset to go on with unused ones.
+struct Book {
+    positions: Vec<Position>,
+    order: Vec<u32>,
+    /// Hash of the book file, to tell whether a previous rotation was of the same book.
+    hash: u64,
+    seed: u64,
+    taken: AtomicU64,
+}
+
+impl Book {
+    fn new(positions: Vec<Position>, hash: u64, seed: u64, taken: u64) -> Self {
+        let mut order: Vec<u32> = (0..positions.len() as u32).collect();
+        order.shuffle(&mut Xoshiro256PlusPlus::seed_from_u64(seed));
+        Book {
+            positions,
+            order,
+            hash,
+            seed,
+            taken: AtomicU64::new(taken),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// The next position of the rotation, starting over once all were taken.
+    fn next(&self) -> Option<Position> {
+        if self.positions.is_empty() {
+            return None;
+        }
+        let taken = self.taken.fetch_add(1, Ordering::Relaxed);
+        let index = self.order[(taken % self.positions.len() as u64) as usize];
+        Some(self.positions[index as usize].clone())
+    }
+
+    fn rotation(&self) -> String {
+        format!(
+            "{:016x}:{}:{}",
+            self.hash,
+            self.seed,
+            self.taken.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// The seed and positions taken of the book rotation recorded by the last run writing
+/// to `sink`, if it played the book with hash `hash`.
+fn previous_rotation(sink: &DatasetSink, hash: u64) -> anyhow::Result<Option<(u64, u64)>> {
+    let Some(manifest) = manifest::previous(sink)? else {
+        return Ok(None);
+    };
+    let Some(rotation) = manifest.setting(BOOK_ROTATION_SETTING) else {
+        return Ok(None);
+    };
+    let invalid = || format!("invalid book rotation `{}` in the manifest", rotation);
+    let mut fields = rotation.split(':');
+    let (Some(book_hash), Some(seed), Some(taken), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        anyhow::bail!(invalid());
+    };
+    if u64::from_str_radix(book_hash, 16).with_context(invalid)? != hash {
+        return Ok(None);
+    }
+    Ok(Some((
+        seed.parse().with_context(invalid)?,
+        taken.parse().with_context(invalid)?,
+    )))
+}
+
+async fn load_book(path: &Path) -> anyhow::Result<Vec<Position>> {
+    let book = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to open book `{}`", path.display()))?;
+
+    let mut positions = Vec::new();
+    for (n, line) in book.lines().enumerate() {
+        let fields = line.split_whitespace().take(4).collect::<Vec<_>>();
+        if fields.is_empty() {
+            continue;
+        }
+        let fen = format!("{} 0 1", fields.join(" "));
+        let position = Position::from_fen(&fen)
+            .with_context(|| format!("invalid position in line {} of book", n + 1))?;
+        positions.push(position);
+    }
+
+    if positions.is_empty() {
+        anyhow::bail!("book `{}` contains no positions", path.display());
+    }
+    Ok(positions)
+}
+
+/// How long an engine may take to start up and declare its options.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an engine may take to answer a ping, including after a new game.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) struct Engine {
     stdin: process::ChildStdin,
     lines: io::Lines<BufReader<process::ChildStdout>>,
+    protocol: Box<dyn Protocol>,
+    /// The `MultiPV` option last sent to the engine.
+    multipv: u32,
+    /// Names of the options declared by the engine in the handshake.
+    options: Vec<String>,
+    /// Messages printed by the engine before it was ready, often about the files it
+    /// loaded.
+    messages: Vec<String>,
 }
 
-struct Go {
-    nodes: Option<u64>,
-    depth: Option<u32>,
+pub(crate) struct Go {
+    pub(crate) nodes: Option<u64>,
+    pub(crate) depth: Option<u32>,
+    /// Time limit in milliseconds.
+    pub(crate) movetime: Option<u64>,
+}
+
+/// What a search found, and how much searching the engine says it took to find it.
+pub(crate) struct Search {
+    pub(crate) best_move: Move,
+    /// Eval of the best line in centipawns, `None` for mate scores.
+    pub(crate) eval: Option<i32>,
+    /// First move and centipawn score of every line reported with MultiPV.
+    pub(crate) lines: Vec<(Move, i32)>,
+    /// The last depth, node count and time in milliseconds the engine reported.
+    pub(crate) depth: Option<u32>,
+    pub(crate) nodes: Option<u64>,
+    pub(crate) time_ms: Option<u64>,
 }
 
 impl Engine {
-    async fn new(mut process: process::Child) -> anyhow::Result<Engine> {
+    pub(crate) async fn new(
+        mut process: process::Child,
+        protocol: Box<dyn Protocol>,
+    ) -> anyhow::Result<Engine> {
         let stdin = process.stdin.take().expect("failed to get process stdin");
         let lines =
             BufReader::new(process.stdout.take().expect("failed to get process stdout")).lines();
-        let mut engine = Engine { stdin, lines };
-        engine.ping().await?;
+        let mut engine = Engine {
+            stdin,
+            lines,
+            protocol,
+            multipv: 1,
+            options: Vec::new(),
+            messages: Vec::new(),
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, engine.handshake())
+            .await
+            .map_err(|_| anyhow::Error::msg("engine response timeout"))??;
+        for cmd in engine.protocol.configure(&engine.options) {
+            engine.send(cmd).await?;
+        }
+        engine.sync().await?;
         Ok(engine)
     }
 
-    async fn ping(&mut self) -> anyhow::Result<()> {
-        self.send("uci").await?;
-
-        let start = Instant::now();
-        let timeout = Duration::from_millis(5000);
-
+    async fn handshake(&mut self) -> anyhow::Result<()> {
+        for cmd in self.protocol.handshake() {
+            self.send(cmd).await?;
+        }
+        let mut handshake = Handshake::default();
         while let Some(cmd) = self.read().await? {
-            if cmd.trim() == "uciok" {
-                return Ok(());
+            let done = self.protocol.read_handshake(&cmd, &mut handshake)?;
+            for reply in handshake.replies.drain(..) {
+                self.send(reply).await?;
             }
-            if start.elapsed() > timeout {
-                return Err(anyhow::Error::msg("engine response timeout"));
+            if done {
+                break;
             }
         }
+        self.options = handshake.options;
+        self.messages = handshake.messages;
+        Ok(())
+    }
+
+    /// Waits for the engine to answer a ping, as some engines drop commands sent while
+    /// they're still busy. Anything printed before the answer, like a late best move, is
+    /// discarded.
+    async fn sync(&mut self) -> anyhow::Result<()> {
+        let ping = self.protocol.ping();
+        self.send(ping.clone()).await?;
+        let ready = async {
+            while let Some(cmd) = self.read().await? {
+                if self.protocol.is_pong(&cmd) {
+                    return Ok(());
+                }
+            }
+            Err(anyhow::anyhow!(
+                "program finished before answering '{}'",
+                ping
+            ))
+        };
+        tokio::time::timeout(READY_TIMEOUT, ready)
+            .await
+            .map_err(|_| anyhow::anyhow!("engine did not answer '{}' in time", ping))?
+    }
 
+    /// Option names are matched ignoring case, as UCI asks of engines and xboard engines
+    /// don't mind.
+    fn has_option(&self, name: &str) -> bool {
+        self.options
+            .iter()
+            .any(|option| option.eq_ignore_ascii_case(name))
+    }
+
+    /// Sets an option declared by the engine, failing on any other as the engine would
+    /// silently ignore it.
+    async fn set_option(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        if !self.has_option(name) {
+            if self.options.is_empty() {
+                anyhow::bail!("engine declares no options, so `{}` cannot be set", name);
+            }
+            anyhow::bail!(
+                "engine has no option `{}`, it declares {}",
+                name,
+                self.options
+                    .iter()
+                    .map(|option| format!("`{}`", option))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        self.send(self.protocol.set_option(name, value)).await?;
         Ok(())
     }
 
-    async fn new_game(&mut self) -> anyhow::Result<()> {
-        self.send("ucinewgame").await?;
+    pub(crate) async fn new_game(&mut self) -> anyhow::Result<()> {
+        for cmd in self.protocol.new_game() {
+            self.send(cmd).await?;
+        }
+        self.sync().await
+    }
+
+    pub(crate) async fn quit(&mut self) -> anyhow::Result<()> {
+        self.send(self.protocol.quit()).await?;
         Ok(())
     }
 
-    async fn quit(&mut self) -> anyhow::Result<()> {
-        self.send("quit").await?;
+    /// Sets the number of lines searched, only telling the engine when it changes.
+    pub(crate) async fn set_multipv(&mut self, multipv: u32) -> anyhow::Result<()> {
+        if multipv != self.multipv {
+            let cmd = self
+                .protocol
+                .multipv(multipv)
+                .context("the engine's protocol cannot search several lines")?;
+            self.send(cmd).await?;
+            self.multipv = multipv;
+        }
         Ok(())
     }
 
-    async fn go(&mut self, position: &Position, go: Go) -> anyhow::Result<(Move, Option<i32>)> {
-        self.send(format!("position fen {}", position.fen()))
-            .await?;
-        let mut cmd = String::from("go");
-        if let Some(depth) = go.depth {
-            cmd.write_fmt(format_args!(" depth {}", depth))?;
+    pub(crate) async fn go(
+        &mut self,
+        position: &Position,
+        go: Go,
+    ) -> anyhow::Result<(Move, Option<i32>)> {
+        let search = self.go_multipv(position, go).await?;
+        Ok((search.best_move, search.eval))
+    }
+
+    /// Searches the position, keeping track of every line reported with MultiPV.
+    pub(crate) async fn go_multipv(
+        &mut self,
+        position: &Position,
+        go: Go,
+    ) -> anyhow::Result<Search> {
+        for cmd in self.protocol.position(position) {
+            self.send(cmd).await?;
         }
-        if let Some(nodes) = go.nodes {
-            cmd.write_fmt(format_args!(" nodes {}", nodes))?;
+        self.sync().await?;
+        for cmd in self.protocol.go(&go)? {
+            self.send(cmd).await?;
         }
-        self.send(cmd).await?;
 
-        let mut eval = None;
+        let mut info = SearchInfo::default();
         while let Some(cmd) = self.read().await? {
-            let mut parts = cmd.split_whitespace();
-            match parts.next() {
-                Some("bestmove") => {
-                    let mv = parts.next().context("invalid 'bestmove' usage")?;
-                    let mv = mv.parse::<UciMove>()?;
-                    return Ok((mv.to_move(position)?, eval));
-                }
-                Some("info") => {
-                    while let Some(part) = parts.next() {
-                        if part == "score" {
-                            match parts.next() {
-                                Some("cp") => {
-                                    let info_eval = parts
-                                        .next()
-                                        .context("centipawn score not present")?
-                                        .parse::<i32>()?;
-                                    if matches!(parts.next(), Some("upperbound" | "lowerbound")) {
-                                        break;
-                                    }
-                                    eval = Some(info_eval);
-                                    break;
-                                }
-                                _ => {
-                                    eval = None;
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => {}
+            if let Some(search) = self.protocol.read_search(&cmd, position, &mut info)? {
+                return Ok(search);
             }
         }
 
@@ -353,6 +1771,9 @@ impl Engine {
 pub struct Game {
     stack: Vec<Position>,
     data_stack: Vec<(Move, Option<i32>)>,
+    /// Eval of each position moved in that adjudication goes by, the adjudicator's if
+    /// there is one and otherwise the player's.
+    adjudication_evals: Vec<Option<i32>>,
 }
 
 impl Game {
@@ -361,6 +1782,7 @@ impl Game {
         Game {
             stack: vec![initial_position],
             data_stack: vec![],
+            adjudication_evals: vec![],
         }
     }
 
@@ -370,12 +1792,19 @@ impl Game {
     }
 
     #[inline]
-    fn play(&mut self, mv: &Move, eval: Option<i32>) {
+    fn play(&mut self, mv: &Move, eval: Option<i32>, adjudication_eval: Option<i32>) {
         self.stack.push(self.position().clone());
         self.data_stack.push((*mv, eval));
+        self.adjudication_evals.push(adjudication_eval);
         self.stack.last_mut().unwrap().play_unchecked(mv);
     }
 
+    /// Number of moves played since the start position.
+    #[inline]
+    fn plies(&self) -> usize {
+        self.data_stack.len()
+    }
+
     #[inline]
     fn history(&self) -> impl Iterator<Item = (&Position, Move, Option<i32>)> + '_ {
         self.stack
@@ -385,26 +1814,91 @@ impl Game {
     }
 
     #[inline]
-    fn outcome(&self) -> Option<Outcome> {
+    fn outcome(&self) -> Option<(Outcome, Termination)> {
         let moves = self.position().legal_moves();
         if moves.is_empty() {
             if self.position().is_in_check() {
-                return Some(Outcome::Winner(!self.position().side_to_move()));
+                return Some((
+                    Outcome::Winner(!self.position().side_to_move()),
+                    Termination::Checkmate,
+                ));
             } else {
-                return Some(Outcome::Draw);
+                return Some((Outcome::Draw, Termination::Stalemate));
             }
         }
-        if self.is_draw() {
-            return Some(Outcome::Draw);
+        self.draw().map(|termination| (Outcome::Draw, termination))
+    }
+
+    /// Ends the game early when the recent evals or the tablebases settle it.
+    fn adjudicate(
+        &self,
+        rules: &AdjudicationRules,
+        tablebase: Option<&Tablebase>,
+    ) -> Option<(Outcome, Termination)> {
+        // The probe assumes a zeroing move was just played, so it is only exact right after one.
+        if let Some(tablebase) = tablebase
+            && self.position().halfmove_clock() == 0
+            && let Some(outcome) = tablebase.probe_outcome(self.position())
+        {
+            return Some((outcome, Termination::Tablebase));
+        }
+
+        if let Some(resign_eval) = rules.resign_eval
+            && let Some(evals) = self.recent_white_evals(rules.resign_plies)
+        {
+            if evals.iter().all(|&eval| eval >= resign_eval) {
+                return Some((Outcome::Winner(Color::White), Termination::Resign));
+            }
+            if evals.iter().all(|&eval| eval <= -resign_eval) {
+                return Some((Outcome::Winner(Color::Black), Termination::Resign));
+            }
+        }
+
+        if let Some(draw_eval) = rules.draw_eval
+            && self.position().fullmove_number() >= rules.draw_after
+            && let Some(evals) = self.recent_white_evals(rules.draw_plies)
+            && evals.iter().all(|eval| eval.abs() <= draw_eval)
+        {
+            return Some((Outcome::Draw, Termination::DrawAdjudication));
         }
         None
     }
 
+    /// The adjudication evals of the last `plies` moves from white's point of view, if
+    /// that many moves were played and all of them came with a centipawn eval.
+    fn recent_white_evals(&self, plies: u32) -> Option<Vec<i32>> {
+        let plies = plies as usize;
+        if plies == 0 || self.data_stack.len() < plies {
+            return None;
+        }
+        self.white_evals(plies).collect()
+    }
+
+    /// The adjudication evals of the last `plies` moves, or of every move of shorter
+    /// games, from white's point of view.
+    fn white_evals(&self, plies: usize) -> impl Iterator<Item = Option<i32>> + '_ {
+        let start = self.data_stack.len().saturating_sub(plies);
+        self.stack[start..]
+            .iter()
+            .zip(&self.adjudication_evals[start..])
+            .map(|(position, &eval)| match position.side_to_move() {
+                Color::White => eval,
+                Color::Black => eval.map(|eval| -eval),
+            })
+    }
+
+    /// The rule the game is drawn by, if any.
     #[inline]
-    fn is_draw(&self) -> bool {
-        self.position().halfmove_clock() >= 100
-            || self.repetitions() >= 3
-            || self.position().is_insufficient_material()
+    fn draw(&self) -> Option<Termination> {
+        if self.position().halfmove_clock() >= 100 {
+            Some(Termination::FiftyMoves)
+        } else if self.repetitions() >= 3 {
+            Some(Termination::Repetition)
+        } else if self.position().is_insufficient_material() {
+            Some(Termination::InsufficientMaterial)
+        } else {
+            None
+        }
     }
 
     #[inline]
@@ -419,3 +1913,178 @@ impl Game {
         repetitions
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dama::{ToMove, UciMove};
+
+    fn play(game: &mut Game, moves: &[(&str, Option<i32>)]) {
+        for &(mv, eval) in moves {
+            let mv = mv
+                .parse::<UciMove>()
+                .unwrap()
+                .to_move(game.position())
+                .unwrap();
+            game.play(&mv, eval, eval);
+        }
+    }
+
+    #[test]
+    fn repetitions_count_the_same_position_with_the_same_side_to_move() {
+        let mut game = Game::from_position(Position::new_initial());
+        let shuffle = [
+            ("g1f3", None),
+            ("g8f6", None),
+            ("f3g1", None),
+            ("f6g8", None),
+        ];
+        play(&mut game, &shuffle);
+        assert_eq!(game.repetitions(), 2);
+        assert_eq!(game.draw(), None);
+        play(&mut game, &shuffle);
+        assert_eq!(game.repetitions(), 3);
+        assert_eq!(
+            game.outcome(),
+            Some((Outcome::Draw, Termination::Repetition))
+        );
+
+        // A pawn move makes the earlier positions unreachable.
+        play(&mut game, &[("e2e4", None)]);
+        assert_eq!(game.repetitions(), 1);
+    }
+
+    #[test]
+    fn adjudicate_goes_by_the_recent_evals() {
+        let rules = AdjudicationRules {
+            resign_eval: Some(400),
+            resign_plies: 2,
+            draw_eval: Some(10),
+            draw_plies: 2,
+            draw_after: 2,
+        };
+        let adjudicate = |moves: &[(&str, Option<i32>)]| {
+            let mut game = Game::from_position(Position::new_initial());
+            play(&mut game, moves);
+            game.adjudicate(&rules, None)
+        };
+
+        // Evals are from the side to move's point of view.
+        assert_eq!(
+            adjudicate(&[("e2e4", Some(500)), ("e7e5", Some(-450))]),
+            Some((Outcome::Winner(Color::White), Termination::Resign))
+        );
+        assert_eq!(
+            adjudicate(&[("e2e4", Some(-600)), ("e7e5", Some(600))]),
+            Some((Outcome::Winner(Color::Black), Termination::Resign))
+        );
+        assert_eq!(
+            adjudicate(&[("e2e4", Some(500)), ("e7e5", Some(-300))]),
+            None
+        );
+        assert_eq!(adjudicate(&[("e2e4", Some(500)), ("e7e5", None)]), None);
+        assert_eq!(adjudicate(&[("e2e4", Some(500))]), None);
+        assert_eq!(
+            adjudicate(&[("e2e4", Some(5)), ("e7e5", Some(-10))]),
+            Some((Outcome::Draw, Termination::DrawAdjudication))
+        );
+
+        // Draws aren't adjudicated before move `draw_after`.
+        let rules = AdjudicationRules {
+            draw_after: 3,
+            ..rules
+        };
+        let mut game = Game::from_position(Position::new_initial());
+        play(&mut game, &[("e2e4", Some(5)), ("e7e5", Some(-10))]);
+        assert_eq!(game.adjudicate(&rules, None), None);
+    }
+
+    #[test]
+    fn scramble_rules_changes_only_the_clock_and_castling() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+        let initial = Position::new_initial();
+        assert_eq!(
+            scramble_rules(initial.clone(), None, 0.0, &mut rng),
+            initial
+        );
+
+        let scrambled = scramble_rules(initial.clone(), Some(20), 1.0, &mut rng);
+        let fen = scrambled.fen();
+        assert!(fen.setup.halfmove_clock <= 20);
+        for color in [Color::White, Color::Black] {
+            assert_eq!(fen.setup.castling[color].king_side, None);
+            assert_eq!(fen.setup.castling[color].queen_side, None);
+        }
+        let placement = |position: &Position| {
+            let fen = position.fen().to_string();
+            fen.split(' ').next().unwrap().to_string()
+        };
+        assert_eq!(placement(&scrambled), placement(&initial));
+
+        let clocks: HashSet<u32> = (0..100)
+            .map(|_| {
+                scramble_rules(initial.clone(), Some(3), 0.0, &mut rng)
+                    .fen()
+                    .setup
+                    .halfmove_clock
+            })
+            .collect();
+        assert_eq!(clocks, HashSet::from([0, 1, 2, 3]));
+    }
+
+    fn book_positions() -> Vec<Position> {
+        [
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/2P5/8/PP1PPPPP/RNBQKBNR b KQkq - 0 1",
+            "rnbqkbnr/pppppppp/8/8/8/5N2/PPPPPPPP/RNBQKB1R b KQkq - 1 1",
+        ]
+        .into_iter()
+        .map(|fen| Position::from_fen(fen).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn book_plays_every_position_before_repeating_and_resumes_its_rotation() {
+        let positions = book_positions();
+        let book = Book::new(positions.clone(), 0xb00c, 7, 0);
+        let first: Vec<Position> = (0..4).map(|_| book.next().unwrap()).collect();
+        for position in &positions {
+            assert!(first.contains(position));
+        }
+        assert_eq!(book.next().unwrap(), first[0]);
+        assert_eq!(book.rotation(), "000000000000b00c:7:5");
+
+        let resumed = Book::new(positions, 0xb00c, 7, 2);
+        assert_eq!(resumed.next().unwrap(), first[2]);
+        assert_eq!(resumed.next().unwrap(), first[3]);
+
+        assert_eq!(Book::new(Vec::new(), 0, 7, 0).next(), None);
+    }
+
+    #[test]
+    fn previous_rotation_reads_the_manifest_of_the_same_book() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = DatasetSink::from_path(&dir.path().join("games.bin"), false);
+        assert_eq!(previous_rotation(&sink, 0xb00c).unwrap(), None);
+
+        let write_rotation = |rotation: &str| {
+            sink.create(false).unwrap().finish().unwrap();
+            manifest::write_for_sink(
+                &sink,
+                "selfplay",
+                &[(BOOK_ROTATION_SETTING, rotation.to_string())],
+                Vec::new(),
+            )
+            .unwrap();
+        };
+        write_rotation("000000000000b00c:7:12");
+        assert_eq!(previous_rotation(&sink, 0xb00c).unwrap(), Some((7, 12)));
+        assert_eq!(previous_rotation(&sink, 0xb00d).unwrap(), None);
+
+        for invalid in ["b00c:7", "b00c:7:12:1", "b00c:seed:12", "book:7:12"] {
+            write_rotation(invalid);
+            assert!(previous_rotation(&sink, 0xb00c).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -1,60 +1,282 @@
-use std::{io::SeekFrom, mem, path::PathBuf};
+use std::{ops::Range, str::FromStr};
 use anyhow::Context;
 use dama::{Color, Outcome};
-use dataformat::PackedSample;
+use dataformat::{EvalPerspective, PackedSample, Sample};
+use dataloader::feature::FeatureSet;
 use rand::{Rng, SeedableRng};
 use rand_xoshiro::Xoshiro128PlusPlus;
-use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}};
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{io::{DatasetSource, SampleFiles}, logging, manifest, predicate::{self, Predicate}};
 
 #[derive(clap::Args)]
 pub struct Args {
-    #[clap(help("File to show random samples from."))]
-    file: PathBuf,
+    #[clap(help("Uncompressed dataset, a file or a directory of shards, to show samples from."))]
+    file: DatasetSource,
     #[clap(short('s'), long("samples"), default_value_t = 16)]
     samples: u32,
     #[clap(short('S'), long("seed"))]
     seed: Option<u64>,
+    #[clap(
+        short('f'),
+        long("filter"),
+        help("Only shows samples satisfying the predicate, e.g. `abs-eval<100` or `outcome=draw`.")
+    )]
+    filters: Vec<Predicate>,
+    #[clap(long("fen-only"), help("Prints one FEN per line and nothing else."))]
+    fen_only: bool,
+    #[clap(
+        long("features"),
+        help("Also prints the feature indices the dataloader produces with the given feature set, e.g. `chess768`.")
+    )]
+    features: Option<FeatureSet>,
+    #[clap(
+        long("offset"),
+        conflicts_with("range"),
+        help("Shows `--samples` consecutive samples starting at this index instead of random ones.")
+    )]
+    offset: Option<u64>,
+    #[clap(
+        long("range"),
+        help("Shows every sample in the range of indices `A..B`, end excluded.")
+    )]
+    range: Option<SampleRange>,
+    #[clap(
+        short('i'),
+        long("interactive"),
+        help("Steps through samples one at a time, starting at `--offset` or the start of `--range`.")
+    )]
+    interactive: bool,
+}
+
+/// A range of sample indices written as `A..B`, with either end optional.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SampleRange {
+    start: Option<u64>,
+    end: Option<u64>,
+}
+
+impl SampleRange {
+    fn clamp(self, positions: u64) -> Range<u64> {
+        let end = self.end.unwrap_or(positions).min(positions);
+        self.start.unwrap_or(0).min(end)..end
+    }
 }
 
+impl FromStr for SampleRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once("..").with_context(|| format!("invalid range `{}`, expected `A..B`", s))?;
+        let parse = |bound: &str| -> anyhow::Result<Option<u64>> {
+            if bound.is_empty() {
+                Ok(None)
+            } else {
+                bound.parse().map(Some).with_context(|| format!("invalid index `{}` in range `{}`", bound, s))
+            }
+        };
+        Ok(SampleRange { start: parse(start)?, end: parse(end)? })
+    }
+}
+
+/// How many random draws are made per requested sample before giving up on the filters.
+const MAX_ATTEMPTS_PER_SAMPLE: u64 = 100_000;
+
 pub async fn run(args: Args) -> anyhow::Result<()> {
-    let mut file = File::open(&args.file)
-        .await
-        .with_context(|| format!("failed to open file `{}`", args.file.display()))?;
+    let mut file = SampleFiles::open(&args.file, false)?;
+    let positions = file.len();
+    if positions == 0 {
+        anyhow::bail!("dataset `{}` contains no samples", args.file);
+    }
+    let perspective = manifest::perspective_of(&args.file)?;
 
-    let step = mem::size_of::<PackedSample>() as u64;
-    let positions = file.seek(SeekFrom::End(0)).await? / step;
+    if args.interactive {
+        let start = args.offset.or(args.range.and_then(|range| range.start)).unwrap_or(0);
+        return browse(&args, perspective, &mut file, positions, start).await;
+    }
+    let sequential = match (args.offset, args.range) {
+        (Some(offset), _) => Some(SampleRange { start: Some(offset), end: Some(offset.saturating_add(args.samples as u64)) }),
+        (None, range) => range,
+    };
+    if let Some(range) = sequential {
+        return show_range(&args, perspective, &mut file, range.clamp(positions));
+    }
 
     let mut rng = if let Some(seed) = args.seed {
         Xoshiro128PlusPlus::seed_from_u64(seed)
     } else {
         Xoshiro128PlusPlus::from_os_rng()
-    }; 
+    };
+
+    let mut attempts = 0;
+    let max_attempts = MAX_ATTEMPTS_PER_SAMPLE * args.samples as u64;
+    let mut n = 0;
+    while n < args.samples {
+        if attempts >= max_attempts {
+            anyhow::bail!("no more samples matching the filters found after {} attempts", attempts);
+        }
+        attempts += 1;
 
-    for n in 0..args.samples {
         let position = rng.random_range(0..positions);
-        file.seek(SeekFrom::Start(position * step)).await?;
-
-        let mut sample = PackedSample::default();
-        file.read_exact(bytemuck::bytes_of_mut(&mut sample)).await?;
-
-        let sample = sample.unpack()?;
-        println!("{}\n", sample.position);
-        println!("FEN: {}", sample.position.fen());
-        println!("Side to move: {}", sample.position.side_to_move());
-        println!("Outcome: {} ({})", sample.outcome, match sample.outcome {
-            Outcome::Winner(Color::White) => "white wins",
-            Outcome::Winner(Color::Black) => "black wins",
-            Outcome::Draw => "draw",
-        });
-        if let Some(eval) = sample.eval {
-            println!("Evaluation: {}", eval);
+        let mut sample = read_sample(&mut file, position)?.unpack()?;
+        manifest::to_side_to_move(&mut sample, perspective);
+        if !predicate::matches_all(&args.filters, &sample) {
+            continue;
+        }
+
+        if args.fen_only {
+            logging::report!("{}", sample.position.fen());
+        } else {
+            print_sample(&sample, args.features);
+            if n != args.samples - 1 {
+                logging::report!("\n———————————————————\n");
+            }
         }
+        n += 1;
+    }
+
+    Ok(())
+}
+
+fn read_sample(file: &mut SampleFiles, index: u64) -> anyhow::Result<PackedSample> {
+    let mut sample = [PackedSample::default()];
+    file.read(index, &mut sample)?;
+    Ok(sample[0])
+}
 
-        if n != args.samples - 1 {
-            println!("\n———————————————————\n");
+/// Prints a sample with its index, reporting samples that fail to unpack instead of
+/// stopping, since corrupt regions are what sequential browsing is mostly used for.
+/// Returns whether anything was printed, after a separator if `separate` is set.
+fn show_indexed(args: &Args, perspective: EvalPerspective, file: &mut SampleFiles, index: u64, separate: bool) -> anyhow::Result<bool> {
+    let sample = read_sample(file, index)?.unpack().map(|mut sample| {
+        manifest::to_side_to_move(&mut sample, perspective);
+        sample
+    });
+    if sample.as_ref().is_ok_and(|sample| !predicate::matches_all(&args.filters, sample)) {
+        return Ok(false);
+    }
+    if separate && !args.fen_only {
+        logging::report!("\n———————————————————\n");
+    }
+    let sample = match sample {
+        Ok(sample) => sample,
+        Err(err) => {
+            logging::report!("#{}: failed to unpack sample: {}", index, err);
+            return Ok(true);
         }
+    };
+    if args.fen_only {
+        logging::report!("{}", sample.position.fen());
+    } else {
+        logging::report!("Sample #{}\n", index);
+        print_sample(&sample, args.features);
     }
+    Ok(true)
+}
 
+fn show_range(args: &Args, perspective: EvalPerspective, file: &mut SampleFiles, range: Range<u64>) -> anyhow::Result<()> {
+    let mut shown = false;
+    for index in range {
+        shown |= show_indexed(args, perspective, file, index, shown)?;
+    }
     Ok(())
 }
 
+/// Shows one sample at a time, reading commands from stdin to move between them.
+async fn browse(args: &Args, perspective: EvalPerspective, file: &mut SampleFiles, positions: u64, start: u64) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(io::stdin()).lines();
+    let mut stdout = io::stdout();
+    let mut index = start.min(positions - 1);
+    let mut forward = true;
+    loop {
+        // Samples rejected by the filters are skipped in the direction of travel.
+        while !show_indexed(args, perspective, file, index, false)? {
+            match forward {
+                true if index + 1 < positions => index += 1,
+                false if index > 0 => index -= 1,
+                _ => {
+                    logging::report!("no more samples matching the filters");
+                    break;
+                }
+            }
+        }
+
+        stdout.write_all(format!("\n[{}/{}] n: next, p: previous, <index>: jump, q: quit > ", index, positions).as_bytes()).await?;
+        stdout.flush().await?;
+        let Some(line) = lines.next_line().await? else {
+            return Ok(());
+        };
+        match line.trim() {
+            "" | "n" => {
+                forward = true;
+                index = (index + 1).min(positions - 1);
+            }
+            "p" => {
+                forward = false;
+                index = index.saturating_sub(1);
+            }
+            "q" => return Ok(()),
+            other => match other.parse::<u64>() {
+                Ok(target) if target < positions => {
+                    forward = true;
+                    index = target;
+                }
+                Ok(_) => logging::report!("index out of range, the dataset has {} samples", positions),
+                Err(_) => logging::report!("unknown command `{}`", other),
+            },
+        }
+        logging::report!();
+    }
+}
+
+fn print_sample(sample: &Sample, features: Option<FeatureSet>) {
+    logging::report!("{}\n", sample.position);
+    logging::report!("FEN: {}", sample.position.fen());
+    logging::report!("Side to move: {}", sample.position.side_to_move());
+    logging::report!("Outcome: {} ({})", sample.outcome, match sample.outcome {
+        Outcome::Winner(Color::White) => "white wins",
+        Outcome::Winner(Color::Black) => "black wins",
+        Outcome::Draw => "draw",
+    });
+    if let Some(eval) = sample.eval {
+        logging::report!("Evaluation: {} {}", eval, eval_bar(eval));
+    }
+    if let Some(features) = features {
+        print_features(sample, features);
+    }
+}
+
+const EVAL_BAR_WIDTH: i32 = 20;
+/// Evaluation in centipawns at which the bar is full.
+const EVAL_BAR_RANGE: i32 = 1000;
+
+/// Draws the evaluation as a bar filled from the left for the side to move, e.g. `[#####|-----]`.
+fn eval_bar(eval: i16) -> String {
+    let half = EVAL_BAR_WIDTH / 2;
+    let filled = half + (eval as i32).clamp(-EVAL_BAR_RANGE, EVAL_BAR_RANGE) * half / EVAL_BAR_RANGE;
+    let mut bar = String::with_capacity(EVAL_BAR_WIDTH as usize + 3);
+    bar.push('[');
+    for i in 0..EVAL_BAR_WIDTH {
+        if i == half {
+            bar.push('|');
+        }
+        bar.push(if i < filled { '#' } else { '-' });
+    }
+    bar.push(']');
+    bar
+}
+
+fn print_features(sample: &Sample, features: FeatureSet) {
+    let mut active = Vec::new();
+    features.active_features(&sample.position, |stm, non_stm| active.push((stm, non_stm)));
+    logging::report!("\nFeatures ({}, {} active):", features, active.len());
+    for (stm, non_stm) in active {
+        logging::report!(
+            "  stm {:>4} ({:<22}) | non-stm {:>4} ({})",
+            stm,
+            features.describe(stm),
+            non_stm,
+            features.describe(non_stm)
+        );
+    }
+}
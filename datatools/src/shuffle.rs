@@ -2,27 +2,162 @@ use anyhow::Context;
 use core::mem;
 use dataformat::PackedSample;
 use indicatif::{ProgressBar, ProgressStyle};
-use rand::{Rng, seq::SliceRandom};
+use rand::{Rng, SeedableRng, seq::SliceRandom};
+use rand_xoshiro::Xoshiro256PlusPlus;
 use std::{
-    io::SeekFrom,
+    fs,
+    io::{self, Read, Seek, Write},
     path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
     time::Duration,
 };
-use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter},
-    sync::mpsc::{UnboundedSender, unbounded_channel},
+use tokio::fs::{File, OpenOptions};
+
+use crate::{
+    compression,
+    digest::SampleDigest,
+    io::{DatasetSink, DatasetSource},
+    logging, manifest, threads,
+    throttle::{Throttle, Throttled},
+    units::{ByteRate, ByteSize},
 };
 
 #[derive(clap::Args)]
 pub struct Args {
-    #[clap(help("Input data file."))]
+    #[clap(help(
+        "Input data file, or a directory of shards which are shuffled across each other."
+    ))]
     input: PathBuf,
     #[clap(short('o'))]
     output: Option<PathBuf>,
+    #[clap(
+        long("shard-size"),
+        requires("output"),
+        help(
+            "Writes the output as a directory of shards of this size, e.g. `4GiB`. Defaults to the size of the first input shard for directories of uncompressed shards."
+        )
+    )]
+    shard_size: Option<ByteSize>,
+    #[clap(flatten)]
+    options: ShuffleOptions,
+}
+
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct ShuffleOptions {
+    #[clap(
+        long("subfile-size"),
+        conflicts_with("memory_limit"),
+        help("Number of samples shuffled in memory at once, defaults to 2097152.")
+    )]
+    subfile_size: Option<u64>,
+    #[clap(
+        long("memory-limit"),
+        help("Memory budget shared by concurrently shuffled subfiles (e.g. `8G`).")
+    )]
+    memory_limit: Option<ByteSize>,
+    #[clap(
+        long("jobs"),
+        help("Number of subfiles shuffled concurrently, defaults to min(threads, 4).")
+    )]
+    jobs: Option<usize>,
+    #[clap(
+        long("temp-dir"),
+        help("Directory for the temporary subfiles, defaults to the system temp directory.")
+    )]
+    temp_dir: Option<PathBuf>,
+    #[clap(
+        long("seed"),
+        help("Seed for the shuffle, making the output reproducible for a given input.")
+    )]
+    seed: Option<u64>,
+    #[clap(
+        long("verify"),
+        help("Re-reads the output and checks it holds exactly the same samples as the input.")
+    )]
+    verify: bool,
+    #[clap(
+        long("compress"),
+        help("Compresses the output with zstd, implied by a `.zst` output extension.")
+    )]
+    compress: bool,
+    #[clap(
+        long("compression-level"),
+        help("Zstd compression level, defaults to 3.")
+    )]
+    compression_level: Option<i32>,
+    #[clap(
+        long("compress-temp"),
+        help("Compresses temporary subfiles with zstd, always done for compressed input.")
+    )]
+    compress_temp: bool,
+    #[clap(
+        long("io-limit"),
+        help(
+            "Caps the rate at which temporary subfiles and the output are written, e.g. `100M/s`."
+        )
+    )]
+    io_limit: Option<ByteRate>,
+    #[clap(
+        long("two-level"),
+        help(
+            "Merges the shuffled subfiles in groups before writing the output, which mixes them evenly when there are thousands of them."
+        )
+    )]
+    two_level: bool,
+}
+
+impl ShuffleOptions {
+    /// The default options, writing no faster than `io_limit` if set.
+    pub fn with_io_limit(io_limit: Option<ByteRate>) -> Self {
+        ShuffleOptions {
+            io_limit,
+            ..ShuffleOptions::default()
+        }
+    }
+
+    fn subfile_size(&self) -> u64 {
+        let size = match (self.subfile_size, self.memory_limit) {
+            (Some(size), _) => size,
+            (None, Some(limit)) => {
+                limit.bytes() / (self.jobs() * mem::size_of::<PackedSample>()) as u64
+            }
+            (None, None) => DEFAULT_SUBFILE_SIZE,
+        };
+        size.max(1)
+    }
+
+    fn jobs(&self) -> usize {
+        self.jobs
+            .unwrap_or_else(|| threads::available().min(DEFAULT_MAX_JOBS))
+            .max(1)
+    }
+
+    fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    fn compression_level(&self) -> i32 {
+        self.compression_level.unwrap_or(compression::DEFAULT_LEVEL)
+    }
+
+    fn rng(&self) -> Xoshiro256PlusPlus {
+        match self.seed {
+            Some(seed) => Xoshiro256PlusPlus::seed_from_u64(seed),
+            None => Xoshiro256PlusPlus::from_os_rng(),
+        }
+    }
 }
 
 pub async fn run(args: Args) -> anyhow::Result<()> {
+    let input = DatasetSource::from_path(&args.input);
+    if matches!(input, DatasetSource::Shards(_)) || args.shard_size.is_some() {
+        return run_sharded(&args, &input).await;
+    }
+
     let input_file = OpenOptions::new()
         .create(false)
         .read(true)
@@ -31,109 +166,800 @@ pub async fn run(args: Args) -> anyhow::Result<()> {
         .await
         .with_context(|| format!("failed to open file `{}`", args.input.display()))?;
 
-    shuffle(input_file, args.output.as_deref()).await
+    shuffle(input_file, args.output.as_deref(), &args.options).await?;
+
+    match &args.output {
+        Some(output) => manifest::write_for_sink(
+            &DatasetSink::from_path(output, false),
+            "shuffle",
+            &[],
+            vec![manifest::dataset_source(&input)?],
+        ),
+        None => manifest::refresh(&input),
+    }
 }
 
-const SUBFILE_SIZE: u64 = 2097152;
+/// Shuffles into a directory of shards, or from one into any output.
+async fn run_sharded(args: &Args, input: &DatasetSource) -> anyhow::Result<()> {
+    let Some(output) = &args.output else {
+        anyhow::bail!("a directory of shards can't be shuffled in place, give an output with -o");
+    };
+    if fs::canonicalize(output).ok() == Some(fs::canonicalize(&args.input)?) {
+        anyhow::bail!("the output must not be the input, which is read while it is written");
+    }
+    let shard_size = match (args.shard_size, input) {
+        (Some(shard_size), _) => Some(shard_size),
+        (None, DatasetSource::Shards(_)) => {
+            let files = input.files()?;
+            let first = files
+                .first()
+                .with_context(|| format!("`{}` holds no shards", input))?;
+            if DatasetSource::File(first.clone()).plain_file()?.is_none() {
+                anyhow::bail!(
+                    "the input shards are compressed, give the size of the output shards with --shard-size"
+                );
+            }
+            Some(ByteSize(fs::metadata(first)?.len()))
+        }
+        (None, _) => None,
+    };
+    let sink = DatasetSink::from_path(output, args.options.compress).sharded(shard_size)?;
 
-pub async fn shuffle(mut input_file: File, output_path: Option<&Path>) -> anyhow::Result<()> {
-    input_file.seek(SeekFrom::Start(0)).await?;
+    shuffle_to_sink(input, &sink, &args.options).await?;
+    manifest::write_for_sink(
+        &sink,
+        "shuffle",
+        &[],
+        vec![manifest::dataset_source(input)?],
+    )
+}
 
-    let progress = ProgressBar::no_length()
-        .with_style(
-            ProgressStyle::with_template(
-                "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} blocks done. ",
-            )
-            .unwrap()
-            .progress_chars("##-"),
-        )
-        .with_message("shuffling positions...");
-    progress.enable_steady_tick(Duration::from_millis(50));
+/// Shuffles a freshly written dataset in place. Shards are each shuffled on their own,
+/// which leaves their order as written, and datasets written to stdout are left as is.
+pub async fn shuffle_sink(sink: &DatasetSink, options: &ShuffleOptions) -> anyhow::Result<()> {
+    if *sink == DatasetSink::Stdout {
+        eprintln!("warning: output written to stdout is not shuffled");
+        return Ok(());
+    }
+    for path in sink.files()? {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("failed to open file `{}`", path.display()))?;
+        shuffle(file, None, options).await?;
+    }
+    Ok(())
+}
 
-    let (subfiles, remaining, positions) = divide_and_shuffle(&progress, &mut input_file).await?;
+const DEFAULT_SUBFILE_SIZE: u64 = 2097152;
+const DEFAULT_MAX_JOBS: usize = 4;
 
-    let output_file = if let Some(output_path) = output_path {
-        File::create(output_path)
-            .await
+pub async fn shuffle(
+    input_file: File,
+    output_path: Option<&Path>,
+    options: &ShuffleOptions,
+) -> anyhow::Result<()> {
+    let subfile_size = options.subfile_size();
+    let temp_dir = options.temp_dir();
+    let mut rng = options.rng();
+    let throttle = options.io_limit.map(Throttle::new);
+    let mut input_file = input_file.into_std().await;
+    let input_compressed = compression::is_compressed(&mut input_file)?;
+    let compress_temp = options.compress_temp || input_compressed;
+
+    let positions = if input_compressed {
+        // Compressed samples take up to their uncompressed size once in temporary
+        // files, so they are counted, which can only be done by decoding them.
+        let bytes = io::copy(
+            &mut compression::reader(input_file.try_clone()?, true)?,
+            &mut io::sink(),
+        )?;
+        input_file.rewind()?;
+        bytes / mem::size_of::<PackedSample>() as u64
+    } else {
+        input_file.metadata()?.len() / mem::size_of::<PackedSample>() as u64
+    };
+    check_free_space(&temp_dir, positions)?;
+
+    let progress = subfile_progress();
+
+    let input = if input_compressed {
+        SubfileInput::Stream(Mutex::new((
+            0,
+            compression::reader(input_file.try_clone()?, true)?,
+        )))
+    } else {
+        SubfileInput::Plain {
+            positions,
+            file: input_file.try_clone()?,
+            next: AtomicU64::new(0),
+        }
+    };
+    let (subfiles, remaining, positions, input_digest) = divide_and_shuffle(
+        &progress,
+        input,
+        &temp_dir,
+        subfile_size,
+        options.jobs(),
+        compress_temp.then_some(compression::FAST_LEVEL),
+        throttle.clone(),
+        &mut rng,
+    )
+    .await?;
+    let (subfiles, remaining) = if options.two_level {
+        merge_groups(
+            subfiles,
+            remaining,
+            positions,
+            &temp_dir,
+            options.jobs(),
+            compress_temp,
+            throttle.clone(),
+            &mut rng,
+        )
+        .await?
+    } else {
+        (subfiles, remaining)
+    };
+
+    // In-place shuffles keep the compression of the input.
+    let compress_output = options.compress
+        || match output_path {
+            Some(output_path) => compression::has_zstd_extension(output_path),
+            None => input_compressed,
+        };
+    let mut output_file = if let Some(output_path) = output_path {
+        fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(output_path)
             .with_context(|| format!("failed to open file `{}`", output_path.display()))?
     } else {
-        input_file.seek(SeekFrom::Start(0)).await?;
+        input_file.rewind()?;
         input_file
     };
 
-    let progress = ProgressBar::new(positions)
-        .with_style(ProgressStyle::with_template("{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions written.")
-            .unwrap()
-            .progress_chars("##-"))
-        .with_message("writing data to output file...");
+    let mut result = write_output(
+        &mut output_file,
+        subfiles,
+        remaining,
+        positions,
+        // Groups are merged evenly into the output, whatever their size.
+        (!options.two_level).then_some(subfile_size),
+        compress_temp,
+        compress_output.then_some(options.compression_level()),
+        throttle,
+        rng,
+    )
+    .await;
+    if result.is_ok() && options.verify {
+        result = verify_file(&output_file, compress_output, input_digest).await;
+    }
+    if result.is_err() {
+        // Temporary subfiles are anonymous and vanish on their own, but a partially
+        // written output file must not be mistaken for a complete dataset.
+        if let Some(output_path) = output_path {
+            let _ = tokio::fs::remove_file(output_path).await;
+        }
+    }
+    result
+}
+
+/// Shuffles any dataset into a sink, shuffling across the shards of sharded datasets
+/// rather than one shard at a time like [`shuffle_sink`]. The sink must not overlap
+/// with the source.
+pub async fn shuffle_to_sink(
+    source: &DatasetSource,
+    sink: &DatasetSink,
+    options: &ShuffleOptions,
+) -> anyhow::Result<()> {
+    let subfile_size = options.subfile_size();
+    let temp_dir = options.temp_dir();
+    let mut rng = options.rng();
+    let throttle = options.io_limit.map(Throttle::new);
+
+    let mut input_compressed = false;
+    for path in source.files()? {
+        let mut file = fs::File::open(&path)
+            .with_context(|| format!("failed to open file `{}`", path.display()))?;
+        input_compressed |= compression::is_compressed(&mut file)?;
+    }
+    let positions = match manifest::known_samples(source)? {
+        Some(positions) => positions,
+        None => source.count()?,
+    };
+    check_free_space(&temp_dir, positions)?;
+    let compress_temp = options.compress_temp || input_compressed;
+
+    let progress = subfile_progress();
+    let input = match source.plain_file()? {
+        Some(path) => {
+            let file = fs::File::open(path)
+                .with_context(|| format!("failed to open file `{}`", path.display()))?;
+            SubfileInput::Plain {
+                positions: file.metadata()?.len() / mem::size_of::<PackedSample>() as u64,
+                file,
+                next: AtomicU64::new(0),
+            }
+        }
+        None => SubfileInput::Stream(Mutex::new((0, Box::new(source.open()?)))),
+    };
+    let (subfiles, remaining, positions, input_digest) = divide_and_shuffle(
+        &progress,
+        input,
+        &temp_dir,
+        subfile_size,
+        options.jobs(),
+        compress_temp.then_some(compression::FAST_LEVEL),
+        throttle.clone(),
+        &mut rng,
+    )
+    .await?;
+    let (subfiles, remaining) = if options.two_level {
+        merge_groups(
+            subfiles,
+            remaining,
+            positions,
+            &temp_dir,
+            options.jobs(),
+            compress_temp,
+            throttle,
+            &mut rng,
+        )
+        .await?
+    } else {
+        (subfiles, remaining)
+    };
+
+    let mut writer = sink.create_with_limit(false, options.io_limit)?;
+    let progress = output_progress(positions);
+    let subfile_size = (!options.two_level).then_some(subfile_size);
+    let mut result = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        merge_output(
+            subfiles,
+            remaining,
+            positions,
+            subfile_size,
+            compress_temp,
+            rng,
+            &mut writer,
+            &progress,
+        )?;
+        writer.finish()?;
+        progress.finish();
+        Ok(())
+    })
+    .await?;
+    if result.is_ok()
+        && options.verify
+        && let Some(output) = sink.source()
+    {
+        result = verify_output(Box::new(output.open()?), input_digest).await;
+    }
+    if result.is_err() {
+        for path in sink.files().unwrap_or_default() {
+            let _ = fs::remove_file(path);
+        }
+    }
+    result
+}
+
+/// Checks that there is room for the temporary files of `positions` samples, which take
+/// at most their uncompressed size whatever the size of the input on disk.
+fn check_free_space(temp_dir: &Path, positions: u64) -> anyhow::Result<()> {
+    let required = positions.saturating_mul(mem::size_of::<PackedSample>() as u64);
+    let available = fs2::available_space(temp_dir)
+        .with_context(|| format!("failed to query free space of `{}`", temp_dir.display()))?;
+    if available < required {
+        anyhow::bail!(
+            "not enough free space in `{}` for temporary files: {} required, {} available \
+            (use --temp-dir to choose another directory)",
+            temp_dir.display(),
+            ByteSize(required),
+            ByteSize(available),
+        );
+    }
+    Ok(())
+}
+
+fn subfile_progress() -> ProgressBar {
+    let progress = logging::track(
+        ProgressBar::no_length()
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} blocks done. ",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("shuffling positions..."),
+    );
     progress.enable_steady_tick(Duration::from_millis(50));
+    progress
+}
 
-    let (send, mut recv) = unbounded_channel();
-    let task = tokio::spawn(sample_subfiles(subfiles, remaining, positions, send));
+fn output_progress(positions: u64) -> ProgressBar {
+    let progress = logging::track(
+        ProgressBar::new(positions)
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions written.",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("writing data to output file..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+    progress
+}
 
-    let mut writer = BufWriter::new(output_file);
-    while let Some(sample) = recv.recv().await {
-        writer.write_all(bytemuck::bytes_of(&sample)).await?;
-        progress.inc(1);
+async fn verify_file(
+    output_file: &fs::File,
+    compressed: bool,
+    input_digest: SampleDigest,
+) -> anyhow::Result<()> {
+    let mut file = output_file.try_clone()?;
+    file.sync_all()?;
+    file.rewind()?;
+    verify_output(compression::reader(file, compressed)?, input_digest).await
+}
+
+/// Checks that the output holds exactly the samples the input digest was taken of.
+async fn verify_output(
+    mut reader: Box<dyn Read + Send>,
+    input_digest: SampleDigest,
+) -> anyhow::Result<()> {
+    let progress = logging::track(
+        ProgressBar::new(input_digest.count())
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions verified.",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("verifying output..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let output_digest = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let mut digest = SampleDigest::default();
+        let mut buffer = vec![PackedSample::default(); VERIFY_BUFFER_SIZE];
+        loop {
+            let read = compression::read_samples(&mut reader, &mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            digest.extend(&buffer[..read]);
+            progress.inc(read as u64);
+        }
+        progress.finish();
+        Ok(digest)
+    })
+    .await??;
+
+    if output_digest != input_digest {
+        anyhow::bail!(
+            "verification failed: output does not contain the same samples as the input \
+            ({} input samples, {} output samples)",
+            input_digest.count(),
+            output_digest.count(),
+        );
+    }
+    Ok(())
+}
+
+const VERIFY_BUFFER_SIZE: usize = 8192;
+
+#[allow(clippy::too_many_arguments)]
+async fn write_output(
+    output_file: &mut fs::File,
+    subfiles: Vec<fs::File>,
+    remaining: Vec<u64>,
+    positions: u64,
+    subfile_size: Option<u64>,
+    compressed_subfiles: bool,
+    compression_level: Option<i32>,
+    throttle: Option<Throttle>,
+    rng: Xoshiro256PlusPlus,
+) -> anyhow::Result<()> {
+    let progress = output_progress(positions);
+
+    let file = output_file.try_clone()?;
+    tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut writer =
+            compression::Writer::new(Throttled::new(file, throttle), compression_level)?;
+        merge_output(
+            subfiles,
+            remaining,
+            positions,
+            subfile_size,
+            compressed_subfiles,
+            rng,
+            &mut writer,
+            &progress,
+        )?;
+        let mut file = writer.finish()?.into_inner();
+        // Compressed output rarely matches the size of what it overwrites in place.
+        let len = file.stream_position()?;
+        file.set_len(len)?;
+        progress.finish();
+        Ok(())
+    })
+    .await?
+}
+
+/// Writes the shuffled subfiles out in a random interleaving, evenly weighted by the
+/// samples left in each when they aren't all of `subfile_size` but the last.
+#[allow(clippy::too_many_arguments)]
+fn merge_output(
+    subfiles: Vec<fs::File>,
+    remaining: Vec<u64>,
+    positions: u64,
+    subfile_size: Option<u64>,
+    compressed: bool,
+    mut rng: Xoshiro256PlusPlus,
+    writer: &mut impl Write,
+    progress: &ProgressBar,
+) -> anyhow::Result<()> {
+    match subfile_size {
+        Some(subfile_size) => sample_subfiles(
+            subfiles,
+            remaining,
+            positions,
+            subfile_size,
+            compressed,
+            rng,
+            writer,
+            progress,
+        ),
+        None => merge_subfiles(subfiles, remaining, compressed, &mut rng, writer, progress),
     }
-    writer.flush().await?;
-    progress.finish();
-    task.await?
 }
 
+/// Merges the shuffled subfiles in groups of about the square root of their number into
+/// new temporary files, so that the output is merged from few evenly mixed groups rather
+/// than thousands of subfiles. Returns the merged groups and their sizes.
+#[allow(clippy::too_many_arguments)]
+async fn merge_groups(
+    subfiles: Vec<fs::File>,
+    remaining: Vec<u64>,
+    positions: u64,
+    temp_dir: &Path,
+    jobs: usize,
+    compressed: bool,
+    throttle: Option<Throttle>,
+    rng: &mut impl Rng,
+) -> anyhow::Result<(Vec<fs::File>, Vec<u64>)> {
+    let group_size = (subfiles.len() as f64).sqrt().ceil().max(1.0) as usize;
+    let seed: u64 = rng.random();
+    let mut groups = Vec::new();
+    let mut subfiles = subfiles.into_iter();
+    let mut remaining = remaining.into_iter();
+    loop {
+        let group: Vec<_> = subfiles.by_ref().take(group_size).collect();
+        if group.is_empty() {
+            break;
+        }
+        let sizes: Vec<_> = remaining.by_ref().take(group.len()).collect();
+        groups.push((groups.len() as u64, group, sizes));
+    }
+
+    let progress = logging::track(
+        ProgressBar::new(positions)
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions merged.",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("merging subfiles in groups..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let temp_dir = temp_dir.to_path_buf();
+    let groups = Mutex::new(groups);
+    let merged = Mutex::new(Vec::new());
+    let mut merged = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| {
+                    scope.spawn(|| -> anyhow::Result<()> {
+                        while let Some((idx, group, sizes)) = groups.lock().unwrap().pop() {
+                            let group_positions: u64 = sizes.iter().sum();
+                            let tempfile = tempfile::tempfile_in(&temp_dir).with_context(|| {
+                                format!(
+                                    "failed to create temporary file in `{}`",
+                                    temp_dir.display()
+                                )
+                            })?;
+                            let mut writer = compression::Writer::new(
+                                Throttled::new(tempfile, throttle.clone()),
+                                compressed.then_some(compression::FAST_LEVEL),
+                            )?;
+                            merge_subfiles(
+                                group,
+                                sizes,
+                                compressed,
+                                &mut Xoshiro256PlusPlus::seed_from_u64(seed.wrapping_add(idx)),
+                                &mut writer,
+                                &progress,
+                            )?;
+                            let mut tempfile = writer.finish()?.into_inner();
+                            tempfile.sync_all()?;
+                            tempfile.rewind()?;
+                            merged
+                                .lock()
+                                .unwrap()
+                                .push((idx, tempfile, group_positions));
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("merge worker panicked"))
+        })?;
+        progress.finish();
+        Ok(merged.into_inner().unwrap())
+    })
+    .await??;
+    merged.sort_by_key(|(idx, ..)| *idx);
+    Ok(merged
+        .into_iter()
+        .map(|(_, tempfile, positions)| (tempfile, positions))
+        .unzip())
+}
+
+/// Where subfiles are read from. Plain files are read concurrently at known offsets,
+/// while compressed files and shards can only be read front to back and are handed out
+/// in order.
+enum SubfileInput {
+    Plain {
+        file: fs::File,
+        positions: u64,
+        next: AtomicU64,
+    },
+    Stream(Mutex<(u64, Box<dyn Read + Send>)>),
+}
+
+impl SubfileInput {
+    /// Reads the next unclaimed subfile, returning its index and samples,
+    /// or `None` once the input is exhausted.
+    fn next_subfile(
+        &self,
+        subfile_size: u64,
+        progress: &ProgressBar,
+    ) -> anyhow::Result<Option<(u64, Vec<PackedSample>)>> {
+        match self {
+            SubfileInput::Plain {
+                file,
+                positions,
+                next,
+            } => {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let offset = idx * subfile_size;
+                if offset >= *positions {
+                    return Ok(None);
+                }
+                let step = mem::size_of::<PackedSample>() as u64;
+                let mut subfile =
+                    vec![PackedSample::default(); (positions - offset).min(subfile_size) as usize];
+                read_exact_at(file, bytemuck::cast_slice_mut(&mut subfile), offset * step)?;
+                Ok(Some((idx, subfile)))
+            }
+            SubfileInput::Stream(reader) => {
+                let mut reader = reader.lock().unwrap();
+                let (next, reader) = &mut *reader;
+                let mut subfile = vec![PackedSample::default(); subfile_size as usize];
+                let read = compression::read_samples(reader, &mut subfile)?;
+                if read == 0 {
+                    return Ok(None);
+                }
+                subfile.truncate(read);
+                let idx = *next;
+                *next += 1;
+                progress.inc_length(1);
+                Ok(Some((idx, subfile)))
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn divide_and_shuffle(
     progress: &ProgressBar,
-    file: &mut File,
-) -> anyhow::Result<(Vec<File>, Vec<u64>, u64)> {
-    let positions = file.seek(SeekFrom::End(0)).await? / mem::size_of::<PackedSample>() as u64;
-    file.rewind().await?;
-
-    let mut positions_remaining = positions;
-    let subfiles = positions.div_ceil(SUBFILE_SIZE);
-    progress.set_length(subfiles);
-
-    let mut tempfiles: Vec<File> = (0..subfiles)
-        .map(|_| Ok(File::from_std(tempfile::tempfile()?)))
-        .collect::<Result<Vec<_>, anyhow::Error>>()?;
-    let mut remaining = Vec::new();
+    input: SubfileInput,
+    temp_dir: &Path,
+    subfile_size: u64,
+    jobs: usize,
+    compression_level: Option<i32>,
+    throttle: Option<Throttle>,
+    rng: &mut impl Rng,
+) -> anyhow::Result<(Vec<fs::File>, Vec<u64>, u64, SampleDigest)> {
+    if let SubfileInput::Plain { positions, .. } = &input {
+        progress.set_length(positions.div_ceil(subfile_size));
+    } else {
+        progress.set_length(0);
+    }
 
-    for tempfile in tempfiles.iter_mut() {
-        let subfile_positions = positions_remaining.min(SUBFILE_SIZE);
-        let mut subfile = vec![PackedSample::default(); subfile_positions as usize];
-        file.read_exact(bytemuck::cast_slice_mut(&mut subfile))
-            .await?;
-        subfile.shuffle(&mut rand::rng());
-        tempfile.write_all(bytemuck::cast_slice(&subfile)).await?;
+    // Every subfile derives its own seed from its index so the result doesn't depend on
+    // which worker happens to pick it up.
+    let seed: u64 = rng.random();
+    let results = Mutex::new(Vec::new());
+
+    let progress = progress.clone();
+    let temp_dir = temp_dir.to_path_buf();
+    let mut results = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| {
+                    scope.spawn(|| -> anyhow::Result<()> {
+                        while let Some((idx, subfile)) =
+                            input.next_subfile(subfile_size, &progress)?
+                        {
+                            let subfile_positions = subfile.len() as u64;
+                            let mut digest = SampleDigest::default();
+                            digest.extend(&subfile);
+                            let tempfile = shuffle_subfile(
+                                subfile,
+                                &temp_dir,
+                                seed.wrapping_add(idx),
+                                compression_level,
+                                throttle.clone(),
+                            )?;
+                            results.lock().unwrap().push((
+                                idx,
+                                tempfile,
+                                subfile_positions,
+                                digest,
+                            ));
+                            progress.inc(1);
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("shuffle worker panicked"))
+        })?;
+        progress.finish();
+        Ok(results.into_inner().unwrap())
+    })
+    .await??;
+    results.sort_by_key(|(idx, ..)| *idx);
+
+    let mut tempfiles = Vec::new();
+    let mut remaining = Vec::new();
+    let mut positions = 0;
+    let mut input_digest = SampleDigest::default();
+    for (_, tempfile, subfile_positions, digest) in results {
+        tempfiles.push(tempfile);
         remaining.push(subfile_positions);
-        positions_remaining -= subfile_positions;
-        progress.inc(1);
+        positions += subfile_positions;
+        input_digest += digest;
     }
-    for tempfile in tempfiles.iter_mut() {
-        tempfile.flush().await?;
-        tempfile.sync_all().await?;
-        tempfile.seek(SeekFrom::Start(0)).await?;
+
+    Ok((tempfiles, remaining, positions, input_digest))
+}
+
+fn shuffle_subfile(
+    mut subfile: Vec<PackedSample>,
+    temp_dir: &Path,
+    seed: u64,
+    compression_level: Option<i32>,
+    throttle: Option<Throttle>,
+) -> anyhow::Result<fs::File> {
+    subfile.shuffle(&mut Xoshiro256PlusPlus::seed_from_u64(seed));
+
+    let tempfile = tempfile::tempfile_in(temp_dir).with_context(|| {
+        format!(
+            "failed to create temporary file in `{}`",
+            temp_dir.display()
+        )
+    })?;
+    let mut writer =
+        compression::Writer::new(Throttled::new(tempfile, throttle), compression_level)?;
+    writer.write_all(bytemuck::cast_slice(&subfile))?;
+    let mut tempfile = writer.finish()?.into_inner();
+    tempfile.sync_all()?;
+    tempfile.rewind()?;
+    Ok(tempfile)
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &fs::File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
     }
-    progress.finish();
+    Ok(())
+}
 
-    Ok((tempfiles, remaining, positions))
+/// Interleaves shuffled subfiles, taking each sample from a subfile with a probability
+/// proportional to the samples it has left, which makes the result a uniform shuffle of
+/// all of them.
+fn merge_subfiles(
+    tempfiles: Vec<fs::File>,
+    mut remaining: Vec<u64>,
+    compressed: bool,
+    rng: &mut impl Rng,
+    writer: &mut impl Write,
+    progress: &ProgressBar,
+) -> anyhow::Result<()> {
+    let mut tempfiles = tempfiles
+        .into_iter()
+        .map(|tempfile| compression::reader(tempfile, compressed))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut left: u64 = remaining.iter().sum();
+    while left > 0 {
+        let mut pick = rng.random_range(0..left);
+        let idx = remaining
+            .iter()
+            .position(|&count| {
+                if pick < count {
+                    return true;
+                }
+                pick -= count;
+                false
+            })
+            .expect("samples left in some subfile");
+
+        let mut sample = PackedSample::default();
+        tempfiles[idx].read_exact(bytemuck::bytes_of_mut(&mut sample))?;
+        remaining[idx] -= 1;
+        left -= 1;
+
+        writer.write_all(bytemuck::bytes_of(&sample))?;
+        progress.inc(1);
+    }
+    Ok(())
 }
 
-async fn sample_subfiles(
-    tempfiles: Vec<File>,
+#[allow(clippy::too_many_arguments)]
+fn sample_subfiles(
+    tempfiles: Vec<fs::File>,
     mut remaining: Vec<u64>,
     positions: u64,
-    send: UnboundedSender<PackedSample>,
+    subfile_size: u64,
+    compressed: bool,
+    mut rng: impl Rng,
+    writer: &mut impl Write,
+    progress: &ProgressBar,
 ) -> anyhow::Result<()> {
-    let mut tempfiles: Vec<_> = tempfiles.into_iter().map(BufReader::new).collect();
+    let mut tempfiles = tempfiles
+        .into_iter()
+        .map(|tempfile| compression::reader(tempfile, compressed))
+        .collect::<Result<Vec<_>, _>>()?;
     let subfiles = tempfiles.len();
     let mut remaining_subfiles = subfiles;
-    let last_subfile_size = positions % SUBFILE_SIZE;
+    let last_subfile_size = positions % subfile_size;
     let last_subfile_idx = positions - last_subfile_size;
 
     while remaining_subfiles > 0 {
-        let rand = rand::rng().random_range(0..positions);
+        let rand = rng.random_range(0..positions);
         let idx = if rand < last_subfile_idx {
-            (rand / SUBFILE_SIZE) as usize
+            (rand / subfile_size) as usize
         } else {
             subfiles - 1
         };
@@ -143,17 +969,113 @@ async fn sample_subfiles(
         }
 
         let mut sample = PackedSample::default();
-        tempfiles[idx]
-            .read_exact(bytemuck::bytes_of_mut(&mut sample))
-            .await?;
+        tempfiles[idx].read_exact(bytemuck::bytes_of_mut(&mut sample))?;
         remaining[idx] -= 1;
 
         if remaining[idx] == 0 {
             remaining_subfiles -= 1;
         }
 
-        send.send(sample)?;
+        writer.write_all(bytemuck::bytes_of(&sample))?;
+        progress.inc(1);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: u16 = 100;
+
+    fn samples() -> Vec<PackedSample> {
+        (0..SAMPLES)
+            .map(|n| {
+                let mut sample: PackedSample = bytemuck::Zeroable::zeroed();
+                bytemuck::bytes_of_mut(&mut sample)[..2].copy_from_slice(&n.to_le_bytes());
+                sample
+            })
+            .collect()
+    }
+
+    fn options(temp_dir: &Path, two_level: bool) -> ShuffleOptions {
+        ShuffleOptions {
+            subfile_size: Some(7),
+            jobs: Some(2),
+            temp_dir: Some(temp_dir.to_path_buf()),
+            seed: Some(3),
+            verify: true,
+            two_level,
+            ..ShuffleOptions::default()
+        }
+    }
+
+    /// The samples of a dataset in the order they are stored.
+    fn read(dataset: &DatasetSource) -> Vec<Vec<u8>> {
+        let mut reader = dataset.open().unwrap();
+        let mut samples = Vec::new();
+        while let Some(sample) = reader.read_sample().unwrap() {
+            samples.push(bytemuck::bytes_of(&sample).to_vec());
+        }
+        samples
+    }
+
+    /// Checks that `shuffled` holds every sample once, in another order.
+    fn assert_shuffled(shuffled: Vec<Vec<u8>>) {
+        let expected: Vec<Vec<u8>> = samples()
+            .iter()
+            .map(|sample| bytemuck::bytes_of(sample).to_vec())
+            .collect();
+        assert_ne!(shuffled, expected);
+        let mut sorted = shuffled;
+        sorted.sort_by_key(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+        assert_eq!(sorted, expected);
+    }
+
+    #[tokio::test]
+    async fn shuffle_keeps_every_sample() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.bin");
+        for (output, two_level) in [
+            (None, false),
+            (Some(dir.path().join("output.bin")), true),
+            (Some(dir.path().join("output.bin.zst")), false),
+        ] {
+            fs::write(&input, bytemuck::cast_slice::<_, u8>(&samples())).unwrap();
+            let file = OpenOptions::new()
+                .read(true)
+                .write(output.is_none())
+                .open(&input)
+                .await
+                .unwrap();
+            shuffle(file, output.as_deref(), &options(dir.path(), two_level))
+                .await
+                .unwrap();
+            let output = output.unwrap_or_else(|| input.clone());
+            assert_shuffled(read(&DatasetSource::File(output)));
+        }
+    }
+
+    #[tokio::test]
+    async fn shuffle_to_sink_mixes_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let shard_size = Some(ByteSize(30 * mem::size_of::<PackedSample>() as u64));
+        let input = DatasetSink::from_path(&dir.path().join("input"), false)
+            .sharded(shard_size)
+            .unwrap();
+        let mut writer = input.create(false).unwrap();
+        writer.write_samples(&samples()).unwrap();
+        writer.finish().unwrap();
+
+        let output = DatasetSink::from_path(&dir.path().join("output"), true)
+            .sharded(shard_size)
+            .unwrap();
+        let source = input.source().unwrap();
+        shuffle_to_sink(&source, &output, &options(dir.path(), false))
+            .await
+            .unwrap();
+        assert_eq!(output.files().unwrap().len(), 4);
+        assert_shuffled(read(&output.source().unwrap()));
+    }
+}
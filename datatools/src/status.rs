@@ -0,0 +1,300 @@
+use anyhow::Context;
+use std::{
+    collections::VecDeque,
+    fmt::{Display, Write as _},
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::logging::json_string;
+
+/// A JSON object describing the state of a long-running command.
+#[derive(Clone, Debug, Default)]
+pub struct StatusPage {
+    fields: String,
+}
+
+impl StatusPage {
+    pub fn number(mut self, name: &str, value: impl Display) -> Self {
+        self.field(name, &value.to_string());
+        self
+    }
+
+    pub fn string(mut self, name: &str, value: &str) -> Self {
+        self.field(name, &json_string(value));
+        self
+    }
+
+    pub fn strings<'a>(mut self, name: &str, values: impl IntoIterator<Item = &'a str>) -> Self {
+        let values: Vec<_> = values.into_iter().map(json_string).collect();
+        self.field(name, &format!("[{}]", values.join(",")));
+        self
+    }
+
+    pub fn objects(mut self, name: &str, pages: impl IntoIterator<Item = StatusPage>) -> Self {
+        let pages: Vec<_> = pages
+            .into_iter()
+            .map(|page| format!("{{{}}}", page.fields))
+            .collect();
+        self.field(name, &format!("[{}]", pages.join(",")));
+        self
+    }
+
+    fn field(&mut self, name: &str, value: &str) {
+        if !self.fields.is_empty() {
+            self.fields.push(',');
+        }
+        write!(self.fields, "{}:{}", json_string(name), value).unwrap();
+    }
+
+    fn to_json(&self) -> String {
+        format!("{{{}}}\n", self.fields)
+    }
+}
+
+/// Serves the page returned by `status` as JSON to every `GET` request on `addr`,
+/// from a background thread. Returns the address actually bound.
+pub fn serve(
+    addr: &str,
+    status: impl Fn() -> StatusPage + Send + 'static,
+) -> anyhow::Result<SocketAddr> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("failed to listen on `{}`", addr))?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A misbehaving client only costs its own request.
+            let _ = respond(stream, &status);
+        }
+    });
+    Ok(local_addr)
+}
+
+fn respond(mut stream: TcpStream, status: &impl Fn() -> StatusPage) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skips the headers, the request never has a body worth reading.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let (code, body) = if request_line.starts_with("GET ") {
+        ("200 OK", status().to_json())
+    } else {
+        ("405 Method Not Allowed", "{}\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Errors kept around for the status page.
+const RECENT_ERRORS: usize = 10;
+
+/// The last few errors of a command, shown on its status page.
+#[derive(Debug, Default)]
+pub struct RecentErrors(Mutex<VecDeque<String>>);
+
+impl RecentErrors {
+    pub fn record(&self, error: String) {
+        let mut errors = self.0.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(error);
+    }
+
+    pub fn to_vec(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// A running average of values reported from several threads.
+#[derive(Debug, Default)]
+pub struct Average {
+    total: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Average {
+    pub fn add(&self, value: u64) {
+        self.total.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The average of the values added so far, if any.
+    pub fn get(&self) -> Option<f64> {
+        let count = self.count.load(Ordering::Relaxed);
+        (count > 0).then(|| self.total.load(Ordering::Relaxed) as f64 / count as f64)
+    }
+}
+
+/// What the engines reported of their searches, to tell whether they searched as deep
+/// as they were meant to. Engines leaving some of it out of their `info` lines don't
+/// count towards that average.
+#[derive(Debug, Default)]
+pub struct SearchStats {
+    pub depth: Average,
+    pub nodes: Average,
+    pub time_ms: Average,
+}
+
+impl SearchStats {
+    pub fn add(&self, depth: Option<u64>, nodes: Option<u64>, time_ms: Option<u64>) {
+        for (average, value) in [
+            (&self.depth, depth),
+            (&self.nodes, nodes),
+            (&self.time_ms, time_ms),
+        ] {
+            if let Some(value) = value {
+                average.add(value);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkerState {
+    Running,
+    Finished,
+    /// Stopped early when the command was interrupted.
+    Stopped,
+    Failed,
+}
+
+impl WorkerState {
+    fn name(self) -> &'static str {
+        match self {
+            WorkerState::Running => "running",
+            WorkerState::Finished => "finished",
+            WorkerState::Stopped => "stopped",
+            WorkerState::Failed => "failed",
+        }
+    }
+}
+
+struct Worker {
+    name: String,
+    state: WorkerState,
+    games: u64,
+    last_active: Instant,
+}
+
+/// Progress of a command generating samples from games, shared between its workers
+/// and its status page.
+pub struct GenerationStatus {
+    started: Instant,
+    games: AtomicU64,
+    positions: AtomicU64,
+    workers: Mutex<Vec<Worker>>,
+    pub recent_errors: RecentErrors,
+    pub searches: SearchStats,
+}
+
+impl GenerationStatus {
+    pub fn new(workers: impl IntoIterator<Item = String>) -> Self {
+        let now = Instant::now();
+        let workers = workers
+            .into_iter()
+            .map(|name| Worker {
+                name,
+                state: WorkerState::Running,
+                games: 0,
+                last_active: now,
+            })
+            .collect();
+        GenerationStatus {
+            started: now,
+            games: AtomicU64::new(0),
+            positions: AtomicU64::new(0),
+            workers: Mutex::new(workers),
+            recent_errors: RecentErrors::default(),
+            searches: SearchStats::default(),
+        }
+    }
+
+    /// Adds a worker started after the others, returning its index.
+    pub fn add_worker(&self, name: String) -> usize {
+        let mut workers = self.workers.lock().unwrap();
+        workers.push(Worker {
+            name,
+            state: WorkerState::Running,
+            games: 0,
+            last_active: Instant::now(),
+        });
+        workers.len() - 1
+    }
+
+    pub fn game_finished(&self, worker: usize) {
+        self.games.fetch_add(1, Ordering::Relaxed);
+        let mut workers = self.workers.lock().unwrap();
+        workers[worker].games += 1;
+        workers[worker].last_active = Instant::now();
+    }
+
+    pub fn games(&self) -> u64 {
+        self.games.load(Ordering::Relaxed)
+    }
+
+    pub fn add_positions(&self, positions: u64) {
+        self.positions.fetch_add(positions, Ordering::Relaxed);
+    }
+
+    pub fn set_state(&self, worker: usize, state: WorkerState) {
+        let mut workers = self.workers.lock().unwrap();
+        workers[worker].state = state;
+        workers[worker].last_active = Instant::now();
+    }
+
+    pub fn page(&self) -> StatusPage {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let positions = self.positions.load(Ordering::Relaxed);
+        let workers = self.workers.lock().unwrap();
+        let errors = self.recent_errors.to_vec();
+        let mut page = StatusPage::default()
+            .number("games", self.games.load(Ordering::Relaxed))
+            .number("positions", positions)
+            .number(
+                "positions_per_sec",
+                format!("{:.1}", positions as f64 / elapsed.max(1e-3)),
+            )
+            .number("elapsed_secs", format!("{:.1}", elapsed));
+        for (name, average) in [
+            ("avg_depth", &self.searches.depth),
+            ("avg_nodes", &self.searches.nodes),
+            ("avg_search_ms", &self.searches.time_ms),
+        ] {
+            if let Some(average) = average.get() {
+                page = page.number(name, format!("{:.1}", average));
+            }
+        }
+        page.objects(
+            "workers",
+            workers.iter().map(|worker| {
+                StatusPage::default()
+                    .string("name", &worker.name)
+                    .string("state", worker.state.name())
+                    .number("games", worker.games)
+                    .number(
+                        "idle_secs",
+                        format!("{:.1}", worker.last_active.elapsed().as_secs_f64()),
+                    )
+            }),
+        )
+        .strings("recent_errors", errors.iter().map(String::as_str))
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::Context;
+use dama::{Color, Outcome, Position};
+use dataformat::convert::shakmaty::to_shakmaty;
+use shakmaty::Chess;
+use shakmaty_syzygy::Wdl;
+use std::path::Path;
+
+/// Syzygy tablebases, probed through shakmaty by converting positions to its own.
+pub struct Tablebase {
+    tables: shakmaty_syzygy::Tablebase<Chess>,
+}
+
+impl Tablebase {
+    pub fn open(path: &Path) -> anyhow::Result<Tablebase> {
+        let mut tables = shakmaty_syzygy::Tablebase::new();
+        let added = tables
+            .add_directory(path)
+            .with_context(|| format!("failed to open tablebase directory `{}`", path.display()))?;
+        if added == 0 {
+            anyhow::bail!("no tablebase files found in `{}`", path.display());
+        }
+        Ok(Tablebase { tables })
+    }
+
+    #[inline]
+    pub fn max_pieces(&self) -> usize {
+        self.tables.max_pieces()
+    }
+
+    /// Probes the position, ignoring its halfmove clock, returning the WDL
+    /// from the side to move's point of view if the position is covered.
+    pub fn probe_wdl(&self, position: &Position) -> Option<Wdl> {
+        self.tables
+            .probe_wdl_after_zeroing(&self.convert(position)?)
+            .ok()
+    }
+
+    /// Probes the DTZ tables, returning the distance in plies to the next zeroing move
+    /// under optimal play, negative when losing. Requires the `.rtbz` files.
+    pub fn probe_dtz(&self, position: &Position) -> Option<i32> {
+        let dtz = self.tables.probe_dtz(&self.convert(position)?).ok()?;
+        Some(dtz.ignore_rounding().0)
+    }
+
+    fn convert(&self, position: &Position) -> Option<Chess> {
+        if position.occupied().to_bits().count_ones() as usize > self.max_pieces() {
+            return None;
+        }
+        to_shakmaty(position).ok()
+    }
+
+    /// Returns the game outcome under perfect play, counting cursed wins and blessed
+    /// losses as draws.
+    pub fn probe_outcome(&self, position: &Position) -> Option<Outcome> {
+        let stm = position.side_to_move();
+        self.probe_wdl(position).map(|wdl| wdl_outcome(wdl, stm))
+    }
+}
+
+#[inline]
+pub fn wdl_outcome(wdl: Wdl, side_to_move: Color) -> Outcome {
+    match wdl {
+        Wdl::Win => Outcome::Winner(side_to_move),
+        Wdl::Loss => Outcome::Winner(!side_to_move),
+        Wdl::CursedWin | Wdl::Draw | Wdl::BlessedLoss => Outcome::Draw,
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::Context;
+use dataformat::PackedSample;
+use indicatif::{ProgressBar, ProgressStyle};
+use shakmaty_syzygy::Wdl;
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    io::{DatasetSource, SampleFiles},
+    logging, manifest,
+    tablebase::{self, Tablebase},
+};
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[clap(help("Uncompressed dataset, a file or a directory of shards, to relabel in place."))]
+    file: DatasetSource,
+    #[clap(long("syzygy"), help("Directory of Syzygy tablebases to probe."))]
+    syzygy: PathBuf,
+    #[clap(
+        long("win-eval"),
+        default_value_t = 10000,
+        help(
+            "Eval given to tablebase wins, minus the distance to zeroing in plies when DTZ tables are available."
+        )
+    )]
+    win_eval: i16,
+    #[clap(
+        long("check"),
+        help("Only reports the samples that would be relabeled, leaving the file untouched.")
+    )]
+    check: bool,
+}
+
+const BLOCK_SIZE: u64 = 65536;
+/// Longest distance to zeroing of an unconditional win.
+const MAX_WIN_DTZ: i32 = 100;
+
+#[derive(Default)]
+struct Stats {
+    probed: u64,
+    with_dtz: u64,
+    outcomes_changed: u64,
+    evals_changed: u64,
+}
+
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    if args.win_eval <= MAX_WIN_DTZ as i16 {
+        anyhow::bail!("--win-eval must be greater than {}", MAX_WIN_DTZ);
+    }
+    let tablebase = Tablebase::open(&args.syzygy)?;
+    let perspective = manifest::perspective_of(&args.file)?;
+
+    let mut files = SampleFiles::open(&args.file, !args.check)?;
+    let positions = files.len();
+
+    let progress = logging::track(
+        ProgressBar::new(positions)
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} positions checked.",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message("probing tablebases..."),
+    );
+    progress.enable_steady_tick(Duration::from_millis(50));
+
+    let mut stats = Stats::default();
+    let mut block = vec![PackedSample::default(); BLOCK_SIZE as usize];
+    let mut offset = 0;
+    while offset < positions {
+        let len = (positions - offset).min(BLOCK_SIZE) as usize;
+        let block = &mut block[..len];
+        files.read(offset, block)?;
+
+        let mut modified = false;
+        for (n, packed) in block.iter_mut().enumerate() {
+            let mut sample = packed
+                .unpack()
+                .with_context(|| format!("failed to unpack sample #{}", offset + n as u64))?;
+            let Some(wdl) = tablebase.probe_wdl(&sample.position) else {
+                continue;
+            };
+            stats.probed += 1;
+            let dtz = tablebase.probe_dtz(&sample.position);
+            stats.with_dtz += dtz.is_some() as u64;
+
+            let side_to_move = sample.position.side_to_move();
+            let outcome = tablebase::wdl_outcome(wdl, side_to_move);
+            let eval = exact_eval(wdl, dtz, args.win_eval);
+            let eval = Some(perspective.from_side_to_move(eval, side_to_move));
+            if outcome == sample.outcome && eval == sample.eval {
+                continue;
+            }
+            stats.outcomes_changed += (outcome != sample.outcome) as u64;
+            stats.evals_changed += (eval != sample.eval) as u64;
+            sample.outcome = outcome;
+            sample.eval = eval;
+            packed.repack(&sample)?;
+            modified = true;
+        }
+
+        if modified && !args.check {
+            files.write(offset, block)?;
+        }
+
+        offset += len as u64;
+        progress.inc(len as u64);
+    }
+    progress.finish();
+    if !args.check {
+        manifest::refresh(&args.file)?;
+    }
+
+    logging::summary(
+        &format!(
+            "{} of {} positions found in the tablebases ({} with DTZ), {} outcomes and {} evals {}",
+            stats.probed,
+            positions,
+            stats.with_dtz,
+            stats.outcomes_changed,
+            stats.evals_changed,
+            if args.check {
+                "to relabel"
+            } else {
+                "relabeled"
+            },
+        ),
+        &[
+            ("positions", positions),
+            ("probed", stats.probed),
+            ("with_dtz", stats.with_dtz),
+            ("outcomes_changed", stats.outcomes_changed),
+            ("evals_changed", stats.evals_changed),
+        ],
+    );
+    Ok(())
+}
+
+/// The eval of a position from the side to move's point of view under perfect play.
+/// Wins closer to a zeroing move score higher, and wins spoiled by the 50-move rule are
+/// draws like in [`tablebase::wdl_outcome`].
+fn exact_eval(wdl: Wdl, dtz: Option<i32>, win_eval: i16) -> i16 {
+    let win = win_eval - dtz.map_or(0, |dtz| dtz.abs().min(MAX_WIN_DTZ)) as i16;
+    match wdl {
+        Wdl::Win => win,
+        Wdl::Loss => -win,
+        Wdl::CursedWin | Wdl::Draw | Wdl::BlessedLoss => 0,
+    }
+}
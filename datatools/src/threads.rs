@@ -0,0 +1,33 @@
+use std::{sync::OnceLock, thread};
+
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct ThreadOptions {
+    #[clap(
+        long("threads"),
+        global(true),
+        help("Number of cores kept busy at once, defaults to all of them.")
+    )]
+    threads: Option<usize>,
+}
+
+static THREADS: OnceLock<usize> = OnceLock::new();
+
+/// Sets the number of threads for the whole process, must be called before the runtime
+/// is started. Returns the number of threads.
+pub fn init(options: ThreadOptions) -> usize {
+    let threads = options.threads.unwrap_or_else(cores).max(1);
+    THREADS.set(threads).expect("threads are only set up once");
+    threads
+}
+
+/// The number of threads commands may keep busy, for the ones dividing work between
+/// threads of their own.
+pub fn available() -> usize {
+    *THREADS.get_or_init(cores)
+}
+
+fn cores() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
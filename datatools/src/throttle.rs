@@ -0,0 +1,81 @@
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::units::ByteRate;
+
+/// A byte budget refilled at a fixed rate. Clones share the same budget, so writers on
+/// different threads stay under the limit together.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    state: Arc<Mutex<ThrottleState>>,
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: ByteRate) -> Self {
+        Throttle {
+            bytes_per_sec: rate.bytes_per_sec().max(1),
+            state: Arc::new(Mutex::new(ThrottleState {
+                started: Instant::now(),
+                bytes: 0,
+            })),
+        }
+    }
+
+    /// Accounts for `bytes` just written, sleeping until the average rate since the
+    /// throttle was created is back under the limit.
+    pub fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            state.bytes += bytes;
+            let due = Duration::from_secs_f64(state.bytes as f64 / self.bytes_per_sec as f64);
+            due.saturating_sub(state.started.elapsed())
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// A writer passing everything through to `inner`, held back by a [`Throttle`] if
+/// there is one.
+pub struct Throttled<W> {
+    inner: W,
+    throttle: Option<Throttle>,
+}
+
+impl<W: Write> Throttled<W> {
+    pub fn new(inner: W, throttle: Option<Throttle>) -> Self {
+        Throttled { inner, throttle }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(throttle) = &self.throttle {
+            throttle.consume(written as u64);
+        }
+        Ok(written)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
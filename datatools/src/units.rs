@@ -0,0 +1,87 @@
+use anyhow::Context;
+use std::{fmt, str::FromStr};
+
+/// A size in bytes, parsed from strings like `512M`, `4GiB` or `1048576`.
+/// Suffixes are interpreted as powers of 1024.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    #[inline]
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|ch: char| !ch.is_ascii_digit() && ch != '.')
+            .unwrap_or(s.len());
+        let (number, suffix) = s.split_at(split);
+        let number = number
+            .parse::<f64>()
+            .with_context(|| format!("invalid size `{}`", s))?;
+        let multiplier = match suffix.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 1u64,
+            "K" | "KB" | "KIB" => 1 << 10,
+            "M" | "MB" | "MIB" => 1 << 20,
+            "G" | "GB" | "GIB" => 1 << 30,
+            "T" | "TB" | "TIB" => 1 << 40,
+            _ => anyhow::bail!("invalid size suffix in `{}`", s),
+        };
+        Ok(ByteSize((number * multiplier as f64) as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} {}", self.0, UNITS[0])
+        } else {
+            write!(f, "{:.2} {}", value, UNITS[unit])
+        }
+    }
+}
+
+/// A transfer rate in bytes per second, parsed like [`ByteSize`] with an optional
+/// `/s` suffix, e.g. `100M/s` or `1.5GiB`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteRate(pub u64);
+
+impl ByteRate {
+    #[inline]
+    pub fn bytes_per_sec(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteRate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let size = s.strip_suffix("/s").unwrap_or(s);
+        let ByteSize(bytes) = size.parse()?;
+        if bytes == 0 {
+            anyhow::bail!("rate `{}` must be greater than zero", s);
+        }
+        Ok(ByteRate(bytes))
+    }
+}
+
+impl fmt::Display for ByteRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", ByteSize(self.0))
+    }
+}
@@ -0,0 +1,134 @@
+use dama::{Color, Outcome};
+
+/// Whose point of view the evals in PGN comments are given from, which differs between
+/// the tools writing them.
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvalSign {
+    /// The side that just played the move the comment follows, like cutechess.
+    #[default]
+    Mover,
+    White,
+}
+
+impl EvalSign {
+    pub fn name(self) -> &'static str {
+        match self {
+            EvalSign::Mover => "mover",
+            EvalSign::White => "white",
+        }
+    }
+}
+
+/// Reads the eval, in pawns, out of the comment following a move.
+pub trait EvalParser {
+    /// `None` for comments without an eval, or with one that can't be trained on such
+    /// as a mate score.
+    fn parse(&self, comment: &str) -> Option<f64>;
+}
+
+/// Comments of the form `<eval>/<depth> <time>` as cutechess writes them, where book
+/// moves are commented `book` and mates `+M<n>` or `-M<n>`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CutechessEval;
+
+impl EvalParser for CutechessEval {
+    fn parse(&self, comment: &str) -> Option<f64> {
+        if comment == "book" {
+            return None;
+        }
+        let info = comment.split('/').next()?;
+        if info.starts_with("+M") || info.starts_with("-M") {
+            return None;
+        }
+        info.parse().ok()
+    }
+}
+
+/// Games read from the start of an input file to tell the sign of its evals.
+pub const SIGN_CHECK_GAMES: usize = 2000;
+/// Evals closer to zero than this, in pawns, say too little about the result to count.
+pub const SIGN_CHECK_EVAL: f64 = 1.0;
+/// Evals needed before the sign is judged at all.
+pub const MIN_SIGN_VOTES: u64 = 200;
+/// Share of evals one reading of the sign must agree with to be trusted.
+pub const SIGN_MAJORITY: f64 = 0.75;
+
+/// Counts of evals after Black's moves in decisive games, where the two readings of the
+/// sign differ, that predict the result when read each way.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SignVotes {
+    pub mover: u64,
+    pub white: u64,
+}
+
+impl SignVotes {
+    /// Counts an eval given in a game with the given outcome, with `side_to_move` about
+    /// to reply to the move it follows.
+    pub fn record(&mut self, eval: f64, side_to_move: Color, outcome: Outcome) {
+        if let Outcome::Winner(winner) = outcome
+            && side_to_move == Color::White
+            && eval.abs() >= SIGN_CHECK_EVAL
+        {
+            if (eval > 0.0) == (winner == Color::Black) {
+                self.mover += 1;
+            } else {
+                self.white += 1;
+            }
+        }
+    }
+
+    /// The reading most evals agree with, if there are enough of them and the majority
+    /// is clear. Engines misjudging games make either reading miss some of the time.
+    pub fn verdict(&self) -> Option<EvalSign> {
+        let total = self.mover + self.white;
+        if total < MIN_SIGN_VOTES {
+            return None;
+        }
+        if self.mover as f64 >= SIGN_MAJORITY * total as f64 {
+            Some(EvalSign::Mover)
+        } else if self.white as f64 >= SIGN_MAJORITY * total as f64 {
+            Some(EvalSign::White)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CutechessEval, EvalParser, EvalSign, MIN_SIGN_VOTES, SignVotes};
+    use dama::{Color, Outcome};
+
+    #[test]
+    fn parse_cutechess_comments() {
+        let parser = CutechessEval;
+        assert_eq!(parser.parse("+0.35/12 0.101s"), Some(0.35));
+        assert_eq!(parser.parse("-1.20/20 1.5s"), Some(-1.2));
+        assert_eq!(parser.parse("book"), None);
+        assert_eq!(parser.parse("+M7/30 0.2s"), None);
+        assert_eq!(parser.parse("-M2/5 0.1s"), None);
+        assert_eq!(parser.parse("White resigns"), None);
+    }
+
+    #[test]
+    fn votes_need_a_clear_majority() {
+        let mut votes = SignVotes::default();
+        for _ in 0..MIN_SIGN_VOTES {
+            // Black won and White, about to move, is told it's winning: only the
+            // mover's reading predicts the result.
+            votes.record(2.0, Color::White, Outcome::Winner(Color::Black));
+            // Evals after White's moves and small ones don't count.
+            votes.record(-2.0, Color::Black, Outcome::Winner(Color::Black));
+            votes.record(0.5, Color::White, Outcome::Winner(Color::White));
+        }
+        assert_eq!(votes.mover, MIN_SIGN_VOTES);
+        assert_eq!(votes.white, 0);
+        assert_eq!(votes.verdict(), Some(EvalSign::Mover));
+
+        for _ in 0..MIN_SIGN_VOTES {
+            votes.record(2.0, Color::White, Outcome::Winner(Color::White));
+        }
+        assert_eq!(votes.verdict(), None);
+    }
+}
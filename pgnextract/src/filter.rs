@@ -0,0 +1,51 @@
+use dama::{Position, SanMove};
+
+/// Decides whether the position a move is played from is kept as a sample.
+pub trait MoveFilter {
+    fn keep(&self, position: &Position, mv: &SanMove) -> bool;
+}
+
+/// Skips positions where the side to move is in check, whose evals hinge on the reply.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NotInCheck;
+
+impl MoveFilter for NotInCheck {
+    fn keep(&self, position: &Position, _mv: &SanMove) -> bool {
+        !position.is_in_check()
+    }
+}
+
+/// Skips positions where a capture is played, which are mid-exchange and so not quiet.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NotCapture;
+
+impl MoveFilter for NotCapture {
+    fn keep(&self, _position: &Position, mv: &SanMove) -> bool {
+        !mv.is_capture()
+    }
+}
+
+/// The filters keeping only quiet positions, which extraction has always used.
+pub fn quiet() -> Vec<Box<dyn MoveFilter + Send>> {
+    vec![Box::new(NotInCheck), Box::new(NotCapture)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MoveFilter, NotCapture, NotInCheck};
+    use dama::{Position, SanMove};
+
+    #[test]
+    fn quiet_filters() {
+        // Black is in check from the bishop on b5, the filters only look at the moves.
+        let position =
+            Position::from_fen("rnbqkbnr/ppp2ppp/8/1B1pp3/4P3/8/PPPP1PPP/RNBQK1NR b KQkq - 1 3")
+                .unwrap();
+        let capture: SanMove = "dxe4".parse().unwrap();
+        let block: SanMove = "c6".parse().unwrap();
+        assert!(!NotCapture.keep(&position, &capture));
+        assert!(NotCapture.keep(&position, &block));
+        assert!(!NotInCheck.keep(&position, &block));
+        assert!(NotInCheck.keep(&Position::new_initial(), &"e4".parse().unwrap()));
+    }
+}
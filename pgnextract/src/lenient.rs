@@ -0,0 +1,169 @@
+use dama::pgn::{self, ParseError, ParseErrorKind};
+use std::{
+    io::{self, BufRead, Cursor},
+    mem,
+};
+
+/// Reads PGN games one at a time, each split off the input at its first tag pair line,
+/// so that a malformed game is skipped up to the next one. dama's own recovery skips to
+/// the next tag pair outside a comment, which an unterminated comment can push to the
+/// end of the file.
+///
+/// A game starts at an `[Event` line, or at any tag pair line following movetext for
+/// games without the tag.
+pub struct LenientReader<R> {
+    input: R,
+    /// The line starting the next game, read while looking for the end of the last one.
+    next_line: Vec<u8>,
+    /// Lines read from the input so far.
+    lines: u32,
+    game: Option<Game>,
+    recovered: u64,
+}
+
+/// The text of a single game, with the line of the input it starts at.
+struct Game {
+    reader: pgn::Reader<Cursor<Vec<u8>>>,
+    first_line: u32,
+}
+
+impl<R: BufRead> LenientReader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            next_line: Vec::new(),
+            lines: 0,
+            game: None,
+            recovered: 0,
+        }
+    }
+
+    /// Visits the next game like [`pgn::Reader::visit_game`]. After an error, reading
+    /// resumes with the next game. Only errors reading the input are unrecoverable.
+    pub fn visit_game<V: pgn::Visitor>(
+        &mut self,
+        visitor: &mut V,
+    ) -> Result<bool, pgn::Error<V::Error>> {
+        loop {
+            if self.game.is_none() {
+                match self.read_game() {
+                    Ok(Some(game)) => self.game = Some(game),
+                    Ok(None) => return Ok(false),
+                    Err(err) => {
+                        return Err(pgn::Error::Parse(ParseError {
+                            line: self.lines + 1,
+                            column: 1,
+                            kind: ParseErrorKind::Io(err),
+                            is_recoverable: false,
+                        }));
+                    }
+                }
+            }
+            let game = self.game.as_mut().unwrap();
+            match game.reader.visit_game(visitor) {
+                Ok(true) => return Ok(true),
+                Ok(false) => self.game = None,
+                Err(mut err) => {
+                    if let pgn::Error::Parse(err) = &mut err {
+                        err.line += game.first_line - 1;
+                    }
+                    self.game = None;
+                    self.recovered += 1;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Malformed games skipped to resume at the next one.
+    #[inline]
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    /// The next game, `None` at the end of the input.
+    fn read_game(&mut self) -> io::Result<Option<Game>> {
+        let mut game = mem::take(&mut self.next_line);
+        let first_line = if game.is_empty() {
+            self.lines + 1
+        } else {
+            self.lines
+        };
+        let mut movetext = false;
+        loop {
+            let mut line = Vec::new();
+            if self.input.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            self.lines += 1;
+            let trimmed = line.trim_ascii_start();
+            let tag_pair = trimmed.starts_with(b"[");
+            if (tag_pair && movetext)
+                || (trimmed.starts_with(b"[Event ") && !game.trim_ascii().is_empty())
+            {
+                self.next_line = line;
+                break;
+            }
+            movetext |= !tag_pair && !trimmed.is_empty();
+            game.extend_from_slice(&line);
+        }
+        if game.trim_ascii().is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Game {
+            reader: pgn::Reader::new(Cursor::new(game)),
+            first_line,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LenientReader;
+    use crate::Extractor;
+    use dama::pgn;
+
+    // The comment left open in the second game runs over the third one.
+    const GAMES: &str = r#"[Event "?"]
+[Result "1-0"]
+
+1. e4 e5 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0
+
+[Event "?"]
+[Result "0-1"]
+
+1. f3 {oops e5 2. g4 Qh4# 0-1
+
+[Event "?"]
+[Result "1/2-1/2"]
+
+1. d4 d5 1/2-1/2
+[Result "1/2-1/2"]
+
+1. c4 c5 1/2-1/2
+"#;
+
+    #[test]
+    fn resume_after_malformed_games() {
+        let mut extractor = Extractor::default().without_evals(true);
+        let mut reader = pgn::Reader::new(GAMES.as_bytes());
+        while let Ok(true) | Err(_) = reader.visit_game(&mut extractor) {}
+        assert_eq!(extractor.stats().games_read, 2);
+
+        let mut extractor = Extractor::default().without_evals(true);
+        let mut reader = LenientReader::new(GAMES.as_bytes());
+        let mut errors = Vec::new();
+        loop {
+            match reader.visit_game(&mut extractor) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(pgn::Error::Parse(err)) => errors.push(err.line),
+                Err(err) => panic!("unexpected error: {}", err),
+            }
+        }
+        assert_eq!(extractor.stats().games_read, 4);
+        assert_eq!(reader.recovered(), 1);
+        // The end of the second game, where the comment is still open.
+        assert_eq!(errors, [11]);
+    }
+}
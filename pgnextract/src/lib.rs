@@ -0,0 +1,330 @@
+//! Turns PGN games into training samples through a pipeline of move filters and an eval
+//! parser, shared by the tools reading engine games.
+
+pub mod eval;
+pub mod filter;
+pub mod lenient;
+
+use dama::{Color, FenError, Outcome, Position, SanError, SanMove, pgn};
+use dataformat::{EvalOutOfRangeError, EvalOverflow, FittedEval, Sample};
+use std::{mem, str};
+use thiserror::Error;
+
+pub use eval::{CutechessEval, EvalParser, EvalSign, SignVotes};
+pub use filter::MoveFilter;
+pub use lenient::LenientReader;
+
+#[derive(Debug, Error)]
+pub enum ExtractError {
+    #[error("invalid FEN tag: {0}")]
+    InvalidFen(#[from] FenError),
+    #[error("invalid result `{0}`")]
+    InvalidResult(String),
+    #[error("illegal move, position: '{fen}', move: '{mv}': {source}")]
+    IllegalMove {
+        fen: String,
+        mv: String,
+        source: SanError,
+    },
+    #[error("comment is not valid UTF-8")]
+    InvalidComment(#[from] str::Utf8Error),
+    #[error(transparent)]
+    EvalOutOfRange(#[from] EvalOutOfRangeError),
+}
+
+/// Counts kept across the games an extractor reads.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExtractStats {
+    pub games_read: u64,
+    pub games_skipped: u64,
+    pub positions_seen: u64,
+    pub positions_kept: u64,
+}
+
+/// A PGN visitor turning each game into samples, the positions before the moves every
+/// filter keeps with the eval of the comment on the previous move. Games without a result
+/// or which ended abnormally are skipped.
+pub struct Extractor {
+    filters: Vec<Box<dyn MoveFilter + Send>>,
+    eval_parser: Box<dyn EvalParser + Send>,
+    eval_sign: EvalSign,
+    eval_overflow: EvalOverflow,
+    /// Whether positions are kept without evals, ignoring the comments.
+    no_eval: bool,
+
+    /// Samples of the current game.
+    samples: Vec<Sample>,
+    skip: bool,
+    position: Position,
+    outcome: Option<Outcome>,
+    eval: Option<i16>,
+    /// Whether the comment on the previous move had an eval too large to keep.
+    eval_dropped: bool,
+    votes: SignVotes,
+    stats: ExtractStats,
+}
+
+impl Default for Extractor {
+    fn default() -> Self {
+        Self {
+            filters: filter::quiet(),
+            eval_parser: Box::new(CutechessEval),
+            eval_sign: EvalSign::default(),
+            eval_overflow: EvalOverflow::default(),
+            no_eval: false,
+            samples: Vec::new(),
+            skip: false,
+            position: Position::new_initial(),
+            outcome: None,
+            eval: None,
+            eval_dropped: false,
+            votes: SignVotes::default(),
+            stats: ExtractStats::default(),
+        }
+    }
+}
+
+impl Extractor {
+    /// Replaces the filters, which default to [`filter::quiet`].
+    pub fn with_filters(mut self, filters: Vec<Box<dyn MoveFilter + Send>>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    pub fn with_eval_parser(mut self, eval_parser: impl EvalParser + Send + 'static) -> Self {
+        self.eval_parser = Box::new(eval_parser);
+        self
+    }
+
+    pub fn with_eval_sign(mut self, eval_sign: EvalSign) -> Self {
+        self.eval_sign = eval_sign;
+        self
+    }
+
+    /// Sets what happens to evals past what samples hold, which are clamped by default.
+    pub fn with_eval_overflow(mut self, eval_overflow: EvalOverflow) -> Self {
+        self.eval_overflow = eval_overflow;
+        self
+    }
+
+    /// Keeps positions without evals, for games without eval comments.
+    pub fn without_evals(mut self, no_eval: bool) -> Self {
+        self.no_eval = no_eval;
+        self
+    }
+
+    #[inline]
+    pub fn eval_sign(&self) -> EvalSign {
+        self.eval_sign
+    }
+
+    /// How the evals read so far agree with the game results under each sign.
+    #[inline]
+    pub fn votes(&self) -> SignVotes {
+        self.votes
+    }
+
+    #[inline]
+    pub fn stats(&self) -> ExtractStats {
+        self.stats
+    }
+
+    /// Samples of the games visited since the last call, with evals from the side to
+    /// move's perspective.
+    pub fn take_samples(&mut self) -> Vec<Sample> {
+        mem::take(&mut self.samples)
+    }
+}
+
+impl pgn::Visitor for Extractor {
+    type Error = ExtractError;
+
+    fn prepare(&mut self) {
+        self.position = Position::new_initial();
+        self.outcome = None;
+        self.skip = false;
+        self.eval = None;
+        self.eval_dropped = false;
+    }
+
+    fn visit_tag_pair(&mut self, name: &str, value: &str) -> Result<(), ExtractError> {
+        match name {
+            "FEN" => self.position = Position::from_fen(value)?,
+            "Result" if value == "*" => self.outcome = None,
+            "Result" => {
+                self.outcome = Some(
+                    value
+                        .parse()
+                        .map_err(|_| ExtractError::InvalidResult(value.to_string()))?,
+                )
+            }
+            "Termination" if value != "normal" => self.skip = true,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn enter_game(&mut self) -> pgn::ControlFlow {
+        if self.skip || self.outcome.is_none() {
+            self.stats.games_skipped += 1;
+            pgn::ControlFlow::Skip
+        } else {
+            self.stats.games_read += 1;
+            pgn::ControlFlow::Continue
+        }
+    }
+
+    fn enter_variation(&mut self) -> pgn::ControlFlow {
+        pgn::ControlFlow::Skip
+    }
+
+    fn visit_move(&mut self, _number: Option<u32>, mv: SanMove) -> Result<(), ExtractError> {
+        if (self.no_eval || self.eval.is_some() || self.eval_dropped)
+            && let Some(outcome) = self.outcome
+            && self
+                .filters
+                .iter()
+                .all(|filter| filter.keep(&self.position, &mv))
+        {
+            self.samples.push(Sample {
+                position: self.position.clone(),
+                outcome,
+                eval: self.eval,
+            });
+            self.stats.positions_kept += 1;
+        }
+
+        self.position
+            .play(&mv)
+            .map_err(|source| ExtractError::IllegalMove {
+                fen: self.position.fen().to_string(),
+                mv: mv.to_string(),
+                source,
+            })?;
+        self.eval = None;
+        self.eval_dropped = false;
+        self.stats.positions_seen += 1;
+        Ok(())
+    }
+
+    fn visit_comment(&mut self, comment: &[u8]) -> Result<(), ExtractError> {
+        if self.no_eval {
+            return Ok(());
+        }
+        let Some(eval) = self.eval_parser.parse(str::from_utf8(comment)?) else {
+            return Ok(());
+        };
+
+        // The comment follows a move, so the side to move is the one about to reply.
+        let side_to_move = self.position.side_to_move();
+        if let Some(outcome) = self.outcome {
+            self.votes.record(eval, side_to_move, outcome);
+        }
+        let eval = (eval * 100.0).round() as i32;
+        let eval = match (self.eval_sign, side_to_move) {
+            (EvalSign::White, Color::White) => eval,
+            _ => eval.saturating_neg(),
+        };
+        (self.eval, self.eval_dropped) = match self.eval_overflow.fit(eval)? {
+            FittedEval::Eval(eval) => (Some(eval), false),
+            FittedEval::NoEval => (None, true),
+            FittedEval::Skip => (None, false),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EvalSign, Extractor};
+    use dama::{Color, Outcome, pgn};
+    use dataformat::EvalOverflow;
+
+    // The abnormal termination of the first game doesn't carry over to the next.
+    const GAMES: &str = r#"[Event "?"]
+[Result "1/2-1/2"]
+[Termination "time forfeit"]
+
+1. c4 {+0.10/10 0.1s} 1/2-1/2
+
+[Event "?"]
+[Result "1-0"]
+
+1. e4 {+0.30/10 0.1s} e5 {-0.20/10 0.1s} 2. Qh5 {book} Nc6 {+0.50/12 0.1s}
+3. Bc4 {-0.10/12 0.1s} Nf6 {+1.00/12 0.1s} 4. Qxf7# {+M1/1 0.1s} 1-0
+
+[Event "?"]
+[Result "*"]
+
+1. d4 {+0.10/10 0.1s} *
+"#;
+
+    fn extract(mut extractor: Extractor) -> Extractor {
+        let mut reader = pgn::Reader::new(GAMES.as_bytes());
+        while reader.visit_game(&mut extractor).unwrap() {}
+        extractor
+    }
+
+    #[test]
+    fn extract_quiet_positions_with_evals() {
+        let mut extractor = extract(Extractor::default());
+        let samples = extractor.take_samples();
+        let stats = extractor.stats();
+        assert_eq!(stats.games_read, 1);
+        assert_eq!(stats.games_skipped, 2);
+        assert_eq!(stats.positions_seen, 7);
+        // 2... Nc6 follows a book move and 4. Qxf7# is a capture.
+        let evals: Vec<_> = samples.iter().map(|sample| sample.eval).collect();
+        assert_eq!(evals, [Some(-30), Some(20), Some(-50), Some(10)]);
+        assert!(
+            samples
+                .iter()
+                .all(|sample| sample.outcome == Outcome::Winner(Color::White))
+        );
+        assert_eq!(
+            samples[0].position.side_to_move(),
+            Color::Black,
+            "evals are from the side to move's perspective"
+        );
+    }
+
+    #[test]
+    fn extract_white_evals_and_without_evals() {
+        let mut extractor = extract(Extractor::default().with_eval_sign(EvalSign::White));
+        let evals: Vec<_> = extractor
+            .take_samples()
+            .iter()
+            .map(|sample| sample.eval)
+            .collect();
+        assert_eq!(evals, [Some(-30), Some(-20), Some(50), Some(10)]);
+
+        let mut extractor = extract(Extractor::default().without_evals(true));
+        let samples = extractor.take_samples();
+        assert_eq!(samples.len(), 6);
+        assert!(samples.iter().all(|sample| sample.eval.is_none()));
+    }
+
+    #[test]
+    fn extract_evals_past_the_packed_range() {
+        const GAME: &str = r#"[Event "?"]
+[Result "0-1"]
+
+1. e4 {-500.00/20 0.1s} e5 {+0.20/20 0.1s} 2. Nf3 0-1
+"#;
+        let evals = |overflow| {
+            let mut extractor = Extractor::default().with_eval_overflow(overflow);
+            let mut reader = pgn::Reader::new(GAME.as_bytes());
+            reader.visit_game(&mut extractor).map(|_| {
+                let samples = extractor.take_samples();
+                samples.iter().map(|sample| sample.eval).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(
+            evals(EvalOverflow::Clamp).unwrap(),
+            [Some(32767), Some(-20)]
+        );
+        assert_eq!(evals(EvalOverflow::DropEval).unwrap(), [None, Some(-20)]);
+        assert_eq!(evals(EvalOverflow::Skip).unwrap(), [Some(-20)]);
+        assert!(evals(EvalOverflow::Error).is_err());
+    }
+}
@@ -12,6 +12,20 @@ class Batch:
     non_stm_features: torch.Tensor
     evals: torch.Tensor
     outcomes: torch.Tensor
+    eval_scores: torch.Tensor
+    targets: torch.Tensor
+    weights: torch.Tensor
+    fens: list[str] | None = None
+    piece_counts: torch.Tensor | None = None
+    phases: torch.Tensor | None = None
+
+class _Overlap(ctypes.Structure):
+    _fields_ = [
+        ("samples", ctypes.c_uint64),
+        ("contaminated", ctypes.c_uint64),
+        ("reference_positions", ctypes.c_uint64),
+        ("shared_positions", ctypes.c_uint64),
+    ]
 
 def load_data_lib():
     lib = ctypes.cdll.LoadLibrary(
@@ -22,15 +36,76 @@ def load_data_lib():
     lib.batch_capacity.restype = ctypes.c_uint32
     lib.batch_size.restype = ctypes.c_uint32
     lib.batch_total_features.restype = ctypes.c_uint32
+    lib.batch_truncated_samples.restype = ctypes.c_uint32
+    lib.batch_truncated_features.restype = ctypes.c_uint32
+    lib.batch_fen.argtypes = [ctypes.c_void_p, ctypes.c_uint32]
+    lib.batch_fen.restype = ctypes.c_char_p
+    lib.batch_piece_counts.restype = ctypes.POINTER(ctypes.c_uint32)
+    lib.batch_phases.restype = ctypes.POINTER(ctypes.c_uint32)
     lib.batch_stm_features.restype = ctypes.POINTER(ctypes.c_uint32)
     lib.batch_non_stm_features.restype = ctypes.POINTER(ctypes.c_uint32)
     lib.batch_evals.restype = ctypes.POINTER(ctypes.c_float)
     lib.batch_outcomes.restype = ctypes.POINTER(ctypes.c_float)
+    lib.batch_outcome_width.restype = ctypes.c_uint32
+    lib.batch_eval_scores.restype = ctypes.POINTER(ctypes.c_float)
+    lib.batch_targets.restype = ctypes.POINTER(ctypes.c_float)
+    lib.batch_weights.restype = ctypes.POINTER(ctypes.c_float)
     lib.open_loader.restype = ctypes.c_void_p
+    lib.open_loader_with_options.restype = ctypes.c_void_p
+    lib.open_loader_sharded.restype = ctypes.c_void_p
+    lib.reload_loader.restype = ctypes.c_bool
+    lib.loader_max_features.restype = ctypes.c_uint32
+    lib.loader_options_new.restype = ctypes.c_void_p
+    lib.loader_options_set_eval_weight.argtypes = [ctypes.c_void_p, ctypes.c_float]
+    lib.loader_options_set_keep_fens.argtypes = [ctypes.c_void_p, ctypes.c_bool]
+    lib.loader_options_set_keep_material.argtypes = [ctypes.c_void_p, ctypes.c_bool]
+    lib.loader_options_set_outcome_smoothing.argtypes = [ctypes.c_void_p, ctypes.c_float]
+    lib.loader_options_set_eval_temperature.argtypes = [ctypes.c_void_p, ctypes.c_float]
+    lib.loader_options_set_ply_ramp.argtypes = [ctypes.c_void_p, ctypes.c_uint32]
+    lib.loader_options_set_eval_agreement_weight.argtypes = [ctypes.c_void_p, ctypes.c_float]
+    lib.loader_options_set_adjudicated_weight.argtypes = [ctypes.c_void_p, ctypes.c_float]
+    lib.loader_options_add_weight_rule.restype = ctypes.c_bool
+    lib.loader_options_add_filter.restype = ctypes.c_bool
+    lib.loader_set_filters.restype = ctypes.c_bool
+    lib.loader_options_set_eval_perspective.restype = ctypes.c_bool
+    lib.loader_options_set_missing_eval.restype = ctypes.c_bool
+    lib.loader_options_set_outcome_encoding.restype = ctypes.c_bool
+    lib.loader_options_set_stratification.restype = ctypes.c_bool
+    lib.loader_options_set_augmentation.restype = ctypes.c_bool
+    lib.loader_options_set_seed.argtypes = [ctypes.c_void_p, ctypes.c_uint64]
+    lib.loader_options_set_partition.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.c_uint32]
+    lib.loader_options_set_partition.restype = ctypes.c_bool
+    lib.set_resource_limits.argtypes = [ctypes.c_uint64, ctypes.c_uint64]
+    lib.check_overlap.restype = ctypes.c_bool
+    lib.loader_state_words.restype = ctypes.c_uint32
+    lib.loader_state.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_uint64)]
+    lib.loader_restore.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_uint64)]
+    lib.loader_restore.restype = ctypes.c_bool
+    lib.loader_options_set_wdl_model.restype = ctypes.c_bool
+    lib.loader_options_set_exclusions.restype = ctypes.c_bool
+    if hasattr(lib, "batch_arrow_ipc"):
+        lib.batch_arrow_ipc.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_size_t)]
+        lib.batch_arrow_ipc.restype = ctypes.c_void_p
+        lib.drop_arrow_ipc.argtypes = [ctypes.c_void_p, ctypes.c_size_t]
     return lib
 
 lib = load_data_lib()
 
+def set_resource_limits(max_open_files: int | None = None, max_buffer_bytes: int | None = None):
+    lib.set_resource_limits(max_open_files or 0, max_buffer_bytes or 0)
+
+def check_overlap(dataset: str, reference: str) -> dict:
+    overlap = _Overlap()
+    if not lib.check_overlap(ctypes.create_string_buffer(bytes(dataset, "ascii")), ctypes.create_string_buffer(bytes(reference, "ascii")), ctypes.byref(overlap)):
+        raise Exception(f"failed to check '{dataset}' against '{reference}'")
+    return {
+        "samples": overlap.samples,
+        "contaminated": overlap.contaminated,
+        "rate": overlap.contaminated / max(overlap.samples, 1),
+        "reference_positions": overlap.reference_positions,
+        "shared_positions": overlap.shared_positions,
+    }
+
 class _Batch:
     def __init__(self, ptr):
         self._ptr = ptr
@@ -52,6 +127,30 @@ class _Batch:
     def total_features(self) -> int:
         return ctypes.c_uint32(lib.batch_total_features(self._ptr)).value
 
+    def truncated_samples(self) -> int:
+        return ctypes.c_uint32(lib.batch_truncated_samples(self._ptr)).value
+
+    def truncated_features(self) -> int:
+        return ctypes.c_uint32(lib.batch_truncated_features(self._ptr)).value
+
+    def fens(self) -> list[str] | None:
+        size = self.size()
+        if size == 0 or lib.batch_fen(self._ptr, 0) is None:
+            return None
+        return [lib.batch_fen(self._ptr, i).decode("ascii") for i in range(size)]
+
+    def piece_counts(self) -> torch.Tensor | None:
+        return self._material(lib.batch_piece_counts(self._ptr))
+
+    def phases(self) -> torch.Tensor | None:
+        return self._material(lib.batch_phases(self._ptr))
+
+    def _material(self, ptr) -> torch.Tensor | None:
+        size = self.size()
+        if size == 0 or not ptr:
+            return None
+        return torch.from_numpy(np.ctypeslib.as_array(ptr, shape=(size, 1)).astype(np.int64))
+
     def stm_features(self):
         return lib.batch_stm_features(self._ptr)
 
@@ -64,10 +163,39 @@ class _Batch:
     def outcomes(self):
         return lib.batch_outcomes(self._ptr)
 
+    def outcome_width(self) -> int:
+        return ctypes.c_uint32(lib.batch_outcome_width(self._ptr)).value
+
+    def eval_scores(self):
+        return lib.batch_eval_scores(self._ptr)
+
+    def targets(self):
+        return lib.batch_targets(self._ptr)
+
+    def weights(self):
+        return lib.batch_weights(self._ptr)
+
+    def to_arrow(self):
+        import pyarrow
+        if not hasattr(lib, "batch_arrow_ipc"):
+            raise Exception("the dataloader was built without the arrow feature")
+        size = ctypes.c_size_t()
+        ptr = lib.batch_arrow_ipc(self._ptr, ctypes.byref(size))
+        if ptr is None:
+            raise Exception("failed to convert batch to arrow")
+        try:
+            stream = ctypes.string_at(ptr, size.value)
+        finally:
+            lib.drop_arrow_ipc(ptr, size)
+        return pyarrow.ipc.open_stream(stream).read_next_batch()
+
     def to_torch(self) -> Batch:
         size = self.size()
         evals = torch.from_numpy(np.ctypeslib.as_array(self.evals(), shape=(size, 1)))
-        outcomes = torch.from_numpy(np.ctypeslib.as_array(self.outcomes(), shape=(size, 1)))
+        outcomes = torch.from_numpy(np.ctypeslib.as_array(self.outcomes(), shape=(size, self.outcome_width())))
+        eval_scores = torch.from_numpy(np.ctypeslib.as_array(self.eval_scores(), shape=(size, 1)))
+        targets = torch.from_numpy(np.ctypeslib.as_array(self.targets(), shape=(size, 1)))
+        weights = torch.from_numpy(np.ctypeslib.as_array(self.weights(), shape=(size, 1)))
         
         active_features = self.total_features()
         stm_indices = torch.transpose(
@@ -91,36 +219,135 @@ class _Batch:
             size=size,
             evals=evals,
             outcomes=outcomes,
+            eval_scores=eval_scores,
+            targets=targets,
+            weights=weights,
             stm_features=stm_features,
             non_stm_features=non_stm_features,
+            fens=self.fens(),
+            piece_counts=self.piece_counts(),
+            phases=self.phases(),
         )
 
+class _LoaderOptions:
+    def __init__(self, eval_weight: float, wdl_model: str | None, outcome_smoothing: float = 0.0, eval_temperature: float = 1.0, exclude: str | None = None, weighting: dict | None = None, eval_perspective: str | None = None, missing_eval: str | None = None, keep_fens: bool = False, outcome_encoding: str | None = None, keep_material: bool = False, filters: list[str] | None = None, stratification: str | None = None, seed: int | None = None, augmentation: str | None = None, rank: int = 0, world_size: int = 1):
+        self._ptr = ctypes.c_void_p(lib.loader_options_new())
+        lib.loader_options_set_keep_fens(self._ptr, keep_fens)
+        lib.loader_options_set_keep_material(self._ptr, keep_material)
+        lib.loader_options_set_eval_weight(self._ptr, ctypes.c_float(eval_weight))
+        lib.loader_options_set_outcome_smoothing(self._ptr, ctypes.c_float(outcome_smoothing))
+        lib.loader_options_set_eval_temperature(self._ptr, ctypes.c_float(eval_temperature))
+        if wdl_model is not None and not lib.loader_options_set_wdl_model(
+            self._ptr, ctypes.create_string_buffer(bytes(wdl_model, "ascii"))
+        ):
+            raise Exception(f"failed to load WDL model from file '{wdl_model}'")
+        if exclude is not None and not lib.loader_options_set_exclusions(
+            self._ptr, ctypes.create_string_buffer(bytes(exclude, "ascii"))
+        ):
+            raise Exception(f"failed to load position hashes from file '{exclude}'")
+        weighting = weighting or {}
+        if weighting.get("ply_ramp") is not None:
+            lib.loader_options_set_ply_ramp(self._ptr, ctypes.c_uint32(weighting["ply_ramp"]))
+        lib.loader_options_set_eval_agreement_weight(self._ptr, ctypes.c_float(weighting.get("eval_agreement", 0.0)))
+        lib.loader_options_set_adjudicated_weight(self._ptr, ctypes.c_float(weighting.get("adjudicated", 1.0)))
+        for rule in weighting.get("rules", []):
+            if not lib.loader_options_add_weight_rule(self._ptr, ctypes.create_string_buffer(bytes(rule, "ascii"))):
+                raise Exception(f"invalid weight rule '{rule}'")
+        if eval_perspective is not None and not lib.loader_options_set_eval_perspective(
+            self._ptr, ctypes.create_string_buffer(bytes(eval_perspective, "ascii"))
+        ):
+            raise Exception(f"unknown eval perspective '{eval_perspective}'")
+        if missing_eval is not None and not lib.loader_options_set_missing_eval(
+            self._ptr, ctypes.create_string_buffer(bytes(missing_eval, "ascii"))
+        ):
+            raise Exception(f"unknown missing eval handling '{missing_eval}'")
+        if outcome_encoding is not None and not lib.loader_options_set_outcome_encoding(
+            self._ptr, ctypes.create_string_buffer(bytes(outcome_encoding, "ascii"))
+        ):
+            raise Exception(f"unknown outcome encoding '{outcome_encoding}'")
+        for filter in filters or []:
+            if not lib.loader_options_add_filter(self._ptr, ctypes.create_string_buffer(bytes(filter, "ascii"))):
+                raise Exception(f"invalid filter '{filter}'")
+        if stratification is not None and not lib.loader_options_set_stratification(
+            self._ptr, ctypes.create_string_buffer(bytes(stratification, "ascii"))
+        ):
+            raise Exception(f"invalid stratification '{stratification}'")
+        if seed is not None:
+            lib.loader_options_set_seed(self._ptr, ctypes.c_uint64(seed))
+        if augmentation is not None and not lib.loader_options_set_augmentation(
+            self._ptr, ctypes.create_string_buffer(bytes(augmentation, "ascii"))
+        ):
+            raise Exception(f"invalid augmentation '{augmentation}'")
+        if world_size > 1 and not lib.loader_options_set_partition(self._ptr, rank, world_size):
+            raise Exception(f"rank {rank} is out of a world of {world_size}")
+
+    def __del__(self):
+        lib.loader_options_free(self._ptr)
+
 class _BatchLoader:
-    def __init__(self, path: str, batch_size: int):
-        self._ptr = ctypes.c_void_p(lib.open_loader(
+    def __init__(self, path: str, batch_size: int, options: _LoaderOptions):
+        self._ptr = ctypes.c_void_p(lib.open_loader_with_options(
             ctypes.create_string_buffer(bytes(path, "ascii")), 
-            ctypes.c_uint32(batch_size)
+            ctypes.c_uint32(batch_size),
+            options._ptr
             ))
         if self._ptr.value is None:
             raise Exception(f"failed to load data from file '{path}'")
 
+    def reload(self, path: str):
+        if not lib.reload_loader(self._ptr, ctypes.create_string_buffer(bytes(path, "ascii"))):
+            raise Exception(f"failed to load data from file '{path}'")
+
+    def set_filters(self, filters: list[str]):
+        if not lib.loader_set_filters(self._ptr, ctypes.create_string_buffer(bytes(",".join(filters), "ascii"))):
+            raise Exception(f"invalid filters '{','.join(filters)}'")
+
+    def state(self) -> list[int]:
+        words = (ctypes.c_uint64 * lib.loader_state_words())()
+        lib.loader_state(self._ptr, words)
+        return list(words)
+
+    def restore(self, state: list[int]):
+        words = (ctypes.c_uint64 * lib.loader_state_words())(*state)
+        if len(state) != len(words) or not lib.loader_restore(self._ptr, words):
+            raise Exception("loader state is not one of this dataset")
+
     def close(self):
         if self._ptr.value is not None:
             lib.close_loader(self._ptr)
             self._ptr.value = None
 
+    def max_features(self) -> int:
+        return ctypes.c_uint32(lib.loader_max_features(self._ptr)).value
+
     def load(self) -> _Batch:
         return _Batch(ctypes.c_void_p(lib.load_batch(self._ptr)))
 
 class NnueDataset(torch.utils.data.IterableDataset):
-    def __init__(self, path: str, batch_size: int, epoch_size: int):
+    def __init__(self, path: str, batch_size: int, epoch_size: int, eval_weight: float = 0.0, wdl_model: str | None = None, outcome_smoothing: float = 0.0, eval_temperature: float = 1.0, exclude: str | None = None, weighting: dict | None = None, eval_perspective: str | None = None, missing_eval: str | None = None, keep_fens: bool = False, outcome_encoding: str | None = None, keep_material: bool = False, filters: list[str] | None = None, stratification: str | None = None, seed: int | None = None, augmentation: str | None = None, rank: int = 0, world_size: int = 1):
         self._last_batch = None
-        self._loader = _BatchLoader(path, batch_size)
+        self._loader = _BatchLoader(path, batch_size, _LoaderOptions(eval_weight, wdl_model, outcome_smoothing, eval_temperature, exclude, weighting, eval_perspective, missing_eval, keep_fens, outcome_encoding, keep_material, filters, stratification, seed, augmentation, rank, world_size))
         self.batches = (epoch_size + batch_size - 1) // batch_size
 
     def __del__(self):
         self._loader.close()
 
+    def reload(self, path: str):
+        self._loader.reload(path)
+
+    def set_filters(self, filters: list[str]):
+        self._loader.set_filters(filters)
+
+    def state(self) -> list[int]:
+        return self._loader.state()
+
+    def restore(self, state: list[int]):
+        self._loader.restore(state)
+
+    def to_arrow(self, batches: int):
+        import pyarrow
+        return pyarrow.Table.from_batches([self._loader.load().to_arrow() for _ in range(batches)])
+
     def __len__(self):
         return self.batches
 
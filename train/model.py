@@ -47,10 +47,12 @@ def quantize_output_biases(tensor: torch.Tensor):
         tensor.detach().numpy() * OUTPUT_WEIGHT_SCALING * OUTPUT_SCALING
         ).astype('<i4').flatten()
 
-def cross_entropy_loss(target, prediction):
+def cross_entropy_loss(target, prediction, weights=None):
     epsilon = 1e-9
     bce = target * torch.log(target + epsilon) + (1 - target) * torch.log(1 - target + epsilon) \
         - target * torch.log(prediction + epsilon) - (1 - target) * torch.log(1 - prediction + epsilon)
+    if weights is not None:
+        bce = bce * weights
     return bce.mean()
 
 
@@ -89,13 +91,12 @@ class NNUE(pl.LightningModule):
 
     def _step(self, batch, batch_idx):
         self._clip_weights()
-        target_scaling = 400
         prediction = torch.sigmoid(self(batch))
-        target_eval = torch.sigmoid(batch.evals / target_scaling)
+        target_eval = batch.eval_scores
         target_outcome = batch.outcomes
 
-        loss_eval = cross_entropy_loss(target_eval, prediction)
-        loss_outcome = cross_entropy_loss(target_outcome, prediction)
+        loss_eval = cross_entropy_loss(target_eval, prediction, batch.weights)
+        loss_outcome = cross_entropy_loss(target_outcome, prediction, batch.weights)
         loss = self.eval_weight * loss_eval + (1.0 - self.eval_weight) * loss_outcome
 
         return loss
@@ -106,9 +107,8 @@ class NNUE(pl.LightningModule):
         return loss
 
     def validation_step(self, batch, batch_idx):
-        target_scaling = 400
         prediction = torch.sigmoid(self(batch))
-        target_eval = torch.sigmoid(batch.evals / target_scaling)
+        target_eval = batch.eval_scores
         target_outcome = batch.outcomes
 
         loss_eval = cross_entropy_loss(target_eval, prediction)
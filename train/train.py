@@ -4,9 +4,9 @@ import pytorch_lightning as pl
 import model as m
 import data
 
-def open_dataloaders(train_path: str, val_path: str, batch_size: int, epoch_size: int, val_size: int) -> tuple[DataLoader, DataLoader]:
-    train_loader = DataLoader(data.NnueDataset(train_path, batch_size, epoch_size), batch_size=None, sampler=None)
-    val_loader = DataLoader(data.NnueDataset(val_path, batch_size, val_size), batch_size=None, sampler=None)
+def open_dataloaders(train_path: str, val_path: str, batch_size: int, epoch_size: int, val_size: int, eval_weight: float, wdl_model: str | None, outcome_smoothing: float, eval_temperature: float, exclude: str | None, weighting: dict, eval_perspective: str | None, missing_eval: str) -> tuple[DataLoader, DataLoader]:
+    train_loader = DataLoader(data.NnueDataset(train_path, batch_size, epoch_size, eval_weight, wdl_model, outcome_smoothing, eval_temperature, exclude, weighting, eval_perspective, missing_eval), batch_size=None, sampler=None)
+    val_loader = DataLoader(data.NnueDataset(val_path, batch_size, val_size, eval_weight, wdl_model, eval_perspective=eval_perspective, missing_eval=missing_eval), batch_size=None, sampler=None)
     return train_loader, val_loader
 
 def main():
@@ -25,12 +25,32 @@ def main():
     parser.add_argument('--epoch-size', type=int, default=1000000, help='Number of samples in each training epoch')
     parser.add_argument('--val-size', type=int, default=1000000, help='Number of validation samples')
     parser.add_argument('--eval-weight', type=float, default=0.0, help='0.0 to train on game results and 1.0 to train on engine evaluations, values in between interpolate between both')
+    parser.add_argument('--wdl-model', type=str, default=None, help='WDL model fitted by `datatools fit-wdl` turning evaluations into expected scores, a 400 cp sigmoid is used by default')
+    parser.add_argument('--outcome-smoothing', type=float, default=0.0, help='Share of the outcome target moved towards a draw when training')
+    parser.add_argument('--eval-temperature', type=float, default=1.0, help='Temperature dividing evaluations before they become expected scores when training')
+    parser.add_argument('--exclude', type=str, default=None, help='File of position hashes written by `datatools export-hashes` left out of the training data')
+    parser.add_argument('--ply-ramp', type=int, default=None, help='Weighs samples in linearly over this many plies from the start of the game')
+    parser.add_argument('--eval-agreement-weight', type=float, default=0.0, help='Lowers the weight of samples whose eval score disagrees with the outcome')
+    parser.add_argument('--adjudicated-weight', type=float, default=1.0, help='Weight of samples from adjudicated games')
+    parser.add_argument('--weight-rule', type=str, action='append', default=[], help='Scales the weight of matching samples, e.g. `ply<16:0.5`')
+    parser.add_argument('--eval-perspective', type=str, default=None, choices=['side-to-move', 'white'], help='Whose point of view the datasets\' evals are given from, instead of what their manifests say')
+    parser.add_argument('--missing-eval', type=str, default='outcome', choices=['outcome', 'skip'], help='Whether samples without an evaluation are trained on their outcome alone or left out')
+    parser.add_argument('--check-overlap', action='store_true', help='Reports how many training samples hold positions of the validation set before training')
     parser.add_argument('-o', "--output", type=str, help='Output file for the trained network')
     args = parser.parse_args()
 
+    if args.check_overlap:
+        overlap = data.check_overlap(args.dataset, args.val_dataset)
+        print(f"{overlap['contaminated']} of {overlap['samples']} training samples ({overlap['rate']:.2%}) hold {overlap['shared_positions']} of the {overlap['reference_positions']} validation positions")
+
     model = m.NNUE(lr=args.lr, eval_weight=args.eval_weight)
     trainer = pl.Trainer(max_epochs=args.epochs)
-    train_data, val_data = open_dataloaders(args.dataset, args.val_dataset, args.batch_size, args.epoch_size, args.val_size)
+    train_data, val_data = open_dataloaders(args.dataset, args.val_dataset, args.batch_size, args.epoch_size, args.val_size, args.eval_weight, args.wdl_model, args.outcome_smoothing, args.eval_temperature, args.exclude, {
+        "ply_ramp": args.ply_ramp,
+        "eval_agreement": args.eval_agreement_weight,
+        "adjudicated": args.adjudicated_weight,
+        "rules": args.weight_rule,
+    }, args.eval_perspective, args.missing_eval)
     trainer.fit(model, train_data, val_data)
     model.write_to_file(args.output)
 
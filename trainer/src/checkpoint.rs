@@ -0,0 +1,125 @@
+use anyhow::Context;
+use candle_core::Device;
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    model::Model,
+    network::{Network, read_u32},
+    optimizer::AdamW,
+};
+
+/// Magic bytes at the start of a checkpoint file.
+const CHECKPOINT_MAGIC: [u8; 4] = *b"TTCK";
+
+/// Everything needed to resume training: the model, the optimizer state and how many
+/// epochs have been completed.
+pub struct Checkpoint {
+    pub model: Model,
+    pub optimizer: AdamW,
+    pub epochs_done: usize,
+}
+
+/// Saves a checkpoint, writing to a temporary file first so that an interrupted save
+/// never leaves a truncated checkpoint behind.
+pub fn save(
+    path: &Path,
+    model: &Model,
+    optimizer: &AdamW,
+    epochs_done: usize,
+) -> anyhow::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let file = File::create(&temp_path)
+        .with_context(|| format!("failed to create file `{}`", temp_path.display()))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&CHECKPOINT_MAGIC)?;
+    writer.write_all(&(epochs_done as u32).to_le_bytes())?;
+    model.to_network()?.write(&mut writer)?;
+    optimizer.write(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&temp_path, path)
+        .with_context(|| format!("failed to write checkpoint `{}`", path.display()))?;
+    Ok(())
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path, weight_decay: f64, device: &Device) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open file `{}`", path.display()))?;
+        let mut reader = BufReader::new(file);
+        Self::read(&mut reader, weight_decay, device)
+            .with_context(|| format!("failed to read checkpoint `{}`", path.display()))
+    }
+
+    fn read(reader: &mut impl Read, weight_decay: f64, device: &Device) -> anyhow::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != CHECKPOINT_MAGIC {
+            anyhow::bail!("not a checkpoint file");
+        }
+        let epochs_done = read_u32(reader)? as usize;
+        let model = Model::new(&Network::read(reader)?, device)?;
+        let mut optimizer = AdamW::new(&model, weight_decay)?;
+        optimizer.read(reader)?;
+        Ok(Checkpoint {
+            model,
+            optimizer,
+            epochs_done,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(3);
+        let network = Network::new(16, 4, &mut rng);
+        let model = Model::new(&network, &Device::Cpu).unwrap();
+        let mut optimizer = AdamW::new(&model, 0.01).unwrap();
+        let loss = model
+            .vars()
+            .iter()
+            .map(|(var, _)| var.sqr().unwrap().sum_all().unwrap())
+            .reduce(|sum, term| (sum + term).unwrap())
+            .unwrap();
+        for _ in 0..3 {
+            optimizer.step(&loss.backward().unwrap(), 1e-3).unwrap();
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("trainer-checkpoint-{}.bin", std::process::id()));
+        save(&path, &model, &optimizer, 7).unwrap();
+        let checkpoint = Checkpoint::load(&path, 0.01, &Device::Cpu).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(checkpoint.epochs_done, 7);
+        let (saved, loaded) = (
+            model.to_network().unwrap(),
+            checkpoint.model.to_network().unwrap(),
+        );
+        assert_eq!(loaded.feature_count(), 16);
+        assert_eq!(loaded.hidden(), 4);
+        assert_eq!(loaded.params(), saved.params());
+        let (mut saved, mut loaded) = (Vec::new(), Vec::new());
+        optimizer.write(&mut saved).unwrap();
+        checkpoint.optimizer.write(&mut loaded).unwrap();
+        assert_eq!(saved, loaded);
+    }
+
+    #[test]
+    fn load_rejects_other_files() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(4);
+        let mut bytes = Vec::new();
+        Network::new(16, 4, &mut rng).write(&mut bytes).unwrap();
+        assert!(Checkpoint::read(&mut bytes.as_slice(), 0.0, &Device::Cpu).is_err());
+    }
+}
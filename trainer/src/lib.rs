@@ -0,0 +1,5 @@
+pub mod checkpoint;
+pub mod model;
+pub mod network;
+pub mod optimizer;
+pub mod schedule;
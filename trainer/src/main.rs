@@ -0,0 +1,574 @@
+use anyhow::Context;
+use candle_core::Device;
+use clap::Parser;
+use dataformat::{
+    EvalPerspective,
+    manifest::{self, FileEntry, Manifest, Source},
+};
+use dataloader::{
+    exclude::{self, ExclusionFilter},
+    feature::FeatureSet,
+    loader::{BatchLoader, LoaderOptions, MissingEval, OutcomeEncoding},
+    wdl::WdlModel,
+    weight::{SampleWeighting, WeightRule},
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use trainer::{
+    checkpoint::{self, Checkpoint},
+    model::{BatchInputs, Model},
+    network::Network,
+    optimizer::AdamW,
+    schedule::{LrSchedule, Scheduler},
+};
+
+#[derive(Parser)]
+#[command(version, about("Trains NNUE networks for the Teras chess engine"))]
+struct Options {
+    #[clap(
+        long("dataset"),
+        help("Training dataset, a file or a directory of shards.")
+    )]
+    dataset: PathBuf,
+    #[clap(
+        long("val-dataset"),
+        help("Validation dataset, evaluated after every epoch.")
+    )]
+    val_dataset: Option<PathBuf>,
+    #[clap(
+        short('o'),
+        long("output"),
+        help("Output file for the trained float network.")
+    )]
+    output: PathBuf,
+    #[clap(
+        long("hidden"),
+        default_value_t = 256,
+        help("Size of the feature transformer output for each perspective.")
+    )]
+    hidden: usize,
+    #[clap(
+        long("feature-set"),
+        default_value_t,
+        help("Input encoding of the positions, e.g. `chess768-threats` to add attacked squares.")
+    )]
+    feature_set: FeatureSet,
+    #[clap(long("epochs"), default_value_t = 10)]
+    epochs: usize,
+    #[clap(
+        long("epoch-size"),
+        default_value_t = 100_000_000,
+        help("Number of samples in each training epoch.")
+    )]
+    epoch_size: usize,
+    #[clap(
+        long("val-size"),
+        default_value_t = 1_000_000,
+        help("Number of validation samples.")
+    )]
+    val_size: usize,
+    #[clap(long("batch-size"), default_value_t = 16384)]
+    batch_size: usize,
+    #[clap(long("lr"), default_value_t = 0.001, help("Initial learning rate."))]
+    lr: f32,
+    #[clap(long("lr-schedule"), value_enum, default_value_t)]
+    lr_schedule: LrSchedule,
+    #[clap(
+        long("lr-decay"),
+        default_value_t = 0.98,
+        help("Learning rate factor applied after every epoch with the exponential schedule.")
+    )]
+    lr_decay: f32,
+    #[clap(
+        long("min-lr"),
+        default_value_t = 0.0,
+        help("Final learning rate of the cosine schedule.")
+    )]
+    min_lr: f32,
+    #[clap(long("weight-decay"), default_value_t = 0.01)]
+    weight_decay: f32,
+    #[clap(
+        long("eval-weight"),
+        default_value_t = 0.0,
+        help(
+            "0 trains on game outcomes and 1 on engine evaluations, values in between blend both."
+        )
+    )]
+    eval_weight: f32,
+    #[clap(
+        long("wdl-model"),
+        help(
+            "WDL model fitted by `datatools fit-wdl` turning evaluations into expected scores, a 400 cp sigmoid is used by default."
+        )
+    )]
+    wdl_model: Option<PathBuf>,
+    #[clap(
+        long("exclude"),
+        help(
+            "File of position hashes written by `datatools export-hashes`, such as those of the validation set, left out of training."
+        )
+    )]
+    exclude: Option<PathBuf>,
+    #[clap(
+        long("check-overlap"),
+        requires("val_dataset"),
+        help(
+            "Reports how many training samples hold positions of the validation set before training, reading the training data once more."
+        )
+    )]
+    check_overlap: bool,
+    #[clap(
+        long("skip-adjudicated"),
+        help("Leaves out samples from games whose outcome was adjudicated instead of played out.")
+    )]
+    skip_adjudicated: bool,
+    #[clap(
+        long("adjudicated-outcome-scale"),
+        conflicts_with("skip_adjudicated"),
+        help(
+            "Scales the outcome's share of the target for samples from adjudicated games, the evaluation makes up the rest."
+        )
+    )]
+    adjudicated_outcome_scale: Option<f32>,
+    #[clap(
+        long("outcome-smoothing"),
+        default_value_t = 0.0,
+        help("Share of the outcome target moved towards a draw, 0.1 turns a win into 0.95.")
+    )]
+    outcome_smoothing: f32,
+    #[clap(
+        long("eval-temperature"),
+        help(
+            "Divides evaluations before they become expected scores, values above 1 soften the eval targets."
+        )
+    )]
+    eval_temperature: Option<f32>,
+    #[clap(
+        long("eval-perspective"),
+        help(
+            "Whose point of view the datasets' evals are given from, `side-to-move` or `white`, instead of what their manifests say."
+        )
+    )]
+    eval_perspective: Option<EvalPerspective>,
+    #[clap(
+        long("missing-eval"),
+        default_value_t,
+        help(
+            "What becomes of samples without an evaluation, `outcome` trains them on their outcome alone and `skip` leaves them out."
+        )
+    )]
+    missing_eval: MissingEval,
+    #[clap(
+        long("ply-ramp"),
+        help("Weighs samples in linearly over this many plies from the start of the game.")
+    )]
+    ply_ramp: Option<u32>,
+    #[clap(
+        long("eval-agreement-weight"),
+        default_value_t = 0.0,
+        help(
+            "Lowers the weight of samples whose eval score disagrees with the outcome, 1 zeroes those predicting the opposite outcome."
+        )
+    )]
+    eval_agreement_weight: f32,
+    #[clap(
+        long("adjudicated-weight"),
+        default_value_t = 1.0,
+        conflicts_with("skip_adjudicated"),
+        help("Weight of samples from adjudicated games.")
+    )]
+    adjudicated_weight: f32,
+    #[clap(
+        long("weight-rule"),
+        help(
+            "Scales the weight of matching samples, e.g. `ply<16:0.5`. Fields are ply, phase, pieces, eval and abs-eval."
+        )
+    )]
+    weight_rules: Vec<WeightRule>,
+    #[clap(
+        long("checkpoint-dir"),
+        help("Directory where a checkpoint is saved after every `--save-every` epochs.")
+    )]
+    checkpoint_dir: Option<PathBuf>,
+    #[clap(long("save-every"), default_value_t = 1)]
+    save_every: usize,
+    #[clap(long("resume"), help("Checkpoint to resume training from."))]
+    resume: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let options = Options::parse();
+    if options.batch_size == 0 {
+        anyhow::bail!("--batch-size must be at least 1");
+    }
+    if !(0.0..=1.0).contains(&options.eval_weight) {
+        anyhow::bail!("--eval-weight must be between 0 and 1");
+    }
+    if options
+        .adjudicated_outcome_scale
+        .is_some_and(|scale| !(0.0..=1.0).contains(&scale))
+    {
+        anyhow::bail!("--adjudicated-outcome-scale must be between 0 and 1");
+    }
+    if !(0.0..=1.0).contains(&options.outcome_smoothing) {
+        anyhow::bail!("--outcome-smoothing must be between 0 and 1");
+    }
+    if options
+        .eval_temperature
+        .is_some_and(|temperature| temperature <= 0.0)
+    {
+        anyhow::bail!("--eval-temperature must be positive");
+    }
+    if !(0.0..=1.0).contains(&options.eval_agreement_weight) {
+        anyhow::bail!("--eval-agreement-weight must be between 0 and 1");
+    }
+    if options.adjudicated_weight < 0.0 {
+        anyhow::bail!("--adjudicated-weight must not be negative");
+    }
+
+    let feature_set = options.feature_set;
+    let device = Device::Cpu;
+    let weight_decay = options.weight_decay as f64;
+    let Checkpoint {
+        model,
+        mut optimizer,
+        epochs_done,
+    } = match &options.resume {
+        Some(path) => {
+            let checkpoint = Checkpoint::load(path, weight_decay, &device)?;
+            if checkpoint.model.feature_count() != feature_set.feature_count() {
+                anyhow::bail!(
+                    "checkpoint `{}` was not trained with the {} feature set",
+                    path.display(),
+                    feature_set
+                );
+            }
+            checkpoint
+        }
+        None => {
+            let network = Network::new(
+                feature_set.feature_count(),
+                options.hidden,
+                &mut rand::rng(),
+            );
+            let model = Model::new(&network, &device)?;
+            Checkpoint {
+                optimizer: AdamW::new(&model, weight_decay)?,
+                model,
+                epochs_done: 0,
+            }
+        }
+    };
+
+    let wdl_model = match &options.wdl_model {
+        Some(path) => WdlModel::load(path)
+            .with_context(|| format!("failed to read WDL model `{}`", path.display()))?,
+        None => WdlModel::default(),
+    };
+    let exclusions = match &options.exclude {
+        Some(path) => {
+            let filter = ExclusionFilter::load(path)
+                .with_context(|| format!("failed to read position hashes `{}`", path.display()))?;
+            println!(
+                "excluding {} positions listed in `{}`",
+                filter.len(),
+                path.display()
+            );
+            Some(Arc::new(filter))
+        }
+        None => None,
+    };
+    let loader_options = LoaderOptions {
+        wdl_model,
+        eval_weight: options.eval_weight,
+        feature_set,
+        skip_adjudicated: options.skip_adjudicated,
+        adjudicated_outcome_scale: options.adjudicated_outcome_scale,
+        outcome_smoothing: options.outcome_smoothing,
+        // The network trains on the blended targets, which don't depend on it.
+        outcome_encoding: OutcomeEncoding::Score,
+        eval_temperature: options.eval_temperature,
+        exclusions,
+        weighting: SampleWeighting {
+            ply_ramp: options.ply_ramp,
+            eval_agreement: options.eval_agreement_weight,
+            adjudicated: options.adjudicated_weight,
+            rules: options.weight_rules.clone(),
+        },
+        eval_perspective: options.eval_perspective,
+        missing_eval: options.missing_eval,
+        keep_fens: false,
+        keep_material: false,
+        filters: Vec::new(),
+        stratification: None,
+        seed: None,
+        augmentation: None,
+        partition: None,
+        transforms: Vec::new(),
+    };
+    describe_dataset("training", &options.dataset)?;
+    if let Some(path) = &options.val_dataset {
+        describe_dataset("validation", path)?;
+        if options.check_overlap {
+            report_overlap(&options.dataset, path, options.exclude.is_some())?;
+        }
+    }
+    let mut train_loader =
+        BatchLoader::with_options(&options.dataset, options.batch_size, loader_options.clone())
+            .with_context(|| format!("failed to open dataset `{}`", options.dataset.display()))?;
+    let mut val_loader = options
+        .val_dataset
+        .as_ref()
+        .map(|path| {
+            // Validation loss is measured against the unsmoothed targets with every
+            // sample weighing the same, and the exclusions are usually the validation
+            // set itself.
+            let loader_options = LoaderOptions {
+                outcome_smoothing: 0.0,
+                eval_temperature: None,
+                exclusions: None,
+                weighting: SampleWeighting::default(),
+                ..loader_options.clone()
+            };
+            BatchLoader::with_options(path, options.batch_size, loader_options)
+                .with_context(|| format!("failed to open dataset `{}`", path.display()))
+        })
+        .transpose()?;
+    if let Some(dir) = &options.checkpoint_dir {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory `{}`", dir.display()))?;
+    }
+
+    let scheduler = Scheduler {
+        schedule: options.lr_schedule,
+        lr: options.lr,
+        decay: options.lr_decay,
+        min_lr: options.min_lr,
+        epochs: options.epochs,
+    };
+    let batches = options.epoch_size.div_ceil(options.batch_size);
+
+    for epoch in epochs_done..options.epochs {
+        let lr = scheduler.lr(epoch);
+        let progress = ProgressBar::new(batches as u64)
+            .with_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise:.yellow}] [{bar:20}] {msg} {pos}/{len} batches ({eta})",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message(format!("epoch {}/{}", epoch + 1, options.epochs));
+
+        let mut train_loss = 0.0;
+        for _ in 0..batches {
+            let batch = BatchInputs::new(&train_loader.load(), &device)?;
+            let loss = model.loss(&batch)?;
+            optimizer.step(&loss.backward()?, lr as f64)?;
+            train_loss += loss.to_scalar::<f32>()? as f64;
+            progress.inc(1);
+        }
+        progress.finish_and_clear();
+        train_loss /= batches.max(1) as f64;
+
+        let val_loss = match val_loader.as_mut() {
+            Some(loader) => {
+                let val_batches = options.val_size.div_ceil(options.batch_size).max(1);
+                let mut loss = 0.0;
+                for _ in 0..val_batches {
+                    let batch = BatchInputs::new(&loader.load(), &device)?;
+                    loss += model.loss(&batch)?.to_scalar::<f32>()? as f64;
+                }
+                Some(loss / val_batches as f64)
+            }
+            None => None,
+        };
+        match val_loss {
+            Some(val_loss) => println!(
+                "epoch {}: train loss {:.6}, validation loss {:.6}, lr {:.2e}",
+                epoch + 1,
+                train_loss,
+                val_loss,
+                lr
+            ),
+            None => println!(
+                "epoch {}: train loss {:.6}, lr {:.2e}",
+                epoch + 1,
+                train_loss,
+                lr
+            ),
+        }
+
+        if let Some(dir) = &options.checkpoint_dir
+            && ((epoch + 1) % options.save_every.max(1) == 0 || epoch + 1 == options.epochs)
+        {
+            let path = dir.join(format!("epoch-{:04}.ckpt", epoch + 1));
+            checkpoint::save(&path, &model, &optimizer, epoch + 1)?;
+        }
+    }
+
+    model.to_network()?.save(&options.output)?;
+    println!("network written to `{}`", options.output.display());
+    write_manifest(&options)?;
+    Ok(())
+}
+
+/// Prints how a dataset was generated, as recorded in its manifest.
+fn describe_dataset(role: &str, path: &Path) -> anyhow::Result<()> {
+    let manifest = Manifest::find(path)
+        .with_context(|| format!("failed to read the manifest of `{}`", path.display()))?;
+    match manifest {
+        Some(manifest) => println!(
+            "{} dataset `{}`: {} samples from `{}` ({:016x}), evals from the {} perspective",
+            role,
+            path.display(),
+            manifest.samples(),
+            manifest.setting("command").unwrap_or("unknown"),
+            manifest.dataset_hash(),
+            manifest
+                .eval_perspective()
+                .with_context(|| format!("invalid manifest for `{}`", path.display()))?
+        ),
+        None => println!("{} dataset `{}`: no manifest", role, path.display()),
+    }
+    Ok(())
+}
+
+/// Reports how much of the training data repeats the validation set, whose positions
+/// are only left out with exclusions.
+fn report_overlap(dataset: &Path, validation: &Path, excluded: bool) -> anyhow::Result<()> {
+    let overlap = exclude::check_overlap(dataset, validation).with_context(|| {
+        format!(
+            "failed to check `{}` against `{}`",
+            dataset.display(),
+            validation.display()
+        )
+    })?;
+    println!(
+        "{} of {} training samples ({:.3}%) hold {} of the {} validation positions",
+        overlap.contaminated,
+        overlap.samples,
+        overlap.rate() * 100.0,
+        overlap.shared_positions,
+        overlap.reference_positions
+    );
+    if overlap.contaminated > 0 && !excluded {
+        eprintln!(
+            "warning: the validation loss is measured partly on positions trained on, consider `--exclude`"
+        );
+    }
+    Ok(())
+}
+
+/// Records the datasets and settings a network was trained with next to it.
+fn write_manifest(options: &Options) -> anyhow::Result<()> {
+    let mut generation = vec![
+        ("command".to_string(), "trainer".to_string()),
+        (
+            manifest::COMMAND_LINE_SETTING.to_string(),
+            manifest::command_line(),
+        ),
+        (
+            "trainer_version".to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        ),
+        ("hidden".to_string(), options.hidden.to_string()),
+        ("feature_set".to_string(), options.feature_set.to_string()),
+        ("epochs".to_string(), options.epochs.to_string()),
+        ("epoch_size".to_string(), options.epoch_size.to_string()),
+        ("batch_size".to_string(), options.batch_size.to_string()),
+        ("lr".to_string(), options.lr.to_string()),
+        ("weight_decay".to_string(), options.weight_decay.to_string()),
+        ("eval_weight".to_string(), options.eval_weight.to_string()),
+    ];
+    if let Some(path) = &options.wdl_model {
+        generation.push(("wdl_model".to_string(), path.display().to_string()));
+    }
+    if let Some(path) = &options.exclude {
+        generation.push(("exclude".to_string(), path.display().to_string()));
+    }
+    if options.skip_adjudicated {
+        generation.push(("skip_adjudicated".to_string(), "true".to_string()));
+    }
+    if let Some(scale) = options.adjudicated_outcome_scale {
+        generation.push(("adjudicated_outcome_scale".to_string(), scale.to_string()));
+    }
+    if options.outcome_smoothing > 0.0 {
+        generation.push((
+            "outcome_smoothing".to_string(),
+            options.outcome_smoothing.to_string(),
+        ));
+    }
+    if let Some(temperature) = options.eval_temperature {
+        generation.push(("eval_temperature".to_string(), temperature.to_string()));
+    }
+    if let Some(perspective) = options.eval_perspective {
+        generation.push(("eval_perspective".to_string(), perspective.to_string()));
+    }
+    if options.missing_eval != MissingEval::default() {
+        generation.push(("missing_eval".to_string(), options.missing_eval.to_string()));
+    }
+    if let Some(plies) = options.ply_ramp {
+        generation.push(("ply_ramp".to_string(), plies.to_string()));
+    }
+    if options.eval_agreement_weight > 0.0 {
+        generation.push((
+            "eval_agreement_weight".to_string(),
+            options.eval_agreement_weight.to_string(),
+        ));
+    }
+    if options.adjudicated_weight != 1.0 {
+        generation.push((
+            "adjudicated_weight".to_string(),
+            options.adjudicated_weight.to_string(),
+        ));
+    }
+    if !options.weight_rules.is_empty() {
+        let rules: Vec<_> = options
+            .weight_rules
+            .iter()
+            .map(|rule| rule.to_string())
+            .collect();
+        generation.push(("weight_rules".to_string(), rules.join(", ")));
+    }
+
+    let mut sources = Vec::new();
+    for path in std::iter::once(&options.dataset).chain(&options.val_dataset) {
+        let hash = Manifest::find(path)
+            .with_context(|| format!("failed to read the manifest of `{}`", path.display()))?
+            .map(|manifest| manifest.dataset_hash());
+        sources.push(Source {
+            path: path.display().to_string(),
+            hash,
+        });
+    }
+
+    let output = &options.output;
+    let network = FileEntry {
+        name: output
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        samples: 0,
+        bytes: fs::metadata(output)?.len(),
+        hash: manifest::hash_file(output)
+            .with_context(|| format!("failed to hash `{}`", output.display()))?,
+    };
+    let history = manifest::history_of(&sources)
+        .context("failed to read the history of the training data")?;
+    let manifest = Manifest {
+        generation,
+        files: vec![network],
+        sources,
+        history,
+    };
+    let path = Manifest::path_for(output);
+    manifest
+        .save(&path)
+        .with_context(|| format!("failed to write manifest `{}`", path.display()))
+}
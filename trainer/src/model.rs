@@ -0,0 +1,297 @@
+use candle_core::{DType, Device, Result, Tensor, Var};
+use dataloader::batch::Batch;
+
+use crate::network::Network;
+
+/// Keeps the logarithms of the cross entropy finite for predictions of exactly 0 or 1.
+const EPSILON: f64 = 1e-9;
+
+/// The trainable form of a [`Network`], its layers held as candle variables so their
+/// gradients are found by backpropagation.
+pub struct Model {
+    feature_count: usize,
+    hidden: usize,
+    /// One row of `hidden` weights per feature.
+    ft_weight: Var,
+    ft_bias: Var,
+    /// A column of `2 * hidden` weights, those of the side to move's accumulator first.
+    out_weight: Var,
+    out_bias: Var,
+}
+
+/// The tensors of a batch of samples the model is trained and evaluated on.
+pub struct BatchInputs {
+    len: usize,
+    /// The sample of every active feature in `stm_features`.
+    stm_entries: Tensor,
+    stm_features: Tensor,
+    /// The sample of every active feature in `non_stm_features`.
+    non_stm_entries: Tensor,
+    non_stm_features: Tensor,
+    targets: Tensor,
+    weights: Tensor,
+}
+
+impl BatchInputs {
+    /// Copies a batch from the loader to `device`.
+    pub fn new(batch: &Batch, device: &Device) -> Result<Self> {
+        let split = |pairs: &[u32]| -> Result<(Tensor, Tensor)> {
+            let (entries, features): (Vec<u32>, Vec<u32>) =
+                pairs.chunks_exact(2).map(|pair| (pair[0], pair[1])).unzip();
+            Ok((
+                Tensor::new(entries, device)?,
+                Tensor::new(features, device)?,
+            ))
+        };
+        let (stm_entries, stm_features) = split(batch.stm_features())?;
+        let (non_stm_entries, non_stm_features) = split(batch.non_stm_features())?;
+        Ok(BatchInputs {
+            len: batch.len(),
+            stm_entries,
+            stm_features,
+            non_stm_entries,
+            non_stm_features,
+            targets: Tensor::new(batch.targets(), device)?,
+            weights: Tensor::new(batch.weights(), device)?,
+        })
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Model {
+    /// Copies the parameters of a network to `device` to train them.
+    pub fn new(network: &Network, device: &Device) -> Result<Self> {
+        let (features, hidden) = (network.feature_count(), network.hidden());
+        Ok(Model {
+            feature_count: features,
+            hidden,
+            ft_weight: Var::from_slice(network.feature_weights(), (features, hidden), device)?,
+            ft_bias: Var::from_slice(network.feature_biases(), hidden, device)?,
+            out_weight: Var::from_slice(network.output_weights(), (2 * hidden, 1), device)?,
+            out_bias: Var::from_slice(&[network.output_bias()], 1, device)?,
+        })
+    }
+
+    #[inline]
+    pub fn feature_count(&self) -> usize {
+        self.feature_count
+    }
+
+    #[inline]
+    pub fn hidden(&self) -> usize {
+        self.hidden
+    }
+
+    /// The network with the current parameters.
+    pub fn to_network(&self) -> anyhow::Result<Network> {
+        let output_bias = self.out_bias.flatten_all()?.to_vec1::<f32>()?[0];
+        Network::from_layers(
+            self.feature_count,
+            self.hidden,
+            &self.ft_weight.flatten_all()?.to_vec1()?,
+            &self.ft_bias.to_vec1()?,
+            &self.out_weight.flatten_all()?.to_vec1()?,
+            output_bias,
+        )
+    }
+
+    /// Every variable, in the order of [`Network::params`], with whether weight decay
+    /// applies to it: it does to the weights of the layers but not to their biases.
+    pub fn vars(&self) -> Vec<(Var, bool)> {
+        vec![
+            (self.ft_weight.clone(), true),
+            (self.ft_bias.clone(), false),
+            (self.out_weight.clone(), true),
+            (self.out_bias.clone(), false),
+        ]
+    }
+
+    /// The raw outputs before the sigmoid, one per sample.
+    pub fn forward(&self, inputs: &BatchInputs) -> Result<Tensor> {
+        let stm = self.accumulate(inputs, &inputs.stm_entries, &inputs.stm_features)?;
+        let non_stm = self.accumulate(inputs, &inputs.non_stm_entries, &inputs.non_stm_features)?;
+        let hidden = Tensor::cat(&[stm, non_stm], 1)?.clamp(0f32, 1f32)?;
+        hidden
+            .matmul(self.out_weight.as_tensor())?
+            .squeeze(1)?
+            .broadcast_add(self.out_bias.as_tensor())
+    }
+
+    /// The mean cross entropy between the predictions and the targets blended by the
+    /// loader from evaluations and outcomes, each sample's scaled by the weight the
+    /// loader gave it.
+    pub fn loss(&self, inputs: &BatchInputs) -> Result<Tensor> {
+        let predictions = candle_nn::ops::sigmoid(&self.forward(inputs)?)?;
+        let targets = &inputs.targets;
+        let misses = (1.0 - targets)?;
+        // The entropy of the targets makes the loss 0 for perfect predictions.
+        let entropy = ((targets * (targets + EPSILON)?.log()?)?
+            + (&misses * (&misses + EPSILON)?.log()?)?)?;
+        let cross = ((targets * (&predictions + EPSILON)?.log()?)?
+            + (&misses * (1.0 - &predictions)?.affine(1.0, EPSILON)?.log()?)?)?;
+        let losses = ((entropy - cross)? * &inputs.weights)?;
+        losses.sum_all()? / inputs.len().max(1) as f64
+    }
+
+    /// Sums the feature transformer rows of the active features of every sample.
+    fn accumulate(
+        &self,
+        inputs: &BatchInputs,
+        entries: &Tensor,
+        features: &Tensor,
+    ) -> Result<Tensor> {
+        let rows = self.ft_weight.index_select(features, 0)?;
+        Tensor::zeros(
+            (inputs.len(), self.hidden),
+            DType::F32,
+            self.ft_weight.device(),
+        )?
+        .index_add(entries, &rows, 0)?
+        .broadcast_add(self.ft_bias.as_tensor())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dama::{Outcome, Position};
+    use dataformat::{Adjudication, Sample};
+    use dataloader::{feature::FeatureSet, loader::LoaderOptions};
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    const FENS: [(&str, Outcome, i16); 4] = [
+        (
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+            Outcome::Draw,
+            -30,
+        ),
+        (
+            "r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4",
+            Outcome::Winner(dama::Color::White),
+            45,
+        ),
+        (
+            "8/5k2/8/8/3K4/8/4P3/8 w - - 0 1",
+            Outcome::Winner(dama::Color::White),
+            300,
+        ),
+        (
+            "6k1/5ppp/8/8/8/8/r4PPP/6K1 b - - 0 1",
+            Outcome::Winner(dama::Color::Black),
+            250,
+        ),
+    ];
+
+    fn batch() -> Batch {
+        let options = LoaderOptions {
+            eval_weight: 0.5,
+            ..LoaderOptions::default()
+        };
+        let mut batch = Batch::new(FENS.len(), options.feature_set);
+        for (fen, outcome, eval) in FENS {
+            let sample = Sample {
+                position: Position::from_fen(fen).unwrap(),
+                outcome,
+                eval: Some(eval),
+            };
+            batch.add(&sample, Adjudication::None, &options);
+        }
+        batch
+    }
+
+    fn network(hidden: usize, seed: u64) -> Network {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+        Network::new(FeatureSet::default().feature_count(), hidden, &mut rng)
+    }
+
+    #[test]
+    fn forward_matches_the_network() {
+        let batch = batch();
+        let network = network(8, 2);
+        let model = Model::new(&network, &Device::Cpu).unwrap();
+        let outputs = model
+            .forward(&BatchInputs::new(&batch, &Device::Cpu).unwrap())
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        for (entry, (fen, _, _)) in FENS.iter().enumerate() {
+            let (mut stm, mut non_stm) = (Vec::new(), Vec::new());
+            FeatureSet::default().active_features(&Position::from_fen(fen).unwrap(), |s, n| {
+                stm.push(s);
+                non_stm.push(n);
+            });
+            let expected = network.evaluate(&stm, &non_stm);
+            assert!((outputs[entry] - expected).abs() < 1e-5, "{}", fen);
+        }
+    }
+
+    #[test]
+    fn gradient_matches_finite_differences() {
+        let batch = batch();
+        let inputs = BatchInputs::new(&batch, &Device::Cpu).unwrap();
+        let mut network = network(8, 0x5EED);
+        let model = Model::new(&network, &Device::Cpu).unwrap();
+        let grads = model.loss(&inputs).unwrap().backward().unwrap();
+        let grads = model.to_gradient(&grads);
+
+        // Every parameter the batch has a gradient for, and some it doesn't touch.
+        let ft_biases = network.feature_count() * 8;
+        let mut checked: Vec<usize> = (ft_biases..network.params().len()).collect();
+        for pair in batch.stm_features().chunks_exact(2) {
+            let feature = pair[1] as usize;
+            checked.extend(feature * 8..(feature + 1) * 8);
+        }
+        checked.extend(0..8);
+
+        let loss = |network: &Network| -> f64 {
+            let model = Model::new(network, &Device::Cpu).unwrap();
+            model.loss(&inputs).unwrap().to_scalar::<f32>().unwrap() as f64
+        };
+        const STEP: f32 = 1e-3;
+        for index in checked {
+            let param = network.params()[index];
+            network.params_mut()[index] = param + STEP;
+            let above = loss(&network);
+            network.params_mut()[index] = param - STEP;
+            let below = loss(&network);
+            network.params_mut()[index] = param;
+            let numeric = (above - below) / (2.0 * STEP as f64);
+            let analytic = grads[index] as f64;
+            assert!(
+                (numeric - analytic).abs() <= 1e-4 + 1e-2 * analytic.abs(),
+                "parameter {}: analytic gradient {} but numeric {}",
+                index,
+                analytic,
+                numeric
+            );
+        }
+    }
+
+    impl Model {
+        /// The gradient of every parameter, laid out like [`Network::params`].
+        fn to_gradient(&self, grads: &candle_core::backprop::GradStore) -> Vec<f32> {
+            [
+                &self.ft_weight,
+                &self.ft_bias,
+                &self.out_weight,
+                &self.out_bias,
+            ]
+            .into_iter()
+            .flat_map(|var| match grads.get(var) {
+                Some(grad) => grad.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+                None => vec![0.0; var.elem_count()],
+            })
+            .collect()
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use anyhow::Context;
+use rand::Rng;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    ops::Range,
+    path::Path,
+};
+
+/// Magic bytes at the start of a float network file.
+const NETWORK_MAGIC: [u8; 4] = *b"TTNN";
+const NETWORK_VERSION: u32 = 1;
+
+/// A `(features -> hidden) x 2 -> 1` network: one feature transformer shared by both
+/// perspectives, whose clipped outputs are concatenated, side to move first, and fed
+/// into a single output neuron.
+///
+/// All parameters live in one flat buffer, which is what gets saved; training works on
+/// a [`Model`](crate::model::Model) of the same layers. The layout is, in order: feature
+/// transformer weights (one row of `hidden` weights per feature), feature transformer
+/// biases, output weights, output bias.
+#[derive(Clone, Debug)]
+pub struct Network {
+    feature_count: usize,
+    hidden: usize,
+    params: Vec<f32>,
+}
+
+impl Network {
+    /// Creates a network with uniformly initialized parameters, like PyTorch's linear layers.
+    pub fn new(feature_count: usize, hidden: usize, rng: &mut impl Rng) -> Self {
+        let mut network = Network {
+            feature_count,
+            hidden,
+            params: vec![0.0; Self::param_count(feature_count, hidden)],
+        };
+        let ft_bound = 1.0 / (feature_count as f32).sqrt();
+        let out_bound = 1.0 / (2.0 * hidden as f32).sqrt();
+        for (range, bound) in [
+            (network.ft_weights(), ft_bound),
+            (network.ft_biases(), ft_bound),
+            (network.out_weights(), out_bound),
+            (network.out_bias(), out_bound),
+        ] {
+            for param in &mut network.params[range] {
+                *param = rng.random_range(-bound..bound);
+            }
+        }
+        network
+    }
+
+    #[inline]
+    pub fn param_count(feature_count: usize, hidden: usize) -> usize {
+        feature_count * hidden + hidden + 2 * hidden + 1
+    }
+
+    #[inline]
+    pub fn feature_count(&self) -> usize {
+        self.feature_count
+    }
+
+    #[inline]
+    pub fn hidden(&self) -> usize {
+        self.hidden
+    }
+
+    #[inline]
+    pub fn params(&self) -> &[f32] {
+        &self.params
+    }
+
+    #[inline]
+    pub fn params_mut(&mut self) -> &mut [f32] {
+        &mut self.params
+    }
+
+    #[inline]
+    fn ft_weights(&self) -> Range<usize> {
+        0..self.feature_count * self.hidden
+    }
+
+    #[inline]
+    fn ft_biases(&self) -> Range<usize> {
+        let start = self.feature_count * self.hidden;
+        start..start + self.hidden
+    }
+
+    #[inline]
+    fn out_weights(&self) -> Range<usize> {
+        let start = self.feature_count * self.hidden + self.hidden;
+        start..start + 2 * self.hidden
+    }
+
+    #[inline]
+    fn out_bias(&self) -> Range<usize> {
+        let start = self.feature_count * self.hidden + 3 * self.hidden;
+        start..start + 1
+    }
+
+    /// Evaluates a position given the indices of its active features from both
+    /// perspectives, returning the raw output before the sigmoid.
+    pub fn evaluate(&self, stm: &[u32], non_stm: &[u32]) -> f32 {
+        let mut accumulators = vec![0.0; 2 * self.hidden];
+        self.accumulate(stm.iter().copied(), &mut accumulators[..self.hidden]);
+        self.accumulate(non_stm.iter().copied(), &mut accumulators[self.hidden..]);
+        self.output(&accumulators)
+    }
+
+    /// Feature transformer weights, one row of [`Network::hidden`] weights per feature.
+    #[inline]
+    pub fn feature_weights(&self) -> &[f32] {
+        &self.params[self.ft_weights()]
+    }
+
+    #[inline]
+    pub fn feature_biases(&self) -> &[f32] {
+        &self.params[self.ft_biases()]
+    }
+
+    /// Output weights, those of the side to move's accumulator first.
+    #[inline]
+    pub fn output_weights(&self) -> &[f32] {
+        &self.params[self.out_weights()]
+    }
+
+    #[inline]
+    pub fn output_bias(&self) -> f32 {
+        self.params[self.out_bias()][0]
+    }
+
+    fn accumulate(&self, features: impl Iterator<Item = u32>, accumulator: &mut [f32]) {
+        accumulator.copy_from_slice(&self.params[self.ft_biases()]);
+        let weights = &self.params[self.ft_weights()];
+        for feature in features {
+            let row = feature as usize * self.hidden;
+            for (acc, weight) in accumulator.iter_mut().zip(&weights[row..row + self.hidden]) {
+                *acc += weight;
+            }
+        }
+    }
+
+    fn output(&self, accumulators: &[f32]) -> f32 {
+        let weights = &self.params[self.out_weights()];
+        let mut output = self.params[self.out_bias()][0];
+        for (acc, weight) in accumulators.iter().zip(weights) {
+            output += acc.clamp(0.0, 1.0) * weight;
+        }
+        output
+    }
+
+    /// Writes the float network, to be quantized into the engine's format later.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create file `{}`", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open file `{}`", path.display()))?;
+        Self::read(&mut BufReader::new(file))
+            .with_context(|| format!("failed to read network `{}`", path.display()))
+    }
+
+    /// Builds a network from separately stored layers, as laid out by [`Network::params`].
+    pub fn from_layers(
+        feature_count: usize,
+        hidden: usize,
+        feature_weights: &[f32],
+        feature_biases: &[f32],
+        output_weights: &[f32],
+        output_bias: f32,
+    ) -> anyhow::Result<Self> {
+        if feature_weights.len() != feature_count * hidden
+            || feature_biases.len() != hidden
+            || output_weights.len() != 2 * hidden
+        {
+            anyhow::bail!(
+                "layer sizes don't match a {}x{} network",
+                feature_count,
+                hidden
+            );
+        }
+        let mut params = Vec::with_capacity(Self::param_count(feature_count, hidden));
+        params.extend_from_slice(feature_weights);
+        params.extend_from_slice(feature_biases);
+        params.extend_from_slice(output_weights);
+        params.push(output_bias);
+        Ok(Network {
+            feature_count,
+            hidden,
+            params,
+        })
+    }
+
+    pub(crate) fn write(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        writer.write_all(&NETWORK_MAGIC)?;
+        writer.write_all(&NETWORK_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.feature_count as u32).to_le_bytes())?;
+        writer.write_all(&(self.hidden as u32).to_le_bytes())?;
+        write_floats(writer, &self.params)?;
+        Ok(())
+    }
+
+    pub(crate) fn read(reader: &mut impl Read) -> anyhow::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != NETWORK_MAGIC {
+            anyhow::bail!("not a network file");
+        }
+        let version = read_u32(reader)?;
+        if version != NETWORK_VERSION {
+            anyhow::bail!("unsupported network version {}", version);
+        }
+        let feature_count = read_u32(reader)? as usize;
+        let hidden = read_u32(reader)? as usize;
+        let mut params = vec![0.0; Self::param_count(feature_count, hidden)];
+        read_floats(reader, &mut params)?;
+        Ok(Network {
+            feature_count,
+            hidden,
+            params,
+        })
+    }
+}
+
+pub(crate) fn write_floats(writer: &mut impl Write, floats: &[f32]) -> std::io::Result<()> {
+    for float in floats {
+        writer.write_all(&float.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_floats(reader: &mut impl Read, floats: &mut [f32]) -> std::io::Result<()> {
+    let mut bytes = [0; 4];
+    for float in floats {
+        reader.read_exact(&mut bytes)?;
+        *float = f32::from_le_bytes(bytes);
+    }
+    Ok(())
+}
+
+pub(crate) fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
@@ -0,0 +1,147 @@
+use candle_core::{Result, Tensor, Var, backprop::GradStore};
+use std::io::{Read, Write};
+
+use crate::{
+    model::Model,
+    network::{read_floats, write_floats},
+};
+
+/// Adam with decoupled weight decay, which only applies to the weights of the layers:
+/// decaying the biases would pull the outputs towards 0 for no gain in generalization.
+pub struct AdamW {
+    pub beta1: f64,
+    pub beta2: f64,
+    pub epsilon: f64,
+    pub weight_decay: f64,
+    step: u64,
+    params: Vec<Param>,
+}
+
+struct Param {
+    var: Var,
+    decay: bool,
+    moment: Tensor,
+    velocity: Tensor,
+}
+
+impl AdamW {
+    pub fn new(model: &Model, weight_decay: f64) -> Result<Self> {
+        let params = model
+            .vars()
+            .into_iter()
+            .map(|(var, decay)| {
+                Ok(Param {
+                    moment: var.zeros_like()?,
+                    velocity: var.zeros_like()?,
+                    var,
+                    decay,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            epsilon: 1e-8,
+            weight_decay,
+            step: 0,
+            params,
+        })
+    }
+
+    /// Takes one optimization step with the given learning rate. Variables without a
+    /// gradient are left as they are.
+    pub fn step(&mut self, grads: &GradStore, lr: f64) -> Result<()> {
+        self.step += 1;
+        let correction1 = 1.0 - self.beta1.powi(self.step.min(i32::MAX as u64) as i32);
+        let correction2 = 1.0 - self.beta2.powi(self.step.min(i32::MAX as u64) as i32);
+        for param in &mut self.params {
+            let Some(grad) = grads.get(&param.var) else {
+                continue;
+            };
+            param.moment = ((&param.moment * self.beta1)? + (grad * (1.0 - self.beta1))?)?;
+            param.velocity =
+                ((&param.velocity * self.beta2)? + (grad.sqr()? * (1.0 - self.beta2))?)?;
+            let moment = (&param.moment / correction1)?;
+            let velocity = (&param.velocity / correction2)?;
+            let mut update = (moment / (velocity.sqrt()? + self.epsilon)?)?;
+            if param.decay {
+                update = (update + (param.var.as_tensor() * self.weight_decay)?)?;
+            }
+            param.var.set(&param.var.sub(&(update * lr)?)?)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        writer.write_all(&self.step.to_le_bytes())?;
+        for param in &self.params {
+            write_floats(writer, &param.moment.flatten_all()?.to_vec1()?)?;
+        }
+        for param in &self.params {
+            write_floats(writer, &param.velocity.flatten_all()?.to_vec1()?)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read(&mut self, reader: &mut impl Read) -> anyhow::Result<()> {
+        let mut step = [0; 8];
+        reader.read_exact(&mut step)?;
+        self.step = u64::from_le_bytes(step);
+        for param in &mut self.params {
+            param.moment = read_tensor(reader, &param.var)?;
+        }
+        for param in &mut self.params {
+            param.velocity = read_tensor(reader, &param.var)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a tensor shaped like `var`.
+fn read_tensor(reader: &mut impl Read, var: &Var) -> anyhow::Result<Tensor> {
+    let mut floats = vec![0.0; var.elem_count()];
+    read_floats(reader, &mut floats)?;
+    Ok(Tensor::from_vec(floats, var.shape(), var.device())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use candle_core::{DType, Device};
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256PlusPlus;
+
+    #[test]
+    fn weight_decay_leaves_biases_alone() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(5);
+        let network = Network::new(16, 4, &mut rng);
+        let model = Model::new(&network, &Device::Cpu).unwrap();
+        let mut optimizer = AdamW::new(&model, 0.5).unwrap();
+        // A gradient of 0 for every variable, so only the weight decay moves them.
+        let mut loss = Tensor::zeros((), DType::F32, &Device::Cpu).unwrap();
+        for (var, _) in model.vars() {
+            let sum = var.sum_all().unwrap();
+            loss = (loss + (&sum - &sum).unwrap()).unwrap();
+        }
+        optimizer.step(&loss.backward().unwrap(), 0.1).unwrap();
+
+        let trained = model.to_network().unwrap();
+        for (before, after) in network
+            .feature_weights()
+            .iter()
+            .zip(trained.feature_weights())
+        {
+            assert!((before * 0.95 - after).abs() < 1e-6);
+        }
+        for (before, after) in network
+            .output_weights()
+            .iter()
+            .zip(trained.output_weights())
+        {
+            assert!((before * 0.95 - after).abs() < 1e-6);
+        }
+        assert_eq!(trained.feature_biases(), network.feature_biases());
+        assert_eq!(trained.output_bias(), network.output_bias());
+    }
+}
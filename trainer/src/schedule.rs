@@ -0,0 +1,35 @@
+use std::f32::consts::PI;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LrSchedule {
+    /// Keeps the initial learning rate.
+    Constant,
+    /// Multiplies the learning rate by `--lr-decay` after every epoch.
+    #[default]
+    Exponential,
+    /// Anneals the learning rate down to `--min-lr` along a cosine over all epochs.
+    Cosine,
+}
+
+/// Computes the learning rate of every epoch.
+#[derive(Clone, Copy, Debug)]
+pub struct Scheduler {
+    pub schedule: LrSchedule,
+    pub lr: f32,
+    pub decay: f32,
+    pub min_lr: f32,
+    pub epochs: usize,
+}
+
+impl Scheduler {
+    pub fn lr(&self, epoch: usize) -> f32 {
+        match self.schedule {
+            LrSchedule::Constant => self.lr,
+            LrSchedule::Exponential => self.lr * self.decay.powi(epoch as i32),
+            LrSchedule::Cosine => {
+                let progress = epoch as f32 / self.epochs.max(1) as f32;
+                self.min_lr + 0.5 * (self.lr - self.min_lr) * (1.0 + (PI * progress).cos())
+            }
+        }
+    }
+}